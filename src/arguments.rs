@@ -0,0 +1,180 @@
+//! The arguments and environment of programs, and the stack they start with.
+//!
+//! Programs start like on System V: the stack pointer points to `argc`, which
+//! is followed by the `argv` and `envp` arrays, each ending with a null pointer,
+//! and by the auxiliary vector of key and value pairs, which ends with `AT_NULL`.
+//! The strings and the random bytes of `AT_RANDOM` are above them.
+
+use core::ptr::NonNull;
+
+use crate::cpu;
+use crate::elf::{LoadedImage, PROGRAM_HEADER_ENTRY_SIZE};
+use crate::memory::addressspace::{AddressSpace, MapError};
+use crate::memory::pageallocator::{Page, PAGE_ALLOCATOR, PAGE_SIZE};
+use crate::random;
+
+/// The most bytes the strings can take up, with their terminating nulls.
+pub const MAX_STRINGS_SIZE: usize = PAGE_SIZE;
+/// The most arguments and variables a program can be given together.
+pub const MAX_STRINGS: usize = 256;
+
+const POINTER_SIZE: usize = 8;
+const STACK_ALIGNMENT: usize = 16;
+const RANDOM_SIZE: usize = 16;
+
+// auxiliary vector keys
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_HWCAP: u64 = 16;
+pub const AT_RANDOM: u64 = 25;
+const NUM_AUXILIARY_ENTRIES: usize = 8;
+
+#[derive(Debug)]
+pub enum ArgumentsError {
+    /// There are more than `MAX_STRINGS` strings, or they take up
+    /// more than `MAX_STRINGS_SIZE` bytes.
+    TooLarge,
+    OutOfMemory,
+}
+
+/// The arguments and environment variables of a program, copied into a page of
+/// the kernel so that they outlive the address space they came from.
+pub struct Arguments {
+    // allocated when the first string is added
+    page: Option<NonNull<Page>>,
+    len: usize,
+    num_arguments: usize,
+    num_variables: usize,
+}
+
+impl Arguments {
+    /// No arguments and an empty environment.
+    pub const fn new() -> Arguments {
+        Arguments {
+            page: None,
+            len: 0,
+            num_arguments: 0,
+            num_variables: 0,
+        }
+    }
+
+    /// Adds an argument, which can't be done after adding variables.
+    /// `string` must not contain nulls.
+    pub fn push_argument(&mut self, string: &[u8]) -> Result<(), ArgumentsError> {
+        assert_eq!(self.num_variables, 0);
+        self.push(string)?;
+        self.num_arguments += 1;
+        Ok(())
+    }
+
+    /// Adds an environment variable, which is usually of the form `NAME=value`.
+    /// `string` must not contain nulls.
+    pub fn push_variable(&mut self, string: &[u8]) -> Result<(), ArgumentsError> {
+        self.push(string)?;
+        self.num_variables += 1;
+        Ok(())
+    }
+
+    fn push(&mut self, string: &[u8]) -> Result<(), ArgumentsError> {
+        let end = self.len + string.len() + 1;
+        if self.num_arguments + self.num_variables >= MAX_STRINGS || end > MAX_STRINGS_SIZE {
+            return Err(ArgumentsError::TooLarge);
+        }
+        let page = match self.page {
+            Some(page) => page,
+            None => {
+                let page = PAGE_ALLOCATOR
+                    .lock()
+                    .alloc_page(1)
+                    .ok_or(ArgumentsError::OutOfMemory)?;
+                *self.page.insert(page)
+            }
+        };
+        unsafe {
+            let destination = page.as_ptr().cast::<u8>().add(self.len);
+            core::ptr::copy_nonoverlapping(string.as_ptr(), destination, string.len());
+            destination.add(string.len()).write(0);
+        }
+        self.len = end;
+        Ok(())
+    }
+
+    /// The arguments followed by the variables, each with a terminating null.
+    fn strings(&self) -> &[u8] {
+        match self.page {
+            Some(page) => unsafe {
+                core::slice::from_raw_parts(page.as_ptr().cast::<u8>(), self.len)
+            },
+            None => &[],
+        }
+    }
+}
+
+impl Drop for Arguments {
+    fn drop(&mut self) {
+        if let Some(page) = self.page {
+            unsafe { PAGE_ALLOCATOR.lock().free_page(page) };
+        }
+    }
+}
+
+/// Writes the initial stack of a program below `top` in `address_space`,
+/// and returns the stack pointer the program starts with.
+pub fn set_up_stack(
+    address_space: &AddressSpace,
+    top: usize,
+    arguments: &Arguments,
+    image: &LoadedImage,
+) -> Result<usize, MapError> {
+    let strings = arguments.strings();
+    let strings_address = top - strings.len();
+    let random_address = (strings_address - RANDOM_SIZE) / STACK_ALIGNMENT * STACK_ALIGNMENT;
+    let num_strings = arguments.num_arguments + arguments.num_variables;
+    // argc, the pointers and the null pointers after argv and envp, and the auxiliary vector
+    let vectors_size = (1 + num_strings + 2 + 2 * NUM_AUXILIARY_ENTRIES) * POINTER_SIZE;
+    let stack_pointer = (random_address - vectors_size) / STACK_ALIGNMENT * STACK_ALIGNMENT;
+
+    address_space.write(strings_address, strings)?;
+    let mut random_bytes = [0; RANDOM_SIZE];
+    random::fill(&mut random_bytes);
+    address_space.write(random_address, &random_bytes)?;
+
+    let mut address = stack_pointer;
+    let mut push = |value: u64| {
+        address_space.write(address, &value.to_le_bytes())?;
+        address += POINTER_SIZE;
+        Ok(())
+    };
+    push(arguments.num_arguments as u64)?;
+    let mut string_address = strings_address;
+    for (index, string) in strings.split_inclusive(|&byte| byte == 0).enumerate() {
+        if index == arguments.num_arguments {
+            push(0)?;
+        }
+        push(string_address as u64)?;
+        string_address += string.len();
+    }
+    if arguments.num_variables == 0 {
+        push(0)?;
+    }
+    push(0)?;
+
+    for (key, value) in [
+        (AT_PHDR, image.program_headers as u64),
+        (AT_PHENT, PROGRAM_HEADER_ENTRY_SIZE as u64),
+        (AT_PHNUM, image.num_program_headers as u64),
+        (AT_PAGESZ, PAGE_SIZE as u64),
+        (AT_ENTRY, image.entry),
+        (AT_HWCAP, cpu::user_hwcap()),
+        (AT_RANDOM, random_address as u64),
+        (AT_NULL, 0),
+    ] {
+        push(key)?;
+        push(value)?;
+    }
+    Ok(stack_pointer)
+}
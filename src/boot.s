@@ -28,6 +28,12 @@ _start:
     orr     x1, x1, #0b10
     msr     hcr_el2, x1
 
+    // allow el1 to access the physical counter and timer
+    // and make the virtual counter equal to the physical one
+    mov     x1, #0b11
+    msr     cnthctl_el2, x1
+    msr     cntvoff_el2, xzr
+
     // set stack pointer for execution level 1
     // stack grows from 0x80000 downward
     ldr     x1, =_start
@@ -42,3 +48,32 @@ _start:
     // return from "exception"
     // takes us to kernel_start
     eret
+
+// the start point of the secondary cores, which the firmware
+// releases from the spin table in el2 with the mmu disabled
+.globl _start_secondary
+_start_secondary:
+    mov     x1, #0b1111000101
+    msr     spsr_el2, x1
+
+    mov     x1, #(1 << 31)
+    orr     x1, x1, #0b10
+    msr     hcr_el2, x1
+
+    mov     x1, #0b11
+    msr     cnthctl_el2, x1
+    msr     cntvoff_el2, xzr
+
+    // each core has its own stack, set up
+    // by the boot core before waking this one
+    mrs     x0, mpidr_el1
+    and     x0, x0, #0xff
+    ldr     x1, =SECONDARY_STACK_TOPS
+    ldr     x1, [x1, x0, lsl #3]
+    msr     sp_el1, x1
+
+    // the core index is passed as the first argument
+    ldr     x1, =secondary_kernel_start
+    msr     elr_el2, x1
+
+    eret
@@ -3,7 +3,9 @@ use core::{
     mem::MaybeUninit,
 };
 
-use crate::nolock::NoLock;
+use spin::mutex::spin::SpinMutex;
+
+use crate::cpu::without_interrupts;
 
 mod font;
 mod framebuffer;
@@ -25,7 +27,23 @@ const FONT_COLOR: Color = Color {
     a: 255,
 };
 
-pub static CONSOLE: NoLock<MaybeUninit<Console>> = NoLock::new(MaybeUninit::uninit());
+// initialized by `kernel_start` before anything is printed
+pub static CONSOLE: SpinMutex<MaybeUninit<Console>> = SpinMutex::new(MaybeUninit::uninit());
+
+const OUTPUT_BUFFER_SIZE: usize = 4096;
+
+// output that is drawn later by the worker thread, since drawing is slow
+static OUTPUT_BUFFER: SpinMutex<OutputBuffer> = SpinMutex::new(OutputBuffer {
+    bytes: [0; OUTPUT_BUFFER_SIZE],
+    len: 0,
+    is_flush_queued: false,
+});
+
+struct OutputBuffer {
+    bytes: [u8; OUTPUT_BUFFER_SIZE],
+    len: usize,
+    is_flush_queued: bool,
+}
 
 pub struct Console {
     cur_row: u32,
@@ -35,6 +53,9 @@ pub struct Console {
     framebuffer: Framebuffer,
 }
 
+// the framebuffer is only drawn to through the lock of the console
+unsafe impl Send for Console {}
+
 impl Console {
     pub fn init() -> Console {
         let framebuffer = Framebuffer::init();
@@ -118,3 +139,51 @@ impl Write for Console {
         Ok(())
     }
 }
+
+/// Runs `f` with the console, with interrupts masked so that
+/// a handler that prints can't interrupt it on the same core.
+pub fn with_console<T>(f: impl FnOnce(&mut Console) -> T) -> T {
+    without_interrupts(|| {
+        let mut console = CONSOLE.lock();
+        f(unsafe { console.assume_init_mut() })
+    })
+}
+
+/// Buffers `bytes` to be written to the console by the worker thread,
+/// and returns how many of them fit in the buffer.
+pub fn write_deferred(bytes: &[u8]) -> usize {
+    let (written, should_queue_flush) = without_interrupts(|| {
+        let mut buffer = OUTPUT_BUFFER.lock();
+        let len = buffer.len;
+        let written = bytes.len().min(OUTPUT_BUFFER_SIZE - len);
+        buffer.bytes[len..len + written].copy_from_slice(&bytes[..written]);
+        buffer.len += written;
+        let should_queue_flush = !buffer.is_flush_queued;
+        buffer.is_flush_queued = true;
+        (written, should_queue_flush)
+    });
+    // without a worker thread the output is drawn right away
+    if should_queue_flush && !crate::thread::queue_work(flush_output) {
+        flush_output();
+    }
+    written
+}
+
+fn flush_output() {
+    let mut bytes = [0; OUTPUT_BUFFER_SIZE];
+    let len = without_interrupts(|| {
+        let mut buffer = OUTPUT_BUFFER.lock();
+        let len = buffer.len;
+        bytes[..len].copy_from_slice(&buffer.bytes[..len]);
+        buffer.len = 0;
+        buffer.is_flush_queued = false;
+        len
+    });
+
+    // the console is locked for one character at a time,
+    // so that drawing doesn't keep interrupts masked for long
+    for &byte in &bytes[..len] {
+        // writing to the framebuffer never fails
+        with_console(|console| console.write_char(byte as char)).unwrap();
+    }
+}
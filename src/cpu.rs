@@ -0,0 +1,135 @@
+use core::arch::asm;
+use core::mem::size_of;
+use core::ptr::addr_of;
+
+pub const NUM_CORES: usize = 4;
+
+/// Returns the index of the core this is running on.
+pub fn core_id() -> usize {
+    let affinity: u64;
+    unsafe { asm!("mrs {}, mpidr_el1", out(reg) affinity) };
+    (affinity & 0xff) as usize
+}
+
+/// Returns the `AT_HWCAP` bits of linux for the features user code can use.
+/// Floating point and SIMD are not enabled for user code, so they are left out
+/// along with the crypto instructions that use the SIMD registers.
+pub fn user_hwcap() -> u64 {
+    const HWCAP_CRC32: u64 = 1 << 7;
+    const HWCAP_ATOMICS: u64 = 1 << 8;
+
+    let features: u64;
+    unsafe { asm!("mrs {}, id_aa64isar0_el1", out(reg) features) };
+    let crc32 = (features >> 16) & 0xf;
+    let atomics = (features >> 20) & 0xf;
+    let mut hwcap = 0;
+    if crc32 >= 1 {
+        hwcap |= HWCAP_CRC32;
+    }
+    if atomics >= 2 {
+        hwcap |= HWCAP_ATOMICS;
+    }
+    hwcap
+}
+
+/// Runs `f` with IRQs and FIQs masked on the current core, so that it
+/// can't be interrupted by a handler that takes the same locks.
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let saved_mask: u64;
+    unsafe {
+        asm!("mrs {}, daif", out(reg) saved_mask);
+        asm!("msr daifset, #0b0011");
+    }
+    let result = f();
+    unsafe { asm!("msr daif, {}", in(reg) saved_mask) };
+    result
+}
+
+/// A set of cores, for example the cores a process is allowed to run on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CoreMask(u8);
+
+impl CoreMask {
+    pub const ALL: CoreMask = CoreMask((1 << NUM_CORES) - 1);
+
+    /// Returns `None` if `bits` contains cores that don't exist.
+    pub fn from_bits(bits: u64) -> Option<CoreMask> {
+        if bits & !(CoreMask::ALL.0 as u64) != 0 {
+            return None;
+        }
+        Some(CoreMask(bits as u8))
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0 as u64
+    }
+
+    pub fn contains(self, core: usize) -> bool {
+        core < NUM_CORES && self.0 & (1 << core) != 0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+const SECONDARY_STACK_SIZE: usize = 0x4000;
+const CACHE_LINE_SIZE: usize = 64;
+
+// the firmware parks the secondary cores until they
+// find a non-zero entry address in their slot of this table
+const SPIN_TABLE_PTR: *mut u64 = 0xd8 as _;
+
+#[repr(C, align(16))]
+struct SecondaryStack([u8; SECONDARY_STACK_SIZE]);
+
+const EMPTY_STACK: SecondaryStack = SecondaryStack([0; SECONDARY_STACK_SIZE]);
+
+static mut SECONDARY_STACKS: [SecondaryStack; NUM_CORES - 1] = [EMPTY_STACK; NUM_CORES - 1];
+
+// read by boot.s, indexed by core
+#[no_mangle]
+static mut SECONDARY_STACK_TOPS: [u64; NUM_CORES] = [0; NUM_CORES];
+
+extern "C" {
+    fn _start_secondary();
+}
+
+/// Releases the secondary cores from the firmware spin table.
+/// They start at `_start_secondary` in boot.s and continue to `secondary_kernel_start`.
+///
+/// # Safety
+/// Must be called once by the boot core after the mmu is enabled.
+pub unsafe fn start_secondary_cores() {
+    for core in 1..NUM_CORES {
+        let stack = addr_of!(SECONDARY_STACKS[core - 1]) as usize;
+        SECONDARY_STACK_TOPS[core] = (stack + SECONDARY_STACK_SIZE) as u64;
+        // the stack is used before the mmu of the core is enabled,
+        // so none of it may stay in the caches of this core
+        clean_and_invalidate_data_cache(stack, SECONDARY_STACK_SIZE);
+    }
+    clean_and_invalidate_data_cache(
+        addr_of!(SECONDARY_STACK_TOPS) as usize,
+        size_of::<[u64; NUM_CORES]>(),
+    );
+
+    for core in 1..NUM_CORES {
+        let entry_ptr = SPIN_TABLE_PTR.add(core);
+        entry_ptr.write_volatile(_start_secondary as unsafe extern "C" fn() as usize as u64);
+        clean_and_invalidate_data_cache(entry_ptr as usize, size_of::<u64>());
+    }
+
+    // wake up the cores waiting in wfe
+    asm!("sev");
+}
+
+/// Writes back and invalidates the cache lines covering `len` bytes from `start`,
+/// so that the memory can be read by cores that have their caches disabled.
+unsafe fn clean_and_invalidate_data_cache(start: usize, len: usize) {
+    let mut line = start & !(CACHE_LINE_SIZE - 1);
+    while line < start + len {
+        asm!("dc civac, {}", in(reg) line);
+        line += CACHE_LINE_SIZE;
+    }
+    asm!("dsb sy");
+}
@@ -0,0 +1,248 @@
+//! Core dumps, which are `ET_CORE` ELF files that debuggers can open
+//! along with the program that crashed.
+//!
+//! A dump starts with a `PT_NOTE` segment, holding the registers of the thread that
+//! crashed in an `NT_PRSTATUS` note and the auxiliary vector in an `NT_AUXV` note,
+//! which debuggers use to find where position independent programs were put. It is
+//! followed by a `PT_LOAD` segment with the contents of each run of mapped pages
+//! that have the same flags.
+
+use core::time::Duration;
+
+use super::{
+    to_buffer_bytes, LoadedImage, SegmentType, AARCH64_INSTRUCTION_SET, BITWIDTH_64, CORE_FILE,
+    CURRENT_ELF_HEADER_VERSION, CURRENT_ELF_VERSION, EXECUTE_FLAG_BIT, HEADER_SIZE, LITTLE_ENDIAN,
+    MAGIC_VALUE, NOTE_ALIGNMENT, NOTE_HEADER_SIZE, PROGRAM_HEADER_ENTRY_SIZE, READ_FLAG_BIT,
+    SECTION_HEADER_ENTRY_SIZE, SYSTEM_V_ABI, WRITE_FLAG_BIT,
+};
+use crate::arguments::{AT_ENTRY, AT_NULL, AT_PAGESZ, AT_PHDR, AT_PHENT, AT_PHNUM};
+use crate::exceptions::ExceptionFrame;
+use crate::memory::addressspace::{AddressSpace, PageFlags};
+use crate::memory::pageallocator::PAGE_SIZE;
+
+const CORE_NOTE_NAME: &[u8] = b"CORE\0";
+const PROCESS_STATUS_NOTE: u32 = 1; // NT_PRSTATUS
+const AUXILIARY_VECTOR_NOTE: u32 = 6; // NT_AUXV
+
+// the size of `struct elf_prstatus` of linux on aarch64
+const PROCESS_STATUS_SIZE: usize = 392;
+const NUM_AUXILIARY_ENTRIES: usize = 6;
+const AUXILIARY_VECTOR_SIZE: usize = NUM_AUXILIARY_ENTRIES * 16;
+const NOTES_SIZE: usize = note_size(PROCESS_STATUS_SIZE) + note_size(AUXILIARY_VECTOR_SIZE);
+
+/// What the kernel knows about a crashed process, besides its memory and registers.
+pub struct CoreStatus {
+    pub signal: u32,
+    pub pid: u32,
+    pub parent_pid: u32,
+    pub user_time: Duration,
+    pub system_time: Duration,
+    pub children_user_time: Duration,
+    pub children_system_time: Duration,
+}
+
+/// Returns the size of the dump `write_core` writes of `address_space`.
+pub fn core_size(address_space: &AddressSpace) -> usize {
+    let mut num_regions = 0;
+    let mut memory_size = 0;
+    address_space.for_each_region(|_, len, _| {
+        num_regions += 1;
+        memory_size += len;
+    });
+    data_offset(num_regions) + memory_size
+}
+
+/// Passes a core dump of the process with `address_space` and `image`, which crashed
+/// with the registers in `frame`, to `write` in parts. The address space must not
+/// change while the dump is written.
+pub fn write_core(
+    address_space: &AddressSpace,
+    image: &LoadedImage,
+    frame: &ExceptionFrame,
+    status: &CoreStatus,
+    write: &mut dyn FnMut(&[u8]),
+) {
+    let mut num_regions = 0;
+    address_space.for_each_region(|_, _, _| num_regions += 1);
+    let num_segments = 1 + num_regions;
+
+    let mut header = [0; HEADER_SIZE];
+    header[0x00..0x04].copy_from_slice(MAGIC_VALUE);
+    header[0x04] = BITWIDTH_64;
+    header[0x05] = LITTLE_ENDIAN;
+    header[0x06] = CURRENT_ELF_HEADER_VERSION;
+    header[0x07] = SYSTEM_V_ABI;
+    to_buffer_bytes!(u16, CORE_FILE, 0x10, header);
+    to_buffer_bytes!(u16, AARCH64_INSTRUCTION_SET, 0x12, header);
+    to_buffer_bytes!(u32, CURRENT_ELF_VERSION, 0x14, header);
+    // the program header table follows the header, and there are no sections
+    to_buffer_bytes!(u64, HEADER_SIZE as u64, 0x20, header);
+    to_buffer_bytes!(u16, HEADER_SIZE as u16, 0x34, header);
+    to_buffer_bytes!(u16, PROGRAM_HEADER_ENTRY_SIZE, 0x36, header);
+    to_buffer_bytes!(u16, num_segments as u16, 0x38, header);
+    to_buffer_bytes!(u16, SECTION_HEADER_ENTRY_SIZE, 0x3a, header);
+    write(&header);
+
+    let notes_offset = HEADER_SIZE + num_segments * PROGRAM_HEADER_ENTRY_SIZE as usize;
+    write(&program_header(
+        SegmentType::Note,
+        0,
+        notes_offset,
+        0,
+        NOTES_SIZE,
+        NOTE_ALIGNMENT,
+    ));
+    let mut offset = data_offset(num_regions);
+    address_space.for_each_region(|start, len, flags| {
+        write(&program_header(
+            SegmentType::Load,
+            segment_flags(flags),
+            offset,
+            start,
+            len,
+            PAGE_SIZE,
+        ));
+        offset += len;
+    });
+
+    let mut notes = [0; NOTES_SIZE];
+    let mut auxiliary_vector = [0; AUXILIARY_VECTOR_SIZE];
+    for (index, (key, value)) in [
+        (AT_PHDR, image.program_headers as u64),
+        (AT_PHENT, PROGRAM_HEADER_ENTRY_SIZE as u64),
+        (AT_PHNUM, image.num_program_headers as u64),
+        (AT_PAGESZ, PAGE_SIZE as u64),
+        (AT_ENTRY, image.entry),
+        (AT_NULL, 0),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        to_buffer_bytes!(u64, key, index * 16, auxiliary_vector);
+        to_buffer_bytes!(u64, value, index * 16 + 8, auxiliary_vector);
+    }
+    let len = put_note(
+        &mut notes,
+        PROCESS_STATUS_NOTE,
+        &process_status(frame, status),
+    );
+    put_note(&mut notes[len..], AUXILIARY_VECTOR_NOTE, &auxiliary_vector);
+    write(&notes);
+
+    // the contents of the loadable segments are page aligned
+    let zeros = [0; 64];
+    let mut padding = data_offset(num_regions) - (notes_offset + NOTES_SIZE);
+    while padding > 0 {
+        let len = padding.min(zeros.len());
+        write(&zeros[..len]);
+        padding -= len;
+    }
+
+    address_space.for_each_region(|start, len, _| {
+        for page_address in (start..start + len).step_by(PAGE_SIZE) {
+            // physical memory is identity mapped in the kernel
+            let physical = address_space.translate(page_address).unwrap();
+            write(unsafe { core::slice::from_raw_parts(physical as *const u8, PAGE_SIZE) });
+        }
+    });
+}
+
+/// The `struct elf_prstatus` of linux, which has the registers as `pr_reg`.
+fn process_status(frame: &ExceptionFrame, status: &CoreStatus) -> [u8; PROCESS_STATUS_SIZE] {
+    // #[repr(C)]
+    // struct ProcessStatus {
+    //     signal_info: [i32; 3], // the number, code and errno of the signal
+    //     current_signal: u16,
+    //     pending_signals: u64,
+    //     held_signals: u64,
+    //     pid: i32,
+    //     parent_pid: i32,
+    //     process_group: i32,
+    //     session: i32,
+    //     user_time: [i64; 2], // seconds and microseconds
+    //     system_time: [i64; 2],
+    //     children_user_time: [i64; 2],
+    //     children_system_time: [i64; 2],
+    //     registers: [u64; 34], // x0-x30, sp, pc and pstate
+    //     is_fp_valid: i32,
+    // }
+
+    let mut buffer = [0; PROCESS_STATUS_SIZE];
+    to_buffer_bytes!(u32, status.signal, 0x00, buffer);
+    to_buffer_bytes!(u16, status.signal as u16, 0x0c, buffer);
+    to_buffer_bytes!(u32, status.pid, 0x20, buffer);
+    to_buffer_bytes!(u32, status.parent_pid, 0x24, buffer);
+    for (index, time) in [
+        status.user_time,
+        status.system_time,
+        status.children_user_time,
+        status.children_system_time,
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        to_buffer_bytes!(u64, time.as_secs(), 0x30 + index * 16, buffer);
+        to_buffer_bytes!(u64, time.subsec_micros() as u64, 0x38 + index * 16, buffer);
+    }
+    for index in 0..31 {
+        to_buffer_bytes!(u64, frame.register(index), 0x70 + index * 8, buffer);
+    }
+    to_buffer_bytes!(u64, frame.user_stack_pointer(), 0x168, buffer);
+    to_buffer_bytes!(u64, frame.program_counter(), 0x170, buffer);
+    to_buffer_bytes!(u64, frame.saved_program_status(), 0x178, buffer);
+    // the floating point registers are not enabled for user code, so they are not saved
+    buffer
+}
+
+/// Puts a note owned by `CORE` at the start of `buffer`, and returns its size.
+fn put_note(buffer: &mut [u8], note_type: u32, descriptor: &[u8]) -> usize {
+    let name_end = NOTE_HEADER_SIZE + CORE_NOTE_NAME.len();
+    let descriptor_start = name_end.next_multiple_of(NOTE_ALIGNMENT);
+    to_buffer_bytes!(u32, CORE_NOTE_NAME.len() as u32, 0x00, buffer);
+    to_buffer_bytes!(u32, descriptor.len() as u32, 0x04, buffer);
+    to_buffer_bytes!(u32, note_type, 0x08, buffer);
+    buffer[NOTE_HEADER_SIZE..name_end].copy_from_slice(CORE_NOTE_NAME);
+    buffer[descriptor_start..descriptor_start + descriptor.len()].copy_from_slice(descriptor);
+    note_size(descriptor.len())
+}
+
+fn program_header(
+    segment_type: SegmentType,
+    flags: u32,
+    offset: usize,
+    address: usize,
+    size: usize,
+    alignment: usize,
+) -> [u8; PROGRAM_HEADER_ENTRY_SIZE as usize] {
+    let mut buffer = [0; PROGRAM_HEADER_ENTRY_SIZE as usize];
+    to_buffer_bytes!(u32, segment_type.number(), 0x00, buffer);
+    to_buffer_bytes!(u32, flags, 0x04, buffer);
+    to_buffer_bytes!(u64, offset as u64, 0x08, buffer);
+    to_buffer_bytes!(u64, address as u64, 0x10, buffer);
+    to_buffer_bytes!(u64, size as u64, 0x20, buffer);
+    to_buffer_bytes!(u64, size as u64, 0x28, buffer);
+    to_buffer_bytes!(u64, alignment as u64, 0x30, buffer);
+    buffer
+}
+
+fn segment_flags(flags: PageFlags) -> u32 {
+    let mut segment_flags = READ_FLAG_BIT;
+    if flags.is_writable {
+        segment_flags |= WRITE_FLAG_BIT;
+    }
+    if flags.is_executable {
+        segment_flags |= EXECUTE_FLAG_BIT;
+    }
+    segment_flags
+}
+
+/// Where the contents of the loadable segments start, in a dump with `num_regions` of them.
+fn data_offset(num_regions: usize) -> usize {
+    let program_headers_size = (1 + num_regions) * PROGRAM_HEADER_ENTRY_SIZE as usize;
+    (HEADER_SIZE + program_headers_size + NOTES_SIZE).next_multiple_of(PAGE_SIZE)
+}
+
+const fn note_size(descriptor_size: usize) -> usize {
+    (NOTE_HEADER_SIZE + CORE_NOTE_NAME.len()).next_multiple_of(NOTE_ALIGNMENT)
+        + descriptor_size.next_multiple_of(NOTE_ALIGNMENT)
+}
@@ -0,0 +1,715 @@
+pub mod coredump;
+mod relocation;
+pub mod section;
+
+use core::convert::TryInto;
+use core::fmt;
+use core::ops::Range;
+
+use crate::memory::addressspace::{AddressSpace, MapError, PageFlags};
+use crate::memory::pageallocator::{PAGE_ALLOCATOR, PAGE_SIZE};
+use crate::random;
+
+#[derive(Debug)]
+struct ElfHeader {
+    // set for `ET_DYN` images, which can be loaded anywhere
+    is_position_independent: bool,
+    program_entry_point_address: u64,
+    program_header_table_address: u64,
+    section_header_table_address: u64,
+    num_program_header_entries: u16,
+    num_section_header_entries: u16,
+    section_name_entry_index: u16,
+}
+
+#[derive(Debug)]
+pub enum ElfParseError {
+    TooSmall,
+    WrongMagic,
+    WrongEndianness,
+    WrongBitwidth,
+    WrongABI,
+    WrongInstructionSet,
+    WrongHeaderSize,
+    WrongProgramHeaderSize,
+    WrongSectionHeaderSize,
+    WrongAlignment,
+    UnsupportedElfVersion,
+    UnsupportedFileType,
+    UnsupportedFlags,
+    UnsupportedSegmentType,
+    /// A note in a `PT_NOTE` segment extends past the end of the segment.
+    MalformedNote,
+    /// The dynamic section has tables with entries of the wrong size.
+    MalformedDynamic,
+}
+
+/// What a segment described by a program header is for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SegmentType {
+    Null,
+    Load,
+    Dynamic,
+    /// The path of the dynamic linker, which this kernel does not have.
+    Interpreter,
+    Note,
+    /// Reserved, and not allowed in valid images.
+    SharedLibrary,
+    ProgramHeaders,
+    ThreadLocalStorage,
+    GnuEhFrame,
+    /// Says whether the stack should be executable.
+    GnuStack,
+    GnuRelro,
+    GnuProperty,
+    /// A type the loader knows nothing about, which is skipped.
+    Unknown(u32),
+}
+
+macro from_buffer_bytes($int_t:ty, $index:expr, $buffer:expr) {{
+    const NUM_BYTES: usize = (<$int_t>::BITS / 8) as usize;
+    <$int_t>::from_le_bytes($buffer[$index..$index + NUM_BYTES].try_into().unwrap())
+}}
+
+macro to_buffer_bytes($int_t:ty, $value:expr, $index:expr, $buffer:expr) {{
+    const NUM_BYTES: usize = (<$int_t>::BITS / 8) as usize;
+    $buffer[$index..$index + NUM_BYTES].copy_from_slice(&<$int_t>::to_le_bytes($value));
+}}
+
+// header sizes
+const HEADER_SIZE: usize = 64;
+pub const PROGRAM_HEADER_ENTRY_SIZE: u16 = 0x38;
+const SECTION_HEADER_ENTRY_SIZE: u16 = 0x40;
+
+// ElfHeader constants
+const MAGIC_VALUE: &[u8] = &[0x7f, 0x45, 0x4c, 0x46];
+const BITWIDTH_64: u8 = 2;
+const LITTLE_ENDIAN: u8 = 1;
+const CURRENT_ELF_HEADER_VERSION: u8 = 1;
+const SYSTEM_V_ABI: u8 = 0;
+const EXECUTABLE_FILE: u16 = 2;
+const SHARED_OBJECT_FILE: u16 = 3;
+const CORE_FILE: u16 = 4;
+const AARCH64_INSTRUCTION_SET: u16 = 0xb7;
+const CURRENT_ELF_VERSION: u32 = 1;
+const NO_FLAGS_SET: u32 = 0;
+
+// ElfProgramHeader constans
+const EXECUTE_FLAG_BIT: u32 = 0b001;
+const WRITE_FLAG_BIT: u32 = 0b010;
+const READ_FLAG_BIT: u32 = 0b100;
+
+// position independent images are put at a multiple of their alignment, up to this
+const MAX_LOAD_ALIGNMENT: usize = 2 * 1024 * 1024;
+
+// note constants
+const NOTE_HEADER_SIZE: usize = 12;
+const NOTE_ALIGNMENT: usize = 4;
+const GNU_NOTE_NAME: &[u8] = b"GNU";
+const GNU_BUILD_ID_NOTE: u32 = 3;
+
+/// Build IDs are usually SHA-1 hashes, but can be other sizes up to this.
+const MAX_BUILD_ID_SIZE: usize = 20;
+/// Thread local storage is set up at the top of the stack of the first thread,
+/// so it has to leave most of the stack free.
+const MAX_TLS_SIZE: usize = 16 * 1024;
+
+impl SegmentType {
+    fn from_number(number: u32) -> SegmentType {
+        use SegmentType::*;
+        match number {
+            0 => Null,
+            1 => Load,
+            2 => Dynamic,
+            3 => Interpreter,
+            4 => Note,
+            5 => SharedLibrary,
+            6 => ProgramHeaders,
+            7 => ThreadLocalStorage,
+            0x6474_e550 => GnuEhFrame,
+            0x6474_e551 => GnuStack,
+            0x6474_e552 => GnuRelro,
+            0x6474_e553 => GnuProperty,
+            number => Unknown(number),
+        }
+    }
+
+    fn number(self) -> u32 {
+        use SegmentType::*;
+        match self {
+            Null => 0,
+            Load => 1,
+            Dynamic => 2,
+            Interpreter => 3,
+            Note => 4,
+            SharedLibrary => 5,
+            ProgramHeaders => 6,
+            ThreadLocalStorage => 7,
+            GnuEhFrame => 0x6474_e550,
+            GnuStack => 0x6474_e551,
+            GnuRelro => 0x6474_e552,
+            GnuProperty => 0x6474_e553,
+            Unknown(number) => number,
+        }
+    }
+}
+
+impl ElfHeader {
+    pub fn from_buffer(buffer: &[u8]) -> Result<ElfHeader, ElfParseError> {
+        // #[repr(C)]
+        // struct ElfHeader64bit {
+        //     magic: [u8; 4],               // must be [0x7f, 0x45, 0x4c, 0x46] == "\u{7f}ELF"
+        //     bitwidth_class: u8,           // must be 2 for 64 bits
+        //     data_endianness: u8,          // must be 1 for little endian
+        //     elf_header_version: u8,       // must be 1 for original and current elf version
+        //     os_abi: u8,                   // must be 0 for system V / unknown
+        //     abi_version: u8,              // should probably be 0 or ignored
+        //     _pad: [u8; 7],                // ignore this padding
+        //     file_type: u16,               // 2 for executable, 3 for position independent
+        //     machine_instruction_set: u16, // must be 0xb7 for aarch64
+        //     elf_version: u32,             // must be 1 for original and current elf version
+        //     program_entry_point_address: u64,
+        //     program_header_table_address: u64,
+        //     section_header_table_address: u64,
+        //     flags: u32, // should probably be ignored, see https://github.com/ARM-software/abi-aa/tree/main/aaelf64
+        //     header_size: u16, // must be 0x40 == 64 for the size of this header
+        //     program_header_entry_size: u16, // must be 0x38 == 56 for 64 bits
+        //     num_program_header_entries: u16,
+        //     section_header_entry_size: u16, // must be 0x40 == 64 for 64 bits
+        //     num_section_header_entries: u16,
+        //     section_name_entry_index: u16,
+        // }
+
+        use ElfParseError::*;
+
+        if buffer.len() < HEADER_SIZE {
+            return Err(TooSmall);
+        }
+
+        let magic = &buffer[0x00..0x04];
+        let bitwidth_class = buffer[0x04];
+        let data_endianness = buffer[0x05];
+        let elf_header_version = buffer[0x06];
+        let os_abi = buffer[0x07];
+        let _pad = &buffer[0x08..0x10];
+        let file_type = from_buffer_bytes!(u16, 0x10, buffer);
+        let machine_instruction_set = from_buffer_bytes!(u16, 0x12, buffer);
+        let elf_version = from_buffer_bytes!(u32, 0x14, buffer);
+        let program_entry_point_address = from_buffer_bytes!(u64, 0x18, buffer);
+        let program_header_table_address = from_buffer_bytes!(u64, 0x20, buffer);
+        let section_header_table_address = from_buffer_bytes!(u64, 0x28, buffer);
+        let flags = from_buffer_bytes!(u32, 0x30, buffer);
+        let header_size = from_buffer_bytes!(u16, 0x34, buffer);
+        let program_header_entry_size = from_buffer_bytes!(u16, 0x36, buffer);
+        let num_program_header_entries = from_buffer_bytes!(u16, 0x38, buffer);
+        let section_header_entry_size = from_buffer_bytes!(u16, 0x3a, buffer);
+        let num_section_header_entries = from_buffer_bytes!(u16, 0x3c, buffer);
+        let section_name_entry_index = from_buffer_bytes!(u16, 0x3e, buffer);
+
+        match () {
+            _ if magic != MAGIC_VALUE => Err(WrongMagic),
+            _ if bitwidth_class != BITWIDTH_64 => Err(WrongBitwidth),
+            _ if data_endianness != LITTLE_ENDIAN => Err(WrongEndianness),
+            _ if elf_header_version != CURRENT_ELF_HEADER_VERSION => Err(UnsupportedElfVersion),
+            _ if os_abi != SYSTEM_V_ABI => Err(WrongABI),
+            _ if file_type != EXECUTABLE_FILE && file_type != SHARED_OBJECT_FILE => {
+                Err(UnsupportedFileType)
+            }
+            _ if machine_instruction_set != AARCH64_INSTRUCTION_SET => Err(WrongInstructionSet),
+            _ if elf_version != CURRENT_ELF_VERSION => Err(UnsupportedElfVersion),
+            _ if flags != NO_FLAGS_SET => Err(UnsupportedFlags),
+            _ if header_size as usize != HEADER_SIZE => Err(WrongHeaderSize),
+            _ if program_header_entry_size != PROGRAM_HEADER_ENTRY_SIZE => {
+                Err(WrongProgramHeaderSize)
+            }
+            _ if section_header_entry_size != SECTION_HEADER_ENTRY_SIZE => {
+                Err(WrongSectionHeaderSize)
+            }
+            _ => Ok(ElfHeader {
+                is_position_independent: file_type == SHARED_OBJECT_FILE,
+                program_entry_point_address,
+                program_header_table_address,
+                section_header_table_address,
+                num_program_header_entries,
+                num_section_header_entries,
+                section_name_entry_index,
+            }),
+        }
+    }
+}
+
+struct ElfProgramHeader {
+    segment_type: SegmentType,
+    segment_offset: u64,
+    segment_address: u64,
+    segment_file_size: u64,
+    segment_memory_size: u64,
+    segment_alignment: u64,
+    // user pages are always readable, so the read flag is not kept
+    is_executable: bool,
+    is_writable: bool,
+}
+
+impl ElfProgramHeader {
+    pub fn from_buffer(buffer: &[u8]) -> Result<ElfProgramHeader, ElfParseError> {
+        // #[repr(C)]
+        // struct ElfProgramHeader64bit {
+        //     segment_type: u32, // 1 for loadable segments, which are the ones mapped
+        //     segment_flags: u32,
+        //     segment_offset: u64,
+        //     segment_virtual_address: u64,
+        //     segment_physical_address: u64, // ignored
+        //     segment_file_size: u64,
+        //     segment_memory_size: u64,
+        //     segment_alignment: u64, // must be a power of 2, and vaddr equiv offset
+        // }
+
+        use ElfParseError::*;
+
+        if buffer.len() < PROGRAM_HEADER_ENTRY_SIZE as usize {
+            return Err(TooSmall);
+        }
+
+        let segment_type = SegmentType::from_number(from_buffer_bytes!(u32, 0x00, buffer));
+        let segment_flags: u32 = from_buffer_bytes!(u32, 0x04, buffer);
+        let segment_offset: u64 = from_buffer_bytes!(u64, 0x08, buffer);
+        let segment_virtual_address: u64 = from_buffer_bytes!(u64, 0x10, buffer);
+        let _segment_physical_address: u64 = from_buffer_bytes!(u64, 0x18, buffer);
+        let segment_file_size: u64 = from_buffer_bytes!(u64, 0x20, buffer);
+        let segment_memory_size: u64 = from_buffer_bytes!(u64, 0x28, buffer);
+        let segment_alignment: u64 = from_buffer_bytes!(u64, 0x30, buffer);
+
+        if segment_alignment != 0 {
+            if !segment_alignment.is_power_of_two()
+                || (segment_offset ^ segment_virtual_address) & (segment_alignment - 1) != 0
+            {
+                return Err(WrongAlignment);
+            }
+        }
+
+        // other segments may use flags that only matter to other systems
+        let is_flags_supported =
+            segment_flags & !(EXECUTE_FLAG_BIT | WRITE_FLAG_BIT | READ_FLAG_BIT) == 0;
+
+        match () {
+            _ if segment_type == SegmentType::Load && !is_flags_supported => Err(UnsupportedFlags),
+            _ => Ok(ElfProgramHeader {
+                segment_type,
+                segment_offset,
+                segment_address: segment_virtual_address,
+                segment_file_size,
+                segment_memory_size,
+                segment_alignment,
+                is_executable: segment_flags & EXECUTE_FLAG_BIT != 0,
+                is_writable: segment_flags & WRITE_FLAG_BIT != 0,
+            }),
+        }
+    }
+}
+
+// the payloads are only read through `Debug`
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum ElfLoadError {
+    Parse(ElfParseError),
+    /// A header or segment extends past the end of the image.
+    OutOfBounds,
+    /// The image has no loadable segments.
+    NoSegments,
+    /// The entry point is not in an executable segment.
+    InvalidEntryPoint,
+    /// The thread local storage of the image is larger than `MAX_TLS_SIZE`,
+    /// or aligned to more than a page.
+    TlsTooLarge,
+    /// A segment is outside the region the image has to be loaded in,
+    /// or the region has no room for a position independent image.
+    InvalidAddress,
+    /// A relocation of a type the loader can't apply.
+    UnsupportedRelocation(u32),
+    /// The image needs shared libraries or symbols from outside of it.
+    NeedsDynamicLinker,
+    Map(MapError),
+}
+
+/// A program that `load_elf` has mapped into an address space.
+#[derive(Clone, Copy, Debug)]
+pub struct LoadedImage {
+    pub entry: u64,
+    /// How far past its link addresses a position independent image was put,
+    /// wrapping around, or 0 for images that are not position independent.
+    pub base: usize,
+    /// The page aligned range of user space the segments are mapped in.
+    pub start: usize,
+    pub end: usize,
+    /// Where the program header table is mapped, or 0 if it is not in any segment.
+    pub program_headers: usize,
+    pub num_program_headers: usize,
+    /// Set if the image has an executable `PT_GNU_STACK`. Stacks are not
+    /// executable in images without one.
+    pub is_stack_executable: bool,
+    pub tls: Option<TlsTemplate>,
+    pub build_id: Option<BuildId>,
+}
+
+/// The initial contents of the thread local storage of each thread, from `PT_TLS`.
+/// The first `file_size` bytes are copied from `address`, and the rest is zeroed.
+#[derive(Clone, Copy, Debug)]
+pub struct TlsTemplate {
+    pub address: usize,
+    pub file_size: usize,
+    pub memory_size: usize,
+    pub alignment: usize,
+}
+
+/// The identifier the linker gave to the image, from its `NT_GNU_BUILD_ID` note.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BuildId {
+    bytes: [u8; MAX_BUILD_ID_SIZE],
+    len: usize,
+}
+
+/// A note in a `PT_NOTE` segment.
+#[derive(Clone, Copy, Debug)]
+pub struct Note<'a> {
+    /// The owner of the note, without the terminating null.
+    pub name: &'a [u8],
+    pub note_type: u32,
+    pub descriptor: &'a [u8],
+}
+
+/// Iterates over the notes in the contents of a `PT_NOTE` segment.
+/// A malformed note ends the iteration with an error.
+pub struct Notes<'a> {
+    buffer: &'a [u8],
+}
+
+impl BuildId {
+    /// Returns `None` if the descriptor of the note is too long to be a build ID.
+    fn from_descriptor(descriptor: &[u8]) -> Option<BuildId> {
+        let mut bytes = [0; MAX_BUILD_ID_SIZE];
+        bytes
+            .get_mut(..descriptor.len())?
+            .copy_from_slice(descriptor);
+        Some(BuildId {
+            bytes,
+            len: descriptor.len(),
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+impl fmt::Display for BuildId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.as_bytes() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Notes<'a> {
+    pub fn new(buffer: &'a [u8]) -> Notes<'a> {
+        Notes { buffer }
+    }
+}
+
+impl<'a> Iterator for Notes<'a> {
+    type Item = Result<Note<'a>, ElfParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // struct NoteHeader {
+        //     name_size: u32, // including the terminating null
+        //     descriptor_size: u32,
+        //     note_type: u32,
+        // }
+        // followed by the name and the descriptor, each padded to 4 bytes
+
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let buffer = core::mem::take(&mut self.buffer);
+        if buffer.len() < NOTE_HEADER_SIZE {
+            return Some(Err(ElfParseError::MalformedNote));
+        }
+        let name_size = from_buffer_bytes!(u32, 0x00, buffer) as usize;
+        let descriptor_size = from_buffer_bytes!(u32, 0x04, buffer) as usize;
+        let note_type = from_buffer_bytes!(u32, 0x08, buffer);
+
+        let name_end = NOTE_HEADER_SIZE + name_size;
+        let descriptor_start = name_end.next_multiple_of(NOTE_ALIGNMENT);
+        let descriptor_end = descriptor_start + descriptor_size;
+        // the padding after the last note may be left out
+        if descriptor_end > buffer.len() {
+            return Some(Err(ElfParseError::MalformedNote));
+        }
+        let name = &buffer[NOTE_HEADER_SIZE..name_end];
+        let name = name.strip_suffix(&[0]).unwrap_or(name);
+        let descriptor = &buffer[descriptor_start..descriptor_end];
+        self.buffer = buffer
+            .get(descriptor_end.next_multiple_of(NOTE_ALIGNMENT)..)
+            .unwrap_or(&[]);
+        Some(Ok(Note {
+            name,
+            note_type,
+            descriptor,
+        }))
+    }
+}
+
+/// Maps the loadable segments of `image` into `address_space`, inside `region`.
+/// Position independent images are put at a random place in it and relocated.
+/// On failure the segments that were already mapped stay mapped, and are freed
+/// with the address space.
+pub fn load_elf(
+    image: &[u8],
+    address_space: &mut AddressSpace,
+    region: Range<usize>,
+) -> Result<LoadedImage, ElfLoadError> {
+    use ElfLoadError::*;
+
+    let header = ElfHeader::from_buffer(image).map_err(Parse)?;
+    let table_start = header.program_header_table_address as usize;
+    let table_len = header.num_program_header_entries as usize * PROGRAM_HEADER_ENTRY_SIZE as usize;
+    let table_end = table_start.checked_add(table_len).ok_or(OutOfBounds)?;
+    let table = image.get(table_start..table_end).ok_or(OutOfBounds)?;
+    let base = match header.is_position_independent {
+        true => choose_base(table, &region)?,
+        false => 0,
+    };
+
+    let mut loaded = LoadedImage {
+        entry: header.program_entry_point_address.wrapping_add(base as u64),
+        base,
+        start: usize::MAX,
+        end: 0,
+        program_headers: 0,
+        num_program_headers: header.num_program_header_entries as usize,
+        is_stack_executable: false,
+        tls: None,
+        build_id: None,
+    };
+    let mut dynamic = None;
+    let mut is_entry_mapped = false;
+    for buffer in table.chunks_exact(PROGRAM_HEADER_ENTRY_SIZE as usize) {
+        let program_header = ElfProgramHeader::from_buffer(buffer).map_err(Parse)?;
+        match program_header.segment_type {
+            SegmentType::Load => {
+                let (start, end) = load_segment(image, &program_header, address_space, base)?;
+                if start == end {
+                    continue;
+                }
+                loaded.start = loaded.start.min(start - start % PAGE_SIZE);
+                loaded.end = loaded.end.max(end.next_multiple_of(PAGE_SIZE));
+                if program_header.is_executable && (start..end).contains(&(loaded.entry as usize)) {
+                    is_entry_mapped = true;
+                }
+                // images without a `PT_PHDR` may still load the table with their first page
+                let offset = program_header.segment_offset as usize;
+                let file_end = offset + program_header.segment_file_size as usize;
+                if loaded.program_headers == 0 && (offset..file_end).contains(&table_start) {
+                    loaded.program_headers = start + (table_start - offset);
+                }
+            }
+            SegmentType::ProgramHeaders => {
+                loaded.program_headers =
+                    (program_header.segment_address as usize).wrapping_add(base);
+            }
+            SegmentType::Dynamic => dynamic = Some(segment_contents(image, &program_header)?),
+            SegmentType::GnuStack => loaded.is_stack_executable = program_header.is_executable,
+            SegmentType::ThreadLocalStorage => {
+                let mut tls = tls_template(&program_header)?;
+                tls.address = tls.address.wrapping_add(base);
+                loaded.tls = Some(tls);
+            }
+            SegmentType::Note => {
+                let contents = segment_contents(image, &program_header)?;
+                for note in Notes::new(contents) {
+                    let note = note.map_err(Parse)?;
+                    if note.name == GNU_NOTE_NAME && note.note_type == GNU_BUILD_ID_NOTE {
+                        loaded.build_id = BuildId::from_descriptor(note.descriptor);
+                    }
+                }
+            }
+            // there is no dynamic linker to hand the image to
+            SegmentType::Interpreter | SegmentType::SharedLibrary => {
+                return Err(Parse(ElfParseError::UnsupportedSegmentType));
+            }
+            // the rest only matter to debuggers and dynamic linkers
+            _ => {}
+        }
+    }
+
+    match () {
+        _ if loaded.start >= loaded.end => return Err(NoSegments),
+        _ if loaded.start < region.start || loaded.end > region.end => return Err(InvalidAddress),
+        _ if !is_entry_mapped => return Err(InvalidEntryPoint),
+        // the template of the thread local storage is copied from the loaded segments
+        _ if matches!(loaded.tls, Some(tls) if tls.address < loaded.start
+            || tls.address.saturating_add(tls.file_size) > loaded.end) =>
+        {
+            return Err(OutOfBounds)
+        }
+        _ => {}
+    }
+    // images that are not position independent were relocated by the linker
+    if let (true, Some(dynamic)) = (header.is_position_independent, dynamic) {
+        relocation::relocate(dynamic, address_space, base)?;
+    }
+    Ok(loaded)
+}
+
+/// Picks a random place in `region` for the loadable segments of a position
+/// independent image, and returns how far past their link addresses it is.
+fn choose_base(table: &[u8], region: &Range<usize>) -> Result<usize, ElfLoadError> {
+    use ElfLoadError::*;
+
+    let mut start = usize::MAX;
+    let mut end = 0;
+    let mut alignment = PAGE_SIZE;
+    for buffer in table.chunks_exact(PROGRAM_HEADER_ENTRY_SIZE as usize) {
+        let program_header = ElfProgramHeader::from_buffer(buffer).map_err(Parse)?;
+        if program_header.segment_type != SegmentType::Load {
+            continue;
+        }
+        let segment_start = program_header.segment_address as usize;
+        let segment_end = segment_start
+            .checked_add(program_header.segment_memory_size as usize)
+            .ok_or(OutOfBounds)?;
+        start = start.min(segment_start);
+        end = end.max(segment_end);
+        alignment = alignment.max(program_header.segment_alignment as usize);
+    }
+    if start >= end {
+        return Err(NoSegments);
+    }
+
+    let alignment = alignment.min(MAX_LOAD_ALIGNMENT);
+    let start = start / alignment * alignment;
+    let len = end - start;
+    let first = region.start.next_multiple_of(alignment);
+    let last = region
+        .end
+        .checked_sub(len)
+        .filter(|&last| last >= first)
+        .ok_or(InvalidAddress)?;
+    let num_places = (last - first) / alignment + 1;
+    let place = random::random_u64() as usize % num_places;
+    // images linked above where they are put get a negative offset
+    Ok((first + place * alignment).wrapping_sub(start))
+}
+
+/// Maps a loadable segment, and returns the range of addresses it takes up.
+fn load_segment(
+    image: &[u8],
+    program_header: &ElfProgramHeader,
+    address_space: &mut AddressSpace,
+    base: usize,
+) -> Result<(usize, usize), ElfLoadError> {
+    use ElfLoadError::*;
+
+    if program_header.segment_file_size > program_header.segment_memory_size {
+        return Err(OutOfBounds);
+    }
+    let contents = segment_contents(image, program_header)?;
+
+    let start = (program_header.segment_address as usize).wrapping_add(base);
+    let end = start
+        .checked_add(program_header.segment_memory_size as usize)
+        .filter(|&end| end <= usize::MAX - PAGE_SIZE)
+        .ok_or(OutOfBounds)?;
+    let flags = PageFlags {
+        is_writable: program_header.is_writable,
+        is_executable: program_header.is_executable,
+    };
+
+    let mut page_address = start - start % PAGE_SIZE;
+    while page_address < end {
+        let copy_start = page_address.max(start);
+        let copy_end = (page_address + PAGE_SIZE).min(start + contents.len());
+        let source = match copy_start < copy_end {
+            true => &contents[copy_start - start..copy_end - start],
+            false => &[],
+        };
+
+        // segments that are not page aligned can share a page with the previous one,
+        // which then gets the contents and the flags of both
+        if let Some(physical) = address_space.translate(page_address) {
+            address_space.add_flags(page_address, flags).map_err(Map)?;
+            unsafe {
+                // physical memory is identity mapped in the kernel
+                let destination = (physical + (copy_start - page_address)) as *mut u8;
+                core::ptr::copy_nonoverlapping(source.as_ptr(), destination, source.len());
+            }
+            page_address += PAGE_SIZE;
+            continue;
+        }
+
+        // the memory past the file contents stays zeroed
+        let page = PAGE_ALLOCATOR
+            .lock()
+            .alloc_zeroed_page(1)
+            .ok_or(Map(MapError::OutOfMemory))?;
+        unsafe {
+            let destination = page.as_ptr().cast::<u8>().add(copy_start - page_address);
+            core::ptr::copy_nonoverlapping(source.as_ptr(), destination, source.len());
+        }
+        if let Err(error) = address_space.map_page(page_address, page, flags) {
+            unsafe { PAGE_ALLOCATOR.lock().free_page(page) };
+            return Err(Map(error));
+        }
+        page_address += PAGE_SIZE;
+    }
+    Ok((start, end))
+}
+
+/// Returns the part of the image that is the contents of a segment.
+fn segment_contents<'a>(
+    image: &'a [u8],
+    program_header: &ElfProgramHeader,
+) -> Result<&'a [u8], ElfLoadError> {
+    let file_start = program_header.segment_offset as usize;
+    let file_end = file_start
+        .checked_add(program_header.segment_file_size as usize)
+        .ok_or(ElfLoadError::OutOfBounds)?;
+    image
+        .get(file_start..file_end)
+        .ok_or(ElfLoadError::OutOfBounds)
+}
+
+fn tls_template(program_header: &ElfProgramHeader) -> Result<TlsTemplate, ElfLoadError> {
+    let template = TlsTemplate {
+        address: program_header.segment_address as usize,
+        file_size: program_header.segment_file_size as usize,
+        memory_size: program_header.segment_memory_size as usize,
+        alignment: program_header.segment_alignment.max(1) as usize,
+    };
+    match () {
+        _ if template.file_size > template.memory_size
+            || template.address.checked_add(template.memory_size).is_none() =>
+        {
+            Err(ElfLoadError::OutOfBounds)
+        }
+        _ if template.memory_size > MAX_TLS_SIZE || template.alignment > PAGE_SIZE => {
+            Err(ElfLoadError::TlsTooLarge)
+        }
+        _ => Ok(template),
+    }
+}
+
+/*
+#[repr(C)]
+struct ElfProgramHeader64bit {
+    segment_type: u32,
+    segment_flags: u32,
+    segment_offset: u64,
+    segment_virtual_address: u64,
+    segment_physical_address: u64,
+    segment_file_size: u64,
+    segment_memory_size: u64,
+    segment_alignment: u64,
+}
+
+*/
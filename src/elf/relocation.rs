@@ -0,0 +1,207 @@
+//! Relocation of position independent images, from their `PT_DYNAMIC` segment.
+//!
+//! Static position independent executables still have a dynamic section, with
+//! relocations that only refer to the image itself. They are applied once every
+//! segment is mapped, so the tables they point to are read from the address space.
+
+use super::{from_buffer_bytes, ElfLoadError, ElfParseError};
+use crate::memory::addressspace::{AddressSpace, MapError};
+
+const DYNAMIC_ENTRY_SIZE: usize = 16;
+const RELOCATION_ENTRY_SIZE: usize = 24;
+const SYMBOL_ENTRY_SIZE: usize = 24;
+
+// dynamic section tags
+const END_TAG: u64 = 0; // DT_NULL
+const NEEDED_LIBRARY_TAG: u64 = 1; // DT_NEEDED
+const PLT_RELOCATIONS_SIZE_TAG: u64 = 2; // DT_PLTRELSZ
+const SYMBOL_TABLE_TAG: u64 = 6; // DT_SYMTAB
+const RELOCATIONS_TAG: u64 = 7; // DT_RELA
+const RELOCATIONS_SIZE_TAG: u64 = 8; // DT_RELASZ
+const RELOCATION_ENTRY_SIZE_TAG: u64 = 9; // DT_RELAENT
+const SYMBOL_ENTRY_SIZE_TAG: u64 = 11; // DT_SYMENT
+const PLT_RELOCATION_KIND_TAG: u64 = 20; // DT_PLTREL
+const PLT_RELOCATIONS_TAG: u64 = 23; // DT_JMPREL
+
+// relocation types
+const NO_RELOCATION: u32 = 0; // R_AARCH64_NONE
+const ABSOLUTE_RELOCATION: u32 = 257; // R_AARCH64_ABS64
+const GLOBAL_DATA_RELOCATION: u32 = 1025; // R_AARCH64_GLOB_DAT
+const JUMP_SLOT_RELOCATION: u32 = 1026; // R_AARCH64_JUMP_SLOT
+const RELATIVE_RELOCATION: u32 = 1027; // R_AARCH64_RELATIVE
+
+// symbol constants
+const UNDEFINED_SECTION: u16 = 0;
+const ABSOLUTE_SECTION: u16 = 0xfff1;
+const WEAK_BINDING: u8 = 2;
+
+/// Where the relocation tables of the image are, at their link addresses.
+#[derive(Default)]
+struct RelocationTables {
+    relocations: u64,
+    relocations_size: u64,
+    plt_relocations: u64,
+    plt_relocations_size: u64,
+    symbol_table: u64,
+}
+
+impl RelocationTables {
+    fn from_dynamic(dynamic: &[u8]) -> Result<RelocationTables, ElfLoadError> {
+        // #[repr(C)]
+        // struct DynamicEntry {
+        //     tag: u64,
+        //     value: u64, // an address or a size, depending on the tag
+        // }
+
+        use ElfParseError::MalformedDynamic;
+
+        let mut tables = RelocationTables::default();
+        for entry in dynamic.chunks_exact(DYNAMIC_ENTRY_SIZE) {
+            let tag = from_buffer_bytes!(u64, 0x00, entry);
+            let value = from_buffer_bytes!(u64, 0x08, entry);
+            match tag {
+                END_TAG => break,
+                NEEDED_LIBRARY_TAG => return Err(ElfLoadError::NeedsDynamicLinker),
+                RELOCATIONS_TAG => tables.relocations = value,
+                RELOCATIONS_SIZE_TAG => tables.relocations_size = value,
+                PLT_RELOCATIONS_TAG => tables.plt_relocations = value,
+                PLT_RELOCATIONS_SIZE_TAG => tables.plt_relocations_size = value,
+                SYMBOL_TABLE_TAG => tables.symbol_table = value,
+                RELOCATION_ENTRY_SIZE_TAG if value != RELOCATION_ENTRY_SIZE as u64 => {
+                    return Err(ElfLoadError::Parse(MalformedDynamic))
+                }
+                SYMBOL_ENTRY_SIZE_TAG if value != SYMBOL_ENTRY_SIZE as u64 => {
+                    return Err(ElfLoadError::Parse(MalformedDynamic))
+                }
+                // the plt only has relocations with addends on aarch64
+                PLT_RELOCATION_KIND_TAG if value != RELOCATIONS_TAG => {
+                    return Err(ElfLoadError::Parse(MalformedDynamic))
+                }
+                _ => {}
+            }
+        }
+        Ok(tables)
+    }
+}
+
+/// Applies the relocations in the dynamic section `dynamic` of an image that
+/// was put `base` bytes past its link addresses in `address_space`.
+pub(super) fn relocate(
+    dynamic: &[u8],
+    address_space: &AddressSpace,
+    base: usize,
+) -> Result<(), ElfLoadError> {
+    let tables = RelocationTables::from_dynamic(dynamic)?;
+    let base = base as u64;
+    for (address, size) in [
+        (tables.relocations, tables.relocations_size),
+        (tables.plt_relocations, tables.plt_relocations_size),
+    ] {
+        if size % RELOCATION_ENTRY_SIZE as u64 != 0 {
+            return Err(ElfLoadError::Parse(ElfParseError::MalformedDynamic));
+        }
+        for offset in (0..size).step_by(RELOCATION_ENTRY_SIZE) {
+            let mut entry = [0; RELOCATION_ENTRY_SIZE];
+            read(
+                address_space,
+                address.wrapping_add(base).wrapping_add(offset),
+                &mut entry,
+            )?;
+            apply(&entry, tables.symbol_table, address_space, base)?;
+        }
+    }
+    Ok(())
+}
+
+fn apply(
+    entry: &[u8],
+    symbol_table: u64,
+    address_space: &AddressSpace,
+    base: u64,
+) -> Result<(), ElfLoadError> {
+    // #[repr(C)]
+    // struct Relocation {
+    //     offset: u64, // the link address of the place to relocate
+    //     info: u64,   // the symbol in the high half and the type in the low half
+    //     addend: i64,
+    // }
+
+    let offset = from_buffer_bytes!(u64, 0x00, entry);
+    let info = from_buffer_bytes!(u64, 0x08, entry);
+    let addend = from_buffer_bytes!(u64, 0x10, entry);
+    let symbol = info >> 32;
+
+    let value = match info as u32 {
+        NO_RELOCATION => return Ok(()),
+        RELATIVE_RELOCATION => base.wrapping_add(addend),
+        ABSOLUTE_RELOCATION | GLOBAL_DATA_RELOCATION | JUMP_SLOT_RELOCATION => {
+            symbol_value(address_space, symbol_table, symbol, base)?.wrapping_add(addend)
+        }
+        kind => return Err(ElfLoadError::UnsupportedRelocation(kind)),
+    };
+    write(
+        address_space,
+        offset.wrapping_add(base),
+        &value.to_le_bytes(),
+    )
+}
+
+/// Returns the address of the symbol `index` of the symbol table, where the image was put.
+fn symbol_value(
+    address_space: &AddressSpace,
+    symbol_table: u64,
+    index: u64,
+    base: u64,
+) -> Result<u64, ElfLoadError> {
+    // #[repr(C)]
+    // struct Symbol {
+    //     name: u32,
+    //     info: u8, // the binding in the high 4 bits and the type in the low 4 bits
+    //     other: u8,
+    //     section_index: u16,
+    //     value: u64,
+    //     size: u64,
+    // }
+
+    // symbol 0 stands for no symbol
+    if index == 0 {
+        return Ok(0);
+    }
+    let mut symbol = [0; SYMBOL_ENTRY_SIZE];
+    let address = index
+        .checked_mul(SYMBOL_ENTRY_SIZE as u64)
+        .ok_or(ElfLoadError::OutOfBounds)?
+        .wrapping_add(symbol_table)
+        .wrapping_add(base);
+    read(address_space, address, &mut symbol)?;
+
+    let binding = symbol[0x04] >> 4;
+    let section_index = from_buffer_bytes!(u16, 0x06, symbol);
+    let value = from_buffer_bytes!(u64, 0x08, symbol);
+    match section_index {
+        // undefined weak symbols are null, others would come from a shared library
+        UNDEFINED_SECTION if binding == WEAK_BINDING => Ok(0),
+        UNDEFINED_SECTION => Err(ElfLoadError::NeedsDynamicLinker),
+        ABSOLUTE_SECTION => Ok(value),
+        _ => Ok(value.wrapping_add(base)),
+    }
+}
+
+fn read(address_space: &AddressSpace, address: u64, buffer: &mut [u8]) -> Result<(), ElfLoadError> {
+    address_space
+        .read(address as usize, buffer)
+        .map_err(ElfLoadError::Map)
+}
+
+/// Writes `bytes` to `address` even if it is in a read only segment, like the
+/// text relocations of images built without `-z text`.
+fn write(address_space: &AddressSpace, address: u64, bytes: &[u8]) -> Result<(), ElfLoadError> {
+    for (offset, &byte) in bytes.iter().enumerate() {
+        let physical = address_space
+            .translate((address as usize).wrapping_add(offset))
+            .ok_or(ElfLoadError::Map(MapError::InvalidAddress))?;
+        // physical memory is identity mapped in the kernel
+        unsafe { (physical as *mut u8).write(byte) };
+    }
+    Ok(())
+}
@@ -0,0 +1,374 @@
+//! Section headers and symbol tables.
+//!
+//! Sections are not needed to run a program, so the loader ignores them, but
+//! they name its addresses. The symbols come from `.symtab`, or from `.dynsym`
+//! in stripped images, with the names in the string table the section links to.
+
+use core::ptr::NonNull;
+
+use super::{from_buffer_bytes, ElfHeader, ElfParseError, SECTION_HEADER_ENTRY_SIZE};
+use crate::memory::pageallocator::{Page, PAGE_ALLOCATOR, PAGE_SIZE};
+
+const SYMBOL_ENTRY_SIZE: usize = 24;
+/// The most pages a copy of the symbols of an image can take up.
+const MAX_SYMBOL_PAGES: usize = 16;
+
+// special section indices
+const NO_SECTION: u16 = 0;
+// the real index is in the link of section 0
+const EXTENDED_SECTION_INDEX: u16 = 0xffff;
+
+// symbol types
+const OBJECT_SYMBOL: u8 = 1; // STT_OBJECT
+const FUNCTION_SYMBOL: u8 = 2; // STT_FUNC
+
+/// What a section holds.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SectionType {
+    Null,
+    ProgramData,
+    SymbolTable,
+    StringTable,
+    RelocationsWithAddends,
+    SymbolHash,
+    Dynamic,
+    Note,
+    /// Takes up memory but no space in the file, like `.bss`.
+    NoBits,
+    Relocations,
+    DynamicSymbolTable,
+    /// A type only linkers and debuggers care about.
+    Other(u32),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SectionHeader {
+    /// Where the name is in the section name string table.
+    pub name_offset: u32,
+    pub section_type: SectionType,
+    pub offset: u64,
+    pub size: u64,
+    /// The index of a related section, like the string table of a symbol table.
+    pub link: u32,
+    pub entry_size: u64,
+}
+
+/// The section header table of an image.
+#[derive(Clone, Copy)]
+pub struct Sections<'a> {
+    image: &'a [u8],
+    table: &'a [u8],
+    // the section holding the names of the sections
+    names: Option<SectionHeader>,
+}
+
+/// Iterates over the section headers of an image.
+pub struct SectionHeaders<'a> {
+    entries: core::slice::ChunksExact<'a, u8>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Symbol<'a> {
+    /// Empty for symbols without a name, or names that are not UTF-8.
+    pub name: &'a str,
+    pub address: u64,
+    pub size: u64,
+    /// The section the symbol is defined in, or 0 for undefined symbols.
+    pub section_index: u16,
+    symbol_type: u8,
+}
+
+/// A copy of the symbols of an image and their names in pages of the kernel,
+/// which can be looked up after the image is gone.
+pub struct SymbolTableCopy {
+    pages: NonNull<Page>,
+    num_pages: usize,
+    table_len: usize,
+    strings_len: usize,
+}
+
+/// Iterates over the entries of a symbol table, skipping the null symbol at index 0.
+pub struct Symbols<'a> {
+    entries: core::iter::Skip<core::slice::ChunksExact<'a, u8>>,
+    strings: &'a [u8],
+}
+
+impl SectionType {
+    fn from_number(number: u32) -> SectionType {
+        use SectionType::*;
+        match number {
+            0 => Null,
+            1 => ProgramData,
+            2 => SymbolTable,
+            3 => StringTable,
+            4 => RelocationsWithAddends,
+            5 => SymbolHash,
+            6 => Dynamic,
+            7 => Note,
+            8 => NoBits,
+            9 => Relocations,
+            11 => DynamicSymbolTable,
+            number => Other(number),
+        }
+    }
+}
+
+impl SectionHeader {
+    fn from_buffer(buffer: &[u8]) -> Result<SectionHeader, ElfParseError> {
+        // #[repr(C)]
+        // struct ElfSectionHeader64bit {
+        //     section_name_offset: u32, // into the section name string table
+        //     section_type: u32,
+        //     section_flags: u64,
+        //     section_virtual_address: u64, // 0 for sections that are not loaded
+        //     section_offset: u64,
+        //     section_size: u64,
+        //     section_link: u32, // depends on the type, the string table for symbol tables
+        //     section_info: u32, // depends on the type
+        //     section_address_align: u64,
+        //     table_entry_size: u64, // for sections that are tables, like symbol tables
+        // }
+
+        if buffer.len() < SECTION_HEADER_ENTRY_SIZE as usize {
+            return Err(ElfParseError::TooSmall);
+        }
+
+        Ok(SectionHeader {
+            name_offset: from_buffer_bytes!(u32, 0x00, buffer),
+            section_type: SectionType::from_number(from_buffer_bytes!(u32, 0x04, buffer)),
+            offset: from_buffer_bytes!(u64, 0x18, buffer),
+            size: from_buffer_bytes!(u64, 0x20, buffer),
+            link: from_buffer_bytes!(u32, 0x28, buffer),
+            entry_size: from_buffer_bytes!(u64, 0x38, buffer),
+        })
+    }
+}
+
+impl<'a> Sections<'a> {
+    /// Finds the section header table of `image`. Images without one,
+    /// like stripped ones, have no sections.
+    pub fn parse(image: &'a [u8]) -> Result<Sections<'a>, ElfParseError> {
+        let header = ElfHeader::from_buffer(image)?;
+        let entry_size = SECTION_HEADER_ENTRY_SIZE as usize;
+        let table_start = header.section_header_table_address as usize;
+        if table_start == 0 {
+            return Ok(Sections {
+                image,
+                table: &[],
+                names: None,
+            });
+        }
+        let first = image
+            .get(table_start..)
+            .and_then(|table| table.get(..entry_size))
+            .ok_or(ElfParseError::TooSmall)
+            .and_then(SectionHeader::from_buffer)?;
+
+        // images with too many sections for the header keep the numbers in section 0
+        let num_entries = match header.num_section_header_entries {
+            0 => first.size as usize,
+            num_entries => num_entries as usize,
+        };
+        let names_index = match header.section_name_entry_index {
+            EXTENDED_SECTION_INDEX => first.link as usize,
+            index => index as usize,
+        };
+        let table = num_entries
+            .checked_mul(entry_size)
+            .and_then(|len| image.get(table_start..)?.get(..len))
+            .ok_or(ElfParseError::TooSmall)?;
+
+        let mut sections = Sections {
+            image,
+            table,
+            names: None,
+        };
+        if names_index != NO_SECTION as usize {
+            sections.names = Some(sections.get(names_index)?);
+        }
+        Ok(sections)
+    }
+
+    /// Returns the section header at `index`.
+    pub fn get(&self, index: usize) -> Result<SectionHeader, ElfParseError> {
+        let entry_size = SECTION_HEADER_ENTRY_SIZE as usize;
+        let start = index
+            .checked_mul(entry_size)
+            .ok_or(ElfParseError::TooSmall)?;
+        self.table
+            .get(start..)
+            .and_then(|table| table.get(..entry_size))
+            .ok_or(ElfParseError::TooSmall)
+            .and_then(SectionHeader::from_buffer)
+    }
+
+    pub fn iter(&self) -> SectionHeaders<'a> {
+        SectionHeaders {
+            entries: self.table.chunks_exact(SECTION_HEADER_ENTRY_SIZE as usize),
+        }
+    }
+
+    /// Returns the name of `section` from the section name string table.
+    pub fn name(&self, section: &SectionHeader) -> Option<&'a str> {
+        let names = self.contents(&self.names?)?;
+        string_at(names, section.name_offset as usize)
+    }
+
+    /// Returns the first section called `name`.
+    pub fn find(&self, name: &str) -> Option<SectionHeader> {
+        self.iter()
+            .filter_map(Result::ok)
+            .find(|section| self.name(section) == Some(name))
+    }
+
+    /// Returns the part of the image that is the contents of `section`,
+    /// or `None` if it is not in the image.
+    pub fn contents(&self, section: &SectionHeader) -> Option<&'a [u8]> {
+        if section.section_type == SectionType::NoBits {
+            return None;
+        }
+        let start = section.offset as usize;
+        let end = start.checked_add(section.size as usize)?;
+        self.image.get(start..end)
+    }
+
+    /// Returns the contents of `.symtab`, or of `.dynsym` if the image
+    /// was stripped, and of the string table it links to.
+    fn symbol_table(&self) -> Option<(&'a [u8], &'a [u8])> {
+        let section = self.find(".symtab").or_else(|| self.find(".dynsym"))?;
+        self.tables_of(&section)
+    }
+
+    fn tables_of(&self, section: &SectionHeader) -> Option<(&'a [u8], &'a [u8])> {
+        let strings = self.get(section.link as usize).ok()?;
+        let is_symbol_table = matches!(
+            section.section_type,
+            SectionType::SymbolTable | SectionType::DynamicSymbolTable
+        );
+        match () {
+            _ if !is_symbol_table => None,
+            _ if section.entry_size != SYMBOL_ENTRY_SIZE as u64 => None,
+            _ if strings.section_type != SectionType::StringTable => None,
+            _ => Some((self.contents(section)?, self.contents(&strings)?)),
+        }
+    }
+}
+
+impl<'a> Iterator for SectionHeaders<'a> {
+    type Item = Result<SectionHeader, ElfParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next().map(SectionHeader::from_buffer)
+    }
+}
+
+impl<'a> Symbol<'a> {
+    pub fn is_function(&self) -> bool {
+        self.symbol_type == FUNCTION_SYMBOL
+    }
+
+    pub fn is_object(&self) -> bool {
+        self.symbol_type == OBJECT_SYMBOL
+    }
+}
+
+impl<'a> Symbols<'a> {
+    /// The symbols in `table`, with names from the string table `strings`.
+    pub fn new(table: &'a [u8], strings: &'a [u8]) -> Symbols<'a> {
+        Symbols {
+            entries: table.chunks_exact(SYMBOL_ENTRY_SIZE).skip(1),
+            strings,
+        }
+    }
+
+    /// Returns the function or object that contains `address`, and how far into it
+    /// `address` is. The addresses are link addresses, so position independent
+    /// images have to have their base taken away first.
+    pub fn symbol_at(self, address: u64) -> Option<(Symbol<'a>, u64)> {
+        // symbols without a size only contain their own address
+        self.filter(|symbol| symbol.is_function() || symbol.is_object())
+            .filter(|symbol| symbol.section_index != NO_SECTION)
+            .filter(|symbol| {
+                address >= symbol.address && address - symbol.address < symbol.size.max(1)
+            })
+            // the innermost symbol, if they nest
+            .max_by_key(|symbol| symbol.address)
+            .map(|symbol| (symbol, address - symbol.address))
+    }
+}
+
+impl<'a> Iterator for Symbols<'a> {
+    type Item = Symbol<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // #[repr(C)]
+        // struct Symbol {
+        //     name: u32, // into the linked string table
+        //     info: u8,  // the binding in the high 4 bits and the type in the low 4 bits
+        //     other: u8,
+        //     section_index: u16,
+        //     value: u64,
+        //     size: u64,
+        // }
+
+        let entry = self.entries.next()?;
+        let name_offset = from_buffer_bytes!(u32, 0x00, entry) as usize;
+        Some(Symbol {
+            name: string_at(self.strings, name_offset).unwrap_or(""),
+            address: from_buffer_bytes!(u64, 0x08, entry),
+            size: from_buffer_bytes!(u64, 0x10, entry),
+            section_index: from_buffer_bytes!(u16, 0x06, entry),
+            symbol_type: entry[0x04] & 0xf,
+        })
+    }
+}
+
+impl SymbolTableCopy {
+    /// Copies the symbols of `image` and their names. Returns `None` if the image
+    /// has no symbols, or they don't fit in `MAX_SYMBOL_PAGES` pages.
+    pub fn new(image: &[u8]) -> Option<SymbolTableCopy> {
+        let (table, strings) = Sections::parse(image).ok()?.symbol_table()?;
+        let len = table.len() + strings.len();
+        let num_pages = len.div_ceil(PAGE_SIZE);
+        if num_pages == 0 || num_pages > MAX_SYMBOL_PAGES {
+            return None;
+        }
+        let pages = PAGE_ALLOCATOR.lock().alloc_page(num_pages)?;
+        unsafe {
+            let destination = pages.as_ptr().cast::<u8>();
+            core::ptr::copy_nonoverlapping(table.as_ptr(), destination, table.len());
+            let destination = destination.add(table.len());
+            core::ptr::copy_nonoverlapping(strings.as_ptr(), destination, strings.len());
+        }
+        Some(SymbolTableCopy {
+            pages,
+            num_pages,
+            table_len: table.len(),
+            strings_len: strings.len(),
+        })
+    }
+
+    pub fn symbols(&self) -> Symbols<'_> {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                self.pages.as_ptr().cast::<u8>(),
+                self.table_len + self.strings_len,
+            )
+        };
+        let (table, strings) = bytes.split_at(self.table_len);
+        Symbols::new(table, strings)
+    }
+}
+
+impl Drop for SymbolTableCopy {
+    fn drop(&mut self) {
+        unsafe { PAGE_ALLOCATOR.lock().free_pages(self.pages, self.num_pages) };
+    }
+}
+
+/// Returns the null terminated string at `offset` in a string table.
+fn string_at(strings: &[u8], offset: usize) -> Option<&str> {
+    let bytes = strings.get(offset..)?;
+    let len = bytes.iter().position(|&byte| byte == 0)?;
+    core::str::from_utf8(&bytes[..len]).ok()
+}
@@ -1,8 +1,17 @@
 use core::arch::{asm, global_asm};
+use core::mem::size_of;
 
-mod syscalls;
+mod syndrome;
+pub mod syscalls;
 
-global_asm!(include_str!("vectortable.s"));
+use crate::signal::{self, Signal};
+use syndrome::{ExceptionSyndrome, FaultStatus};
+
+global_asm!(
+    include_str!("vectortable.s"),
+    FRAME_SIZE = const FRAME_SIZE,
+    FRAME_EXCEPTION_LINK_REG = const FRAME_EXCEPTION_LINK_REG,
+);
 
 pub unsafe fn init_and_enable_exceptions() {
     let vector_addr: u64;
@@ -11,69 +20,316 @@ pub unsafe fn init_and_enable_exceptions() {
     asm!("msr daifclr, #0b1111");
 }
 
-#[derive(Debug)]
+// offsets into `ExceptionFrame` that are used by vectortable.s
+const FRAME_EXCEPTION_LINK_REG: usize = 0x100;
+const FRAME_SIZE: usize = 0x110;
+
+const _: () = assert!(size_of::<ExceptionFrame>() == FRAME_SIZE);
+
+/// The state of the interrupted context, saved on the stack by vectortable.s
+/// and restored from it when the handler returns. Handlers can change where
+/// and how execution resumes by modifying the frame.
+#[derive(Clone, Debug)]
 #[repr(C)]
 pub struct ExceptionFrame {
-    registers: [u64; 31],
+    registers: [u64; 31],          // 0x000: x0-x30
+    stack_pointer_el0: u64,        // 0x0f8: sp_el0
+    exception_link_reg: u64,       // 0x100: elr_el1
+    saved_program_status_reg: u64, // 0x108: spsr_el1
 }
 
-const EXCEPTION_CLASS_MASK: u64 = 0b111111 << 26;
-const SVC_EXCEPTION_CLASS: u64 = 0b010101 << 26;
+const INSTRUCTION_SIZE: u64 = 4;
+const SPSR_MODE_MASK: u64 = 0b1111;
+const SPSR_MODE_EL0: u64 = 0b0000;
+const SPSR_MODE_EL1H: u64 = 0b0101;
+// the condition flags, the only bits user code may choose
+const SPSR_CONDITION_FLAGS_MASK: u64 = 0xf000_0000;
+
+impl ExceptionFrame {
+    /// A frame that starts executing user code at `entry`
+    /// with the given stack and all other registers zeroed.
+    pub fn new_user(entry: u64, stack_pointer: u64) -> ExceptionFrame {
+        ExceptionFrame {
+            registers: [0; 31],
+            stack_pointer_el0: stack_pointer,
+            exception_link_reg: entry,
+            // el0 with all interrupts unmasked
+            saved_program_status_reg: SPSR_MODE_EL0,
+        }
+    }
+
+    /// A frame that starts executing kernel code at `entry` with `argument` in `x0`.
+    /// The code runs on the stack the frame is restored from.
+    pub fn new_kernel(entry: u64, argument: u64) -> ExceptionFrame {
+        let mut registers = [0; 31];
+        registers[0] = argument;
+        ExceptionFrame {
+            registers,
+            stack_pointer_el0: 0,
+            exception_link_reg: entry,
+            // el1 with its own stack pointer and all interrupts unmasked
+            saved_program_status_reg: SPSR_MODE_EL1H,
+        }
+    }
+
+    pub fn register(&self, index: usize) -> u64 {
+        self.registers[index]
+    }
+
+    pub fn set_register(&mut self, index: usize, value: u64) {
+        self.registers[index] = value;
+    }
+
+    /// The address execution resumes from when the handler returns.
+    pub fn program_counter(&self) -> u64 {
+        self.exception_link_reg
+    }
+
+    pub fn set_program_counter(&mut self, address: u64) {
+        self.exception_link_reg = address;
+    }
+
+    /// The stack pointer of el0, which is only in use
+    /// if the exception was taken from el0.
+    pub fn user_stack_pointer(&self) -> u64 {
+        self.stack_pointer_el0
+    }
+
+    pub fn set_user_stack_pointer(&mut self, address: u64) {
+        self.stack_pointer_el0 = address;
+    }
+
+    pub fn saved_program_status(&self) -> u64 {
+        self.saved_program_status_reg
+    }
+
+    /// Makes the frame return to el0 with all interrupts unmasked,
+    /// keeping only the condition flags of the saved status.
+    pub fn force_user_mode(&mut self) {
+        self.saved_program_status_reg =
+            (self.saved_program_status_reg & SPSR_CONDITION_FLAGS_MASK) | SPSR_MODE_EL0;
+    }
+
+    /// Returns true if the exception was taken from el0.
+    pub fn is_from_user(&self) -> bool {
+        self.saved_program_status_reg & SPSR_MODE_MASK == SPSR_MODE_EL0
+    }
+
+    /// Makes execution resume from the instruction after the one that caused
+    /// the exception. This should not be used for supervisor calls, since
+    /// those already return to the next instruction.
+    pub fn skip_instruction(&mut self) {
+        self.exception_link_reg += INSTRUCTION_SIZE;
+    }
+}
 
 #[no_mangle]
 pub extern "C" fn handle_sync_exception(
     frame: &mut ExceptionFrame,
     syndrome_reg: u64,
     fault_addr_reg: u64,
+    from_user: bool,
 ) {
-    match syndrome_reg & EXCEPTION_CLASS_MASK {
-        SVC_EXCEPTION_CLASS => {
-            crate::println!("[INFO]: syscall");
-            return syscalls::syscall(&mut frame.registers[..5]);
+    let syndrome = ExceptionSyndrome::decode(syndrome_reg);
+    match syndrome {
+        ExceptionSyndrome::SupervisorCall { immediate, .. } => {
+            syscalls::syscall(frame, immediate, from_user);
+        }
+        _ if from_user => {
+            // faults of user code only affect its own process
+            let signal = fault_signal(syndrome);
+            crate::println!(
+                "[INFO]: process {} faulted at 0x{:016x}: {}",
+                crate::process::current_pid(),
+                frame.program_counter(),
+                syndrome
+            );
+            if syndrome.is_fault_address_valid() {
+                crate::println!("fault address register: 0x{:016x}", fault_addr_reg);
+            }
+            signal::raise_fault(frame, signal);
         }
-        _ => {
-            crate::println!("[ERROR]: synchronous exception caught");
-            crate::println!("syndrome register: 0x{:016x}", syndrome_reg);
-            crate::println!("fault address register: 0x{:016x}", fault_addr_reg);
+        ExceptionSyndrome::BreakpointInstruction { comment, .. } => {
+            crate::println!(
+                "[INFO]: breakpoint #0x{:x} at 0x{:016x}",
+                comment,
+                frame.program_counter()
+            );
+            frame.skip_instruction();
         }
+        _ => fatal_exception(
+            "synchronous",
+            syndrome,
+            syndrome_reg,
+            fault_addr_reg,
+            frame,
+            from_user,
+        ),
     }
-
-    crate::println!("{:?}", frame);
-    loop {}
 }
 
 #[no_mangle]
 pub extern "C" fn handle_irq_exception(
-    _frame: &mut ExceptionFrame,
-    syndrome_reg: u64,
-    fault_addr_reg: u64,
+    frame: &mut ExceptionFrame,
+    _syndrome_reg: u64,
+    _fault_addr_reg: u64,
+    _from_user: bool,
 ) {
-    crate::println!("[ERROR]: IRQ exception caught");
-    crate::println!("syndrome register: 0x{:016x}", syndrome_reg);
-    crate::println!("fault address register: 0x{:016x}", fault_addr_reg);
-    loop {}
+    crate::interrupts::handle_irq(frame);
 }
 
 #[no_mangle]
 pub extern "C" fn handle_fiq_exception(
-    _frame: &mut ExceptionFrame,
+    frame: &mut ExceptionFrame,
+    _syndrome_reg: u64,
+    _fault_addr_reg: u64,
+    from_user: bool,
+) {
+    crate::println!(
+        "[WARN]: unhandled FIQ exception at 0x{:016x} in {} mode",
+        frame.program_counter(),
+        mode_name(from_user)
+    );
+}
+
+#[no_mangle]
+pub extern "C" fn handle_serror_exception(
+    frame: &mut ExceptionFrame,
     syndrome_reg: u64,
     fault_addr_reg: u64,
+    from_user: bool,
 ) {
-    crate::println!("[ERROR]: FIQ exception caught");
-    crate::println!("syndrome register: 0x{:016x}", syndrome_reg);
-    crate::println!("fault address register: 0x{:016x}", fault_addr_reg);
-    loop {}
+    use syndrome::{SErrorSyndrome::Asynchronous, SErrorType::*};
+
+    let syndrome = ExceptionSyndrome::decode(syndrome_reg);
+    match syndrome {
+        ExceptionSyndrome::SError(Asynchronous {
+            error_type: Corrected | Restartable | Recoverable,
+        }) => {
+            crate::println!(
+                "[WARN]: recoverable SError exception caught in {} mode",
+                mode_name(from_user)
+            );
+            report_exception(syndrome, syndrome_reg, fault_addr_reg, frame);
+        }
+        _ => fatal_exception(
+            "SError",
+            syndrome,
+            syndrome_reg,
+            fault_addr_reg,
+            frame,
+            from_user,
+        ),
+    }
 }
 
+/// Called by vectortable.s before every handler, with `from_user` set
+/// if the exception was taken from el0.
 #[no_mangle]
-pub extern "C" fn handle_serror_exception(
-    _frame: &mut ExceptionFrame,
+pub extern "C" fn start_exception(from_user: bool) {
+    // the time since the thread last left the kernel was spent in user mode
+    if from_user {
+        crate::process::charge_cpu_time(true);
+    }
+}
+
+/// Called by vectortable.s after every handler. Returns the frame to restore,
+/// which differs from `frame` when the scheduler switches contexts.
+#[no_mangle]
+pub extern "C" fn finish_exception(frame: &mut ExceptionFrame) -> *mut ExceptionFrame {
+    // a frame from el0 is the user state of the current thread, which is
+    // where signals are delivered
+    crate::process::charge_cpu_time(false);
+    if frame.is_from_user() {
+        signal::deliver_pending(frame);
+    }
+    crate::scheduler::schedule(frame)
+}
+
+/// Called by vectortable.s once it uses the stack of the frame returned by `finish_exception`.
+#[no_mangle]
+pub extern "C" fn finish_context_switch() {
+    if let Some(thread) = crate::scheduler::finish_switch() {
+        unsafe { crate::process::release_exited_thread(thread) };
+    }
+    crate::process::reset_cpu_time_mark();
+}
+
+fn fatal_exception(
+    kind: &str,
+    syndrome: ExceptionSyndrome,
+    syndrome_reg: u64,
+    fault_addr_reg: u64,
+    frame: &ExceptionFrame,
+    from_user: bool,
+) -> ! {
+    crate::println!(
+        "[ERROR]: {} exception caught in {} mode",
+        kind,
+        mode_name(from_user)
+    );
+    report_exception(syndrome, syndrome_reg, fault_addr_reg, frame);
+    crate::println!("{:?}", frame);
+    panic!("unrecoverable {} exception", kind);
+}
+
+/// The signal raised by a synchronous exception of user code other than a syscall.
+fn fault_signal(syndrome: ExceptionSyndrome) -> Signal {
+    use ExceptionSyndrome::*;
+    match syndrome {
+        InstructionAbort {
+            fault: FaultStatus::Alignment,
+            ..
+        }
+        | DataAbort {
+            fault: FaultStatus::Alignment,
+            ..
+        }
+        | PcAlignmentFault
+        | SpAlignmentFault => Signal::SIGBUS,
+        InstructionAbort { .. } | DataAbort { .. } => Signal::SIGSEGV,
+        FloatingPointException { .. } => Signal::SIGFPE,
+        Breakpoint { .. }
+        | SoftwareStep { .. }
+        | Watchpoint { .. }
+        | BreakpointInstruction { .. } => Signal::SIGTRAP,
+        // everything else is an instruction user code may not execute
+        _ => Signal::SIGILL,
+    }
+}
+
+fn mode_name(from_user: bool) -> &'static str {
+    if from_user {
+        "user"
+    } else {
+        "kernel"
+    }
+}
+
+/// Prints a human-readable description of an exception
+/// along with the registers describing where it was taken.
+fn report_exception(
+    syndrome: ExceptionSyndrome,
     syndrome_reg: u64,
     fault_addr_reg: u64,
+    frame: &ExceptionFrame,
 ) {
-    crate::println!("[ERROR]: SError exception caught");
+    crate::println!("cause: {}", syndrome);
     crate::println!("syndrome register: 0x{:016x}", syndrome_reg);
-    crate::println!("fault address register: 0x{:016x}", fault_addr_reg);
-    loop {}
+    if syndrome.is_fault_address_valid() {
+        crate::println!("fault address register: 0x{:016x}", fault_addr_reg);
+    } else {
+        crate::println!(
+            "fault address register: 0x{:016x} (not valid)",
+            fault_addr_reg
+        );
+    }
+    crate::println!(
+        "exception link register: 0x{:016x}",
+        frame.exception_link_reg
+    );
+    crate::println!(
+        "saved program status register: 0x{:016x}",
+        frame.saved_program_status_reg
+    );
 }
@@ -0,0 +1,497 @@
+use core::fmt;
+
+// ESR_EL1 layout
+const EXCEPTION_CLASS_SHIFT: u64 = 26;
+const EXCEPTION_CLASS_MASK: u64 = 0b11_1111;
+const ISS_MASK: u64 = 0x01ff_ffff;
+
+// exception classes
+const UNKNOWN_REASON: u8 = 0x00;
+const TRAPPED_WFI_WFE: u8 = 0x01;
+const TRAPPED_FP_ACCESS: u8 = 0x07;
+const ILLEGAL_EXECUTION_STATE: u8 = 0x0e;
+const SVC_AARCH32: u8 = 0x11;
+const SVC_AARCH64: u8 = 0x15;
+const HVC_AARCH64: u8 = 0x16;
+const SMC_AARCH64: u8 = 0x17;
+const TRAPPED_SYSTEM_INSTRUCTION: u8 = 0x18;
+const INSTRUCTION_ABORT_LOWER_EL: u8 = 0x20;
+const INSTRUCTION_ABORT_SAME_EL: u8 = 0x21;
+const PC_ALIGNMENT_FAULT: u8 = 0x22;
+const DATA_ABORT_LOWER_EL: u8 = 0x24;
+const DATA_ABORT_SAME_EL: u8 = 0x25;
+const SP_ALIGNMENT_FAULT: u8 = 0x26;
+const FP_EXCEPTION_AARCH32: u8 = 0x28;
+const FP_EXCEPTION_AARCH64: u8 = 0x2c;
+const SERROR_INTERRUPT: u8 = 0x2f;
+const BREAKPOINT_LOWER_EL: u8 = 0x30;
+const BREAKPOINT_SAME_EL: u8 = 0x31;
+const SOFTWARE_STEP_LOWER_EL: u8 = 0x32;
+const SOFTWARE_STEP_SAME_EL: u8 = 0x33;
+const WATCHPOINT_LOWER_EL: u8 = 0x34;
+const WATCHPOINT_SAME_EL: u8 = 0x35;
+const BKPT_AARCH32: u8 = 0x38;
+const BRK_AARCH64: u8 = 0x3c;
+
+// abort ISS fields
+const FAULT_STATUS_CODE_MASK: u32 = 0b11_1111;
+const WRITE_NOT_READ_BIT: u32 = 1 << 6;
+const STAGE1_TABLE_WALK_BIT: u32 = 1 << 7;
+const CACHE_MAINTENANCE_BIT: u32 = 1 << 8;
+const FAR_NOT_VALID_BIT: u32 = 1 << 10;
+
+// SError ISS fields
+const IMPLEMENTATION_DEFINED_SERROR_BIT: u32 = 1 << 24;
+const ERROR_TYPE_SHIFT: u32 = 10;
+const ERROR_TYPE_MASK: u32 = 0b111;
+
+// FP exception ISS fields
+const TRAPPED_FAULT_VALID_BIT: u32 = 1 << 23;
+const FP_EXCEPTION_FLAGS_MASK: u32 = 0b1001_1111;
+
+/// A decoded `ESR_EL1` value.
+#[derive(Clone, Copy, Debug)]
+pub enum ExceptionSyndrome {
+    /// Also used for undefined instructions.
+    UndefinedInstruction,
+    TrappedWfiWfe,
+    TrappedFloatingPointAccess,
+    IllegalExecutionState,
+    SupervisorCall {
+        immediate: u16,
+        from_aarch32: bool,
+    },
+    HypervisorCall {
+        immediate: u16,
+    },
+    SecureMonitorCall {
+        immediate: u16,
+    },
+    TrappedSystemInstruction {
+        iss: u32,
+    },
+    InstructionAbort {
+        from_lower_el: bool,
+        fault: FaultStatus,
+        is_far_valid: bool,
+        on_table_walk: bool,
+    },
+    PcAlignmentFault,
+    DataAbort {
+        from_lower_el: bool,
+        fault: FaultStatus,
+        is_far_valid: bool,
+        on_table_walk: bool,
+        is_write: bool,
+        is_cache_maintenance: bool,
+    },
+    SpAlignmentFault,
+    FloatingPointException {
+        flags: Option<FloatingPointFlags>,
+        from_aarch32: bool,
+    },
+    SError(SErrorSyndrome),
+    Breakpoint {
+        from_lower_el: bool,
+    },
+    SoftwareStep {
+        from_lower_el: bool,
+    },
+    Watchpoint {
+        from_lower_el: bool,
+        is_write: bool,
+    },
+    BreakpointInstruction {
+        comment: u16,
+        from_aarch32: bool,
+    },
+    Other {
+        class: u8,
+        iss: u32,
+    },
+}
+
+/// The fault status code (DFSC or IFSC) of a data or instruction abort.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FaultStatus {
+    AddressSize { level: u8 },
+    Translation { level: u8 },
+    AccessFlag { level: u8 },
+    Permission { level: u8 },
+    SynchronousExternal,
+    SynchronousExternalOnTableWalk { level: u8 },
+    SynchronousParity,
+    SynchronousParityOnTableWalk { level: u8 },
+    Alignment,
+    TlbConflict,
+    UnsupportedAtomicUpdate,
+    Other(u8),
+}
+
+/// The exceptions that were raised by a trapped floating point instruction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FloatingPointFlags {
+    bits: u8,
+}
+
+/// The syndrome of an SError interrupt.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SErrorSyndrome {
+    ImplementationDefined { iss: u32 },
+    Uncategorized,
+    Asynchronous { error_type: SErrorType },
+    Other { status: u8 },
+}
+
+/// The architecturally defined error types (AET) of an SError interrupt.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SErrorType {
+    Uncontainable,
+    Unrecoverable,
+    Restartable,
+    Recoverable,
+    Corrected,
+    Reserved(u8),
+}
+
+impl ExceptionSyndrome {
+    pub fn decode(syndrome_reg: u64) -> ExceptionSyndrome {
+        use ExceptionSyndrome::*;
+
+        let class = ((syndrome_reg >> EXCEPTION_CLASS_SHIFT) & EXCEPTION_CLASS_MASK) as u8;
+        let iss = (syndrome_reg & ISS_MASK) as u32;
+
+        match class {
+            UNKNOWN_REASON => UndefinedInstruction,
+            TRAPPED_WFI_WFE => TrappedWfiWfe,
+            TRAPPED_FP_ACCESS => TrappedFloatingPointAccess,
+            ILLEGAL_EXECUTION_STATE => IllegalExecutionState,
+            SVC_AARCH32 | SVC_AARCH64 => SupervisorCall {
+                immediate: iss as u16,
+                from_aarch32: class == SVC_AARCH32,
+            },
+            HVC_AARCH64 => HypervisorCall {
+                immediate: iss as u16,
+            },
+            SMC_AARCH64 => SecureMonitorCall {
+                immediate: iss as u16,
+            },
+            TRAPPED_SYSTEM_INSTRUCTION => TrappedSystemInstruction { iss },
+            INSTRUCTION_ABORT_LOWER_EL | INSTRUCTION_ABORT_SAME_EL => InstructionAbort {
+                from_lower_el: class == INSTRUCTION_ABORT_LOWER_EL,
+                fault: FaultStatus::decode((iss & FAULT_STATUS_CODE_MASK) as u8),
+                is_far_valid: iss & FAR_NOT_VALID_BIT == 0,
+                on_table_walk: iss & STAGE1_TABLE_WALK_BIT != 0,
+            },
+            PC_ALIGNMENT_FAULT => PcAlignmentFault,
+            DATA_ABORT_LOWER_EL | DATA_ABORT_SAME_EL => DataAbort {
+                from_lower_el: class == DATA_ABORT_LOWER_EL,
+                fault: FaultStatus::decode((iss & FAULT_STATUS_CODE_MASK) as u8),
+                is_far_valid: iss & FAR_NOT_VALID_BIT == 0,
+                on_table_walk: iss & STAGE1_TABLE_WALK_BIT != 0,
+                is_write: iss & WRITE_NOT_READ_BIT != 0,
+                is_cache_maintenance: iss & CACHE_MAINTENANCE_BIT != 0,
+            },
+            SP_ALIGNMENT_FAULT => SpAlignmentFault,
+            FP_EXCEPTION_AARCH32 | FP_EXCEPTION_AARCH64 => FloatingPointException {
+                flags: if iss & TRAPPED_FAULT_VALID_BIT != 0 {
+                    Some(FloatingPointFlags {
+                        bits: (iss & FP_EXCEPTION_FLAGS_MASK) as u8,
+                    })
+                } else {
+                    None
+                },
+                from_aarch32: class == FP_EXCEPTION_AARCH32,
+            },
+            SERROR_INTERRUPT => SError(SErrorSyndrome::decode(iss)),
+            BREAKPOINT_LOWER_EL | BREAKPOINT_SAME_EL => Breakpoint {
+                from_lower_el: class == BREAKPOINT_LOWER_EL,
+            },
+            SOFTWARE_STEP_LOWER_EL | SOFTWARE_STEP_SAME_EL => SoftwareStep {
+                from_lower_el: class == SOFTWARE_STEP_LOWER_EL,
+            },
+            WATCHPOINT_LOWER_EL | WATCHPOINT_SAME_EL => Watchpoint {
+                from_lower_el: class == WATCHPOINT_LOWER_EL,
+                is_write: iss & WRITE_NOT_READ_BIT != 0,
+            },
+            BKPT_AARCH32 | BRK_AARCH64 => BreakpointInstruction {
+                comment: iss as u16,
+                from_aarch32: class == BKPT_AARCH32,
+            },
+            _ => Other { class, iss },
+        }
+    }
+
+    /// Returns true if the fault address register holds
+    /// a meaningful value for this exception.
+    pub fn is_fault_address_valid(&self) -> bool {
+        use ExceptionSyndrome::*;
+        match *self {
+            InstructionAbort { is_far_valid, .. } | DataAbort { is_far_valid, .. } => is_far_valid,
+            PcAlignmentFault | Watchpoint { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+impl FaultStatus {
+    fn decode(code: u8) -> FaultStatus {
+        use FaultStatus::*;
+
+        let level = code & 0b11;
+        match code {
+            0b00_0000..=0b00_0011 => AddressSize { level },
+            0b00_0100..=0b00_0111 => Translation { level },
+            0b00_1000..=0b00_1011 => AccessFlag { level },
+            0b00_1100..=0b00_1111 => Permission { level },
+            0b01_0000 => SynchronousExternal,
+            0b01_0100..=0b01_0111 => SynchronousExternalOnTableWalk { level },
+            0b01_1000 => SynchronousParity,
+            0b01_1100..=0b01_1111 => SynchronousParityOnTableWalk { level },
+            0b10_0001 => Alignment,
+            0b11_0000 => TlbConflict,
+            0b11_0001 => UnsupportedAtomicUpdate,
+            _ => Other(code),
+        }
+    }
+}
+
+impl FloatingPointFlags {
+    const NAMES: [(u8, &'static str); 6] = [
+        (1 << 0, "invalid operation"),
+        (1 << 1, "divide by zero"),
+        (1 << 2, "overflow"),
+        (1 << 3, "underflow"),
+        (1 << 4, "inexact"),
+        (1 << 7, "input denormal"),
+    ];
+}
+
+impl SErrorSyndrome {
+    fn decode(iss: u32) -> SErrorSyndrome {
+        use SErrorSyndrome::*;
+
+        if iss & IMPLEMENTATION_DEFINED_SERROR_BIT != 0 {
+            return ImplementationDefined { iss };
+        }
+
+        match (iss & FAULT_STATUS_CODE_MASK) as u8 {
+            0b00_0000 => Uncategorized,
+            0b01_0001 => Asynchronous {
+                error_type: SErrorType::decode(((iss >> ERROR_TYPE_SHIFT) & ERROR_TYPE_MASK) as u8),
+            },
+            status => Other { status },
+        }
+    }
+}
+
+impl SErrorType {
+    fn decode(value: u8) -> SErrorType {
+        use SErrorType::*;
+        match value {
+            0b000 => Uncontainable,
+            0b001 => Unrecoverable,
+            0b010 => Restartable,
+            0b011 => Recoverable,
+            0b110 => Corrected,
+            _ => Reserved(value),
+        }
+    }
+}
+
+fn origin(from_lower_el: bool) -> &'static str {
+    if from_lower_el {
+        "from a lower exception level"
+    } else {
+        "from the current exception level"
+    }
+}
+
+impl fmt::Display for ExceptionSyndrome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ExceptionSyndrome::*;
+        match *self {
+            UndefinedInstruction => write!(f, "undefined instruction"),
+            TrappedWfiWfe => write!(f, "trapped wfi or wfe instruction"),
+            TrappedFloatingPointAccess => {
+                write!(f, "trapped floating point or simd register access")
+            }
+            IllegalExecutionState => write!(f, "illegal execution state"),
+            SupervisorCall {
+                immediate,
+                from_aarch32,
+            } => {
+                write!(f, "supervisor call #0x{:x}", immediate)?;
+                if from_aarch32 {
+                    write!(f, " from aarch32")?;
+                }
+                Ok(())
+            }
+            HypervisorCall { immediate } => write!(f, "hypervisor call #0x{:x}", immediate),
+            SecureMonitorCall { immediate } => {
+                write!(f, "secure monitor call #0x{:x}", immediate)
+            }
+            TrappedSystemInstruction { iss } => {
+                write!(f, "trapped system register access (iss: 0x{:07x})", iss)
+            }
+            InstructionAbort {
+                from_lower_el,
+                fault,
+                on_table_walk,
+                ..
+            } => {
+                write!(f, "instruction abort {}: {}", origin(from_lower_el), fault)?;
+                if on_table_walk {
+                    write!(f, " during a translation table walk")?;
+                }
+                Ok(())
+            }
+            PcAlignmentFault => write!(f, "misaligned program counter"),
+            DataAbort {
+                from_lower_el,
+                fault,
+                on_table_walk,
+                is_write,
+                is_cache_maintenance,
+                ..
+            } => {
+                let access = match (is_cache_maintenance, is_write) {
+                    (true, _) => "cache maintenance",
+                    (false, true) => "write",
+                    (false, false) => "read",
+                };
+                write!(
+                    f,
+                    "data abort on {} {}: {}",
+                    access,
+                    origin(from_lower_el),
+                    fault
+                )?;
+                if on_table_walk {
+                    write!(f, " during a translation table walk")?;
+                }
+                Ok(())
+            }
+            SpAlignmentFault => write!(f, "misaligned stack pointer"),
+            FloatingPointException {
+                flags,
+                from_aarch32,
+            } => {
+                write!(f, "trapped floating point exception")?;
+                if from_aarch32 {
+                    write!(f, " from aarch32")?;
+                }
+                match flags {
+                    Some(flags) => write!(f, ": {}", flags),
+                    None => Ok(()),
+                }
+            }
+            SError(syndrome) => write!(f, "SError interrupt: {}", syndrome),
+            Breakpoint { from_lower_el } => {
+                write!(f, "hardware breakpoint {}", origin(from_lower_el))
+            }
+            SoftwareStep { from_lower_el } => {
+                write!(f, "software step {}", origin(from_lower_el))
+            }
+            Watchpoint {
+                from_lower_el,
+                is_write,
+            } => write!(
+                f,
+                "watchpoint hit on {} {}",
+                if is_write { "write" } else { "read" },
+                origin(from_lower_el)
+            ),
+            BreakpointInstruction {
+                comment,
+                from_aarch32,
+            } => {
+                if from_aarch32 {
+                    write!(f, "bkpt #0x{:x} instruction", comment)
+                } else {
+                    write!(f, "brk #0x{:x} instruction", comment)
+                }
+            }
+            Other { class, iss } => write!(
+                f,
+                "unknown exception class 0x{:02x} (iss: 0x{:07x})",
+                class, iss
+            ),
+        }
+    }
+}
+
+impl fmt::Display for FaultStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use FaultStatus::*;
+        match *self {
+            AddressSize { level } => write!(f, "address size fault at level {}", level),
+            Translation { level } => write!(f, "translation fault at level {}", level),
+            AccessFlag { level } => write!(f, "access flag fault at level {}", level),
+            Permission { level } => write!(f, "permission fault at level {}", level),
+            SynchronousExternal => write!(f, "synchronous external abort"),
+            SynchronousExternalOnTableWalk { level } => write!(
+                f,
+                "synchronous external abort on table walk at level {}",
+                level
+            ),
+            SynchronousParity => write!(f, "synchronous parity or ecc error"),
+            SynchronousParityOnTableWalk { level } => write!(
+                f,
+                "synchronous parity or ecc error on table walk at level {}",
+                level
+            ),
+            Alignment => write!(f, "alignment fault"),
+            TlbConflict => write!(f, "tlb conflict abort"),
+            UnsupportedAtomicUpdate => write!(f, "unsupported atomic hardware update"),
+            Other(code) => write!(f, "unknown fault status code 0b{:06b}", code),
+        }
+    }
+}
+
+impl fmt::Display for FloatingPointFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut is_first = true;
+        for (bit, name) in FloatingPointFlags::NAMES {
+            if self.bits & bit != 0 {
+                if !is_first {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}", name)?;
+                is_first = false;
+            }
+        }
+        if is_first {
+            write!(f, "no flags set")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SErrorSyndrome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use SErrorSyndrome::*;
+        match *self {
+            ImplementationDefined { iss } => {
+                write!(f, "implementation defined syndrome 0x{:06x}", iss)
+            }
+            Uncategorized => write!(f, "uncategorized error"),
+            Asynchronous { error_type } => write!(f, "asynchronous error, {}", error_type),
+            Other { status } => write!(f, "unknown error status code 0b{:06b}", status),
+        }
+    }
+}
+
+impl fmt::Display for SErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use SErrorType::*;
+        match *self {
+            Uncontainable => write!(f, "uncontainable"),
+            Unrecoverable => write!(f, "unrecoverable"),
+            Restartable => write!(f, "restartable"),
+            Recoverable => write!(f, "recoverable"),
+            Corrected => write!(f, "corrected"),
+            Reserved(value) => write!(f, "reserved error type 0b{:03b}", value),
+        }
+    }
+}
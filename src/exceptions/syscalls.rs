@@ -1 +1,1573 @@
-pub fn syscall(_regs: &mut [u64]) {}
+//! # Syscall ABI
+//!
+//! A syscall is made with the `svc` instruction. If the immediate of the
+//! instruction is non-zero, it is the syscall number, otherwise the number
+//! is read from `x8`. Arguments are passed in `x0`-`x5`.
+//!
+//! On return, `x0` contains the result of the call. Errors are returned as
+//! negated error numbers, so any value in `-4095..0` is an error. All other
+//! registers are preserved.
+
+use core::arch::asm;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of, MaybeUninit};
+use core::ptr::NonNull;
+use core::time::Duration;
+
+use super::{ExceptionFrame, INSTRUCTION_SIZE};
+use crate::arguments::{Arguments, ArgumentsError, MAX_STRINGS_SIZE};
+use crate::cpu::CoreMask;
+use crate::futex::{self, FutexError};
+use crate::handle::{
+    self, Handle, HandleError, HandleTable, Rights, MAX_HANDLES, NO_HANDLE, STDERR, STDIN, STDOUT,
+};
+use crate::ipc::{self, IpcError, MessageInfo, MAX_MESSAGE_SIZE};
+use crate::mailbox::{self, MailboxMessageBuffer, MailboxTagType, MiscTag};
+use crate::memory::addressspace::MapError;
+use crate::memory::pageallocator::{Page, PAGE_ALLOCATOR, PAGE_SIZE};
+use crate::memory::{DEVICE_MEMORY_END, DEVICE_MEMORY_START, USER_SPACE_END, USER_SPACE_START};
+use crate::pipe::{self, PipeError};
+use crate::process::{
+    self, JoinError, Permissions, Process, ProcessError, SendSignalError, WaitError,
+};
+use crate::resource::{Limit, LimitError, Resource, Usage};
+use crate::scheduler::{self, SchedulingPolicy};
+use crate::signal::{self, Signal, SignalAction, SignalSet};
+use crate::thread::Thread;
+use crate::timer::Instant;
+
+pub const SYSCALL_WRITE: u64 = 1;
+pub const SYSCALL_EXIT: u64 = 2;
+pub const SYSCALL_YIELD: u64 = 3;
+pub const SYSCALL_GETPID: u64 = 4;
+pub const SYSCALL_SLEEP: u64 = 5;
+pub const SYSCALL_CLOCK_GETTIME: u64 = 6;
+pub const SYSCALL_SCHED_SETAFFINITY: u64 = 7;
+pub const SYSCALL_SCHED_GETAFFINITY: u64 = 8;
+pub const SYSCALL_SCHED_SETSCHEDULER: u64 = 9;
+pub const SYSCALL_SPAWN: u64 = 10;
+pub const SYSCALL_WAIT: u64 = 11;
+pub const SYSCALL_EXEC: u64 = 12;
+pub const SYSCALL_THREAD_CREATE: u64 = 13;
+pub const SYSCALL_THREAD_EXIT: u64 = 14;
+pub const SYSCALL_JOIN: u64 = 15;
+pub const SYSCALL_FUTEX: u64 = 16;
+pub const SYSCALL_SIGACTION: u64 = 17;
+pub const SYSCALL_SIGPROCMASK: u64 = 18;
+pub const SYSCALL_SIGRETURN: u64 = 19;
+pub const SYSCALL_KILL: u64 = 20;
+pub const SYSCALL_ENDPOINT_CREATE: u64 = 21;
+pub const SYSCALL_SEND: u64 = 22;
+pub const SYSCALL_RECEIVE: u64 = 23;
+pub const SYSCALL_CALL: u64 = 24;
+pub const SYSCALL_REPLY: u64 = 25;
+pub const SYSCALL_HANDLE_DUPLICATE: u64 = 26;
+pub const SYSCALL_CLOSE: u64 = 27;
+pub const SYSCALL_READ: u64 = 28;
+pub const SYSCALL_PIPE: u64 = 29;
+pub const SYSCALL_DUP2: u64 = 30;
+pub const SYSCALL_MAP_DEVICE: u64 = 31;
+pub const SYSCALL_MAILBOX: u64 = 32;
+pub const SYSCALL_DROP_PERMISSIONS: u64 = 33;
+pub const SYSCALL_GETRLIMIT: u64 = 34;
+pub const SYSCALL_SETRLIMIT: u64 = 35;
+pub const SYSCALL_GETRUSAGE: u64 = 36;
+
+const NUM_SYSCALLS: usize = 37;
+
+const SYSCALL_NUMBER_REGISTER: usize = 8;
+const NUM_ARGUMENTS: usize = 6;
+
+const MAX_WRITE_SIZE: usize = 4096;
+const MAX_IMAGE_SIZE: usize = 4 * 1024 * 1024;
+// in `u32`s, including the header and the end tag
+const MAX_MAILBOX_MESSAGE_LEN: usize = 256;
+
+const CLOCK_REALTIME: u64 = 0;
+const CLOCK_MONOTONIC: u64 = 1;
+
+const SCHED_NORMAL: u64 = 0;
+const SCHED_FIFO: u64 = 1;
+const SCHED_RR: u64 = 2;
+
+const FUTEX_WAIT: u64 = 0;
+const FUTEX_WAKE: u64 = 1;
+const FUTEX_REQUEUE: u64 = 3;
+
+const SIG_DFL: u64 = 0;
+const SIG_IGN: u64 = 1;
+
+const SIG_BLOCK: u64 = 0;
+const SIG_UNBLOCK: u64 = 1;
+const SIG_SETMASK: u64 = 2;
+
+const RUSAGE_SELF: u64 = 0;
+const RUSAGE_CHILDREN: u64 = 1;
+
+const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+
+/// Error numbers returned to user space, negated, in `x0`.
+#[allow(dead_code, clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u64)]
+pub enum SyscallError {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EEXIST = 17,
+    EINVAL = 22,
+    ENFILE = 23,
+    EMFILE = 24,
+    EPIPE = 32,
+    ENOSYS = 38,
+    EMSGSIZE = 90,
+    ETIMEDOUT = 110,
+    /// The caller was blocked and the syscall is made again once it runs.
+    /// This is never returned to user space.
+    ERESTARTSYS = 512,
+    /// The syscall replaced the registers of the caller, so `x0` is left as is.
+    /// This is never returned to user space.
+    EJUSTRETURN = 518,
+}
+
+impl From<ProcessError> for SyscallError {
+    fn from(error: ProcessError) -> SyscallError {
+        use ProcessError::*;
+        match error {
+            OutOfMemory => SyscallError::ENOMEM,
+            TooManyProcesses | TooManyThreads | TooManyChildren => SyscallError::EAGAIN,
+            InvalidImage(_) => SyscallError::ENOEXEC,
+            NotAProcess => SyscallError::ESRCH,
+            WouldBlock => SyscallError::ERESTARTSYS,
+            Killed => SyscallError::EINTR,
+        }
+    }
+}
+
+impl From<ArgumentsError> for SyscallError {
+    fn from(error: ArgumentsError) -> SyscallError {
+        match error {
+            ArgumentsError::TooLarge => SyscallError::E2BIG,
+            ArgumentsError::OutOfMemory => SyscallError::ENOMEM,
+        }
+    }
+}
+
+impl From<IpcError> for SyscallError {
+    fn from(error: IpcError) -> SyscallError {
+        use IpcError::*;
+        match error {
+            NotAProcess => SyscallError::ESRCH,
+            InvalidHandle => SyscallError::EBADF,
+            TooManyHandles => SyscallError::EMFILE,
+            TooManyEndpoints => SyscallError::ENFILE,
+            MessageTooLarge => SyscallError::EMSGSIZE,
+            PeerGone => SyscallError::EPIPE,
+            WouldBlock => SyscallError::ERESTARTSYS,
+        }
+    }
+}
+
+impl From<HandleError> for SyscallError {
+    fn from(error: HandleError) -> SyscallError {
+        use HandleError::*;
+        match error {
+            NotAProcess => SyscallError::ESRCH,
+            InvalidHandle => SyscallError::EBADF,
+            TooManyHandles => SyscallError::EMFILE,
+        }
+    }
+}
+
+impl From<PipeError> for SyscallError {
+    fn from(error: PipeError) -> SyscallError {
+        use PipeError::*;
+        match error {
+            NotAProcess => SyscallError::ESRCH,
+            InvalidHandle => SyscallError::EBADF,
+            TooManyHandles => SyscallError::EMFILE,
+            TooManyPipes => SyscallError::ENFILE,
+            BrokenPipe => SyscallError::EPIPE,
+            WouldBlock => SyscallError::ERESTARTSYS,
+        }
+    }
+}
+
+impl From<LimitError> for SyscallError {
+    fn from(error: LimitError) -> SyscallError {
+        use LimitError::*;
+        match error {
+            NotAProcess => SyscallError::ESRCH,
+            InvalidLimit => SyscallError::EINVAL,
+            NotPermitted => SyscallError::EPERM,
+        }
+    }
+}
+
+pub type SyscallResult = Result<u64, SyscallError>;
+
+type SyscallHandler = fn(&mut ExceptionFrame, &SyscallArguments) -> SyscallResult;
+
+/// The handlers of each syscall, indexed by syscall number.
+/// Each handler validates and converts its arguments
+/// before calling the implementation of the syscall.
+const SYSCALL_TABLE: [Option<SyscallHandler>; NUM_SYSCALLS] = {
+    let mut table: [Option<SyscallHandler>; NUM_SYSCALLS] = [None; NUM_SYSCALLS];
+    table[SYSCALL_WRITE as usize] = Some(dispatch_write);
+    table[SYSCALL_EXIT as usize] = Some(dispatch_exit);
+    table[SYSCALL_YIELD as usize] = Some(dispatch_yield);
+    table[SYSCALL_GETPID as usize] = Some(dispatch_getpid);
+    table[SYSCALL_SLEEP as usize] = Some(dispatch_sleep);
+    table[SYSCALL_CLOCK_GETTIME as usize] = Some(dispatch_clock_gettime);
+    table[SYSCALL_SCHED_SETAFFINITY as usize] = Some(dispatch_sched_setaffinity);
+    table[SYSCALL_SCHED_GETAFFINITY as usize] = Some(dispatch_sched_getaffinity);
+    table[SYSCALL_SCHED_SETSCHEDULER as usize] = Some(dispatch_sched_setscheduler);
+    table[SYSCALL_SPAWN as usize] = Some(dispatch_spawn);
+    table[SYSCALL_WAIT as usize] = Some(dispatch_wait);
+    table[SYSCALL_EXEC as usize] = Some(dispatch_exec);
+    table[SYSCALL_THREAD_CREATE as usize] = Some(dispatch_thread_create);
+    table[SYSCALL_THREAD_EXIT as usize] = Some(dispatch_thread_exit);
+    table[SYSCALL_JOIN as usize] = Some(dispatch_join);
+    table[SYSCALL_FUTEX as usize] = Some(dispatch_futex);
+    table[SYSCALL_SIGACTION as usize] = Some(dispatch_sigaction);
+    table[SYSCALL_SIGPROCMASK as usize] = Some(dispatch_sigprocmask);
+    table[SYSCALL_SIGRETURN as usize] = Some(dispatch_sigreturn);
+    table[SYSCALL_KILL as usize] = Some(dispatch_kill);
+    table[SYSCALL_ENDPOINT_CREATE as usize] = Some(dispatch_endpoint_create);
+    table[SYSCALL_SEND as usize] = Some(dispatch_send);
+    table[SYSCALL_RECEIVE as usize] = Some(dispatch_receive);
+    table[SYSCALL_CALL as usize] = Some(dispatch_call);
+    table[SYSCALL_REPLY as usize] = Some(dispatch_reply);
+    table[SYSCALL_HANDLE_DUPLICATE as usize] = Some(dispatch_handle_duplicate);
+    table[SYSCALL_CLOSE as usize] = Some(dispatch_close);
+    table[SYSCALL_READ as usize] = Some(dispatch_read);
+    table[SYSCALL_PIPE as usize] = Some(dispatch_pipe);
+    table[SYSCALL_DUP2 as usize] = Some(dispatch_dup2);
+    table[SYSCALL_MAP_DEVICE as usize] = Some(dispatch_map_device);
+    table[SYSCALL_MAILBOX as usize] = Some(dispatch_mailbox);
+    table[SYSCALL_DROP_PERMISSIONS as usize] = Some(dispatch_drop_permissions);
+    table[SYSCALL_GETRLIMIT as usize] = Some(dispatch_getrlimit);
+    table[SYSCALL_SETRLIMIT as usize] = Some(dispatch_setrlimit);
+    table[SYSCALL_GETRUSAGE as usize] = Some(dispatch_getrusage);
+    table
+};
+
+/// The raw arguments of a syscall, read from `x0`-`x5`.
+pub struct SyscallArguments {
+    values: [u64; NUM_ARGUMENTS],
+    from_user: bool,
+}
+
+impl SyscallArguments {
+    fn get(&self, index: usize) -> u64 {
+        self.values[index]
+    }
+
+    fn get_usize(&self, index: usize) -> Result<usize, SyscallError> {
+        usize::try_from(self.values[index]).map_err(|_| SyscallError::EINVAL)
+    }
+
+    /// Interprets the arguments at `address_index` and `len_index`
+    /// as a buffer of the caller.
+    fn get_buffer(
+        &self,
+        address_index: usize,
+        len_index: usize,
+    ) -> Result<UserBuffer, SyscallError> {
+        self.buffer(self.get_usize(address_index)?, self.get_usize(len_index)?)
+    }
+
+    /// Interprets the arguments at `address_index` and `len_index`
+    /// as an array of `T`s of the caller.
+    fn get_array<T>(
+        &self,
+        address_index: usize,
+        len_index: usize,
+    ) -> Result<UserBuffer, SyscallError> {
+        let address = self.get_usize(address_index)?;
+        let size = self
+            .get_usize(len_index)?
+            .checked_mul(size_of::<T>())
+            .ok_or(SyscallError::EFAULT)?;
+        self.check_range(address, size, align_of::<T>())?;
+        Ok(UserBuffer {
+            address,
+            len: size,
+            from_user: self.from_user,
+        })
+    }
+
+    /// Interprets the argument at `index` as a handle, or as no handle if it is `NO_HANDLE`.
+    fn get_handle(&self, index: usize) -> Result<Option<usize>, SyscallError> {
+        match self.get(index) {
+            NO_HANDLE => Ok(None),
+            handle => usize::try_from(handle)
+                .map(Some)
+                .map_err(|_| SyscallError::EBADF),
+        }
+    }
+
+    /// Interprets the argument at `index` as a pointer to a `T` of the caller.
+    fn get_pointer<T: Copy>(&self, index: usize) -> Result<UserPointer<T>, SyscallError> {
+        self.pointer(self.get_usize(index)?)
+    }
+
+    /// Interprets the argument at `index` as a pointer to a futex word, and returns
+    /// the physical address of the word, which identifies the futex.
+    fn get_futex(&self, index: usize) -> Result<usize, SyscallError> {
+        let address = self.get_usize(index)?;
+        self.check_range(address, size_of::<u32>(), align_of::<u32>())?;
+        if !self.from_user {
+            // the kernel is identity mapped
+            return Ok(address);
+        }
+        // the word is read through its physical address, which must not be a device
+        process::translate_current(address)
+            .filter(|key| !(DEVICE_MEMORY_START..DEVICE_MEMORY_END).contains(key))
+            .ok_or(SyscallError::EFAULT)
+    }
+
+    /// Copies the program image in the buffer at `address_index` and `len_index`
+    /// into the kernel, so that it can be loaded while the caller keeps running.
+    fn copy_image(&self, address_index: usize, len_index: usize) -> Result<PageCopy, SyscallError> {
+        let image = self.get_buffer(address_index, len_index)?;
+        if image.len > MAX_IMAGE_SIZE {
+            return Err(SyscallError::ENOMEM);
+        }
+        PageCopy::new(image)
+    }
+
+    fn buffer(&self, address: usize, len: usize) -> Result<UserBuffer, SyscallError> {
+        self.check_range(address, len, 1)?;
+        Ok(UserBuffer {
+            address,
+            len,
+            from_user: self.from_user,
+        })
+    }
+
+    fn pointer<T: Copy>(&self, address: usize) -> Result<UserPointer<T>, SyscallError> {
+        self.check_range(address, size_of::<T>(), align_of::<T>())?;
+        Ok(UserPointer {
+            buffer: UserBuffer {
+                address,
+                len: size_of::<T>(),
+                from_user: self.from_user,
+            },
+            value_type: PhantomData,
+        })
+    }
+
+    /// Copies the null terminated arrays of strings at `argv` and `envp`,
+    /// which are empty if their pointer is null.
+    fn copy_arguments(&self, argv: u64, envp: u64) -> Result<Arguments, SyscallError> {
+        let mut arguments = Arguments::new();
+        self.copy_strings(argv, |string| arguments.push_argument(string))?;
+        self.copy_strings(envp, |string| arguments.push_variable(string))?;
+        Ok(arguments)
+    }
+
+    fn copy_strings(
+        &self,
+        array: u64,
+        mut push: impl FnMut(&[u8]) -> Result<(), ArgumentsError>,
+    ) -> Result<(), SyscallError> {
+        let mut entry = usize::try_from(array).map_err(|_| SyscallError::EFAULT)?;
+        if entry == 0 {
+            return Ok(());
+        }
+        let mut string = [0; MAX_STRINGS_SIZE];
+        loop {
+            let address = self.pointer::<u64>(entry)?.read()?;
+            if address == 0 {
+                return Ok(());
+            }
+            let address = usize::try_from(address).map_err(|_| SyscallError::EFAULT)?;
+            let mut len = 0;
+            loop {
+                // the string may end just before a page that is not mapped,
+                // so it is copied up to the end of each page at a time
+                let part_address = address.checked_add(len).ok_or(SyscallError::EFAULT)?;
+                let part_len = (PAGE_SIZE - part_address % PAGE_SIZE).min(MAX_STRINGS_SIZE - len);
+                if part_len == 0 {
+                    return Err(SyscallError::E2BIG);
+                }
+                let part = &mut string[len..len + part_len];
+                self.buffer(part_address, part_len)?.read(part)?;
+                match part.iter().position(|&byte| byte == 0) {
+                    Some(end) => {
+                        len += end;
+                        break;
+                    }
+                    None => len += part_len,
+                }
+            }
+            push(&string[..len])?;
+            entry = entry
+                .checked_add(size_of::<u64>())
+                .ok_or(SyscallError::EFAULT)?;
+        }
+    }
+
+    /// Checks that the range is in user space if the caller is in user mode.
+    /// Whether it is mapped is only known once it is copied.
+    fn check_range(&self, address: usize, len: usize, align: usize) -> Result<(), SyscallError> {
+        let end = address.checked_add(len).ok_or(SyscallError::EFAULT)?;
+        if address == 0 || !address.is_multiple_of(align) {
+            return Err(SyscallError::EFAULT);
+        }
+        if self.from_user && (address < USER_SPACE_START || end > USER_SPACE_END) {
+            return Err(SyscallError::EFAULT);
+        }
+        Ok(())
+    }
+}
+
+/// A range of memory of the caller. The memory of a process can be unmapped by its
+/// other threads at any time, so it is only accessed by copying it under the lock of
+/// the process, which fails with `EFAULT` if the range is not mapped.
+#[derive(Clone, Copy)]
+struct UserBuffer {
+    address: usize,
+    len: usize,
+    from_user: bool,
+}
+
+impl UserBuffer {
+    /// Fills `buffer` from the start of the range, which must be at least as long.
+    fn read(&self, buffer: &mut [u8]) -> Result<(), SyscallError> {
+        assert!(buffer.len() <= self.len);
+        if !self.from_user {
+            // the kernel only passes its own memory, which is always mapped
+            unsafe {
+                let source = self.address as *const u8;
+                core::ptr::copy_nonoverlapping(source, buffer.as_mut_ptr(), buffer.len());
+            }
+            return Ok(());
+        }
+        match process::copy_from_current(self.address, buffer) {
+            true => Ok(()),
+            false => Err(SyscallError::EFAULT),
+        }
+    }
+
+    /// Copies `bytes` to the start of the range, which must be at least as long.
+    fn write(&self, bytes: &[u8]) -> Result<(), SyscallError> {
+        assert!(bytes.len() <= self.len);
+        if !self.from_user {
+            unsafe {
+                let destination = self.address as *mut u8;
+                core::ptr::copy_nonoverlapping(bytes.as_ptr(), destination, bytes.len());
+            }
+            return Ok(());
+        }
+        match process::copy_to_current(self.address, bytes) {
+            true => Ok(()),
+            false => Err(SyscallError::EFAULT),
+        }
+    }
+}
+
+/// A pointer to a `T` of the caller, which is copied like a `UserBuffer`.
+/// Only used with types of integers, which are valid for any bytes.
+struct UserPointer<T> {
+    buffer: UserBuffer,
+    value_type: PhantomData<T>,
+}
+
+impl<T: Copy> UserPointer<T> {
+    fn read(&self) -> Result<T, SyscallError> {
+        let mut value = MaybeUninit::<T>::zeroed();
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(value.as_mut_ptr().cast::<u8>(), size_of::<T>())
+        };
+        self.buffer.read(bytes)?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    fn write(&self, value: T) -> Result<(), SyscallError> {
+        let bytes = unsafe {
+            core::slice::from_raw_parts((&value as *const T).cast::<u8>(), size_of::<T>())
+        };
+        self.buffer.write(bytes)
+    }
+}
+
+/// A copy of a buffer of the caller in pages of the kernel, for buffers
+/// too large for the kernel stack, like the images of programs.
+struct PageCopy {
+    pages: NonNull<Page>,
+    num_pages: usize,
+    len: usize,
+}
+
+impl PageCopy {
+    fn new(buffer: UserBuffer) -> Result<PageCopy, SyscallError> {
+        let num_pages = buffer.len.div_ceil(PAGE_SIZE).max(1);
+        let pages = PAGE_ALLOCATOR
+            .lock()
+            .alloc_page(num_pages)
+            .ok_or(SyscallError::ENOMEM)?;
+        let mut copy = PageCopy {
+            pages,
+            num_pages,
+            len: buffer.len,
+        };
+        buffer.read(copy.bytes_mut())?;
+        Ok(copy)
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.pages.as_ptr().cast::<u8>(), self.len) }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.pages.as_ptr().cast::<u8>(), self.len) }
+    }
+}
+
+impl Drop for PageCopy {
+    fn drop(&mut self) {
+        unsafe { PAGE_ALLOCATOR.lock().free_pages(self.pages, self.num_pages) };
+    }
+}
+
+/// Decodes a syscall from the frame, dispatches it,
+/// and writes the result into `x0` of the frame.
+pub fn syscall(frame: &mut ExceptionFrame, immediate: u16, from_user: bool) {
+    let number = if immediate != 0 {
+        immediate as u64
+    } else {
+        frame.register(SYSCALL_NUMBER_REGISTER)
+    };
+
+    let mut values = [0; NUM_ARGUMENTS];
+    for (index, value) in values.iter_mut().enumerate() {
+        *value = frame.register(index);
+    }
+    let arguments = SyscallArguments { values, from_user };
+
+    let handler = usize::try_from(number)
+        .ok()
+        .and_then(|index| SYSCALL_TABLE.get(index).copied().flatten());
+
+    let result = match handler {
+        Some(handler) => handler(frame, &arguments),
+        None => Err(SyscallError::ENOSYS),
+    };
+
+    match result {
+        Err(SyscallError::ERESTARTSYS) => {
+            // run the `svc` again with the same arguments
+            frame.set_program_counter(frame.program_counter() - INSTRUCTION_SIZE);
+        }
+        Err(SyscallError::EJUSTRETURN) => {}
+        result => frame.set_register(0, encode_result(result)),
+    }
+}
+
+fn encode_result(result: SyscallResult) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(error) => (error as u64).wrapping_neg(),
+    }
+}
+
+fn dispatch_write(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    let fd = arguments.get_usize(0).map_err(|_| SyscallError::EBADF)?;
+    let buffer = arguments.get_buffer(1, 2)?;
+    sys_write(fd, buffer)
+}
+
+fn dispatch_exit(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    sys_exit(arguments.get(0) as i32)
+}
+
+fn dispatch_yield(_frame: &mut ExceptionFrame, _arguments: &SyscallArguments) -> SyscallResult {
+    sys_yield()
+}
+
+fn dispatch_getpid(_frame: &mut ExceptionFrame, _arguments: &SyscallArguments) -> SyscallResult {
+    sys_getpid()
+}
+
+fn dispatch_sleep(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    sys_sleep(arguments.get(0))
+}
+
+/// Ends the `sleep` of a thread that is about to run a signal handler, so that the
+/// sleep returns `EINTR` once the handler returns, and the time left is written
+/// to `remaining`. `frame` is the saved user state of the thread.
+///
+/// # Safety
+/// `thread` must be the current thread.
+pub unsafe fn interrupt_sleep(thread: NonNull<Thread>, frame: &mut ExceptionFrame) {
+    let wake_time = match (*thread.as_ptr()).sleep_wake_time.take() {
+        Some(wake_time) => wake_time,
+        None => return,
+    };
+
+    let arguments = SyscallArguments {
+        values: core::array::from_fn(|index| frame.register(index)),
+        from_user: frame.is_from_user(),
+    };
+    // the remaining time is optional
+    if arguments.get(1) != 0 {
+        let remaining = wake_time.duration_since(Instant::now()).as_nanos() as u64;
+        // a bad pointer only loses the remaining time, the sleep is over either way
+        let _ = arguments
+            .get_pointer::<u64>(1)
+            .and_then(|pointer| pointer.write(remaining));
+    }
+    // the frame is at the `svc` of the restarted sleep
+    frame.skip_instruction();
+    frame.set_register(0, encode_result(Err(SyscallError::EINTR)));
+}
+
+fn dispatch_clock_gettime(
+    _frame: &mut ExceptionFrame,
+    arguments: &SyscallArguments,
+) -> SyscallResult {
+    let clock_id = arguments.get(0);
+    let time = arguments.get_pointer::<Timespec>(1)?;
+    sys_clock_gettime(clock_id, time)
+}
+
+fn dispatch_sched_setscheduler(
+    _frame: &mut ExceptionFrame,
+    arguments: &SyscallArguments,
+) -> SyscallResult {
+    let pid = arguments.get(0);
+    // the priority is signed for nice values
+    let priority = arguments.get(2) as i64;
+    let policy = match arguments.get(1) {
+        SCHED_NORMAL => i8::try_from(priority).map(|nice| SchedulingPolicy::Normal { nice }),
+        SCHED_FIFO => u8::try_from(priority).map(|priority| SchedulingPolicy::Fifo { priority }),
+        SCHED_RR => {
+            u8::try_from(priority).map(|priority| SchedulingPolicy::RoundRobin { priority })
+        }
+        _ => return Err(SyscallError::EINVAL),
+    }
+    .map_err(|_| SyscallError::EINVAL)?;
+    if !policy.is_valid() {
+        return Err(SyscallError::EINVAL);
+    }
+    sys_sched_setscheduler(pid, policy, arguments.from_user)
+}
+
+fn dispatch_sched_setaffinity(
+    _frame: &mut ExceptionFrame,
+    arguments: &SyscallArguments,
+) -> SyscallResult {
+    let tid = arguments.get(0);
+    let affinity = CoreMask::from_bits(arguments.get(1)).ok_or(SyscallError::EINVAL)?;
+    sys_sched_setaffinity(tid, affinity, arguments.from_user)
+}
+
+fn dispatch_sched_getaffinity(
+    _frame: &mut ExceptionFrame,
+    arguments: &SyscallArguments,
+) -> SyscallResult {
+    sys_sched_getaffinity(arguments.get(0))
+}
+
+fn dispatch_spawn(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    let image = arguments.copy_image(0, 1)?;
+    // without a list of handles, the child inherits the standard ones
+    let mut handles = [0; MAX_HANDLES];
+    let handles = match arguments.get(2) {
+        0 => None,
+        _ => {
+            let len = arguments.get_usize(3)?;
+            if len > MAX_HANDLES {
+                return Err(SyscallError::EINVAL);
+            }
+            let array = arguments.get_array::<u64>(2, 3)?;
+            let mut bytes = [0; MAX_HANDLES * size_of::<u64>()];
+            array.read(&mut bytes[..array.len])?;
+            for (handle, bytes) in handles.iter_mut().zip(bytes.chunks_exact(size_of::<u64>())) {
+                *handle = u64::from_ne_bytes(bytes.try_into().unwrap());
+            }
+            Some(&handles[..len])
+        }
+    };
+    let permissions = Permissions::from_bits(arguments.get(4));
+    // spawn has no registers left for two arrays, so they are passed as a pair
+    let program_arguments = match arguments.get_usize(5)? {
+        0 => Arguments::new(),
+        address => {
+            let [argv, envp] = arguments.pointer::<[u64; 2]>(address)?.read()?;
+            arguments.copy_arguments(argv, envp)?
+        }
+    };
+    sys_spawn(image.bytes(), handles, permissions, &program_arguments)
+}
+
+fn dispatch_wait(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    let pid = arguments.get_usize(0)?;
+    // the status is optional
+    let status = match arguments.get(1) {
+        0 => None,
+        _ => Some(arguments.get_pointer::<i32>(1)?),
+    };
+    sys_wait(pid, status)
+}
+
+fn dispatch_exec(frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    let image = arguments.copy_image(0, 1)?;
+    let program_arguments = arguments.copy_arguments(arguments.get(2), arguments.get(3))?;
+    sys_exec(frame, image.bytes(), &program_arguments)
+}
+
+fn dispatch_thread_create(
+    _frame: &mut ExceptionFrame,
+    arguments: &SyscallArguments,
+) -> SyscallResult {
+    let entry = arguments.get(0);
+    let argument = arguments.get(1);
+    let thread_pointer = arguments.get(2);
+    sys_thread_create(entry, argument, thread_pointer)
+}
+
+fn dispatch_thread_exit(
+    _frame: &mut ExceptionFrame,
+    arguments: &SyscallArguments,
+) -> SyscallResult {
+    sys_thread_exit(arguments.get(0))
+}
+
+fn dispatch_join(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    let tid = arguments.get_usize(0)?;
+    // the value is optional
+    let value = match arguments.get(1) {
+        0 => None,
+        _ => Some(arguments.get_pointer::<u64>(1)?),
+    };
+    sys_join(tid, value)
+}
+
+fn dispatch_futex(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    let key = arguments.get_futex(0)?;
+    let value = arguments.get(2);
+    match arguments.get(1) {
+        FUTEX_WAIT => {
+            let expected = u32::try_from(value).map_err(|_| SyscallError::EINVAL)?;
+            let timeout = match arguments.get(3) {
+                0 => None,
+                _ => {
+                    let timeout = arguments.get_pointer::<Timespec>(3)?.read()?;
+                    if timeout.nanoseconds >= NANOSECONDS_PER_SECOND {
+                        return Err(SyscallError::EINVAL);
+                    }
+                    Some(Duration::new(timeout.seconds, timeout.nanoseconds as u32))
+                }
+            };
+            sys_futex_wait(key, expected, timeout)
+        }
+        FUTEX_WAKE => sys_futex_wake(key, arguments.get_usize(2)?),
+        FUTEX_REQUEUE => {
+            let new_key = arguments.get_futex(4)?;
+            sys_futex_requeue(
+                key,
+                arguments.get_usize(2)?,
+                new_key,
+                arguments.get_usize(3)?,
+            )
+        }
+        _ => Err(SyscallError::EINVAL),
+    }
+}
+
+fn dispatch_sigaction(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    let signal = get_signal(arguments, 0)?;
+    let action = match arguments.get(1) {
+        SIG_DFL => SignalAction::Default,
+        SIG_IGN => SignalAction::Ignore,
+        address => SignalAction::Handler {
+            address,
+            restorer: arguments.get(2),
+            mask: SignalSet::from_bits(arguments.get(3)),
+        },
+    };
+    sys_sigaction(signal, action)
+}
+
+fn dispatch_sigprocmask(
+    _frame: &mut ExceptionFrame,
+    arguments: &SyscallArguments,
+) -> SyscallResult {
+    let how = arguments.get(0);
+    if how != SIG_BLOCK && how != SIG_UNBLOCK && how != SIG_SETMASK {
+        return Err(SyscallError::EINVAL);
+    }
+    sys_sigprocmask(how, SignalSet::from_bits(arguments.get(1)))
+}
+
+fn dispatch_sigreturn(frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    if !arguments.from_user {
+        return Err(SyscallError::EPERM);
+    }
+    sys_sigreturn(frame)
+}
+
+fn dispatch_kill(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    let pid = arguments.get_usize(0).map_err(|_| SyscallError::ESRCH)?;
+    // signal 0 only checks that the signal could be sent
+    let signal = match arguments.get(1) {
+        0 => None,
+        _ => Some(get_signal(arguments, 1)?),
+    };
+    sys_kill(pid, signal)
+}
+
+fn dispatch_endpoint_create(
+    _frame: &mut ExceptionFrame,
+    _arguments: &SyscallArguments,
+) -> SyscallResult {
+    sys_endpoint_create()
+}
+
+fn dispatch_send(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    let handle = arguments.get_usize(0).map_err(|_| SyscallError::EBADF)?;
+    let mut message = [0; MAX_MESSAGE_SIZE];
+    let message = copy_message(arguments.get_buffer(1, 2)?, &mut message)?;
+    let transfer = arguments.get_handle(3)?;
+    sys_send(handle, message, transfer)
+}
+
+fn dispatch_receive(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    let handle = arguments.get_usize(0).map_err(|_| SyscallError::EBADF)?;
+    let buffer = arguments.get_buffer(1, 2)?;
+    let info = arguments.get_pointer::<MessageInfo>(3)?;
+    sys_receive(handle, buffer, info)
+}
+
+fn dispatch_call(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    let handle = arguments.get_usize(0).map_err(|_| SyscallError::EBADF)?;
+    let mut message = [0; MAX_MESSAGE_SIZE];
+    let message = copy_message(arguments.get_buffer(1, 2)?, &mut message)?;
+    let transfer = arguments.get_handle(3)?;
+    let reply_buffer = arguments.get_buffer(4, 5)?;
+    sys_call(handle, message, transfer, reply_buffer)
+}
+
+fn dispatch_reply(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    let handle = arguments.get_usize(0).map_err(|_| SyscallError::EBADF)?;
+    let mut message = [0; MAX_MESSAGE_SIZE];
+    let message = copy_message(arguments.get_buffer(1, 2)?, &mut message)?;
+    sys_reply(handle, message)
+}
+
+fn dispatch_handle_duplicate(
+    _frame: &mut ExceptionFrame,
+    arguments: &SyscallArguments,
+) -> SyscallResult {
+    let handle = arguments.get_usize(0).map_err(|_| SyscallError::EBADF)?;
+    sys_handle_duplicate(handle, Rights::from_bits(arguments.get(1)))
+}
+
+fn dispatch_close(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    let handle = arguments.get_usize(0).map_err(|_| SyscallError::EBADF)?;
+    sys_close(handle)
+}
+
+fn dispatch_read(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    let fd = arguments.get_usize(0).map_err(|_| SyscallError::EBADF)?;
+    let buffer = arguments.get_buffer(1, 2)?;
+    sys_read(fd, buffer)
+}
+
+fn dispatch_pipe(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    sys_pipe(arguments.get_pointer::<[u64; 2]>(0)?)
+}
+
+fn dispatch_dup2(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    let old = arguments.get_usize(0).map_err(|_| SyscallError::EBADF)?;
+    let new = arguments.get_usize(1).map_err(|_| SyscallError::EBADF)?;
+    sys_dup2(old, new)
+}
+
+/// Copies a message of the caller into `buffer`, and returns the part that was copied.
+fn copy_message(
+    message: UserBuffer,
+    buffer: &mut [u8; MAX_MESSAGE_SIZE],
+) -> Result<&[u8], SyscallError> {
+    let buffer = buffer
+        .get_mut(..message.len)
+        .ok_or(SyscallError::EMSGSIZE)?;
+    message.read(buffer)?;
+    Ok(buffer)
+}
+
+fn dispatch_map_device(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    let physical_address = arguments.get_usize(0)?;
+    let len = arguments.get_usize(1)?;
+    sys_map_device(physical_address, len)
+}
+
+fn dispatch_mailbox(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    if arguments.get_usize(1)? > MAX_MAILBOX_MESSAGE_LEN {
+        return Err(SyscallError::EINVAL);
+    }
+    sys_mailbox(arguments.get_array::<u32>(0, 1)?)
+}
+
+fn dispatch_drop_permissions(
+    _frame: &mut ExceptionFrame,
+    arguments: &SyscallArguments,
+) -> SyscallResult {
+    sys_drop_permissions(Permissions::from_bits(arguments.get(0)))
+}
+
+fn dispatch_getrlimit(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    let resource = get_resource(arguments, 0)?;
+    sys_getrlimit(resource, arguments.get_pointer::<Limit>(1)?)
+}
+
+fn dispatch_setrlimit(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    let resource = get_resource(arguments, 0)?;
+    let limit = arguments.get_pointer::<Limit>(1)?.read()?;
+    sys_setrlimit(resource, limit)
+}
+
+fn dispatch_getrusage(_frame: &mut ExceptionFrame, arguments: &SyscallArguments) -> SyscallResult {
+    let of_children = match arguments.get(0) {
+        RUSAGE_SELF => false,
+        RUSAGE_CHILDREN => true,
+        _ => return Err(SyscallError::EINVAL),
+    };
+    sys_getrusage(of_children, arguments.get_pointer::<Usage>(1)?)
+}
+
+fn get_resource(arguments: &SyscallArguments, index: usize) -> Result<Resource, SyscallError> {
+    Resource::from_number(arguments.get(index)).ok_or(SyscallError::EINVAL)
+}
+
+fn get_signal(arguments: &SyscallArguments, index: usize) -> Result<Signal, SyscallError> {
+    Signal::from_number(arguments.get(index)).ok_or(SyscallError::EINVAL)
+}
+
+/// `write(fd, buffer, len) -> bytes written`
+///
+/// At most `MAX_WRITE_SIZE` bytes are written at once. Console output is drawn later
+/// by the worker thread, and only as much as fits in the console buffer is written.
+/// Writing to a pipe blocks until there is room, and writing to an endpoint sends
+/// the buffer as a message.
+fn sys_write(fd: usize, buffer: UserBuffer) -> SyscallResult {
+    let file = get_file(fd)?;
+    let mut bytes = [0; MAX_WRITE_SIZE];
+    let bytes = &mut bytes[..buffer.len.min(MAX_WRITE_SIZE)];
+    buffer.read(bytes)?;
+    match file {
+        Handle::Console => match crate::console::write_deferred(bytes) {
+            0 if !bytes.is_empty() => Err(SyscallError::EAGAIN),
+            written => Ok(written as u64),
+        },
+        Handle::PipeWriter { .. } => Ok(pipe::write(fd, bytes)? as u64),
+        Handle::Endpoint { .. } => {
+            ipc::send(fd, bytes, None)?;
+            Ok(bytes.len() as u64)
+        }
+        _ => Err(SyscallError::EBADF),
+    }
+}
+
+/// Returns the handle `fd` of the caller. The kernel and kernel threads
+/// have no handles, but write to the console through `STDOUT` and `STDERR`.
+fn get_file(fd: usize) -> Result<Handle, SyscallError> {
+    match process::with_current_handles(|handles| handles.get(fd)) {
+        Some(handle) => Ok(handle?),
+        None if fd == STDOUT || fd == STDERR => Ok(Handle::Console),
+        None => Err(SyscallError::EBADF),
+    }
+}
+
+/// `exit(status) -> !`
+fn sys_exit(status: i32) -> SyscallResult {
+    crate::println!("[INFO]: exit with status {}", status);
+    if process::exit_current(status) {
+        // the scheduler never returns to the caller
+        return Ok(0);
+    }
+    // the caller is not a scheduled thread, so just park the core
+    loop {
+        unsafe { asm!("wfe") };
+    }
+}
+
+/// `yield() -> 0`
+fn sys_yield() -> SyscallResult {
+    // the switch happens when returning to the caller
+    scheduler::yield_current();
+    Ok(0)
+}
+
+/// `getpid() -> pid`
+///
+/// The kernel itself has pid 0.
+fn sys_getpid() -> SyscallResult {
+    Ok(process::current_pid() as u64)
+}
+
+/// `sleep(nanoseconds, *mut u64 remaining) -> 0`
+///
+/// A sleep cut short by a signal handler returns `EINTR`, and the nanoseconds
+/// left are written to `remaining` unless it is null.
+fn sys_sleep(nanoseconds: u64) -> SyscallResult {
+    let thread = scheduler::current_thread();
+    // a restarted sleep keeps the wake time of the first call
+    let wake_time = match thread.and_then(|thread| unsafe { (*thread.as_ptr()).sleep_wake_time }) {
+        Some(wake_time) => wake_time,
+        None => Instant::now()
+            .checked_add(Duration::from_nanos(nanoseconds))
+            .ok_or(SyscallError::EINVAL)?,
+    };
+
+    match thread {
+        Some(thread) if Instant::now() < wake_time => {
+            unsafe { (*thread.as_ptr()).sleep_wake_time = Some(wake_time) };
+            scheduler::sleep_current_until(wake_time);
+            // the thread can be woken early, so the time is checked again
+            Err(SyscallError::ERESTARTSYS)
+        }
+        Some(thread) => {
+            unsafe { (*thread.as_ptr()).sleep_wake_time = None };
+            Ok(0)
+        }
+        None => {
+            // the caller is not a scheduled thread and can't be blocked
+            while Instant::now() < wake_time {
+                core::hint::spin_loop();
+            }
+            Ok(0)
+        }
+    }
+}
+
+/// `clock_gettime(clock_id, *mut Timespec) -> 0`
+///
+/// There is no real-time clock, so `CLOCK_REALTIME` counts from boot
+/// just like `CLOCK_MONOTONIC`.
+fn sys_clock_gettime(clock_id: u64, time: UserPointer<Timespec>) -> SyscallResult {
+    if clock_id != CLOCK_REALTIME && clock_id != CLOCK_MONOTONIC {
+        return Err(SyscallError::EINVAL);
+    }
+
+    let time_since_boot = Instant::now().since_boot();
+    time.write(Timespec {
+        seconds: time_since_boot.as_secs(),
+        nanoseconds: time_since_boot.subsec_nanos() as u64,
+    })?;
+
+    Ok(0)
+}
+
+/// `sched_setscheduler(tid, policy, priority) -> 0`
+///
+/// `priority` is the real-time priority for `SCHED_FIFO` and `SCHED_RR`, and the nice
+/// value for `SCHED_NORMAL`. Threads are scheduled on their own, so this changes one
+/// thread, and a tid of 0 means the caller. Raising the priority or changing threads
+/// of other processes needs the `SET_PRIORITY` permission, but any thread may lower
+/// the priority of the threads in its own process.
+fn sys_sched_setscheduler(tid: u64, policy: SchedulingPolicy, from_user: bool) -> SyscallResult {
+    let caller = scheduler::current_thread().ok_or(SyscallError::ESRCH)?;
+    let tid = match tid {
+        0 => unsafe { caller.as_ref() }.tid(),
+        tid => usize::try_from(tid).map_err(|_| SyscallError::ESRCH)?,
+    };
+
+    // kernel threads have no process and are always allowed to
+    let is_permitted = process::current_permissions().contains(Permissions::SET_PRIORITY);
+    process::with_thread(tid, |thread| unsafe {
+        let is_raise = policy.is_higher_than(scheduler::scheduling_policy(thread));
+        let is_other_process = thread.as_ref().process() != caller.as_ref().process();
+        // the kernel itself is always allowed to
+        if from_user && (is_raise || is_other_process) && !is_permitted {
+            return Err(SyscallError::EPERM);
+        }
+        scheduler::set_scheduling_policy(thread, policy);
+        Ok(0)
+    })
+    .ok_or(SyscallError::ESRCH)?
+}
+
+/// `sched_setaffinity(tid, mask) -> 0`
+///
+/// Restricts a thread to the cores whose bits are set in `mask`, where a tid of 0
+/// means the caller. The mask must not be empty. Changing threads of other processes
+/// needs the `SET_PRIORITY` permission.
+fn sys_sched_setaffinity(tid: u64, affinity: CoreMask, from_user: bool) -> SyscallResult {
+    let caller = scheduler::current_thread().ok_or(SyscallError::ESRCH)?;
+    let tid = match tid {
+        0 => unsafe { caller.as_ref() }.tid(),
+        tid => usize::try_from(tid).map_err(|_| SyscallError::ESRCH)?,
+    };
+
+    let is_permitted = process::current_permissions().contains(Permissions::SET_PRIORITY);
+    process::with_thread(tid, |thread| unsafe {
+        let is_other_process = thread.as_ref().process() != caller.as_ref().process();
+        if from_user && is_other_process && !is_permitted {
+            return Err(SyscallError::EPERM);
+        }
+        scheduler::set_affinity(thread, affinity).map_err(|_| SyscallError::EINVAL)?;
+        Ok(0)
+    })
+    .ok_or(SyscallError::ESRCH)?
+}
+
+/// `sched_getaffinity(tid) -> mask`
+///
+/// Returns the mask of the cores a thread may run on, where a tid of 0 means the caller.
+fn sys_sched_getaffinity(tid: u64) -> SyscallResult {
+    let caller = scheduler::current_thread().ok_or(SyscallError::ESRCH)?;
+    let tid = match tid {
+        0 => unsafe { caller.as_ref() }.tid(),
+        tid => usize::try_from(tid).map_err(|_| SyscallError::ESRCH)?,
+    };
+    process::with_thread(tid, |thread| unsafe { scheduler::affinity(thread) }.bits())
+        .ok_or(SyscallError::ESRCH)
+}
+
+/// `spawn(image, len, *const u64 handles, count, permissions, *const [argv, envp]) -> pid`
+///
+/// Starts a child process running the ELF executable in `image`. The child gets the
+/// `permissions` that the caller has, and copies of the `handles` at the same indices,
+/// where `NO_HANDLE` leaves an index empty. If `handles` is null, the child inherits
+/// `STDIN`, `STDOUT` and `STDERR`, which are the console for children of the kernel.
+/// The last argument points to the `argv` and `envp` arrays, like those of `exec`,
+/// or is null to start the child without arguments or environment.
+fn sys_spawn(
+    image: &[u8],
+    handles: Option<&[u64]>,
+    permissions: Permissions,
+    arguments: &Arguments,
+) -> SyscallResult {
+    let parent = match process::current_pid() {
+        0 => None,
+        pid => Some(pid),
+    };
+    let permissions = process::current_permissions().intersection(permissions);
+    let mut child_handles = HandleTable::new();
+    let result = process::with_current_handles(|parent_handles| {
+        let standard = [STDIN as u64, STDOUT as u64, STDERR as u64];
+        for (index, &handle) in handles.unwrap_or(&standard).iter().enumerate() {
+            if handle == NO_HANDLE {
+                continue;
+            }
+            let handle = usize::try_from(handle).map_err(|_| SyscallError::EBADF)?;
+            let handle = match parent_handles.duplicate(handle, Rights::ALL) {
+                Ok(handle) => handle,
+                // standard handles that the caller has closed are left closed
+                Err(HandleError::InvalidHandle) if handles.is_none() => continue,
+                Err(error) => return Err(SyscallError::from(error)),
+            };
+            child_handles.insert_at(index, handle)?;
+        }
+        Ok(())
+    });
+    match result {
+        Some(result) => result?,
+        None if handles.is_none() => child_handles = HandleTable::with_console(),
+        None => return Err(SyscallError::EBADF),
+    }
+    let pid = Process::create_from_elf(image, arguments, parent, permissions, child_handles)?;
+    Ok(pid as u64)
+}
+
+/// `wait(pid, *mut i32 status) -> pid`
+///
+/// Blocks until the child `pid`, or any child if `pid` is 0, has exited,
+/// and frees it. The exit status is written to `status` unless it is null.
+fn sys_wait(pid: usize, status: Option<UserPointer<i32>>) -> SyscallResult {
+    match process::wait_current(pid) {
+        Ok(child) => {
+            if let Some(status) = status {
+                status.write(child.status)?;
+            }
+            Ok(child.pid as u64)
+        }
+        Err(WaitError::NoChildren) => Err(SyscallError::ECHILD),
+        Err(WaitError::WouldBlock) => Err(SyscallError::ERESTARTSYS),
+    }
+}
+
+/// `exec(image, len, *const *const u8 argv, *const *const u8 envp) -> !`
+///
+/// Replaces the program of the caller with the ELF executable in `image`, which
+/// finds `argv` and `envp` on its stack. Both are arrays of null terminated strings
+/// ending with a null pointer, and a null array is empty.
+/// Only returns if the image can't be loaded.
+fn sys_exec(frame: &mut ExceptionFrame, image: &[u8], arguments: &Arguments) -> SyscallResult {
+    if !frame.is_from_user() {
+        return Err(SyscallError::EPERM);
+    }
+    process::exec_current(image, arguments, frame)?;
+    // the new program starts with every register zeroed
+    Ok(0)
+}
+
+/// `thread_create(entry, argument, thread_pointer) -> tid`
+///
+/// Starts a thread in the process of the caller that runs `entry` with `argument`
+/// in `x0`, `thread_pointer` in `tpidr_el0`, and a stack of its own.
+fn sys_thread_create(entry: u64, argument: u64, thread_pointer: u64) -> SyscallResult {
+    let tid = process::create_thread(entry, argument, thread_pointer)?;
+    Ok(tid as u64)
+}
+
+/// `thread_exit(value) -> !`
+///
+/// Ends the calling thread. The process ends with status 0 if this was its last thread.
+fn sys_thread_exit(value: u64) -> SyscallResult {
+    if process::exit_current_thread(value) {
+        // the scheduler never returns to the caller
+        return Ok(0);
+    }
+    // the caller is not a scheduled thread, so just park the core
+    loop {
+        unsafe { asm!("wfe") };
+    }
+}
+
+/// `join(tid, *mut u64 value) -> 0`
+///
+/// Blocks until the thread `tid` of the same process has exited, and frees it.
+/// The value it exited with is written to `value` unless it is null.
+fn sys_join(tid: usize, value: Option<UserPointer<u64>>) -> SyscallResult {
+    match process::join(tid) {
+        Ok(exit_value) => {
+            if let Some(value) = value {
+                value.write(exit_value)?;
+            }
+            Ok(0)
+        }
+        Err(JoinError::NoSuchThread) => Err(SyscallError::ESRCH),
+        Err(JoinError::NotJoinable) => Err(SyscallError::EINVAL),
+        Err(JoinError::WouldBlock) => Err(SyscallError::ERESTARTSYS),
+    }
+}
+
+/// `futex(address, FUTEX_WAIT, expected, *const Timespec timeout) -> 0`
+///
+/// Blocks while the word at `address` is `expected`, until woken by `FUTEX_WAKE`
+/// or until the timeout has passed. The timeout is relative and optional.
+/// Fails with `EAGAIN` if the word has another value.
+fn sys_futex_wait(key: usize, expected: u32, timeout: Option<Duration>) -> SyscallResult {
+    // a timeout too large to be represented never passes
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+    match futex::wait(key, expected, deadline) {
+        Ok(()) => Ok(0),
+        Err(FutexError::ValueChanged) => Err(SyscallError::EAGAIN),
+        Err(FutexError::TimedOut) => Err(SyscallError::ETIMEDOUT),
+        Err(FutexError::WouldBlock) => Err(SyscallError::ERESTARTSYS),
+        Err(FutexError::NotAThread) => Err(SyscallError::EPERM),
+    }
+}
+
+/// `futex(address, FUTEX_WAKE, count) -> woken`
+///
+/// Wakes up to `count` threads waiting on the futex at `address`.
+fn sys_futex_wake(key: usize, count: usize) -> SyscallResult {
+    Ok(futex::wake(key, count) as u64)
+}
+
+/// `futex(address, FUTEX_REQUEUE, count, requeue_count, new_address) -> woken`
+///
+/// Wakes up to `count` threads waiting on the futex at `address`, and makes up to
+/// `requeue_count` of the others wait on the futex at `new_address` instead.
+fn sys_futex_requeue(
+    key: usize,
+    count: usize,
+    new_key: usize,
+    requeue_count: usize,
+) -> SyscallResult {
+    Ok(futex::requeue(key, count, new_key, requeue_count) as u64)
+}
+
+/// `sigaction(signal, handler, restorer, mask) -> previous handler`
+///
+/// Sets what happens when the caller gets `signal`: the default action if `handler`
+/// is `SIG_DFL`, nothing if it is `SIG_IGN`, and otherwise a call to `handler` with
+/// the signal number in `x0`. The handler runs with `signal` and the signals in `mask`
+/// blocked, and returns to `restorer`, which must make the `sigreturn` syscall.
+/// The previous handler is returned in the same encoding.
+fn sys_sigaction(signal: Signal, action: SignalAction) -> SyscallResult {
+    let previous = process::with_current_signals(|signals| signals.set_action(signal, action))
+        .ok_or(SyscallError::ESRCH)?
+        .map_err(|_| SyscallError::EINVAL)?;
+    Ok(match previous {
+        SignalAction::Default => SIG_DFL,
+        SignalAction::Ignore => SIG_IGN,
+        SignalAction::Handler { address, .. } => address,
+    })
+}
+
+/// `sigprocmask(how, set) -> previous mask`
+///
+/// Adds `set` to the blocked signals with `SIG_BLOCK`, removes it with `SIG_UNBLOCK`,
+/// or replaces them with `SIG_SETMASK`. `SIGKILL` can't be blocked. Signals are
+/// blocked for the whole process, and are bits of the mask by their numbers.
+fn sys_sigprocmask(how: u64, set: SignalSet) -> SyscallResult {
+    let previous = process::with_current_signals(|signals| {
+        let blocked = signals.blocked();
+        let new_blocked = match how {
+            SIG_BLOCK => blocked.union(set),
+            SIG_UNBLOCK => blocked.difference(set),
+            _ => set,
+        };
+        signals.set_blocked(new_blocked)
+    })
+    .ok_or(SyscallError::ESRCH)?;
+    // signals that were unblocked are delivered when returning to the caller
+    Ok(previous.bits())
+}
+
+/// `sigreturn() -> !`
+///
+/// Returns from a signal handler to where the signal interrupted the caller.
+/// Must only be made by the restorer of a handler, with the stack pointer
+/// the handler was started with.
+fn sys_sigreturn(frame: &mut ExceptionFrame) -> SyscallResult {
+    signal::sigreturn(frame);
+    Err(SyscallError::EJUSTRETURN)
+}
+
+/// `kill(pid, signal) -> 0`
+///
+/// Sends `signal` to the process `pid`. With a signal of 0 nothing is sent,
+/// but the errors are still checked. A process may signal itself and its
+/// children, and other processes only with the `KILL` permission.
+fn sys_kill(pid: usize, signal: Option<Signal>) -> SyscallResult {
+    match process::send_signal(pid, signal) {
+        Ok(()) => Ok(0),
+        Err(SendSignalError::NoSuchProcess) => Err(SyscallError::ESRCH),
+        Err(SendSignalError::NotPermitted) => Err(SyscallError::EPERM),
+    }
+}
+
+/// `endpoint_create() -> handle`
+///
+/// Creates an IPC endpoint, and returns a handle to it that may both send and receive.
+fn sys_endpoint_create() -> SyscallResult {
+    Ok(ipc::create_endpoint()? as u64)
+}
+
+/// `send(handle, message, len, transfer) -> 0`
+///
+/// Queues a message on the endpoint of `handle`, blocking while the queue is full.
+/// A copy of the handle `transfer` is sent along unless it is `NO_HANDLE`.
+fn sys_send(handle: usize, message: &[u8], transfer: Option<usize>) -> SyscallResult {
+    ipc::send(handle, message, transfer)?;
+    Ok(0)
+}
+
+/// `receive(handle, buffer, len, *mut MessageInfo info) -> 0`
+///
+/// Takes the next message from the endpoint of `handle`, blocking until there is one.
+/// As much of the message as fits is copied into `buffer`, and the length of the whole
+/// message, the sender and the handles that came with it are written to `info`.
+fn sys_receive(handle: usize, buffer: UserBuffer, info: UserPointer<MessageInfo>) -> SyscallResult {
+    let mut bytes = [0; MAX_MESSAGE_SIZE];
+    let bytes = &mut bytes[..buffer.len.min(MAX_MESSAGE_SIZE)];
+    let message_info = ipc::receive(handle, bytes)?;
+    buffer.write(&bytes[..(message_info.len as usize).min(bytes.len())])?;
+    info.write(message_info)?;
+    Ok(0)
+}
+
+/// `call(handle, message, len, transfer, reply_buffer, reply_len) -> reply length`
+///
+/// Sends a message like `send` along with a reply handle, and blocks until the receiver
+/// replies with it. As much of the reply as fits is copied into `reply_buffer`. Fails
+/// with `EPIPE` if the receiver closes the reply handle or the message is dropped.
+fn sys_call(
+    handle: usize,
+    message: &[u8],
+    transfer: Option<usize>,
+    reply_buffer: UserBuffer,
+) -> SyscallResult {
+    let mut reply = [0; MAX_MESSAGE_SIZE];
+    let reply = &mut reply[..reply_buffer.len.min(MAX_MESSAGE_SIZE)];
+    let len = ipc::call(handle, message, transfer, reply)?;
+    reply_buffer.write(&reply[..len.min(reply.len())])?;
+    Ok(len as u64)
+}
+
+/// `reply(reply_handle, message, len) -> 0`
+///
+/// Answers a call with `message` and closes the reply handle.
+/// Fails with `EPIPE` if the caller has gone away.
+fn sys_reply(handle: usize, message: &[u8]) -> SyscallResult {
+    ipc::reply(handle, message)?;
+    Ok(0)
+}
+
+/// `handle_duplicate(handle, rights) -> handle`
+///
+/// Adds a copy of `handle` with only the `rights` that it has,
+/// for example to send a handle that can only be sent to.
+fn sys_handle_duplicate(handle: usize, rights: Rights) -> SyscallResult {
+    Ok(handle::duplicate(handle, rights)? as u64)
+}
+
+/// `close(handle) -> 0`
+///
+/// Closes a handle of any kind. Closing a reply handle fails its call.
+fn sys_close(handle: usize) -> SyscallResult {
+    handle::close(handle)?;
+    Ok(0)
+}
+
+/// `read(fd, buffer, len) -> bytes read`
+///
+/// At most `MAX_WRITE_SIZE` bytes are read at once.
+/// Reading from a pipe blocks until something is written to it, and returns 0 once
+/// every writing handle is closed. Reading from an endpoint receives a message, and
+/// closes the handles that came with it. There is no keyboard, so the console is
+/// always at the end of file.
+fn sys_read(fd: usize, buffer: UserBuffer) -> SyscallResult {
+    let mut bytes = [0; MAX_WRITE_SIZE];
+    let bytes = &mut bytes[..buffer.len.min(MAX_WRITE_SIZE)];
+    let len = match get_file(fd)? {
+        Handle::Console => 0,
+        Handle::PipeReader { .. } => pipe::read(fd, bytes)?,
+        Handle::Endpoint { .. } => ipc::read(fd, bytes)?,
+        _ => return Err(SyscallError::EBADF),
+    };
+    buffer.write(&bytes[..len])?;
+    Ok(len as u64)
+}
+
+/// `pipe(*mut [u64; 2] handles) -> 0`
+///
+/// Creates a pipe, and writes the handles to its reading
+/// and writing ends to `handles[0]` and `handles[1]`.
+fn sys_pipe(handles: UserPointer<[u64; 2]>) -> SyscallResult {
+    let (reader, writer) = pipe::create()?;
+    handles.write([reader as u64, writer as u64])?;
+    Ok(0)
+}
+
+/// `dup2(old, new) -> new`
+///
+/// Makes `new` a copy of the handle `old`, closing the handle that was at `new`.
+/// This is how the standard handles are redirected before spawning a child.
+fn sys_dup2(old: usize, new: usize) -> SyscallResult {
+    handle::duplicate_to(old, new)?;
+    Ok(new as u64)
+}
+
+/// `map_device(physical_address, len) -> address`
+///
+/// Maps the page aligned range of peripheral memory into the address space of the
+/// caller, and returns where. Needs the `MAP_DEVICE` permission.
+fn sys_map_device(physical_address: usize, len: usize) -> SyscallResult {
+    if !process::current_permissions().contains(Permissions::MAP_DEVICE) {
+        return Err(SyscallError::EPERM);
+    }
+    match process::map_device_current(physical_address, len) {
+        Ok(address) => Ok(address as u64),
+        Err(MapError::OutOfMemory | MapError::LimitExceeded) => Err(SyscallError::ENOMEM),
+        Err(MapError::InvalidAddress) => Err(SyscallError::EINVAL),
+        Err(MapError::AlreadyMapped) => Err(SyscallError::EEXIST),
+    }
+}
+
+/// `mailbox(*mut u32 message, len) -> 0`
+///
+/// Sends a property message with `len` `u32`s of room to the VideoCore, and
+/// writes the response over it. Tags that change clocks, power or voltages need
+/// the `CONFIGURE_HARDWARE` permission, and tags that hand out GPU memory or run
+/// code on the VideoCore are never allowed.
+fn sys_mailbox(message: UserBuffer) -> SyscallResult {
+    let len = message.len / size_of::<u32>();
+    let mut bytes = [0; MAX_MAILBOX_MESSAGE_LEN * size_of::<u32>()];
+    message.read(&mut bytes[..message.len])?;
+    let mut data = [0; MAX_MAILBOX_MESSAGE_LEN];
+    for (word, bytes) in data.iter_mut().zip(bytes.chunks_exact(size_of::<u32>())) {
+        *word = u32::from_ne_bytes(bytes.try_into().unwrap());
+    }
+
+    let mut needed = Permissions::NONE;
+    let mut is_allowed = true;
+    mailbox::check_message(&data[..len], |value| {
+        use MiscTag::*;
+        match MiscTag::from_value(value) {
+            Some(SetPowerState | SetClockState | SetClockRate | SetTurbo | SetVoltage) => {
+                needed = Permissions::CONFIGURE_HARDWARE
+            }
+            Some(AllocateMemory | LockMemory | UnlockMemory | ReleaseMemory | ExecuteCode)
+            | None => is_allowed = false,
+            Some(_) => {}
+        }
+    })
+    .ok_or(SyscallError::EINVAL)?;
+    if !is_allowed || !process::current_permissions().contains(needed) {
+        return Err(SyscallError::EPERM);
+    }
+
+    let buffer = MailboxMessageBuffer::<MAX_MAILBOX_MESSAGE_LEN, MiscTag>::from_words(data);
+    let response = unsafe { buffer.send() }.map_err(|_| SyscallError::EIO)?;
+    for (bytes, word) in bytes
+        .chunks_exact_mut(size_of::<u32>())
+        .zip(response.words())
+    {
+        bytes.copy_from_slice(&word.to_ne_bytes());
+    }
+    message.write(&bytes[..message.len])?;
+    Ok(0)
+}
+
+/// `drop_permissions(permissions) -> permissions left`
+///
+/// Gives up `permissions` for good, for example before running untrusted code.
+/// Dropping no permissions just returns the permissions of the caller.
+fn sys_drop_permissions(permissions: Permissions) -> SyscallResult {
+    process::drop_current_permissions(permissions)
+        .map(|left| left.bits())
+        .ok_or(SyscallError::ESRCH)
+}
+
+/// `getrlimit(resource, *mut Limit) -> 0`
+fn sys_getrlimit(resource: Resource, limit: UserPointer<Limit>) -> SyscallResult {
+    let current = process::current_limit(resource).ok_or(SyscallError::ESRCH)?;
+    limit.write(current)?;
+    Ok(0)
+}
+
+/// `setrlimit(resource, *const Limit) -> 0`
+///
+/// Sets the soft and hard limits of the caller on `resource`, which its children
+/// start with. Going over a soft limit makes allocating the resource fail, except
+/// for cpu time, where it sends `SIGXCPU`. Raising a hard limit needs the
+/// `SET_LIMITS` permission.
+fn sys_setrlimit(resource: Resource, limit: Limit) -> SyscallResult {
+    process::set_current_limit(resource, limit)?;
+    Ok(0)
+}
+
+/// `getrusage(who, *mut Usage) -> 0`
+///
+/// Writes what the caller has used, or with `RUSAGE_CHILDREN` the cpu time of the
+/// children it has collected with `wait`. The other counts of children are 0.
+fn sys_getrusage(of_children: bool, usage: UserPointer<Usage>) -> SyscallResult {
+    let current = process::current_usage(of_children).ok_or(SyscallError::ESRCH)?;
+    usage.write(current)?;
+    Ok(0)
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct Timespec {
+    seconds: u64,
+    nanoseconds: u64,
+}
@@ -1,22 +1,16 @@
-.macro  exception_vector, handler
-    sub     sp, sp,     #0x100
-    stp     x30, xzr,    [sp, #0xf0]
-    ldr     x30, =1f
-    bl      push_registers
-1:
-    ldr     x30, =2f
-    // first argument is a pointer to the frame on the stack
-    mov     x0, sp
-    mrs     x1, esr_el1
-    mrs     x2, far_el1
-    bl      \handler
-2:
-    ldr     x30, =3f
-    bl      pop_registers
-3:    
-    ldp     x30, xzr,    [sp, #0xf0]
-    add     sp, sp,     #0x100
-    eret
+// the layout of the frame is defined by `ExceptionFrame`
+// and the offsets are passed in from exceptions/mod.rs
+//
+// exceptions from el0 are taken using sp_el1, which points to the top
+// of the kernel stack of the current process while it is running in
+// user mode, so the frame of a user exception is always saved into the
+// `saved_register_state` at the top of that stack
+.macro  exception_vector, handler, from_user
+    sub     sp, sp, #{FRAME_SIZE}
+    stp     x0, x1,     [sp, #0x00]
+    ldr     x0, =\handler
+    mov     x1, #\from_user
+    b       handle_exception
 .endm
 
 .global exception_vector_table
@@ -35,43 +29,44 @@ exception_vector_table:
     // same el using el_spx
     
     // Sync
-    exception_vector handle_sync_exception
+    exception_vector handle_sync_exception, 0
     .balign 0x80
     
     // IRQ
-    exception_vector handle_irq_exception
+    exception_vector handle_irq_exception, 0
     .balign 0x80
 
     // FIQ
-    exception_vector handle_fiq_exception
+    exception_vector handle_fiq_exception, 0
     .balign 0x80
 
     // SError
-    exception_vector handle_serror_exception
+    exception_vector handle_serror_exception, 0
     .balign 0x80
 
     // lower el in aarch64 mode
-    b .
+    exception_vector handle_sync_exception, 1
     .balign 0x80
-    b .
+    exception_vector handle_irq_exception, 1
     .balign 0x80
-    b .
+    exception_vector handle_fiq_exception, 1
     .balign 0x80
-    b .
+    exception_vector handle_serror_exception, 1
     .balign 0x80
 
     // lower el in aarch32 mode
-    b .
+    exception_vector handle_sync_exception, 1
     .balign 0x80
-    b .
+    exception_vector handle_irq_exception, 1
     .balign 0x80
-    b .
+    exception_vector handle_fiq_exception, 1
     .balign 0x80
-    b .
+    exception_vector handle_serror_exception, 1
 
-// the stack pointer should be aligned to 16 bytes
-push_registers:
-    stp     x0, x1,     [sp, #0x00]
+// x0 and x1 have already been saved into the frame
+// x0 contains the address of the rust handler
+// x1 is 1 if the exception was taken from el0 and 0 otherwise
+handle_exception:
     stp     x2, x3,     [sp, #0x10]
     stp     x4, x5,     [sp, #0x20]
     stp     x6, x7,     [sp, #0x30]
@@ -86,22 +81,55 @@ push_registers:
     stp     x24, x25,   [sp, #0xc0]
     stp     x26, x27,   [sp, #0xd0]
     stp     x28, x29,   [sp, #0xe0]
-    ret
+    mrs     x2, sp_el0
+    stp     x30, x2,    [sp, #0xf0]
+    mrs     x2, elr_el1
+    mrs     x3, spsr_el1
+    stp     x2, x3,     [sp, #{FRAME_EXCEPTION_LINK_REG}]
 
-pop_registers:
-    ldp     x0, x1,     [sp, #0x00]
-    ldp     x2, x3,     [sp, #0x10]
-    ldp     x4, x5,     [sp, #0x20]
-    ldp     x6, x7,     [sp, #0x30]
-    ldp     x8, x9,     [sp, #0x40]
-    ldp     x10, x11,   [sp, #0x50]
-    ldp     x12, x13,   [sp, #0x60]
-    ldp     x14, x15,   [sp, #0x70]
-    ldp     x16, x17,   [sp, #0x80]
-    ldp     x18, x19,   [sp, #0x90]
-    ldp     x20, x21,   [sp, #0xa0]
-    ldp     x22, x23,   [sp, #0xb0]
-    ldp     x24, x25,   [sp, #0xc0]
-    ldp     x26, x27,   [sp, #0xd0]
+    // x19 and x20 are callee saved and restored from the frame at the end
+    mov     x19, x0
+    mov     x20, x1
+    mov     x0, x1
+    bl      start_exception
+    mov     x0, x19
+    mov     x1, x20
+
+    // first argument is a pointer to the frame on the stack
+    mov     x4, x0
+    mov     x3, x1
+    mov     x0, sp
+    mrs     x1, esr_el1
+    mrs     x2, far_el1
+    blr     x4
+
+    // the scheduler may switch to the frame of another context,
+    // which is restored instead of the one that was just saved
+    mov     x0, sp
+    bl      finish_exception
+    mov     sp, x0
+    bl      finish_context_switch
+
+    // the handler may have modified any of these
+    ldp     x1, x2,     [sp, #{FRAME_EXCEPTION_LINK_REG}]
+    msr     elr_el1, x1
+    msr     spsr_el1, x2
+    ldp     x30, x1,    [sp, #0xf0]
+    msr     sp_el0, x1
     ldp     x28, x29,   [sp, #0xe0]
-    ret
+    ldp     x26, x27,   [sp, #0xd0]
+    ldp     x24, x25,   [sp, #0xc0]
+    ldp     x22, x23,   [sp, #0xb0]
+    ldp     x20, x21,   [sp, #0xa0]
+    ldp     x18, x19,   [sp, #0x90]
+    ldp     x16, x17,   [sp, #0x80]
+    ldp     x14, x15,   [sp, #0x70]
+    ldp     x12, x13,   [sp, #0x60]
+    ldp     x10, x11,   [sp, #0x50]
+    ldp     x8, x9,     [sp, #0x40]
+    ldp     x6, x7,     [sp, #0x30]
+    ldp     x4, x5,     [sp, #0x20]
+    ldp     x2, x3,     [sp, #0x10]
+    ldp     x0, x1,     [sp, #0x00]
+    add     sp, sp, #{FRAME_SIZE}
+    eret
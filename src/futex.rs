@@ -0,0 +1,199 @@
+//! Blocking on a word of user memory, the base of user space locks.
+//!
+//! Futexes are keyed on the physical address of the word, so that processes
+//! sharing memory can use the same futex at different virtual addresses.
+//! A waiting thread is blocked in the middle of its syscall, which is made
+//! again when the thread runs. The restarted call then finds the result of
+//! the wait in `Thread::futex_wait`.
+
+use core::ptr::NonNull;
+
+use spin::mutex::spin::SpinMutex;
+
+use crate::cpu::without_interrupts;
+use crate::scheduler;
+use crate::thread::{Thread, MAX_NUM_THREADS};
+use crate::timer::Instant;
+
+static FUTEXES: SpinMutex<FutexTable> = SpinMutex::new(FutexTable {
+    waiters: [None; MAX_NUM_THREADS],
+    len: 0,
+});
+
+/// The wait of a thread that is blocked on a futex,
+/// or that was woken and has not returned from the syscall yet.
+#[derive(Clone, Copy, Debug)]
+pub struct FutexWait {
+    key: usize,
+    deadline: Option<Instant>,
+    is_woken: bool,
+}
+
+#[derive(Debug)]
+pub enum FutexError {
+    /// The word did not have the expected value.
+    ValueChanged,
+    TimedOut,
+    /// The caller was blocked and has to wait again once it runs.
+    WouldBlock,
+    /// The caller is not a thread and can't be blocked.
+    NotAThread,
+}
+
+/// The waiting threads in the order they started waiting.
+struct FutexTable {
+    waiters: [Option<NonNull<Thread>>; MAX_NUM_THREADS],
+    len: usize,
+}
+
+unsafe impl Send for FutexTable {}
+
+impl FutexTable {
+    fn push(&mut self, thread: NonNull<Thread>) {
+        self.waiters[self.len] = Some(thread);
+        self.len += 1;
+    }
+
+    fn remove(&mut self, index: usize) -> NonNull<Thread> {
+        let thread = self.waiters[index].take().unwrap();
+        self.waiters[index..self.len].rotate_left(1);
+        self.len -= 1;
+        thread
+    }
+
+    fn position(&self, thread: NonNull<Thread>) -> Option<usize> {
+        self.waiters[..self.len]
+            .iter()
+            .position(|&waiter| waiter == Some(thread))
+    }
+
+    /// Applies `f` to the waiters on `key` in order, removing the ones for which it returns true.
+    fn drain_key(
+        &mut self,
+        key: usize,
+        mut f: impl FnMut(NonNull<Thread>, &mut FutexWait) -> bool,
+    ) {
+        let mut index = 0;
+        while index < self.len {
+            let thread = self.waiters[index].unwrap();
+            let wait = unsafe { (*thread.as_ptr()).futex_wait.as_mut().unwrap() };
+            if wait.key == key && f(thread, wait) {
+                self.remove(index);
+            } else {
+                index += 1;
+            }
+        }
+    }
+}
+
+/// Blocks the current thread while the word at the physical address `key` is
+/// `expected`, until it is woken or `deadline` passes. The word is read through
+/// the identity mapping of the kernel, since the page of the caller could be
+/// unmapped by another thread at any time.
+pub fn wait(key: usize, expected: u32, deadline: Option<Instant>) -> Result<(), FutexError> {
+    let thread = scheduler::current_thread().ok_or(FutexError::NotAThread)?;
+
+    without_interrupts(|| {
+        let mut table = FUTEXES.lock();
+        let thread_ref = unsafe { &mut *thread.as_ptr() };
+
+        // the syscall is being made again after the thread was blocked
+        if let Some(wait) = thread_ref.futex_wait.take() {
+            if wait.is_woken {
+                return Ok(());
+            }
+            if matches!(wait.deadline, Some(deadline) if deadline <= Instant::now()) {
+                let index = table.position(thread).unwrap();
+                table.remove(index);
+                return Err(FutexError::TimedOut);
+            }
+            // still waiting, the thread ran for some other reason
+            thread_ref.futex_wait = Some(wait);
+            block_current(wait.deadline);
+            return Err(FutexError::WouldBlock);
+        }
+
+        // the value is checked with wakers locked out, so no wakeup is missed
+        if unsafe { (key as *const u32).read_volatile() } != expected {
+            return Err(FutexError::ValueChanged);
+        }
+        thread_ref.futex_wait = Some(FutexWait {
+            key,
+            deadline,
+            is_woken: false,
+        });
+        table.push(thread);
+        block_current(deadline);
+        Err(FutexError::WouldBlock)
+    })
+}
+
+/// Wakes up to `count` threads waiting on `key`, and returns how many were woken.
+pub fn wake(key: usize, count: usize) -> usize {
+    requeue(key, count, key, 0)
+}
+
+/// Wakes up to `count` threads waiting on `key`, and moves up to `requeue_count`
+/// of the rest to wait on `new_key`. Returns how many were woken.
+pub fn requeue(key: usize, count: usize, new_key: usize, requeue_count: usize) -> usize {
+    without_interrupts(|| {
+        let mut table = FUTEXES.lock();
+        let mut woken = 0;
+        let mut requeued = 0;
+        table.drain_key(key, |thread, wait| {
+            if woken < count {
+                wait.is_woken = true;
+                unsafe { scheduler::make_runnable(thread) };
+                woken += 1;
+                true
+            } else {
+                if requeued < requeue_count {
+                    wait.key = new_key;
+                    requeued += 1;
+                }
+                false
+            }
+        });
+        woken
+    })
+}
+
+/// Forgets the wait of a thread that has exited.
+///
+/// # Safety
+/// `thread` must be a valid thread.
+pub unsafe fn cancel(thread: NonNull<Thread>) {
+    without_interrupts(|| {
+        let mut table = FUTEXES.lock();
+        if let Some(index) = table.position(thread) {
+            table.remove(index);
+        }
+        (*thread.as_ptr()).futex_wait = None;
+    });
+}
+
+/// Ends the wait of a thread that is about to run a signal handler, so that the
+/// handler can use futexes itself. Returns true if the thread was already woken,
+/// in which case the wait has succeeded and must not be made again.
+///
+/// # Safety
+/// `thread` must be a valid thread.
+pub unsafe fn interrupt(thread: NonNull<Thread>) -> bool {
+    without_interrupts(|| {
+        let mut table = FUTEXES.lock();
+        if let Some(index) = table.position(thread) {
+            table.remove(index);
+        }
+        match (*thread.as_ptr()).futex_wait.take() {
+            Some(wait) => wait.is_woken,
+            None => false,
+        }
+    })
+}
+
+fn block_current(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => scheduler::sleep_current_until(deadline),
+        None => scheduler::block_current(),
+    };
+}
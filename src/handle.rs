@@ -0,0 +1,258 @@
+//! The handles of processes, which are also their file descriptors.
+//!
+//! A handle refers to a kernel object: the console, an end of a pipe, an IPC
+//! endpoint or a call to reply to. Handles are capabilities, so a process can
+//! only use the objects it has handles to. Handles can be copied within
+//! a process, given to children at spawn and sent in IPC messages.
+
+use crate::cpu::without_interrupts;
+use crate::ipc;
+use crate::pipe;
+use crate::process;
+
+/// The number of handles each process can have.
+pub const MAX_HANDLES: usize = 32;
+/// Stands for no handle in the handle arguments and results of syscalls.
+pub const NO_HANDLE: u64 = u64::MAX;
+
+/// The handles that programs read their input from and write their output to.
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+/// What a handle allows its process to do with an endpoint.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Rights(u32);
+
+impl Rights {
+    pub const SEND: Rights = Rights(1 << 0);
+    pub const RECEIVE: Rights = Rights(1 << 1);
+    pub const ALL: Rights = Rights(Rights::SEND.0 | Rights::RECEIVE.0);
+
+    /// The rights in the bits of `bits`. Bits that are not rights are dropped.
+    pub fn from_bits(bits: u64) -> Rights {
+        Rights(bits as u32 & Rights::ALL.0)
+    }
+
+    pub fn contains(self, other: Rights) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Rights) -> Rights {
+        Rights(self.0 & other.0)
+    }
+}
+
+/// A reference to a kernel object held by a process.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Handle {
+    /// Writes are drawn on the screen, and reads are at the end of file,
+    /// since there is no keyboard.
+    Console,
+    PipeReader {
+        index: usize,
+    },
+    PipeWriter {
+        index: usize,
+    },
+    Endpoint {
+        index: usize,
+        rights: Rights,
+    },
+    /// Answers the call of the thread `tid`, and is used up by doing so.
+    Reply {
+        tid: usize,
+        sequence: u32,
+    },
+}
+
+#[derive(Debug)]
+pub enum HandleError {
+    /// The caller is a kernel thread or the kernel itself rather than a process.
+    NotAProcess,
+    /// There is no such handle, or it can't be used for the operation.
+    InvalidHandle,
+    /// The handle table of the process is full.
+    TooManyHandles,
+}
+
+/// The handles of a process, indexed by the numbers user code refers to them with.
+pub struct HandleTable {
+    handles: [Option<Handle>; MAX_HANDLES],
+    // handles can only be added below this index
+    limit: usize,
+}
+
+impl Handle {
+    /// Drops the reference of the handle to its object.
+    pub fn release(self) {
+        use Handle::*;
+        match self {
+            Console => {}
+            PipeReader { .. } | PipeWriter { .. } => pipe::release(self),
+            Endpoint { .. } | Reply { .. } => ipc::release(self),
+        }
+    }
+
+    /// Adds a reference to the object of the handle, for a copy of it.
+    /// Reply handles can't be copied.
+    pub fn acquire(self) {
+        use Handle::*;
+        match self {
+            Console => {}
+            PipeReader { .. } | PipeWriter { .. } => pipe::acquire(self),
+            Endpoint { .. } => ipc::acquire(self),
+            // there is only one reply to a call
+            Reply { .. } => unreachable!(),
+        }
+    }
+}
+
+impl HandleTable {
+    pub const fn new() -> HandleTable {
+        HandleTable {
+            handles: [None; MAX_HANDLES],
+            limit: MAX_HANDLES,
+        }
+    }
+
+    /// The handles of a process started by the kernel,
+    /// which reads from and writes to the console.
+    pub fn with_console() -> HandleTable {
+        let mut handles = HandleTable::new();
+        for index in [STDIN, STDOUT, STDERR] {
+            handles.handles[index] = Some(Handle::Console);
+        }
+        handles
+    }
+
+    pub fn get(&self, index: usize) -> Result<Handle, HandleError> {
+        self.handles
+            .get(index)
+            .copied()
+            .flatten()
+            .ok_or(HandleError::InvalidHandle)
+    }
+
+    /// Returns a copy of the handle `index`, which has its own reference and must be
+    /// inserted or released. Endpoint handles keep only the `rights` they have.
+    /// Reply handles can't be copied.
+    pub fn duplicate(&self, index: usize, rights: Rights) -> Result<Handle, HandleError> {
+        let handle = match self.get(index)? {
+            Handle::Reply { .. } => return Err(HandleError::InvalidHandle),
+            Handle::Endpoint {
+                index,
+                rights: handle_rights,
+            } => Handle::Endpoint {
+                index,
+                rights: handle_rights.intersection(rights),
+            },
+            handle => handle,
+        };
+        handle.acquire();
+        Ok(handle)
+    }
+
+    /// Adds `handle` at the lowest free index, which takes over its reference,
+    /// and returns the index.
+    pub fn insert(&mut self, handle: Handle) -> Result<usize, HandleError> {
+        let index = self.handles[..self.limit]
+            .iter()
+            .position(|handle| handle.is_none())
+            .ok_or(HandleError::TooManyHandles)?;
+        self.handles[index] = Some(handle);
+        Ok(index)
+    }
+
+    /// Puts `handle` at `index`, closing the handle that was there.
+    pub fn insert_at(&mut self, index: usize, handle: Handle) -> Result<(), HandleError> {
+        let slot = self.handles[..self.limit]
+            .get_mut(index)
+            .ok_or(HandleError::InvalidHandle)?;
+        if let Some(previous) = slot.replace(handle) {
+            previous.release();
+        }
+        Ok(())
+    }
+
+    /// Removes the handle `index` without dropping its reference.
+    pub fn take(&mut self, index: usize) -> Result<Handle, HandleError> {
+        self.handles
+            .get_mut(index)
+            .and_then(Option::take)
+            .ok_or(HandleError::InvalidHandle)
+    }
+
+    pub fn close(&mut self, index: usize) -> Result<(), HandleError> {
+        self.take(index)?.release();
+        Ok(())
+    }
+
+    /// Closes every handle, when the process ends.
+    pub fn close_all(&mut self) {
+        without_interrupts(|| {
+            for handle in self.handles.iter_mut().filter_map(Option::take) {
+                handle.release();
+            }
+        });
+    }
+
+    pub fn free_slots(&self) -> usize {
+        self.handles[..self.limit]
+            .iter()
+            .filter(|handle| handle.is_none())
+            .count()
+    }
+
+    /// The number of open handles.
+    pub fn open_count(&self) -> usize {
+        self.handles
+            .iter()
+            .filter(|handle| handle.is_some())
+            .count()
+    }
+
+    /// Makes adding handles at `limit` or above fail.
+    /// Handles that are already there stay open.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit.min(MAX_HANDLES);
+    }
+}
+
+impl Drop for HandleTable {
+    fn drop(&mut self) {
+        self.close_all();
+    }
+}
+
+/// Adds a copy of the handle `index` with only the `rights` it has, and returns its index.
+pub fn duplicate(index: usize, rights: Rights) -> Result<usize, HandleError> {
+    process::with_current_handles(|handles| {
+        if handles.free_slots() == 0 {
+            return Err(HandleError::TooManyHandles);
+        }
+        let handle = handles.duplicate(index, rights)?;
+        handles.insert(handle)
+    })
+    .ok_or(HandleError::NotAProcess)?
+}
+
+/// Makes `new` a copy of the handle `old`, closing the handle that was at `new`.
+pub fn duplicate_to(old: usize, new: usize) -> Result<(), HandleError> {
+    process::with_current_handles(|handles| {
+        if new >= MAX_HANDLES {
+            return Err(HandleError::InvalidHandle);
+        }
+        if old == new {
+            return handles.get(old).map(|_| ());
+        }
+        let handle = handles.duplicate(old, Rights::ALL)?;
+        handles.insert_at(new, handle)
+    })
+    .ok_or(HandleError::NotAProcess)?
+}
+
+/// Closes the handle `index`. Closing a reply handle fails its call.
+pub fn close(index: usize) -> Result<(), HandleError> {
+    process::with_current_handles(|handles| handles.close(index)).ok_or(HandleError::NotAProcess)?
+}
@@ -0,0 +1,71 @@
+// BCM2835 ARM interrupt controller, see chapter 7 of
+// https://www.raspberrypi.org/app/uploads/2012/02/BCM2835-ARM-Peripherals.pdf
+
+const ARMCTRL_BASE_ADDR: usize = 0x3F00_B200;
+
+const BASIC_PENDING_PTR: *const u32 = ARMCTRL_BASE_ADDR as _;
+// these are arrays of two registers for gpu interrupts 0..32 and 32..64
+const GPU_PENDING_PTR: *const u32 = (ARMCTRL_BASE_ADDR + 0x04) as _;
+const GPU_ENABLE_PTR: *mut u32 = (ARMCTRL_BASE_ADDR + 0x10) as _;
+const GPU_DISABLE_PTR: *mut u32 = (ARMCTRL_BASE_ADDR + 0x1c) as _;
+const BASIC_ENABLE_PTR: *mut u32 = (ARMCTRL_BASE_ADDR + 0x18) as _;
+const BASIC_DISABLE_PTR: *mut u32 = (ARMCTRL_BASE_ADDR + 0x24) as _;
+
+// the rest of the bits in the basic pending register
+// only tell which of the gpu registers have pending bits
+const BASIC_INTERRUPTS_MASK: u32 = 0xff;
+
+pub const NUM_GPU_IRQS: usize = 64;
+pub const NUM_BASIC_IRQS: usize = 8;
+
+/// Disables all interrupts of the controller.
+pub fn disable_all() {
+    unsafe {
+        GPU_DISABLE_PTR.add(0).write_volatile(u32::MAX);
+        GPU_DISABLE_PTR.add(1).write_volatile(u32::MAX);
+        BASIC_DISABLE_PTR.write_volatile(u32::MAX);
+    }
+}
+
+// the enable and disable registers ignore zero bits,
+// so there is no need to read them first
+
+pub fn enable_gpu(irq: u8) {
+    let irq = irq as usize;
+    assert!(irq < NUM_GPU_IRQS);
+    unsafe { GPU_ENABLE_PTR.add(irq / 32).write_volatile(1 << (irq % 32)) }
+}
+
+pub fn disable_gpu(irq: u8) {
+    let irq = irq as usize;
+    assert!(irq < NUM_GPU_IRQS);
+    unsafe {
+        GPU_DISABLE_PTR
+            .add(irq / 32)
+            .write_volatile(1 << (irq % 32))
+    }
+}
+
+pub fn enable_basic(irq: u8) {
+    assert!((irq as usize) < NUM_BASIC_IRQS);
+    unsafe { BASIC_ENABLE_PTR.write_volatile(1 << irq) }
+}
+
+pub fn disable_basic(irq: u8) {
+    assert!((irq as usize) < NUM_BASIC_IRQS);
+    unsafe { BASIC_DISABLE_PTR.write_volatile(1 << irq) }
+}
+
+/// Returns a bitmask of the pending gpu interrupts.
+pub fn pending_gpu() -> u64 {
+    unsafe {
+        let low = GPU_PENDING_PTR.add(0).read_volatile() as u64;
+        let high = GPU_PENDING_PTR.add(1).read_volatile() as u64;
+        low | high << 32
+    }
+}
+
+/// Returns a bitmask of the pending basic interrupts.
+pub fn pending_basic() -> u8 {
+    unsafe { (BASIC_PENDING_PTR.read_volatile() & BASIC_INTERRUPTS_MASK) as u8 }
+}
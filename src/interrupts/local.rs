@@ -0,0 +1,108 @@
+// BCM2836 local interrupt controller, see
+// https://www.raspberrypi.org/documentation/hardware/raspberrypi/bcm2836/QA7_rev3.4.pdf
+
+use super::LocalIrq;
+
+const LOCAL_PERIPHERAL_BASE_ADDR: usize = 0x4000_0000;
+
+const GPU_INTERRUPT_ROUTING_PTR: *mut u32 = (LOCAL_PERIPHERAL_BASE_ADDR + 0x0c) as _;
+const PMU_INTERRUPT_SET_PTR: *mut u32 = (LOCAL_PERIPHERAL_BASE_ADDR + 0x10) as _;
+const PMU_INTERRUPT_CLEAR_PTR: *mut u32 = (LOCAL_PERIPHERAL_BASE_ADDR + 0x14) as _;
+const LOCAL_TIMER_ROUTING_PTR: *mut u32 = (LOCAL_PERIPHERAL_BASE_ADDR + 0x24) as _;
+const LOCAL_TIMER_CONTROL_PTR: *mut u32 = (LOCAL_PERIPHERAL_BASE_ADDR + 0x34) as _;
+
+// these are arrays with one register for each core
+const CORE_TIMER_INTERRUPT_CONTROL_PTR: *mut u32 = (LOCAL_PERIPHERAL_BASE_ADDR + 0x40) as _;
+const CORE_MAILBOX_INTERRUPT_CONTROL_PTR: *mut u32 = (LOCAL_PERIPHERAL_BASE_ADDR + 0x50) as _;
+const CORE_IRQ_SOURCE_PTR: *const u32 = (LOCAL_PERIPHERAL_BASE_ADDR + 0x60) as _;
+// four mailboxes for each core, writing to the first sets bits and writing to the second clears them
+const CORE_MAILBOX_SET_PTR: *mut u32 = (LOCAL_PERIPHERAL_BASE_ADDR + 0x80) as _;
+const CORE_MAILBOX_CLEAR_PTR: *mut u32 = (LOCAL_PERIPHERAL_BASE_ADDR + 0xc0) as _;
+const NUM_MAILBOXES_PER_CORE: usize = 4;
+
+const LOCAL_TIMER_INTERRUPT_ENABLE_BIT: u32 = 1 << 29;
+
+/// Bit 8 of the interrupt source register is set
+/// when any of the GPU interrupts routed to the core are pending.
+pub const GPU_INTERRUPT_SOURCE_BIT: u32 = 1 << 8;
+
+/// Returns a bitmask of the pending interrupt sources of `core`.
+/// The bit indices are the same as the values of `LocalIrq`.
+pub fn pending_sources(core: usize) -> u32 {
+    unsafe { CORE_IRQ_SOURCE_PTR.add(core).read_volatile() }
+}
+
+pub fn enable(irq: LocalIrq, core: usize) {
+    use LocalIrq::*;
+    unsafe {
+        match irq {
+            SecurePhysicalTimer | NonSecurePhysicalTimer | HypervisorTimer | VirtualTimer => {
+                let control_ptr = CORE_TIMER_INTERRUPT_CONTROL_PTR.add(core);
+                control_ptr.write_volatile(control_ptr.read_volatile() | 1 << irq as u32);
+            }
+            Mailbox0 | Mailbox1 | Mailbox2 | Mailbox3 => {
+                let control_ptr = CORE_MAILBOX_INTERRUPT_CONTROL_PTR.add(core);
+                let mailbox = irq as u32 - Mailbox0 as u32;
+                control_ptr.write_volatile(control_ptr.read_volatile() | 1 << mailbox);
+            }
+            PerformanceMonitor => PMU_INTERRUPT_SET_PTR.write_volatile(1 << core),
+            LocalTimer => {
+                // there is only one local timer, so this also routes it away from other cores
+                LOCAL_TIMER_ROUTING_PTR.write_volatile(core as u32);
+                LOCAL_TIMER_CONTROL_PTR.write_volatile(
+                    LOCAL_TIMER_CONTROL_PTR.read_volatile() | LOCAL_TIMER_INTERRUPT_ENABLE_BIT,
+                );
+            }
+        }
+    }
+}
+
+pub fn disable(irq: LocalIrq, core: usize) {
+    use LocalIrq::*;
+    unsafe {
+        match irq {
+            SecurePhysicalTimer | NonSecurePhysicalTimer | HypervisorTimer | VirtualTimer => {
+                let control_ptr = CORE_TIMER_INTERRUPT_CONTROL_PTR.add(core);
+                control_ptr.write_volatile(control_ptr.read_volatile() & !(1 << irq as u32));
+            }
+            Mailbox0 | Mailbox1 | Mailbox2 | Mailbox3 => {
+                let control_ptr = CORE_MAILBOX_INTERRUPT_CONTROL_PTR.add(core);
+                let mailbox = irq as u32 - Mailbox0 as u32;
+                control_ptr.write_volatile(control_ptr.read_volatile() & !(1 << mailbox));
+            }
+            PerformanceMonitor => PMU_INTERRUPT_CLEAR_PTR.write_volatile(1 << core),
+            LocalTimer => {
+                if LOCAL_TIMER_ROUTING_PTR.read_volatile() as usize == core {
+                    LOCAL_TIMER_CONTROL_PTR.write_volatile(
+                        LOCAL_TIMER_CONTROL_PTR.read_volatile() & !LOCAL_TIMER_INTERRUPT_ENABLE_BIT,
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Routes the IRQs of the ARM interrupt controller to `core`.
+pub fn route_gpu_interrupts(core: usize) {
+    unsafe { GPU_INTERRUPT_ROUTING_PTR.write_volatile(core as u32) }
+}
+
+/// Sets `bits` in `mailbox` of `core`. The mailbox interrupt
+/// of the core is pending while any of its bits are set.
+pub fn set_mailbox_bits(core: usize, mailbox: usize, bits: u32) {
+    unsafe {
+        CORE_MAILBOX_SET_PTR
+            .add(core * NUM_MAILBOXES_PER_CORE + mailbox)
+            .write_volatile(bits)
+    }
+}
+
+/// Clears and returns the bits that are set in `mailbox` of `core`.
+pub fn take_mailbox_bits(core: usize, mailbox: usize) -> u32 {
+    unsafe {
+        let mailbox_ptr = CORE_MAILBOX_CLEAR_PTR.add(core * NUM_MAILBOXES_PER_CORE + mailbox);
+        let bits = mailbox_ptr.read_volatile();
+        mailbox_ptr.write_volatile(bits);
+        bits
+    }
+}
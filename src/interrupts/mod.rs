@@ -0,0 +1,212 @@
+use spin::mutex::spin::SpinMutex;
+
+use crate::cpu::{core_id, without_interrupts, NUM_CORES};
+use crate::exceptions::ExceptionFrame;
+
+mod armctrl;
+mod local;
+
+use armctrl::{NUM_BASIC_IRQS, NUM_GPU_IRQS};
+
+/// An interrupt source that can be routed to the cores.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Irq {
+    /// A per-core interrupt of the BCM2836 local interrupt controller.
+    Local(LocalIrq),
+    /// One of the 64 GPU peripheral interrupts of the BCM2835 ARM interrupt controller.
+    Gpu(u8),
+    /// One of the 8 basic interrupts of the BCM2835 ARM interrupt controller,
+    /// for example the ARM timer (0) or the ARM mailbox (1).
+    Basic(u8),
+}
+
+/// The interrupt sources of the BCM2836 local interrupt controller.
+/// The values are the bit indices in the per-core interrupt source registers.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LocalIrq {
+    SecurePhysicalTimer = 0,
+    NonSecurePhysicalTimer = 1,
+    HypervisorTimer = 2,
+    VirtualTimer = 3,
+    Mailbox0 = 4,
+    Mailbox1 = 5,
+    Mailbox2 = 6,
+    Mailbox3 = 7,
+    PerformanceMonitor = 9,
+    LocalTimer = 11,
+}
+
+impl LocalIrq {
+    const ALL: [LocalIrq; 10] = [
+        LocalIrq::SecurePhysicalTimer,
+        LocalIrq::NonSecurePhysicalTimer,
+        LocalIrq::HypervisorTimer,
+        LocalIrq::VirtualTimer,
+        LocalIrq::Mailbox0,
+        LocalIrq::Mailbox1,
+        LocalIrq::Mailbox2,
+        LocalIrq::Mailbox3,
+        LocalIrq::PerformanceMonitor,
+        LocalIrq::LocalTimer,
+    ];
+}
+
+/// Messages sent between cores with inter-processor interrupts.
+/// The values are the bit indices in the mailbox used for them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Ipi {
+    /// Asks the receiving core to run the scheduler.
+    Reschedule = 0,
+}
+
+/// The interrupt raised on a core when another core sends it an `Ipi`.
+pub const IPI_IRQ: Irq = Irq::Local(LocalIrq::Mailbox0);
+const IPI_MAILBOX: usize = 0;
+
+/// A function called on the core that received the interrupt.
+/// It must clear the interrupt at its source before returning.
+pub type IrqHandler = fn(Irq, &mut ExceptionFrame);
+
+#[derive(Debug)]
+pub enum IrqError {
+    AlreadyRegistered,
+    InvalidIrq,
+}
+
+// the local interrupts are indexed by their bit index, followed by
+// the gpu interrupts and finally the basic interrupts
+const NUM_LOCAL_IRQS: usize = 12;
+const NUM_IRQS: usize = NUM_LOCAL_IRQS + NUM_GPU_IRQS + NUM_BASIC_IRQS;
+
+static IRQ_HANDLERS: SpinMutex<[Option<IrqHandler>; NUM_IRQS]> = SpinMutex::new([None; NUM_IRQS]);
+
+impl Irq {
+    fn index(self) -> Result<usize, IrqError> {
+        match self {
+            Irq::Local(irq) => Ok(irq as usize),
+            Irq::Gpu(irq) if (irq as usize) < NUM_GPU_IRQS => Ok(NUM_LOCAL_IRQS + irq as usize),
+            Irq::Basic(irq) if (irq as usize) < NUM_BASIC_IRQS => {
+                Ok(NUM_LOCAL_IRQS + NUM_GPU_IRQS + irq as usize)
+            }
+            _ => Err(IrqError::InvalidIrq),
+        }
+    }
+}
+
+/// Disables all interrupts and routes the interrupts
+/// of the ARM interrupt controller to the calling core.
+///
+/// # Safety
+/// This must be called once before any interrupts are registered.
+pub unsafe fn init() {
+    armctrl::disable_all();
+    for core in 0..NUM_CORES {
+        for irq in LocalIrq::ALL {
+            local::disable(irq, core);
+        }
+    }
+    local::route_gpu_interrupts(core_id());
+}
+
+/// Registers a handler for `irq`. The interrupt must still be enabled with `enable_irq`.
+pub fn register_irq(irq: Irq, handler: IrqHandler) -> Result<(), IrqError> {
+    let index = irq.index()?;
+    without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        if handlers[index].is_some() {
+            return Err(IrqError::AlreadyRegistered);
+        }
+        handlers[index] = Some(handler);
+        Ok(())
+    })
+}
+
+/// Enables `irq` on `core`.
+///
+/// The interrupts of the ARM interrupt controller can only be routed to one core
+/// at a time, so enabling one of them routes all of them to `core`.
+pub fn enable_irq(irq: Irq, core: usize) -> Result<(), IrqError> {
+    irq.index()?;
+    match irq {
+        Irq::Local(irq) => local::enable(irq, core),
+        Irq::Gpu(irq) => {
+            local::route_gpu_interrupts(core);
+            armctrl::enable_gpu(irq);
+        }
+        Irq::Basic(irq) => {
+            local::route_gpu_interrupts(core);
+            armctrl::enable_basic(irq);
+        }
+    }
+    Ok(())
+}
+
+/// Disables `irq` on `core`. The interrupts of the ARM interrupt
+/// controller are disabled for all cores regardless of `core`.
+pub fn disable_irq(irq: Irq, core: usize) -> Result<(), IrqError> {
+    irq.index()?;
+    match irq {
+        Irq::Local(irq) => local::disable(irq, core),
+        Irq::Gpu(irq) => armctrl::disable_gpu(irq),
+        Irq::Basic(irq) => armctrl::disable_basic(irq),
+    }
+    Ok(())
+}
+
+/// Sends `ipi` to `core`. The receiver needs a handler for `IPI_IRQ`.
+pub fn send_ipi(ipi: Ipi, core: usize) {
+    local::set_mailbox_bits(core, IPI_MAILBOX, 1 << ipi as u32);
+}
+
+/// Returns the IPIs that are pending on the calling core
+/// as a bitmask indexed by `Ipi`, and clears them.
+pub fn take_pending_ipis() -> u32 {
+    local::take_mailbox_bits(core_id(), IPI_MAILBOX)
+}
+
+/// Calls the handlers of all pending interrupts of the current core.
+pub fn handle_irq(frame: &mut ExceptionFrame) {
+    let core = core_id();
+    let sources = local::pending_sources(core);
+
+    for irq in LocalIrq::ALL {
+        if sources & (1 << irq as u32) != 0 {
+            dispatch(Irq::Local(irq), core, frame);
+        }
+    }
+
+    if sources & local::GPU_INTERRUPT_SOURCE_BIT != 0 {
+        let pending_basic = armctrl::pending_basic();
+        for irq in 0..NUM_BASIC_IRQS as u8 {
+            if pending_basic & (1 << irq) != 0 {
+                dispatch(Irq::Basic(irq), core, frame);
+            }
+        }
+
+        let pending_gpu = armctrl::pending_gpu();
+        for irq in 0..NUM_GPU_IRQS as u8 {
+            if pending_gpu & (1 << irq) != 0 {
+                dispatch(Irq::Gpu(irq), core, frame);
+            }
+        }
+    }
+}
+
+fn dispatch(irq: Irq, core: usize, frame: &mut ExceptionFrame) {
+    // the lock must not be held while the handler runs,
+    // so that handlers can register other handlers
+    let handler = irq
+        .index()
+        .ok()
+        .and_then(|index| IRQ_HANDLERS.lock()[index]);
+
+    match handler {
+        Some(handler) => handler(irq, frame),
+        None => {
+            // the interrupt would otherwise fire again right after returning
+            crate::println!("[WARN]: disabling unhandled interrupt {:?}", irq);
+            disable_irq(irq, core).unwrap();
+        }
+    }
+}
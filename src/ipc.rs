@@ -0,0 +1,462 @@
+//! Message passing between processes through endpoints.
+//!
+//! An endpoint is a bounded queue of messages in the kernel. Processes refer
+//! to endpoints through handles, which also carry the rights to send to or
+//! receive from the endpoint. Besides creating an endpoint, the only way to
+//! get a handle is to be sent one in a message or to be given one at spawn.
+//!
+//! A call sends a message with a reply handle attached, and blocks the caller
+//! until the receiver answers through the reply handle. Each thread makes at
+//! most one call at a time, so the state of a call is kept by tid.
+//!
+//! Threads blocked on an endpoint or a call wait in its `WaitQueue`,
+//! so that a sender wakes the blocked receivers itself.
+
+use spin::mutex::spin::SpinMutex;
+
+use crate::cpu::without_interrupts;
+use crate::handle::{Handle, HandleError, HandleTable, Rights, NO_HANDLE};
+use crate::process;
+use crate::scheduler;
+use crate::sync::WaitQueue;
+use crate::thread::MAX_NUM_THREADS;
+
+pub const MAX_MESSAGE_SIZE: usize = 128;
+const MAX_NUM_ENDPOINTS: usize = 64;
+const QUEUE_CAPACITY: usize = 8;
+
+static IPC: SpinMutex<IpcTable> = SpinMutex::new(IpcTable {
+    endpoints: [const { Endpoint::new() }; MAX_NUM_ENDPOINTS],
+    calls: [const { Call::new() }; MAX_NUM_THREADS],
+});
+
+#[derive(Debug)]
+pub enum IpcError {
+    /// The caller is a kernel thread or the kernel itself rather than a process.
+    NotAProcess,
+    /// There is no such handle, or it lacks the rights for the operation.
+    InvalidHandle,
+    /// The handle table of the process is full.
+    TooManyHandles,
+    /// Every endpoint is in use.
+    TooManyEndpoints,
+    MessageTooLarge,
+    /// The caller of a reply handle has gone away,
+    /// or a call was dropped without a reply.
+    PeerGone,
+    /// The caller was blocked and has to try again once it runs.
+    WouldBlock,
+}
+
+/// What the receiver learns about a message besides its contents.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct MessageInfo {
+    /// The length of the message, which may be more than what fit in the buffer.
+    pub len: u64,
+    /// The pid of the sending process.
+    pub sender: u64,
+    /// The handle sent along with the message, or `NO_HANDLE`.
+    pub handle: u64,
+    /// The handle to reply with if the message is a call, or `NO_HANDLE`.
+    pub reply: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Message {
+    data: [u8; MAX_MESSAGE_SIZE],
+    len: usize,
+    sender: usize,
+    // holds a reference to its endpoint while the message is queued
+    handle: Option<Handle>,
+    // set for calls, and used up by the receiver
+    reply: Option<Handle>,
+}
+
+struct Endpoint {
+    // the handles and queued messages referring to the endpoint, 0 if it is free
+    references: usize,
+    queue: [Option<Message>; QUEUE_CAPACITY],
+    head: usize,
+    len: usize,
+    // receivers wait for a message, and senders for room in the queue
+    blocked_receivers: WaitQueue,
+    blocked_senders: WaitQueue,
+}
+
+struct Call {
+    // changes whenever a call ends, so that reply handles to it go stale
+    sequence: u32,
+    state: CallState,
+    blocked_caller: WaitQueue,
+}
+
+#[derive(Clone, Copy)]
+enum CallState {
+    Idle,
+    Waiting,
+    Replied(Message),
+    Failed,
+}
+
+struct IpcTable {
+    endpoints: [Endpoint; MAX_NUM_ENDPOINTS],
+    calls: [Call; MAX_NUM_THREADS],
+}
+
+impl Endpoint {
+    const fn new() -> Endpoint {
+        Endpoint {
+            references: 0,
+            queue: [None; QUEUE_CAPACITY],
+            head: 0,
+            len: 0,
+            blocked_receivers: WaitQueue::new(),
+            blocked_senders: WaitQueue::new(),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == QUEUE_CAPACITY
+    }
+
+    fn push(&mut self, message: Message) {
+        self.queue[(self.head + self.len) % QUEUE_CAPACITY] = Some(message);
+        self.len += 1;
+        self.blocked_receivers.wake_all();
+    }
+
+    fn front(&self) -> Option<&Message> {
+        match self.len {
+            0 => None,
+            _ => self.queue[self.head].as_ref(),
+        }
+    }
+
+    fn pop(&mut self) -> Option<Message> {
+        if self.len == 0 {
+            return None;
+        }
+        let message = self.queue[self.head].take();
+        self.head = (self.head + 1) % QUEUE_CAPACITY;
+        self.len -= 1;
+        self.blocked_senders.wake_all();
+        message
+    }
+}
+
+impl Call {
+    const fn new() -> Call {
+        Call {
+            sequence: 0,
+            state: CallState::Idle,
+            blocked_caller: WaitQueue::new(),
+        }
+    }
+
+    /// Ends the call, making the reply handle to it stale.
+    fn finish(&mut self, state: CallState) {
+        self.sequence = self.sequence.wrapping_add(1);
+        self.state = state;
+        self.blocked_caller.wake_all();
+    }
+}
+
+impl IpcTable {
+    fn create_endpoint(&mut self) -> Result<usize, IpcError> {
+        let index = self
+            .endpoints
+            .iter()
+            .position(|endpoint| endpoint.references == 0)
+            .ok_or(IpcError::TooManyEndpoints)?;
+        let endpoint = &mut self.endpoints[index];
+        endpoint.references = 1;
+        endpoint.head = 0;
+        endpoint.len = 0;
+        Ok(index)
+    }
+
+    fn acquire(&mut self, handle: Handle) {
+        if let Handle::Endpoint { index, .. } = handle {
+            self.endpoints[index].references += 1;
+        }
+    }
+
+    /// Drops a reference. A reply handle fails its call, and the last
+    /// reference to an endpoint frees it along with its queued messages.
+    fn release(&mut self, handle: Handle) {
+        match handle {
+            Handle::Endpoint { index, .. } => {
+                let endpoint = &mut self.endpoints[index];
+                endpoint.references -= 1;
+                if endpoint.references > 0 {
+                    return;
+                }
+                while let Some(message) = self.endpoints[index].pop() {
+                    self.drop_message(message);
+                }
+            }
+            Handle::Reply { tid, sequence } => {
+                let call = &mut self.calls[tid];
+                if call.sequence == sequence {
+                    call.finish(CallState::Failed);
+                }
+            }
+            // handles to other objects can be sent in messages too
+            handle => handle.release(),
+        }
+    }
+
+    fn drop_message(&mut self, message: Message) {
+        for handle in [message.handle, message.reply].into_iter().flatten() {
+            self.release(handle);
+        }
+    }
+}
+
+impl From<HandleError> for IpcError {
+    fn from(error: HandleError) -> IpcError {
+        match error {
+            HandleError::NotAProcess => IpcError::NotAProcess,
+            HandleError::InvalidHandle => IpcError::InvalidHandle,
+            HandleError::TooManyHandles => IpcError::TooManyHandles,
+        }
+    }
+}
+
+/// Creates an endpoint, and returns a handle to it with all rights.
+pub fn create_endpoint() -> Result<usize, IpcError> {
+    process::with_current_handles(|handles| {
+        if handles.free_slots() == 0 {
+            return Err(IpcError::TooManyHandles);
+        }
+        let index = IPC.lock().create_endpoint()?;
+        Ok(handles.insert(Handle::Endpoint {
+            index,
+            rights: Rights::ALL,
+        })?)
+    })
+    .ok_or(IpcError::NotAProcess)?
+}
+
+/// Queues a message with `bytes` on the endpoint of the handle `index`, and wakes
+/// the threads blocked receiving from it. A copy of the handle `transfer` is sent
+/// along. If the queue is full, the current thread is blocked until it is not.
+pub fn send(index: usize, bytes: &[u8], transfer: Option<usize>) -> Result<(), IpcError> {
+    let sender = process::current_pid();
+    process::with_current_handles(|handles| {
+        let mut ipc = IPC.lock();
+        let endpoint = endpoint(handles, index, Rights::SEND)?;
+        wait_for_room(&ipc.endpoints[endpoint])?;
+        let message = new_message(handles, &mut ipc, bytes, sender, transfer, None)?;
+        ipc.endpoints[endpoint].push(message);
+        Ok(())
+    })
+    .ok_or(IpcError::NotAProcess)?
+}
+
+/// Takes the next message from the endpoint of the handle `index`, copying as much
+/// of it as fits into `buffer`. The handles that came with the message are added
+/// to the handle table. If the queue is empty, the current thread is blocked until
+/// a message is sent.
+pub fn receive(index: usize, buffer: &mut [u8]) -> Result<MessageInfo, IpcError> {
+    process::with_current_handles(|handles| {
+        let mut ipc = IPC.lock();
+        let endpoint = &mut ipc.endpoints[endpoint(handles, index, Rights::RECEIVE)?];
+        let message = match endpoint.front() {
+            Some(message) => message,
+            None => return Err(block_on(&endpoint.blocked_receivers)),
+        };
+        let needed_slots = message.handle.iter().chain(&message.reply).count();
+        if handles.free_slots() < needed_slots {
+            return Err(IpcError::TooManyHandles);
+        }
+        let message = endpoint.pop().unwrap();
+
+        let len = message.len.min(buffer.len());
+        buffer[..len].copy_from_slice(&message.data[..len]);
+        let mut insert = |handle: Option<Handle>| match handle {
+            Some(handle) => handles.insert(handle).unwrap() as u64,
+            None => NO_HANDLE,
+        };
+        Ok(MessageInfo {
+            len: message.len as u64,
+            sender: message.sender as u64,
+            handle: insert(message.handle),
+            reply: insert(message.reply),
+        })
+    })
+    .ok_or(IpcError::NotAProcess)?
+}
+
+/// Sends a message like `send` with a reply handle attached, and blocks the current
+/// thread until the receiver replies. Copies as much of the reply as fits into
+/// `reply_buffer`, and returns the length of the reply.
+pub fn call(
+    index: usize,
+    bytes: &[u8],
+    transfer: Option<usize>,
+    reply_buffer: &mut [u8],
+) -> Result<usize, IpcError> {
+    let thread = scheduler::current_thread().ok_or(IpcError::NotAProcess)?;
+    let tid = unsafe { thread.as_ref() }.tid();
+    let caller = process::current_pid();
+
+    process::with_current_handles(|handles| {
+        let mut ipc = IPC.lock();
+        let call = &mut ipc.calls[tid];
+        match call.state {
+            // the syscall is being made again after the caller was blocked
+            CallState::Replied(reply) => {
+                call.state = CallState::Idle;
+                let len = reply.len.min(reply_buffer.len());
+                reply_buffer[..len].copy_from_slice(&reply.data[..len]);
+                return Ok(reply.len);
+            }
+            CallState::Failed => {
+                call.state = CallState::Idle;
+                return Err(IpcError::PeerGone);
+            }
+            CallState::Waiting => return Err(block_on(&call.blocked_caller)),
+            CallState::Idle => {}
+        }
+
+        let endpoint = endpoint(handles, index, Rights::SEND)?;
+        wait_for_room(&ipc.endpoints[endpoint])?;
+        let reply = Handle::Reply {
+            tid,
+            sequence: ipc.calls[tid].sequence,
+        };
+        let message = new_message(handles, &mut ipc, bytes, caller, transfer, Some(reply))?;
+        ipc.endpoints[endpoint].push(message);
+        ipc.calls[tid].state = CallState::Waiting;
+        Err(block_on(&ipc.calls[tid].blocked_caller))
+    })
+    .ok_or(IpcError::NotAProcess)?
+}
+
+/// Answers the call of the reply handle `index` with `bytes`, and closes the handle.
+pub fn reply(index: usize, bytes: &[u8]) -> Result<(), IpcError> {
+    process::with_current_handles(|handles| {
+        let (tid, sequence) = match handles.get(index)? {
+            Handle::Reply { tid, sequence } => (tid, sequence),
+            _ => return Err(IpcError::InvalidHandle),
+        };
+        if bytes.len() > MAX_MESSAGE_SIZE {
+            return Err(IpcError::MessageTooLarge);
+        }
+        handles.take(index)?;
+
+        let mut ipc = IPC.lock();
+        let call = &mut ipc.calls[tid];
+        if call.sequence != sequence {
+            return Err(IpcError::PeerGone);
+        }
+        let mut reply = Message {
+            data: [0; MAX_MESSAGE_SIZE],
+            len: bytes.len(),
+            sender: process::current_pid(),
+            handle: None,
+            reply: None,
+        };
+        reply.data[..bytes.len()].copy_from_slice(bytes);
+        call.finish(CallState::Replied(reply));
+        Ok(())
+    })
+    .ok_or(IpcError::NotAProcess)?
+}
+
+/// Receives a message like `receive`, for reading from an endpoint like from a file.
+/// The handles that came with the message are closed. Returns the number of bytes read.
+pub fn read(index: usize, buffer: &mut [u8]) -> Result<usize, IpcError> {
+    let info = receive(index, buffer)?;
+    for handle in [info.handle, info.reply] {
+        if handle != NO_HANDLE {
+            process::with_current_handles(|handles| handles.close(handle as usize))
+                .ok_or(IpcError::NotAProcess)??;
+        }
+    }
+    Ok((info.len as usize).min(buffer.len()))
+}
+
+/// Adds a reference to the endpoint of `handle`, for a copy of it.
+pub(crate) fn acquire(handle: Handle) {
+    without_interrupts(|| IPC.lock().acquire(handle));
+}
+
+/// Drops the reference of an endpoint or reply handle.
+pub(crate) fn release(handle: Handle) {
+    without_interrupts(|| IPC.lock().release(handle));
+}
+
+/// Fails the call of a thread that is being released, so that its tid can be reused.
+pub fn cancel_call(tid: usize) {
+    without_interrupts(|| {
+        let mut ipc = IPC.lock();
+        let call = &mut ipc.calls[tid];
+        call.sequence = call.sequence.wrapping_add(1);
+        call.state = CallState::Idle;
+    });
+}
+
+fn new_message(
+    handles: &HandleTable,
+    ipc: &mut IpcTable,
+    bytes: &[u8],
+    sender: usize,
+    transfer: Option<usize>,
+    reply: Option<Handle>,
+) -> Result<Message, IpcError> {
+    if bytes.len() > MAX_MESSAGE_SIZE {
+        return Err(IpcError::MessageTooLarge);
+    }
+    let handle = match transfer {
+        Some(transfer) => {
+            let handle = handles.get(transfer)?;
+            match handle {
+                Handle::Reply { .. } => return Err(IpcError::InvalidHandle),
+                Handle::Endpoint { .. } => ipc.acquire(handle),
+                _ => handle.acquire(),
+            }
+            Some(handle)
+        }
+        None => None,
+    };
+    let mut message = Message {
+        data: [0; MAX_MESSAGE_SIZE],
+        len: bytes.len(),
+        sender,
+        handle,
+        reply,
+    };
+    message.data[..bytes.len()].copy_from_slice(bytes);
+    Ok(message)
+}
+
+/// Returns the index of the endpoint of the handle `index` if the handle has `rights`.
+fn endpoint(handles: &HandleTable, index: usize, rights: Rights) -> Result<usize, IpcError> {
+    match handles.get(index)? {
+        Handle::Endpoint {
+            index,
+            rights: handle_rights,
+        } if handle_rights.contains(rights) => Ok(index),
+        _ => Err(IpcError::InvalidHandle),
+    }
+}
+
+/// Blocks the current thread until a message is received from `endpoint` if it is full.
+fn wait_for_room(endpoint: &Endpoint) -> Result<(), IpcError> {
+    match endpoint.is_full() {
+        true => Err(block_on(&endpoint.blocked_senders)),
+        false => Ok(()),
+    }
+}
+
+/// Blocks the current thread in `queue` until the endpoint or call wakes it,
+/// and returns the error that makes the caller try again.
+fn block_on(queue: &WaitQueue) -> IpcError {
+    match queue.block_current() {
+        true => IpcError::WouldBlock,
+        false => IpcError::NotAProcess,
+    }
+}
@@ -1,6 +1,10 @@
 use core::arch::asm;
 use core::marker::PhantomData;
 
+use spin::mutex::spin::SpinMutex;
+
+use crate::cpu::without_interrupts;
+
 const MAILBOX_BASE_ADDR: usize = 0x3F00B880;
 // TODO: could we use offset here?
 const MAILBOX_STATUS_PTR: *const u32 = (MAILBOX_BASE_ADDR + 0x18) as _;
@@ -16,6 +20,9 @@ const END_TAG: u32 = 0x00000000;
 
 const MAILBOX_PROPERTY_CHANNEL: u8 = 8;
 
+// only one message can be in flight, or the responses could be mixed up
+static MAILBOX_LOCK: SpinMutex<()> = SpinMutex::new(());
+
 /// A type that represents a properly aligned Mailbox buffer.
 /// `LEN` is the maximum number of `u32`s that can fit in the buffer.
 #[repr(C, align(16))]
@@ -47,6 +54,17 @@ impl<const LEN: usize, T: MailboxTagType> MailboxMessageBuffer<LEN, T> {
         }
     }
 
+    /// Creates a buffer from a message that is already laid out, like one made by
+    /// a process. The message must have been checked with `check_message`.
+    pub fn from_words(data: [u32; LEN]) -> Self {
+        let end = check_message(&data, |_| {}).expect("malformed mailbox message");
+        Self {
+            data,
+            end,
+            _t: PhantomData,
+        }
+    }
+
     /// Tries to append a tag to the buffer. If it doesn't fit,
     /// the number of available `u32`s in the buffer is returned.
     pub fn try_add_tag<const BUFFER_LEN: usize>(
@@ -91,6 +109,13 @@ impl<const LEN: usize, T: MailboxTagType> MailboxMessageBuffer<LEN, T> {
     /// # Safety
     /// Caller must make sure that the contents of the buffer are safe.
     pub unsafe fn send(&self) -> Result<MailboxResponse<LEN, T>, ()> {
+        without_interrupts(|| {
+            let _guard = MAILBOX_LOCK.lock();
+            self.send_locked()
+        })
+    }
+
+    unsafe fn send_locked(&self) -> Result<MailboxResponse<LEN, T>, ()> {
         while MAILBOX_STATUS_PTR.read_volatile() & MAILBOX_FULL > 0 {
             asm!("nop");
         }
@@ -145,6 +170,31 @@ impl<const LEN: usize, T: MailboxTagType> MailboxResponse<LEN, T> {
             _t: PhantomData,
         }
     }
+
+    /// The whole response, laid out like the message.
+    pub fn words(&self) -> &[u32; LEN] {
+        &self.data
+    }
+}
+
+/// Checks that `data` holds a well formed message, calling `f` with the value
+/// of each of its tags. Returns the index of the end tag.
+pub fn check_message(data: &[u32], mut f: impl FnMut(u32)) -> Option<usize> {
+    let len = *data.first()? as usize / 4;
+    if len < 3 || len > data.len() || data[1] != REQUEST_CODE {
+        return None;
+    }
+    let mut cur = 2;
+    while data[cur] != END_TAG {
+        // the tag, the size of its buffer and its request code come before the buffer
+        let buffer_len = (*data.get(cur + 1)? as usize).div_ceil(4);
+        f(data[cur]);
+        cur = cur.checked_add(3 + buffer_len)?;
+        if cur >= len {
+            return None;
+        }
+    }
+    Some(cur)
 }
 
 pub struct MailboxResponseIterator<'data, T: MailboxTagType> {
@@ -2,7 +2,6 @@
 #![feature(panic_info_message)]
 #![feature(decl_macro)]
 #![feature(core_intrinsics)]
-#![feature(never_type)]
 #![feature(ptr_as_uninit)]
 #![no_std]
 #![no_main]
@@ -12,14 +11,29 @@ use core::mem::MaybeUninit;
 
 global_asm!(include_str!("boot.s"));
 
+mod arguments;
 mod console;
+mod cpu;
 mod elf;
 mod exceptions;
+mod futex;
+mod handle;
+mod interrupts;
+mod ipc;
 mod macros;
 mod mailbox;
 mod memory;
 mod nolock;
+mod pipe;
 mod process;
+mod random;
+mod resource;
+mod scheduler;
+mod serial;
+mod signal;
+mod sync;
+mod thread;
+mod timer;
 
 use console::{Console, CONSOLE};
 use macros::*;
@@ -33,33 +47,65 @@ use macros::*;
 pub unsafe extern "C" fn kernel_start() -> ! {
     memory::zero_bss();
 
-    // this must be initialized before use
-    *CONSOLE.lock() = MaybeUninit::new(Console::init());
+    // this must be initialized before use, but the lock only works with
+    // the mmu enabled, so nothing can be printed until then
+    CONSOLE
+        .as_mut_ptr()
+        .write(MaybeUninit::new(Console::init()));
+
+    exceptions::init_and_enable_exceptions();
+
+    memory::initialize_and_enable_mmu();
     println!("[INFO]: initialized console");
 
     let el: u64;
     asm!("mrs {}, currentel", out(reg) el);
     println!("[INFO]: current execution level: el{}", el >> 2);
-
-    exceptions::init_and_enable_exceptions();
     println!("[INFO]: exceptions initialized and enabled");
-
-    memory::initialize_and_enable_mmu();
     println!("[INFO]: mmu initialized and enabled");
+
+    interrupts::init();
+    println!("[INFO]: interrupt controllers initialized");
+
+    timer::init(timer::DEFAULT_TICK_RATE);
+    println!(
+        "[INFO]: timer initialized at {} Hz, counter frequency: {} Hz",
+        timer::DEFAULT_TICK_RATE,
+        timer::counter_frequency()
+    );
+    timer::start_drift_check();
+
+    random::init();
+    println!("[INFO]: random number generator started");
+
+    serial::init();
+    println!("[INFO]: serial port initialized");
+
+    cpu::start_secondary_cores();
+    println!("[INFO]: released the secondary cores");
     //memory::test();
 
     //(0x81ec4 as *mut u64).write_volatile(42);
 
-    asm!("svc #0xdead");
+    let message = "[INFO]: hello from a syscall\n";
+    let written: u64;
+    asm!(
+        "svc #0",
+        inlateout("x0") 1u64 => written,
+        in("x1") message.as_ptr(),
+        in("x2") message.len(),
+        in("x8") exceptions::syscalls::SYSCALL_WRITE,
+    );
+    assert_eq!(written, message.len() as u64);
 
-    elf::test();
+    thread::start_worker().unwrap();
+    println!("[INFO]: started the worker thread");
 
-    println!("[INFO]: looping forever...");
-    // let mut i = 0;
-    loop {
-        // println!("{}", i);
-        // i += 1;
-    }
+    process::start_core_dump_writer().unwrap();
+    println!("[INFO]: started the core dump thread");
+
+    println!("[INFO]: entering the scheduler...");
+    scheduler::run()
 
     // TODO: test unaligned access
 
@@ -71,12 +117,25 @@ pub unsafe extern "C" fn kernel_start() -> ! {
     // files
 
     // kernel:
-    //  scheduler
     //  mmu
     //  basic fs
     //  basic fb & console
 }
 
+/// The starting point of the secondary cores, called from boot.s
+/// # Safety
+/// this function should only be called once by each secondary core
+#[no_mangle]
+pub unsafe extern "C" fn secondary_kernel_start(core: usize) -> ! {
+    // the locks only work with the mmu enabled
+    memory::enable_mmu_on_secondary_core();
+    exceptions::init_and_enable_exceptions();
+    timer::init(timer::DEFAULT_TICK_RATE);
+    println!("[INFO]: core {} started", core);
+
+    scheduler::run()
+}
+
 #[panic_handler]
 fn panic(panic_info: &core::panic::PanicInfo) -> ! {
     // TODO: what if we panic before/while initializing the console?
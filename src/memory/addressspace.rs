@@ -0,0 +1,440 @@
+use core::arch::asm;
+use core::ptr::NonNull;
+
+use super::pageallocator::{Page, PAGE_ALLOCATOR, PAGE_SIZE};
+use super::{
+    TranslationTable, TranslationTableEntry, BASE_TRANSLATION_TABLE, LEVEL1_BLOCK_SIZE,
+    LEVEL2_BLOCK_SIZE, USER_SPACE_END, USER_SPACE_START,
+};
+
+const ENTRIES_PER_TABLE: usize = 512;
+
+/// How user code may access a mapped page. Every mapped page is readable.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PageFlags {
+    pub is_writable: bool,
+    pub is_executable: bool,
+}
+
+#[derive(Debug)]
+pub enum MapError {
+    OutOfMemory,
+    /// The address is not page aligned or outside of user space.
+    InvalidAddress,
+    AlreadyMapped,
+    /// The address space already has as many pages as its limit allows.
+    LimitExceeded,
+}
+
+/// The translation tables of a process. The kernel is mapped into every address
+/// space exactly like in the kernel's own tables, and the user space range
+/// is mapped in 4 KiB pages that are owned by the address space.
+pub struct AddressSpace {
+    base_table: NonNull<TranslationTable>,
+    // the pages owned by the address space, not counting device memory and tables
+    resident_pages: usize,
+    page_limit: usize,
+}
+
+unsafe impl Send for AddressSpace {}
+
+impl AddressSpace {
+    pub fn new() -> Option<AddressSpace> {
+        let base_table = PAGE_ALLOCATOR
+            .lock()
+            .alloc_zeroed_page(1)?
+            .cast::<TranslationTable>();
+        // the kernel's entries all come before user space
+        let kernel_table = unsafe { BASE_TRANSLATION_TABLE.lock() };
+        for index in 0..USER_SPACE_START / LEVEL1_BLOCK_SIZE as usize {
+            unsafe { (*base_table.as_ptr()).set_entry(index, kernel_table.entries[index]) };
+        }
+        Some(AddressSpace {
+            base_table,
+            resident_pages: 0,
+            page_limit: usize::MAX,
+        })
+    }
+
+    /// The number of pages of memory mapped into the address space.
+    pub fn resident_pages(&self) -> usize {
+        self.resident_pages
+    }
+
+    /// Makes mapping more pages than `page_limit` fail. Pages that are
+    /// already mapped stay mapped even if there are more of them.
+    pub fn set_page_limit(&mut self, page_limit: usize) {
+        self.page_limit = page_limit;
+    }
+
+    /// Maps the page at `virtual_address` to `page`, which is freed with the address space.
+    pub fn map_page(
+        &mut self,
+        virtual_address: usize,
+        page: NonNull<Page>,
+        flags: PageFlags,
+    ) -> Result<(), MapError> {
+        if !virtual_address.is_multiple_of(PAGE_SIZE)
+            || !(USER_SPACE_START..USER_SPACE_END).contains(&virtual_address)
+        {
+            return Err(MapError::InvalidAddress);
+        }
+        if self.resident_pages >= self.page_limit {
+            return Err(MapError::LimitExceeded);
+        }
+
+        let page_table = self.page_table(virtual_address)?;
+        let index = level3_index(virtual_address);
+        let table = unsafe { &mut *page_table.as_ptr() };
+        if table.entries[index].is_valid() {
+            return Err(MapError::AlreadyMapped);
+        }
+        table.set_entry(
+            index,
+            TranslationTableEntry::page_descriptor(page.as_ptr() as u64, flags),
+        );
+        self.resident_pages += 1;
+        Ok(())
+    }
+
+    /// Also allows the accesses of `flags` to the page mapped at `virtual_address`,
+    /// for a page that is shared by two segments of a program.
+    pub fn add_flags(&mut self, virtual_address: usize, flags: PageFlags) -> Result<(), MapError> {
+        let entry = match self.page_entry(virtual_address) {
+            Some(entry) if !entry.is_device() => entry,
+            _ => return Err(MapError::InvalidAddress),
+        };
+        let flags = PageFlags {
+            is_writable: entry.is_writable() || flags.is_writable,
+            is_executable: entry.is_executable() || flags.is_executable,
+        };
+        let page_table = self.page_table_if_present(virtual_address).unwrap();
+        unsafe { &mut *page_table.as_ptr() }.set_entry(
+            level3_index(virtual_address),
+            TranslationTableEntry::page_descriptor(entry.address(), flags),
+        );
+        unsafe { asm!("dsb ishst", "tlbi vmalle1is", "dsb ish", "isb") };
+        Ok(())
+    }
+
+    /// Allocates zeroed pages for the page aligned range and maps them.
+    pub fn map_zeroed(
+        &mut self,
+        start: usize,
+        len: usize,
+        flags: PageFlags,
+    ) -> Result<(), MapError> {
+        for virtual_address in (start..start + len).step_by(PAGE_SIZE) {
+            // checked before allocating, so that a process at its limit can't drain the allocator
+            if self.resident_pages >= self.page_limit {
+                return Err(MapError::LimitExceeded);
+            }
+            let page = PAGE_ALLOCATOR
+                .lock()
+                .alloc_zeroed_page(1)
+                .ok_or(MapError::OutOfMemory)?;
+            if let Err(error) = self.map_page(virtual_address, page, flags) {
+                unsafe { PAGE_ALLOCATOR.lock().free_page(page) };
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Maps the page aligned range to the device memory at `physical_address`,
+    /// which stays owned by the kernel.
+    pub fn map_device(
+        &mut self,
+        start: usize,
+        physical_address: usize,
+        len: usize,
+    ) -> Result<(), MapError> {
+        if !start.is_multiple_of(PAGE_SIZE)
+            || !physical_address.is_multiple_of(PAGE_SIZE)
+            || !len.is_multiple_of(PAGE_SIZE)
+            || start
+                .checked_add(len)
+                .is_none_or(|end| end > USER_SPACE_END)
+        {
+            return Err(MapError::InvalidAddress);
+        }
+
+        for offset in (0..len).step_by(PAGE_SIZE) {
+            let virtual_address = start + offset;
+            if !(USER_SPACE_START..USER_SPACE_END).contains(&virtual_address) {
+                return Err(MapError::InvalidAddress);
+            }
+            let page_table = self.page_table(virtual_address)?;
+            let index = level3_index(virtual_address);
+            let table = unsafe { &mut *page_table.as_ptr() };
+            if table.entries[index].is_valid() {
+                return Err(MapError::AlreadyMapped);
+            }
+            table.set_entry(
+                index,
+                TranslationTableEntry::device_page_descriptor((physical_address + offset) as u64),
+            );
+        }
+        Ok(())
+    }
+
+    /// Unmaps and frees the pages mapped in the page aligned range.
+    /// Pages that are not mapped are skipped, and device memory is only unmapped.
+    pub fn unmap(&mut self, start: usize, len: usize) {
+        let mut page_allocator = PAGE_ALLOCATOR.lock();
+        for virtual_address in (start..start + len).step_by(PAGE_SIZE) {
+            if !(USER_SPACE_START..USER_SPACE_END).contains(&virtual_address) {
+                continue;
+            }
+            let page_table = match self.page_table_if_present(virtual_address) {
+                Some(page_table) => page_table,
+                None => continue,
+            };
+            let index = level3_index(virtual_address);
+            let table = unsafe { &mut *page_table.as_ptr() };
+            let entry = table.entries[index];
+            if entry.is_valid() {
+                table.set_entry(index, TranslationTableEntry::invalid());
+                // other cores may be running threads that use this address space,
+                // so the page can only be reused once none of them can reach it
+                unsafe { asm!("dsb ishst", "tlbi vmalle1is", "dsb ish", "isb") };
+                if !entry.is_device() {
+                    unsafe { page_allocator.free_page(page_at(entry.address())) };
+                    self.resident_pages -= 1;
+                }
+            }
+        }
+    }
+
+    /// Returns the physical address `virtual_address` is mapped to.
+    pub fn translate(&self, virtual_address: usize) -> Option<usize> {
+        let entry = self.page_entry(virtual_address)?;
+        Some(entry.address() as usize + virtual_address % PAGE_SIZE)
+    }
+
+    /// Calls `f` with the start, the length and the flags of each run of mapped pages
+    /// that have the same flags, from the lowest address up. Device memory is skipped.
+    pub fn for_each_region(&self, mut f: impl FnMut(usize, usize, PageFlags)) {
+        let mut run: Option<(usize, usize, PageFlags)> = None;
+        let base_table = unsafe { self.base_table.as_ref() };
+        let user_entries = USER_SPACE_START / LEVEL1_BLOCK_SIZE as usize..ENTRIES_PER_TABLE;
+
+        for index1 in user_entries {
+            let level1_entry = base_table.entries[index1];
+            if !level1_entry.is_valid() {
+                continue;
+            }
+            let level2_table = level1_entry.address() as *const TranslationTable;
+            for (index2, level2_entry) in unsafe { &(*level2_table).entries }.iter().enumerate() {
+                if !level2_entry.is_valid() {
+                    continue;
+                }
+                let level3_table = level2_entry.address() as *const TranslationTable;
+                for (index3, entry) in unsafe { &(*level3_table).entries }.iter().enumerate() {
+                    if !entry.is_valid() || entry.is_device() {
+                        continue;
+                    }
+                    let address = index1 * LEVEL1_BLOCK_SIZE as usize
+                        + index2 * LEVEL2_BLOCK_SIZE as usize
+                        + index3 * PAGE_SIZE;
+                    let flags = PageFlags {
+                        is_writable: entry.is_writable(),
+                        is_executable: entry.is_executable(),
+                    };
+                    match &mut run {
+                        Some((start, len, run_flags))
+                            if *start + *len == address && *run_flags == flags =>
+                        {
+                            *len += PAGE_SIZE
+                        }
+                        _ => {
+                            if let Some((start, len, flags)) =
+                                run.replace((address, PAGE_SIZE, flags))
+                            {
+                                f(start, len, flags);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if let Some((start, len, flags)) = run {
+            f(start, len, flags);
+        }
+    }
+
+    /// Copies `bytes` to the memory mapped at `virtual_address`. Nothing is
+    /// copied unless the whole range is mapped writable.
+    pub fn write(&self, virtual_address: usize, bytes: &[u8]) -> Result<(), MapError> {
+        self.check_range(virtual_address, bytes.len(), true)?;
+        self.copy_pages(
+            virtual_address,
+            bytes.len(),
+            |offset, physical, len| unsafe {
+                core::ptr::copy_nonoverlapping(bytes[offset..].as_ptr(), physical, len);
+            },
+        );
+        Ok(())
+    }
+
+    /// Fills `buffer` from the memory mapped at `virtual_address`.
+    pub fn read(&self, virtual_address: usize, buffer: &mut [u8]) -> Result<(), MapError> {
+        self.check_range(virtual_address, buffer.len(), false)?;
+        self.copy_pages(
+            virtual_address,
+            buffer.len(),
+            |offset, physical, len| unsafe {
+                core::ptr::copy_nonoverlapping(physical, buffer[offset..].as_mut_ptr(), len);
+            },
+        );
+        Ok(())
+    }
+
+    /// Makes the calling core use this address space.
+    ///
+    /// # Safety
+    /// The address space must not be dropped while any core uses it.
+    pub unsafe fn activate(&self) {
+        set_user_translation_table(self.base_table.as_ptr());
+    }
+
+    fn page_entry(&self, virtual_address: usize) -> Option<TranslationTableEntry> {
+        if !(USER_SPACE_START..USER_SPACE_END).contains(&virtual_address) {
+            return None;
+        }
+        let page_table = self.page_table_if_present(virtual_address)?;
+        let entry = unsafe { page_table.as_ref() }.entries[level3_index(virtual_address)];
+        entry.is_valid().then_some(entry)
+    }
+
+    /// Checks that every page of the range is mapped memory rather than device memory,
+    /// and that it is writable if `is_write` is set.
+    fn check_range(&self, start: usize, len: usize, is_write: bool) -> Result<(), MapError> {
+        let end = start.checked_add(len).ok_or(MapError::InvalidAddress)?;
+        let first_page = start / PAGE_SIZE * PAGE_SIZE;
+        for virtual_address in (first_page..end).step_by(PAGE_SIZE) {
+            match self.page_entry(virtual_address) {
+                Some(entry) if !entry.is_device() && (!is_write || entry.is_writable()) => {}
+                _ => return Err(MapError::InvalidAddress),
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls `copy` with the offset into the range, the physical address and
+    /// the length of each part of the mapped range that is within one page.
+    fn copy_pages(&self, start: usize, len: usize, mut copy: impl FnMut(usize, *mut u8, usize)) {
+        let mut offset = 0;
+        while offset < len {
+            let virtual_address = start + offset;
+            // physical memory is identity mapped in the kernel
+            let physical = self.translate(virtual_address).unwrap() as *mut u8;
+            let part_len = (PAGE_SIZE - virtual_address % PAGE_SIZE).min(len - offset);
+            copy(offset, physical, part_len);
+            offset += part_len;
+        }
+    }
+
+    fn page_table_if_present(&self, virtual_address: usize) -> Option<NonNull<TranslationTable>> {
+        let level1_entry =
+            unsafe { self.base_table.as_ref() }.entries[level1_index(virtual_address)];
+        if !level1_entry.is_valid() {
+            return None;
+        }
+        let level2_table = level1_entry.address() as *const TranslationTable;
+        let level2_entry = unsafe { (*level2_table).entries[level2_index(virtual_address)] };
+        if !level2_entry.is_valid() {
+            return None;
+        }
+        NonNull::new(level2_entry.address() as *mut TranslationTable)
+    }
+
+    /// Returns the level 3 table for `virtual_address`, allocating the missing tables.
+    fn page_table(
+        &mut self,
+        virtual_address: usize,
+    ) -> Result<NonNull<TranslationTable>, MapError> {
+        let level2_table = next_level_table(self.base_table, level1_index(virtual_address))?;
+        next_level_table(level2_table, level2_index(virtual_address))
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let mut page_allocator = PAGE_ALLOCATOR.lock();
+        let base_table = unsafe { self.base_table.as_ref() };
+        let user_entries = USER_SPACE_START / LEVEL1_BLOCK_SIZE as usize..ENTRIES_PER_TABLE;
+
+        for level1_entry in &base_table.entries[user_entries] {
+            if !level1_entry.is_valid() {
+                continue;
+            }
+            let level2_table = level1_entry.address() as *mut TranslationTable;
+            for level2_entry in unsafe { &(*level2_table).entries } {
+                if !level2_entry.is_valid() {
+                    continue;
+                }
+                let level3_table = level2_entry.address() as *mut TranslationTable;
+                for level3_entry in unsafe { &(*level3_table).entries } {
+                    if level3_entry.is_valid() && !level3_entry.is_device() {
+                        unsafe { page_allocator.free_page(page_at(level3_entry.address())) };
+                    }
+                }
+                unsafe { page_allocator.free_page(page_at(level2_entry.address())) };
+            }
+            unsafe { page_allocator.free_page(page_at(level1_entry.address())) };
+        }
+        unsafe { page_allocator.free_page(self.base_table.cast()) };
+    }
+}
+
+/// Makes the calling core use the kernel's own tables, for example
+/// before the address space it was using is dropped.
+pub fn activate_kernel_address_space() {
+    unsafe { set_user_translation_table(BASE_TRANSLATION_TABLE.lock()) };
+}
+
+unsafe fn set_user_translation_table(table: *const TranslationTable) {
+    asm!("msr ttbr0_el1, {}", in(reg) table);
+    // the entries of the previous address space may still be cached
+    asm!("dsb ishst", "tlbi vmalle1", "dsb ish", "isb");
+}
+
+/// Returns the table the entry at `index` of `table` points to,
+/// or allocates an empty one and points the entry to it.
+fn next_level_table(
+    table: NonNull<TranslationTable>,
+    index: usize,
+) -> Result<NonNull<TranslationTable>, MapError> {
+    let table = unsafe { &mut *table.as_ptr() };
+    let entry = table.entries[index];
+    if entry.is_valid() {
+        return Ok(page_at(entry.address()).cast());
+    }
+    let next_table = PAGE_ALLOCATOR
+        .lock()
+        .alloc_zeroed_page(1)
+        .ok_or(MapError::OutOfMemory)?
+        .cast::<TranslationTable>();
+    table.set_entry(
+        index,
+        TranslationTableEntry::table_descriptor(next_table.as_ptr()),
+    );
+    Ok(next_table)
+}
+
+fn page_at(address: u64) -> NonNull<Page> {
+    NonNull::new(address as *mut Page).unwrap()
+}
+
+fn level1_index(virtual_address: usize) -> usize {
+    virtual_address / LEVEL1_BLOCK_SIZE as usize % ENTRIES_PER_TABLE
+}
+
+fn level2_index(virtual_address: usize) -> usize {
+    virtual_address / LEVEL2_BLOCK_SIZE as usize % ENTRIES_PER_TABLE
+}
+
+fn level3_index(virtual_address: usize) -> usize {
+    virtual_address / PAGE_SIZE % ENTRIES_PER_TABLE
+}
@@ -3,6 +3,7 @@ use core::cell::UnsafeCell;
 
 use crate::nolock::NoLock;
 
+pub mod addressspace;
 pub mod pageallocator;
 
 global_asm!(include_str!("memcpy.s"));
@@ -14,11 +15,48 @@ extern "C" {
 }
 
 static BASE_TRANSLATION_TABLE: NoLock<TranslationTable> = NoLock::new(TranslationTable::empty());
+// maps the first gigabyte of physical memory in 2 MiB blocks
+static LOW_MEMORY_TRANSLATION_TABLE: NoLock<TranslationTable> =
+    NoLock::new(TranslationTable::empty());
+
+// the range of virtual addresses available to user processes
+pub const USER_SPACE_START: usize = 0x1_0000_0000;
+pub const USER_SPACE_END: usize = 1 << 36;
 
 const ACCESS_FLAG_BIT: u64 = 1 << 10;
 const VALID_ENTRY_BIT: u64 = 0b1;
+const TABLE_DESCRIPTOR_BIT: u64 = 0b10;
+// level 3 entries use the table bit to mark pages
+const PAGE_DESCRIPTOR_BIT: u64 = 0b10;
+// the caches of all cores are kept coherent for inner shareable memory
+const INNER_SHAREABLE_BITS: u64 = 0b11 << 8;
+// access permissions: el0 can access the memory, and it is read only for both el0 and el1
+const USER_ACCESS_BIT: u64 = 1 << 6;
+const READ_ONLY_BIT: u64 = 1 << 7;
+const PRIVILEGED_EXECUTE_NEVER_BIT: u64 = 1 << 53;
+const USER_EXECUTE_NEVER_BIT: u64 = 1 << 54;
+// ignored by the mmu, marks user pages of device memory that are not owned by the address space
+const DEVICE_PAGE_BIT: u64 = 1 << 55;
 const TRANSLATION_TABLE_ADDRESS_MASK: u64 = 0x0000_ffff_ffff_f000;
 
+// indices into the memory attributes in mair_el1
+const ATTRIBUTE_INDEX_SHIFT: u64 = 2;
+const NORMAL_MEMORY_ATTRIBUTE_INDEX: u64 = 0;
+const DEVICE_MEMORY_ATTRIBUTE_INDEX: u64 = 1;
+// write-back cacheable normal memory and device-nGnRnE memory
+const MEMORY_ATTRIBUTES: u64 = 0x00_ff;
+
+const LEVEL2_BLOCK_SIZE: u64 = 0x20_0000;
+const LEVEL1_BLOCK_SIZE: u64 = 0x4000_0000;
+// the peripherals of the BCM2835 and the BCM2836 local peripherals
+const PERIPHERAL_START: u64 = 0x3f00_0000;
+const LOCAL_PERIPHERAL_START: u64 = 0x4000_0000;
+const LOCAL_PERIPHERAL_END: u64 = 0x4004_0000;
+
+// the physical range that processes may map as device memory
+pub const DEVICE_MEMORY_START: usize = PERIPHERAL_START as usize;
+pub const DEVICE_MEMORY_END: usize = LOCAL_PERIPHERAL_END as usize;
+
 #[repr(C, align(4096))]
 struct TranslationTable {
     entries: [TranslationTableEntry; 512],
@@ -38,9 +76,94 @@ impl TranslationTableEntry {
     const fn block_descriptor(address: u64) -> TranslationTableEntry {
         let masked_address = address & TRANSLATION_TABLE_ADDRESS_MASK;
         TranslationTableEntry {
-            value: masked_address | ACCESS_FLAG_BIT | VALID_ENTRY_BIT,
+            value: masked_address
+                | USER_EXECUTE_NEVER_BIT
+                | INNER_SHAREABLE_BITS
+                | (NORMAL_MEMORY_ATTRIBUTE_INDEX << ATTRIBUTE_INDEX_SHIFT)
+                | ACCESS_FLAG_BIT
+                | VALID_ENTRY_BIT,
         }
     }
+
+    const fn device_block_descriptor(address: u64) -> TranslationTableEntry {
+        let masked_address = address & TRANSLATION_TABLE_ADDRESS_MASK;
+        TranslationTableEntry {
+            value: masked_address
+                | USER_EXECUTE_NEVER_BIT
+                | PRIVILEGED_EXECUTE_NEVER_BIT
+                | (DEVICE_MEMORY_ATTRIBUTE_INDEX << ATTRIBUTE_INDEX_SHIFT)
+                | ACCESS_FLAG_BIT
+                | VALID_ENTRY_BIT,
+        }
+    }
+
+    fn table_descriptor(table: *const TranslationTable) -> TranslationTableEntry {
+        let masked_address = table as u64 & TRANSLATION_TABLE_ADDRESS_MASK;
+        TranslationTableEntry {
+            value: masked_address | TABLE_DESCRIPTOR_BIT | VALID_ENTRY_BIT,
+        }
+    }
+
+    /// A level 3 entry mapping a page of user memory.
+    /// The kernel can never execute user memory.
+    fn page_descriptor(address: u64, flags: addressspace::PageFlags) -> TranslationTableEntry {
+        let masked_address = address & TRANSLATION_TABLE_ADDRESS_MASK;
+        let mut value = masked_address
+            | PRIVILEGED_EXECUTE_NEVER_BIT
+            | INNER_SHAREABLE_BITS
+            | USER_ACCESS_BIT
+            | (NORMAL_MEMORY_ATTRIBUTE_INDEX << ATTRIBUTE_INDEX_SHIFT)
+            | ACCESS_FLAG_BIT
+            | PAGE_DESCRIPTOR_BIT
+            | VALID_ENTRY_BIT;
+        if !flags.is_writable {
+            value |= READ_ONLY_BIT;
+        }
+        if !flags.is_executable {
+            value |= USER_EXECUTE_NEVER_BIT;
+        }
+        TranslationTableEntry { value }
+    }
+
+    /// A level 3 entry mapping a page of device memory into user space.
+    fn device_page_descriptor(address: u64) -> TranslationTableEntry {
+        let masked_address = address & TRANSLATION_TABLE_ADDRESS_MASK;
+        TranslationTableEntry {
+            value: masked_address
+                | DEVICE_PAGE_BIT
+                | USER_EXECUTE_NEVER_BIT
+                | PRIVILEGED_EXECUTE_NEVER_BIT
+                | USER_ACCESS_BIT
+                | (DEVICE_MEMORY_ATTRIBUTE_INDEX << ATTRIBUTE_INDEX_SHIFT)
+                | ACCESS_FLAG_BIT
+                | PAGE_DESCRIPTOR_BIT
+                | VALID_ENTRY_BIT,
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        self.value & VALID_ENTRY_BIT != 0
+    }
+
+    /// Returns false for pages that are read only.
+    fn is_writable(&self) -> bool {
+        self.value & READ_ONLY_BIT == 0
+    }
+
+    /// Returns false for pages that user code can't execute.
+    fn is_executable(&self) -> bool {
+        self.value & USER_EXECUTE_NEVER_BIT == 0
+    }
+
+    /// Returns true for user pages of device memory, which are not freed when unmapped.
+    fn is_device(&self) -> bool {
+        self.value & DEVICE_PAGE_BIT != 0
+    }
+
+    /// The address of the next level table or of the mapped memory.
+    fn address(&self) -> u64 {
+        self.value & TRANSLATION_TABLE_ADDRESS_MASK
+    }
 }
 
 impl TranslationTable {
@@ -69,31 +192,59 @@ pub unsafe fn zero_bss() {
 }
 
 pub unsafe fn initialize_and_enable_mmu() {
+    // identity map the first gigabyte, with the peripherals as device memory
+    let low_memory_table = LOW_MEMORY_TRANSLATION_TABLE.lock();
+    for index in 0..512 {
+        let address = index as u64 * LEVEL2_BLOCK_SIZE;
+        let entry = if address < PERIPHERAL_START {
+            TranslationTableEntry::block_descriptor(address)
+        } else {
+            TranslationTableEntry::device_block_descriptor(address)
+        };
+        low_memory_table.set_entry(index, entry);
+    }
+    BASE_TRANSLATION_TABLE
+        .lock()
+        .set_entry(0, TranslationTableEntry::table_descriptor(low_memory_table));
+
+    // the local peripherals only take up the start of the second gigabyte
+    BASE_TRANSLATION_TABLE.lock().set_entry(
+        (LOCAL_PERIPHERAL_START / LEVEL1_BLOCK_SIZE) as usize,
+        TranslationTableEntry::device_block_descriptor(LOCAL_PERIPHERAL_START),
+    );
+
+    enable_mmu();
+
+    crate::println!("kernel_readonly_end: {:?}", _kernel_readonly_end.get());
+    crate::println!("bss_start: {:?}", _bss_start.get());
+    crate::println!("bss_end: {:?}", _bss_end.get());
+
+    crate::println!("{:#?}", pageallocator::PAGE_ALLOCATOR);
+}
+
+/// Enables the mmu of a secondary core with the tables set up by the boot core.
+///
+/// # Safety
+/// Must be called once on each secondary core after `initialize_and_enable_mmu`.
+pub unsafe fn enable_mmu_on_secondary_core() {
+    enable_mmu();
+}
+
+unsafe fn enable_mmu() {
     let base_table_pointer = BASE_TRANSLATION_TABLE.lock();
     asm!("msr ttbr0_el1, {}", in(reg) base_table_pointer);
 
-    let memory_attributes: u64 = 0xff;
-    asm!("msr mair_el1, {}", in(reg) memory_attributes);
+    asm!("msr mair_el1, {}", in(reg) MEMORY_ATTRIBUTES);
 
     // disables ttbr1_el1 if set
     const EDB1_BIT: u64 = 1 << 23;
     let control_value = EDB1_BIT | 28 | (1 << 8) | (1 << 10) | (3 << 12);
     asm!("msr tcr_el1, {}", in(reg) control_value);
 
-    let address = 0x0;
-    BASE_TRANSLATION_TABLE
-        .lock()
-        .set_entry(0, TranslationTableEntry::block_descriptor(address));
-
     let system_control_value: u64 = 0b101 | (1 << 12);
     asm!("dsb sy");
     asm!("msr sctlr_el1, {}", in(reg) system_control_value);
-
-    crate::println!("kernel_readonly_end: {:?}", _kernel_readonly_end.get());
-    crate::println!("bss_start: {:?}", _bss_start.get());
-    crate::println!("bss_end: {:?}", _bss_end.get());
-
-    crate::println!("{:#?}", pageallocator::PAGE_ALLOCATOR);
+    asm!("isb");
 }
 
 pub fn _test() {
@@ -5,11 +5,12 @@ use spin::mutex::spin::SpinMutex;
 
 use super::_bss_end;
 
-const PAGE_SIZE: usize = 4096;
+pub const PAGE_SIZE: usize = 4096;
 
 // DEFINITIONS:
 //
 // free page = page that is part of a linked list and not used by a process
+// free run = several contiguous free pages, the first of which is part of a linked list
 // unused page = possibly uninitialized page, usually past all free pages
 
 const MAX_PAGE_ADDRESS: usize = 0x3e00_0000;
@@ -17,6 +18,7 @@ const MAX_PAGE_ADDRESS: usize = 0x3e00_0000;
 pub static PAGE_ALLOCATOR: SpinMutex<PageAllocator> = SpinMutex::new(PageAllocator {
     max_page_address: MAX_PAGE_ADDRESS,
     first_free_page: None,
+    first_free_run: None,
     // SAFETY: _bss_end should be 4096 byte aligned by the linker script and non-null
     first_unused_page: Some(unsafe { NonNull::new_unchecked(_bss_end.get().cast()) }),
 });
@@ -27,6 +29,7 @@ pub static PAGE_ALLOCATOR: SpinMutex<PageAllocator> = SpinMutex::new(PageAllocat
 pub struct PageAllocator {
     max_page_address: usize,
     first_free_page: Option<NonNull<FreePage>>,
+    first_free_run: Option<NonNull<FreePage>>,
     // SAFETY: invariant: all pages in range `first_unused_page` .. `max_page_address` must be unused
     first_unused_page: Option<NonNull<MaybeUninit<FreePage>>>,
 }
@@ -34,24 +37,47 @@ pub struct PageAllocator {
 unsafe impl Send for PageAllocator {}
 
 impl PageAllocator {
+    /// Allocates `count` physically contiguous pages. The contents are not zeroed.
+    ///
+    /// Single pages are reused from the freed pages, and runs of pages from the
+    /// first freed run that is long enough, before any unused pages are taken.
+    /// Freed runs are not merged, so single pages never become part of a run.
     pub fn alloc_page(&mut self, count: usize) -> Option<NonNull<Page>> {
-        // TODO: comment and maybe zero page and return type
-        if let Some(first_free_page_ptr) = self.first_free_page {
-            let maybe_next_free_page = unsafe { first_free_page_ptr.as_ref().next_free_page };
-            self.first_free_page = maybe_next_free_page;
-            Some(first_free_page_ptr.cast())
-        } else if let Some(first_unused_page_ptr) = self.first_unused_page {
-            self.first_unused_page = if (first_unused_page_ptr.as_ptr() as usize + PAGE_SIZE)
-                < self.max_page_address
-            {
-                Some(unsafe { NonNull::new_unchecked(first_unused_page_ptr.as_ptr().offset(1)) })
-            } else {
-                None
-            };
-            Some(first_unused_page_ptr.cast())
+        if count == 0 {
+            return None;
+        }
+
+        if count == 1 {
+            if let Some(first_free_page_ptr) = self.first_free_page {
+                let maybe_next_free_page = unsafe { first_free_page_ptr.as_ref().next_free_page };
+                self.first_free_page = maybe_next_free_page;
+                return Some(first_free_page_ptr.cast());
+            }
+        }
+
+        if let Some(run) = self.alloc_from_free_runs(count) {
+            return Some(run);
+        }
+
+        let first_unused_page_ptr = self.first_unused_page?;
+        let end_address =
+            (first_unused_page_ptr.as_ptr() as usize).checked_add(count * PAGE_SIZE)?;
+        if end_address > self.max_page_address {
+            return None;
+        }
+        self.first_unused_page = if end_address < self.max_page_address {
+            Some(unsafe { NonNull::new_unchecked(first_unused_page_ptr.as_ptr().add(count)) })
         } else {
             None
-        }
+        };
+        Some(first_unused_page_ptr.cast())
+    }
+
+    /// Allocates `count` physically contiguous pages filled with zeros.
+    pub fn alloc_zeroed_page(&mut self, count: usize) -> Option<NonNull<Page>> {
+        let page = self.alloc_page(count)?;
+        unsafe { page.as_ptr().cast::<u8>().write_bytes(0, count * PAGE_SIZE) };
+        Some(page)
     }
 
     /// # Safety:
@@ -61,14 +87,60 @@ impl PageAllocator {
         let mut freed_page_ptr = ptr.cast::<FreePage>();
         freed_page_ptr.as_uninit_mut().write(FreePage {
             next_free_page: self.first_free_page,
+            num_pages: 1,
         });
         self.first_free_page = Some(freed_page_ptr);
     }
+
+    /// # Safety:
+    /// - `ptr` must point to the start of `count` pages allocated with one call to `alloc_page`
+    /// - the pages must be owned by the caller and not be freed twice
+    pub unsafe fn free_pages(&mut self, ptr: NonNull<Page>, count: usize) {
+        if count == 1 {
+            self.free_page(ptr);
+            return;
+        }
+        let freed_run_ptr = ptr.cast::<FreePage>();
+        freed_run_ptr.as_uninit_mut().write(FreePage {
+            next_free_page: self.first_free_run,
+            num_pages: count,
+        });
+        self.first_free_run = Some(freed_run_ptr);
+    }
+
+    /// Takes `count` pages from the end of the first free run that has at least that
+    /// many, so that the rest of the run stays where it is in the list.
+    fn alloc_from_free_runs(&mut self, count: usize) -> Option<NonNull<Page>> {
+        let mut previous: Option<NonNull<FreePage>> = None;
+        let mut maybe_run = self.first_free_run;
+        while let Some(run_ptr) = maybe_run {
+            let run = unsafe { &mut *run_ptr.as_ptr() };
+            if run.num_pages > count {
+                run.num_pages -= count;
+                let pages = unsafe { run_ptr.as_ptr().add(run.num_pages) };
+                return Some(unsafe { NonNull::new_unchecked(pages) }.cast());
+            }
+            if run.num_pages == count {
+                match previous {
+                    Some(previous) => unsafe {
+                        (*previous.as_ptr()).next_free_page = run.next_free_page
+                    },
+                    None => self.first_free_run = run.next_free_page,
+                }
+                return Some(run_ptr.cast());
+            }
+            previous = maybe_run;
+            maybe_run = run.next_free_page;
+        }
+        None
+    }
 }
 
 #[repr(C, align(4096))]
 struct FreePage {
     next_free_page: Option<NonNull<FreePage>>,
+    // the number of pages in the run that starts with this page, 1 for single free pages
+    num_pages: usize,
 }
 
 #[repr(C, align(4096))]
@@ -0,0 +1,217 @@
+//! Pipes, byte streams from one set of handles to another.
+//!
+//! A pipe is a ring buffer in the kernel with a reading end and a writing end.
+//! Reading from an empty pipe blocks until something is written, or returns
+//! end of file once every writing handle is closed. Writing to a full pipe
+//! blocks until something is read, and writing to a pipe without readers
+//! fails and sends `SIGPIPE` to the writer.
+
+use spin::mutex::spin::SpinMutex;
+
+use crate::cpu::without_interrupts;
+use crate::handle::{Handle, HandleError, HandleTable};
+use crate::process;
+use crate::signal::Signal;
+use crate::sync::WaitQueue;
+
+pub const PIPE_CAPACITY: usize = 4096;
+const MAX_NUM_PIPES: usize = 16;
+
+static PIPES: SpinMutex<[Pipe; MAX_NUM_PIPES]> =
+    SpinMutex::new([const { Pipe::new() }; MAX_NUM_PIPES]);
+
+#[derive(Debug)]
+pub enum PipeError {
+    /// The caller is a kernel thread or the kernel itself rather than a process.
+    NotAProcess,
+    /// There is no such handle, or it is not the right end of a pipe.
+    InvalidHandle,
+    /// The handle table of the process is full.
+    TooManyHandles,
+    /// Every pipe is in use.
+    TooManyPipes,
+    /// The reading end of the pipe is closed.
+    BrokenPipe,
+    /// The caller was blocked and has to try again once it runs.
+    WouldBlock,
+}
+
+struct Pipe {
+    // the handles to each end, including ones in messages, both 0 if the pipe is free
+    readers: usize,
+    writers: usize,
+    buffer: [u8; PIPE_CAPACITY],
+    head: usize,
+    len: usize,
+    // readers wait for something to be written, and writers for room
+    blocked_readers: WaitQueue,
+    blocked_writers: WaitQueue,
+}
+
+impl Pipe {
+    const fn new() -> Pipe {
+        Pipe {
+            readers: 0,
+            writers: 0,
+            buffer: [0; PIPE_CAPACITY],
+            head: 0,
+            len: 0,
+            blocked_readers: WaitQueue::new(),
+            blocked_writers: WaitQueue::new(),
+        }
+    }
+
+    fn is_free(&self) -> bool {
+        self.readers == 0 && self.writers == 0
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> usize {
+        let len = self.len.min(buffer.len());
+        for (i, byte) in buffer[..len].iter_mut().enumerate() {
+            *byte = self.buffer[(self.head + i) % PIPE_CAPACITY];
+        }
+        self.head = (self.head + len) % PIPE_CAPACITY;
+        self.len -= len;
+        self.blocked_writers.wake_all();
+        len
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> usize {
+        let len = bytes.len().min(PIPE_CAPACITY - self.len);
+        for (i, &byte) in bytes[..len].iter().enumerate() {
+            self.buffer[(self.head + self.len + i) % PIPE_CAPACITY] = byte;
+        }
+        self.len += len;
+        self.blocked_readers.wake_all();
+        len
+    }
+}
+
+impl From<HandleError> for PipeError {
+    fn from(error: HandleError) -> PipeError {
+        match error {
+            HandleError::NotAProcess => PipeError::NotAProcess,
+            HandleError::InvalidHandle => PipeError::InvalidHandle,
+            HandleError::TooManyHandles => PipeError::TooManyHandles,
+        }
+    }
+}
+
+/// Creates a pipe, and returns the handles to its reading and writing ends.
+pub fn create() -> Result<(usize, usize), PipeError> {
+    process::with_current_handles(|handles| {
+        if handles.free_slots() < 2 {
+            return Err(PipeError::TooManyHandles);
+        }
+        let mut pipes = PIPES.lock();
+        let index = pipes
+            .iter()
+            .position(Pipe::is_free)
+            .ok_or(PipeError::TooManyPipes)?;
+        let pipe = &mut pipes[index];
+        pipe.readers = 1;
+        pipe.writers = 1;
+        pipe.head = 0;
+        pipe.len = 0;
+        drop(pipes);
+
+        let reader = handles.insert(Handle::PipeReader { index })?;
+        let writer = handles.insert(Handle::PipeWriter { index })?;
+        Ok((reader, writer))
+    })
+    .ok_or(PipeError::NotAProcess)?
+}
+
+/// Reads as much as is in the pipe of the handle `index` and fits in `buffer`, and
+/// returns the number of bytes read, which is 0 at the end of file. If the pipe is
+/// empty, the current thread is blocked until something is written to it.
+pub fn read(index: usize, buffer: &mut [u8]) -> Result<usize, PipeError> {
+    process::with_current_handles(|handles| {
+        let mut pipes = PIPES.lock();
+        let pipe = &mut pipes[pipe_index(handles, index, false)?];
+        if pipe.len == 0 && pipe.writers > 0 && !buffer.is_empty() {
+            return Err(block_on(&pipe.blocked_readers));
+        }
+        Ok(pipe.read(buffer))
+    })
+    .ok_or(PipeError::NotAProcess)?
+}
+
+/// Writes `bytes` to the pipe of the handle `index`, and returns the number of bytes
+/// written. Writes of up to `PIPE_CAPACITY` bytes are never split, so the current
+/// thread is blocked until there is room for all of them. Larger writes write what fits.
+pub fn write(index: usize, bytes: &[u8]) -> Result<usize, PipeError> {
+    let result = process::with_current_handles(|handles| {
+        let mut pipes = PIPES.lock();
+        let pipe = &mut pipes[pipe_index(handles, index, true)?];
+        if pipe.readers == 0 {
+            return Err(PipeError::BrokenPipe);
+        }
+        let room = PIPE_CAPACITY - pipe.len;
+        if room < bytes.len().min(PIPE_CAPACITY) {
+            return Err(block_on(&pipe.blocked_writers));
+        }
+        Ok(pipe.write(bytes))
+    })
+    .ok_or(PipeError::NotAProcess)?;
+
+    if let Err(PipeError::BrokenPipe) = result {
+        let _ = process::send_signal(process::current_pid(), Some(Signal::SIGPIPE));
+    }
+    result
+}
+
+/// Adds a reference to an end of a pipe, for a copy of a handle to it.
+pub(crate) fn acquire(handle: Handle) {
+    without_interrupts(|| {
+        let mut pipes = PIPES.lock();
+        match handle {
+            Handle::PipeReader { index } => pipes[index].readers += 1,
+            Handle::PipeWriter { index } => pipes[index].writers += 1,
+            _ => unreachable!(),
+        }
+    });
+}
+
+/// Drops the reference of a handle to an end of a pipe. Closing the last
+/// handle to an end wakes the threads blocked on the other end.
+pub(crate) fn release(handle: Handle) {
+    without_interrupts(|| {
+        let mut pipes = PIPES.lock();
+        match handle {
+            Handle::PipeReader { index } => {
+                let pipe = &mut pipes[index];
+                pipe.readers -= 1;
+                if pipe.readers == 0 {
+                    pipe.blocked_writers.wake_all();
+                }
+            }
+            Handle::PipeWriter { index } => {
+                let pipe = &mut pipes[index];
+                pipe.writers -= 1;
+                if pipe.writers == 0 {
+                    pipe.blocked_readers.wake_all();
+                }
+            }
+            _ => unreachable!(),
+        }
+    });
+}
+
+/// Returns the index of the pipe of the handle `index` if it is the reading
+/// end, or the writing end if `is_writer` is set.
+fn pipe_index(handles: &HandleTable, index: usize, is_writer: bool) -> Result<usize, PipeError> {
+    match (handles.get(index)?, is_writer) {
+        (Handle::PipeReader { index }, false) | (Handle::PipeWriter { index }, true) => Ok(index),
+        _ => Err(PipeError::InvalidHandle),
+    }
+}
+
+/// Blocks the current thread in `queue` until the pipe wakes it,
+/// and returns the error that makes the caller try again.
+fn block_on(queue: &WaitQueue) -> PipeError {
+    match queue.block_current() {
+        true => PipeError::WouldBlock,
+        false => PipeError::NotAProcess,
+    }
+}
@@ -1,42 +1,1212 @@
+use core::mem::size_of;
+use core::ptr::NonNull;
+
 use spin::mutex::spin::SpinMutex;
 
-use core::ptr::NonNull;
+use crate::arguments::{self, Arguments};
+use crate::cpu::without_interrupts;
+use crate::elf::coredump::{self, CoreStatus};
+use crate::elf::section::{SymbolTableCopy, Symbols};
+use crate::elf::{self, ElfLoadError, LoadedImage, TlsTemplate};
+use crate::exceptions::ExceptionFrame;
+use crate::futex;
+use crate::handle::HandleTable;
+use crate::ipc;
+use crate::memory::addressspace::{
+    activate_kernel_address_space, AddressSpace, MapError, PageFlags,
+};
+use crate::memory::pageallocator::{Page, PAGE_ALLOCATOR, PAGE_SIZE};
+use crate::memory::{DEVICE_MEMORY_END, DEVICE_MEMORY_START, USER_SPACE_END, USER_SPACE_START};
+use crate::resource::{self, CpuTime, Limit, LimitError, Limits, Resource, Usage};
+use crate::scheduler;
+use crate::serial;
+use crate::signal::{Signal, SignalState};
+use crate::sync::{Semaphore, WaitQueue};
+use crate::thread::{self, Thread, ThreadState, ThreadTable};
+use crate::timer;
+
+pub const MAX_NUM_PROCESSES: usize = 256;
+/// The process that is given the children of exited processes.
+pub const INIT_PID: usize = 1;
+/// Each thread of a process has its own user stack, and there is room for this many.
+pub const MAX_THREADS_PER_PROCESS: usize = 64;
+
+const USER_STACK_SIZE: usize = 16 * PAGE_SIZE;
+// the thread pointer points to this control block, which is followed by the tls block
+const TLS_CONTROL_BLOCK_SIZE: usize = 16;
+// an unmapped page between the stacks catches overflows
+const USER_STACK_STRIDE: usize = USER_STACK_SIZE + PAGE_SIZE;
+// device memory is mapped at this offset from its physical address,
+// between the program and the stacks
+const DEVICE_MAPPING_START: usize = USER_SPACE_END / 2;
+const MAX_QUEUED_CORE_DUMPS: usize = 4;
+
+const _: () = assert!(size_of::<Process>() <= PAGE_SIZE);
+const _: () = assert!(MAX_THREADS_PER_PROCESS <= u64::BITS as usize);
+
+/// Every process by pid, and every thread by tid. Pid 0 is the kernel itself,
+/// which has no entry. Both are behind one lock, since threads and their
+/// process often change together.
+static PROCESSES: SpinMutex<ProcessTable> = SpinMutex::new(ProcessTable {
+    processes: [None; MAX_NUM_PROCESSES],
+    threads: ThreadTable::new(),
+    next_pid: INIT_PID,
+});
+
+// the dumps of ended processes, written by the core dump thread
+static CORE_DUMPS: SpinMutex<[Option<CoreDump>; MAX_QUEUED_CORE_DUMPS]> =
+    SpinMutex::new([NO_CORE_DUMP; MAX_QUEUED_CORE_DUMPS]);
+static QUEUED_CORE_DUMPS: Semaphore = Semaphore::new(0);
+
+const NO_CORE_DUMP: Option<CoreDump> = None;
+
+/// What a process is allowed to do to other processes and the system.
+/// A process gets at most the permissions of its parent, and can only give them up.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Permissions(u32);
+
+impl Permissions {
+    pub const NONE: Permissions = Permissions(0);
+    /// Raising the scheduling priority of a process, including its own.
+    pub const SET_PRIORITY: Permissions = Permissions(1 << 0);
+    /// Sending signals to processes other than itself and its children.
+    pub const KILL: Permissions = Permissions(1 << 1);
+    /// Mapping the memory of the peripherals into the address space of the process.
+    pub const MAP_DEVICE: Permissions = Permissions(1 << 2);
+    /// Changing clocks, power and voltages through the mailbox.
+    pub const CONFIGURE_HARDWARE: Permissions = Permissions(1 << 3);
+    /// Raising the hard limits on the resources of the process.
+    pub const SET_LIMITS: Permissions = Permissions(1 << 4);
+    pub const ALL: Permissions = Permissions(
+        Permissions::SET_PRIORITY.0
+            | Permissions::KILL.0
+            | Permissions::MAP_DEVICE.0
+            | Permissions::CONFIGURE_HARDWARE.0
+            | Permissions::SET_LIMITS.0,
+    );
+
+    /// The permissions in the bits of `bits`. Bits that are not permissions are dropped.
+    pub fn from_bits(bits: u64) -> Permissions {
+        Permissions(bits as u32 & Permissions::ALL.0)
+    }
 
-use crate::memory::pageallocator::{Page, PAGE_ALLOCATOR};
+    pub fn bits(self) -> u64 {
+        self.0 as u64
+    }
+
+    pub fn contains(self, other: Permissions) -> bool {
+        self.0 & other.0 == other.0
+    }
 
-const MAX_NUM_PROCESSES: usize = 256;
+    pub fn intersection(self, other: Permissions) -> Permissions {
+        Permissions(self.0 & other.0)
+    }
+
+    pub fn difference(self, other: Permissions) -> Permissions {
+        Permissions(self.0 & !other.0)
+    }
+}
 
+// the payloads are only read through `Debug`
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum ProcessError {
+    OutOfMemory,
+    /// Every pid is in use.
+    TooManyProcesses,
+    /// Every tid, or every user stack of the process, is in use.
+    TooManyThreads,
+    /// The parent already has as many children as its limit allows.
+    TooManyChildren,
+    InvalidImage(ElfLoadError),
+    /// The caller is a kernel thread or the kernel itself rather than a process.
+    NotAProcess,
+    /// The other threads of the process are still on other cores, so the caller
+    /// was blocked until they are released and has to try again once it runs.
+    WouldBlock,
+    /// The caller was ended by another thread of its process in the meantime.
+    Killed,
+}
+
+impl From<ElfLoadError> for ProcessError {
+    fn from(error: ElfLoadError) -> ProcessError {
+        match error {
+            ElfLoadError::Map(MapError::OutOfMemory | MapError::LimitExceeded) => {
+                ProcessError::OutOfMemory
+            }
+            error => ProcessError::InvalidImage(error),
+        }
+    }
+}
+
+/// The status of a child collected with `wait`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ExitedChild {
+    pub pid: usize,
+    pub status: i32,
+}
+
+#[derive(Debug)]
+pub enum WaitError {
+    /// The caller has no children matching the pid.
+    NoChildren,
+    /// No matching child has exited yet, so the caller was blocked
+    /// until one does and has to wait again once it runs.
+    WouldBlock,
+}
+
+#[derive(Debug)]
+pub enum SendSignalError {
+    NoSuchProcess,
+    /// The caller may not signal the process.
+    NotPermitted,
+}
+
+#[derive(Debug)]
+pub enum JoinError {
+    /// There is no such thread in the process of the caller.
+    NoSuchThread,
+    /// The thread is the caller itself, detached, or already being joined.
+    NotJoinable,
+    /// The thread has not exited yet, so the caller was blocked
+    /// until it does and has to join again once it runs.
+    WouldBlock,
+}
+
+/// An address space and the threads running in it.
 #[repr(C, align(4096))]
-struct Process {
+pub struct Process {
+    pid: usize,
     owning_process: Option<usize>,
-    saved_register_state: SpinMutex<[u64; 31]>,
-    top_level_available_virtual_memory: NonNull<AvailableTopLevelVirtualMemory>,
+    permissions: Permissions,
+    // `None` once the process has exited
+    address_space: Option<AddressSpace>,
+    // bit `n` is set while a thread uses the stack in slot `n`
+    used_stack_slots: u64,
+    // the threads that have not been released yet
+    live_threads: usize,
+    // set by the first `exit`, or to 0 if the last thread exits on its own
+    exit_status: Option<i32>,
+    is_zombie: bool,
+    // the threads blocked in `wait` until a child exits
+    child_waiters: WaitQueue,
+    // the thread blocked in `exec` until the other threads are released
+    exec_waiters: WaitQueue,
+    signals: SignalState,
+    handles: HandleTable,
+    // where the program was loaded, and what it asked for
+    image: LoadedImage,
+    // for crash reports, if the program has symbols
+    symbols: Option<SymbolTableCopy>,
+    limits: Limits,
+    cpu_time: CpuTime,
+    // the cpu time of the children collected with `wait`, and of their collected children
+    children_cpu_time: CpuTime,
+    // set when a thread crashes, and queued to be written once the process ends
+    core_dump: Option<CoreDump>,
+}
+
+/// What a core dump is made of. The address space is only kept once the process
+/// has ended, since the dump is written while nothing else uses it.
+struct CoreDump {
+    address_space: Option<AddressSpace>,
+    image: LoadedImage,
+    frame: ExceptionFrame,
+    status: CoreStatus,
 }
 
-// static PROCESSES: SpinMutex<[Option<NonNull<Process>>; MAX_NUM_PROCESSES]> =
-//     SpinMutex::new([const { Option::<NonNull<Process>>::None }; MAX_NUM_PROCESSES]);
+struct ProcessTable {
+    processes: [Option<NonNull<Process>>; MAX_NUM_PROCESSES],
+    threads: ThreadTable,
+    // where the search for a free pid starts, so that pids are not reused right away
+    next_pid: usize,
+}
 
-impl Drop for Process {
-    fn drop(&mut self) {
-        todo!()
+unsafe impl Send for ProcessTable {}
+// the address space of a queued dump belongs to no process anymore
+unsafe impl Send for CoreDump {}
+
+impl ProcessTable {
+    fn allocate_pid(&mut self) -> Option<usize> {
+        let pid = (self.next_pid..MAX_NUM_PROCESSES)
+            .chain(INIT_PID..self.next_pid)
+            .find(|&pid| self.processes[pid].is_none())?;
+        self.next_pid = if pid + 1 < MAX_NUM_PROCESSES {
+            pid + 1
+        } else {
+            INIT_PID
+        };
+        Some(pid)
+    }
+
+    fn get(&self, pid: usize) -> Option<NonNull<Process>> {
+        self.processes.get(pid).copied().flatten()
+    }
+
+    fn children(&self, pid: usize) -> impl Iterator<Item = NonNull<Process>> + '_ {
+        self.processes
+            .iter()
+            .flatten()
+            .copied()
+            .filter(move |child| unsafe { child.as_ref() }.owning_process == Some(pid))
+    }
+
+    /// Fails if the process `pid` can't have another child.
+    fn check_children_limit(&self, pid: Option<usize>) -> Result<(), ProcessError> {
+        let process = match pid.and_then(|pid| self.get(pid)) {
+            Some(process) => process,
+            None => return Ok(()),
+        };
+        let limit = unsafe { process.as_ref() }.limits.get(Resource::Children);
+        if self.children(pid.unwrap()).count() >= limit.soft_count() {
+            return Err(ProcessError::TooManyChildren);
+        }
+        Ok(())
+    }
+
+    /// Frees a thread that has exited and that no core is on anymore.
+    /// The last thread of a process to be released ends the process.
+    ///
+    /// # Safety
+    /// `thread` must be a zombie in the table that is not on any core.
+    unsafe fn release_thread(&mut self, thread: NonNull<Thread>) {
+        futex::cancel(thread);
+        WaitQueue::cancel(thread);
+        let thread_ref = &mut *thread.as_ptr();
+        ipc::cancel_call(thread_ref.tid());
+        thread_ref.is_released = true;
+        thread_ref.exit_waiters.wake_all();
+
+        let process = match thread_ref.process() {
+            Some(process) => process,
+            None => {
+                self.threads.remove(thread);
+                return;
+            }
+        };
+        let process_ref = &mut *process.as_ptr();
+        if let Some(slot) = thread_ref.user_stack_slot.take() {
+            process_ref.used_stack_slots &= !(1 << slot);
+            // a crashed process keeps the stacks of its threads for its core dump
+            match &mut process_ref.address_space {
+                Some(address_space) if process_ref.core_dump.is_none() => {
+                    address_space.unmap(user_stack_top(slot) - USER_STACK_SIZE, USER_STACK_SIZE)
+                }
+                _ => {}
+            }
+        }
+        if thread_ref.is_detached {
+            self.threads.remove(thread);
+        }
+
+        process_ref.live_threads -= 1;
+        process_ref.exec_waiters.wake_all();
+        if process_ref.live_threads == 0 {
+            self.end_process(process);
+        }
+    }
+
+    /// Frees the address space and threads of a process whose threads have all
+    /// been released, and keeps the rest as a zombie until the parent collects
+    /// the status with `wait`. Children are handed over to init.
+    unsafe fn end_process(&mut self, process: NonNull<Process>) {
+        let process_ref = &mut *process.as_ptr();
+        let pid = process_ref.pid;
+        process_ref.exit_status.get_or_insert(0);
+        process_ref.is_zombie = true;
+        // no core uses the address space, since no core is on any of its threads,
+        // so it can be freed or handed to the core dump thread
+        let address_space = process_ref.address_space.take();
+        if let Some(core_dump) = process_ref.core_dump.take() {
+            queue_core_dump(CoreDump {
+                address_space,
+                ..core_dump
+            });
+        }
+        process_ref.symbols = None;
+        process_ref.handles.close_all();
+        loop {
+            let thread = self.threads.of_process(process).next();
+            match thread {
+                Some(thread) => self.threads.remove(thread),
+                None => break,
+            }
+        }
+
+        // orphans go to init, or nowhere if init itself exits
+        let new_owner = match pid {
+            INIT_PID => None,
+            _ => self.get(INIT_PID).map(|_| INIT_PID),
+        };
+        let mut has_zombie_orphans = false;
+        for index in 0..MAX_NUM_PROCESSES {
+            let child = match self.processes[index] {
+                Some(child) if child.as_ref().owning_process == Some(pid) => child,
+                _ => continue,
+            };
+            (*child.as_ptr()).owning_process = new_owner;
+            if child.as_ref().is_zombie {
+                match new_owner {
+                    Some(_) => has_zombie_orphans = true,
+                    None => {
+                        self.reap(child);
+                    }
+                }
+            }
+        }
+        if has_zombie_orphans {
+            self.wake_waiters(INIT_PID);
+        }
+
+        match process_ref.owning_process {
+            Some(parent) => {
+                self.signal(parent, Signal::SIGCHLD);
+                self.wake_waiters(parent);
+            }
+            None => {
+                self.reap(process);
+            }
+        }
+    }
+
+    /// Removes an ended process from the table, frees it, and returns its exit status.
+    ///
+    /// # Safety
+    /// `process` must be a zombie in the table.
+    unsafe fn reap(&mut self, process: NonNull<Process>) -> i32 {
+        let status = process.as_ref().exit_status.unwrap();
+        self.processes[process.as_ref().pid] = None;
+        process.as_ptr().drop_in_place();
+        PAGE_ALLOCATOR.lock().free_page(process.cast());
+        status
+    }
+
+    /// Makes `signal` pending in the process `pid`. If it can be delivered,
+    /// the blocked threads of the process are woken, so that one of them
+    /// handles it when its restarted syscall traps again.
+    fn signal(&mut self, pid: usize, signal: Signal) {
+        let process = match self.get(pid) {
+            Some(process) => process,
+            None => return,
+        };
+        let process_ref = unsafe { &mut *process.as_ptr() };
+        if process_ref.is_zombie || !process_ref.signals.send(signal) {
+            return;
+        }
+        for thread in self.threads.of_process(process) {
+            unsafe { scheduler::make_runnable(thread) };
+        }
+    }
+
+    /// Wakes the threads of the process `pid` that are blocked in `wait`.
+    fn wake_waiters(&self, pid: usize) {
+        if let Some(process) = self.get(pid) {
+            unsafe { process.as_ref() }.child_waiters.wake_all();
+        }
     }
 }
 
 impl Process {
-    pub fn create_independent() -> Process {
-        todo!()
+    /// Creates a process running `image` with `arguments`, one thread and `handles`,
+    /// and makes the thread runnable. The process gets the limits of its owner.
+    /// Returns the pid of the new process.
+    pub fn create_from_elf(
+        image: &[u8],
+        arguments: &Arguments,
+        owning_process: Option<usize>,
+        permissions: Permissions,
+        mut handles: HandleTable,
+    ) -> Result<usize, ProcessError> {
+        let limits = without_interrupts(|| {
+            let table = PROCESSES.lock();
+            owning_process
+                .and_then(|pid| table.get(pid))
+                .map_or_else(Limits::new, |owner| unsafe { owner.as_ref() }.limits)
+        });
+        let (address_space, loaded, initial_thread) = load_image(
+            image,
+            arguments,
+            limits.get(Resource::ResidentPages).soft_count(),
+        )?;
+        handles.set_limit(limits.get(Resource::Handles).soft_count());
+        let cpu_time = CpuTime::new();
+        cpu_time.set_limit(limits.get(Resource::CpuTime));
+        let page = PAGE_ALLOCATOR
+            .lock()
+            .alloc_page(1)
+            .ok_or(ProcessError::OutOfMemory)?
+            .cast::<Process>();
+
+        let process = Process {
+            pid: 0,
+            owning_process,
+            permissions,
+            address_space: Some(address_space),
+            used_stack_slots: 1,
+            live_threads: 1,
+            exit_status: None,
+            is_zombie: false,
+            child_waiters: WaitQueue::new(),
+            exec_waiters: WaitQueue::new(),
+            signals: SignalState::new(),
+            handles,
+            image: loaded,
+            symbols: SymbolTableCopy::new(image),
+            limits,
+            cpu_time,
+            children_cpu_time: CpuTime::new(),
+            core_dump: None,
+        };
+        let context = ExceptionFrame::new_user(loaded.entry, initial_thread.stack_pointer);
+        let thread = Thread::new(Some(page), Some(0), context, initial_thread.thread_pointer);
+        let thread = match thread {
+            Ok(thread) => thread,
+            Err(error) => {
+                unsafe { PAGE_ALLOCATOR.lock().free_page(page.cast()) };
+                return Err(error);
+            }
+        };
+
+        without_interrupts(|| {
+            let mut table = PROCESSES.lock();
+            let result = table
+                .check_children_limit(owning_process)
+                .and_then(|_| table.allocate_pid().ok_or(ProcessError::TooManyProcesses))
+                .and_then(|pid| Ok((pid, table.threads.insert(thread)?)));
+            let (pid, thread) = match result {
+                Ok(inserted) => inserted,
+                Err(error) => {
+                    // dropping the address space and thread frees them
+                    drop(process);
+                    unsafe { PAGE_ALLOCATOR.lock().free_page(page.cast()) };
+                    return Err(error);
+                }
+            };
+            unsafe {
+                page.as_ptr().write(Process { pid, ..process });
+                table.processes[pid] = Some(page);
+                scheduler::make_runnable(thread);
+            }
+            Ok(pid)
+        })
+    }
+
+    /// Makes the calling core use the address space of the process.
+    ///
+    /// # Safety
+    /// The process must not end while any core uses its address space.
+    pub unsafe fn activate_address_space(&self) {
+        match &self.address_space {
+            Some(address_space) => address_space.activate(),
+            None => activate_kernel_address_space(),
+        }
+    }
+}
+
+/// Runs `f` with the thread `tid`, which can't be freed until `f` returns.
+pub fn with_thread<T>(tid: usize, f: impl FnOnce(NonNull<Thread>) -> T) -> Option<T> {
+    without_interrupts(|| {
+        let table = PROCESSES.lock();
+        table.threads.get(tid).map(f)
+    })
+}
+
+/// Returns the pid of the process of the thread running on the calling core,
+/// or 0 for the kernel and kernel threads.
+pub fn current_pid() -> usize {
+    current_process().map_or(0, |process| unsafe { process.as_ref() }.pid)
+}
+
+/// Returns the physical address `virtual_address` is mapped to
+/// in the address space of the current process.
+pub fn translate_current(virtual_address: usize) -> Option<usize> {
+    let process = current_process()?;
+    without_interrupts(|| {
+        let _table = PROCESSES.lock();
+        let address_space = unsafe { process.as_ref() }.address_space.as_ref()?;
+        address_space.translate(virtual_address)
+    })
+}
+
+/// Copies `bytes` to `address` in the address space of the current process.
+/// Returns false, and copies nothing, if the range is not mapped writable.
+pub fn copy_to_current(address: usize, bytes: &[u8]) -> bool {
+    with_current_address_space(|address_space| address_space.write(address, bytes).is_ok())
+}
+
+/// Fills `buffer` from `address` in the address space of the current process.
+/// Returns false if the range is not mapped.
+pub fn copy_from_current(address: usize, buffer: &mut [u8]) -> bool {
+    with_current_address_space(|address_space| address_space.read(address, buffer).is_ok())
+}
+
+fn with_current_address_space(f: impl FnOnce(&AddressSpace) -> bool) -> bool {
+    let process = match current_process() {
+        Some(process) => process,
+        None => return false,
+    };
+    without_interrupts(|| {
+        let _table = PROCESSES.lock();
+        unsafe { process.as_ref() }
+            .address_space
+            .as_ref()
+            .is_some_and(f)
+    })
+}
+
+/// Runs `f` with the handles of the current process.
+/// Returns `None` if the current thread has no process.
+pub fn with_current_handles<T>(f: impl FnOnce(&mut HandleTable) -> T) -> Option<T> {
+    let process = current_process()?;
+    without_interrupts(|| {
+        let _table = PROCESSES.lock();
+        Some(f(unsafe { &mut (*process.as_ptr()).handles }))
+    })
+}
+
+/// Calls `f` with the symbols of the program of the current process,
+/// or returns `None` if it has none.
+pub fn with_current_symbols<T>(f: impl FnOnce(Symbols<'_>) -> T) -> Option<T> {
+    let process = current_process()?;
+    without_interrupts(|| {
+        let _table = PROCESSES.lock();
+        let symbols = unsafe { process.as_ref() }.symbols.as_ref()?;
+        Some(f(symbols.symbols()))
+    })
+}
+
+/// Makes the current process, which crashed with `signal` and the registers in `frame`,
+/// leave a core dump when it ends, unless the dump would be larger than the core size
+/// limit of the process. Returns the size of the dump, or `None` if there is none.
+pub fn request_core_dump(frame: &ExceptionFrame, signal: Signal) -> Option<usize> {
+    let process = current_process()?;
+    without_interrupts(|| {
+        let _table = PROCESSES.lock();
+        let process = unsafe { &mut *process.as_ptr() };
+        // only the first thread to crash is dumped
+        if process.core_dump.is_some() {
+            return None;
+        }
+        let size = coredump::core_size(process.address_space.as_ref()?);
+        if size as u64 > process.limits.get(Resource::CoreSize).soft {
+            return None;
+        }
+        let status = CoreStatus {
+            signal: signal.number(),
+            pid: process.pid as u32,
+            parent_pid: process.owning_process.unwrap_or(0) as u32,
+            user_time: timer::ticks_to_duration(process.cpu_time.user_ticks()),
+            system_time: timer::ticks_to_duration(process.cpu_time.system_ticks()),
+            children_user_time: timer::ticks_to_duration(process.children_cpu_time.user_ticks()),
+            children_system_time: timer::ticks_to_duration(
+                process.children_cpu_time.system_ticks(),
+            ),
+        };
+        process.core_dump = Some(CoreDump {
+            address_space: None,
+            image: process.image,
+            frame: frame.clone(),
+            status,
+        });
+        Some(size)
+    })
+}
+
+/// Starts the thread that writes the core dumps of ended processes to the serial port.
+pub fn start_core_dump_writer() -> Result<(), ProcessError> {
+    spawn_kernel_thread(write_core_dumps)?;
+    Ok(())
+}
+
+/// Returns true if the current process may have signals to deliver.
+pub fn has_pending_signals() -> bool {
+    current_process().is_some_and(|process| unsafe { process.as_ref() }.signals.has_pending())
+}
+
+/// Runs `f` with the signals of the current process.
+/// Returns `None` if the current thread has no process.
+pub fn with_current_signals<T>(f: impl FnOnce(&mut SignalState) -> T) -> Option<T> {
+    let process = current_process()?;
+    without_interrupts(|| {
+        let _table = PROCESSES.lock();
+        Some(f(unsafe { &mut (*process.as_ptr()).signals }))
+    })
+}
+
+/// Sends `signal` to the process `pid` on behalf of the current process, or
+/// only checks that it could if `signal` is `None`. A process may signal itself
+/// and its children, and others only with the `KILL` permission.
+pub fn send_signal(pid: usize, signal: Option<Signal>) -> Result<(), SendSignalError> {
+    let caller = current_process();
+    without_interrupts(|| {
+        let mut table = PROCESSES.lock();
+        let target = table.get(pid).ok_or(SendSignalError::NoSuchProcess)?;
+        // the kernel and kernel threads may signal anything
+        let is_permitted = caller.is_none_or(|caller| unsafe {
+            let caller = caller.as_ref();
+            caller.pid == pid
+                || target.as_ref().owning_process == Some(caller.pid)
+                || caller.permissions.contains(Permissions::KILL)
+        });
+        if !is_permitted {
+            return Err(SendSignalError::NotPermitted);
+        }
+        if let Some(signal) = signal {
+            table.signal(pid, signal);
+        }
+        Ok(())
+    })
+}
+
+fn current_process() -> Option<NonNull<Process>> {
+    scheduler::current_thread().and_then(|thread| unsafe { thread.as_ref() }.process())
+}
+
+/// Returns where the program of the current process was loaded.
+pub fn current_image() -> Option<LoadedImage> {
+    let process = current_process()?;
+    without_interrupts(|| {
+        let _table = PROCESSES.lock();
+        Some(unsafe { process.as_ref() }.image)
+    })
+}
+
+/// Returns the permissions of the current process.
+/// The kernel and kernel threads have every permission.
+pub fn current_permissions() -> Permissions {
+    current_process().map_or(Permissions::ALL, |process| {
+        without_interrupts(|| {
+            let _table = PROCESSES.lock();
+            unsafe { process.as_ref() }.permissions
+        })
+    })
+}
+
+/// Takes `permissions` away from the current process for good,
+/// and returns the permissions it has left.
+pub fn drop_current_permissions(permissions: Permissions) -> Option<Permissions> {
+    let process = current_process()?;
+    without_interrupts(|| {
+        let _table = PROCESSES.lock();
+        let process = unsafe { &mut *process.as_ptr() };
+        process.permissions = process.permissions.difference(permissions);
+        Some(process.permissions)
+    })
+}
+
+/// Maps `len` bytes of the device memory at `physical_address` into the address
+/// space of the current process, and returns the address of the mapping. Each device
+/// has a fixed address in every process, so mapping it twice fails.
+pub fn map_device_current(physical_address: usize, len: usize) -> Result<usize, MapError> {
+    let is_device = physical_address >= DEVICE_MEMORY_START
+        && physical_address
+            .checked_add(len)
+            .is_some_and(|end| end <= DEVICE_MEMORY_END);
+    if !is_device || len == 0 {
+        return Err(MapError::InvalidAddress);
     }
+    let virtual_address = DEVICE_MAPPING_START + physical_address;
+    let process = current_process().ok_or(MapError::InvalidAddress)?;
+    without_interrupts(|| {
+        let _table = PROCESSES.lock();
+        let address_space = unsafe { &mut *process.as_ptr() }
+            .address_space
+            .as_mut()
+            .ok_or(MapError::InvalidAddress)?;
+        address_space.map_device(virtual_address, physical_address, len)
+    })?;
+    Ok(virtual_address)
+}
 
-    pub fn is_executing(&self) -> bool {
-        self.saved_register_state.is_locked()
+/// Charges the time since the last exception entry or exit of the current thread to
+/// its process, as user time if the thread was in user mode. Sends the process
+/// `SIGXCPU` or `SIGKILL` if that takes it over its limits of cpu time.
+/// Called from the exception paths, with interrupts masked.
+pub fn charge_cpu_time(is_user: bool) {
+    let thread = match scheduler::current_thread() {
+        Some(thread) => thread,
+        None => return,
+    };
+    let now = timer::counter_value();
+    let thread = unsafe { &mut *thread.as_ptr() };
+    let ticks = now.saturating_sub(thread.cpu_time_mark);
+    thread.cpu_time_mark = now;
+    let process = match thread.process() {
+        Some(process) => unsafe { process.as_ref() },
+        None => return,
+    };
+    if let Some(signal) = process.cpu_time.charge(ticks, is_user) {
+        PROCESSES.lock().signal(process.pid, signal);
     }
+}
 
-    pub fn try_execute(&self) -> Result<!, ()> {
-        if let Some(guard) = self.saved_register_state.try_lock() {
-            todo!()
+/// Starts charging cpu time to the current thread from now,
+/// when a core switches to it or returns to it.
+pub fn reset_cpu_time_mark() {
+    if let Some(thread) = scheduler::current_thread() {
+        unsafe { (*thread.as_ptr()).cpu_time_mark = timer::counter_value() };
+    }
+}
+
+/// Returns what the current process has used,
+/// or what its collected children have if `of_children` is set.
+pub fn current_usage(of_children: bool) -> Option<Usage> {
+    let process = current_process()?;
+    without_interrupts(|| {
+        let table = PROCESSES.lock();
+        let process = unsafe { process.as_ref() };
+        let cpu_time = match of_children {
+            true => &process.children_cpu_time,
+            false => &process.cpu_time,
+        };
+        let mut usage = Usage {
+            user_nanoseconds: resource::ticks_to_nanoseconds(cpu_time.user_ticks()),
+            system_nanoseconds: resource::ticks_to_nanoseconds(cpu_time.system_ticks()),
+            ..Usage::default()
+        };
+        if !of_children {
+            usage.resident_pages = process
+                .address_space
+                .as_ref()
+                .map_or(0, |address_space| address_space.resident_pages() as u64);
+            usage.handles = process.handles.open_count() as u64;
+            usage.children = table.children(process.pid).count() as u64;
         }
-        Err(())
+        Some(usage)
+    })
+}
+
+/// Returns the limit of the current process on `resource`.
+pub fn current_limit(resource: Resource) -> Option<Limit> {
+    let process = current_process()?;
+    without_interrupts(|| {
+        let _table = PROCESSES.lock();
+        Some(unsafe { process.as_ref() }.limits.get(resource))
+    })
+}
+
+/// Changes the limit of the current process on `resource`. Raising
+/// the hard limit needs the `SET_LIMITS` permission.
+pub fn set_current_limit(resource: Resource, limit: Limit) -> Result<(), LimitError> {
+    let process = current_process().ok_or(LimitError::NotAProcess)?;
+    without_interrupts(|| {
+        let _table = PROCESSES.lock();
+        let process = unsafe { &mut *process.as_ptr() };
+        let may_raise = process.permissions.contains(Permissions::SET_LIMITS);
+        process.limits.set(resource, limit, may_raise)?;
+        let limit = process.limits.get(resource);
+        match resource {
+            Resource::CpuTime => process.cpu_time.set_limit(limit),
+            Resource::ResidentPages => {
+                if let Some(address_space) = &mut process.address_space {
+                    address_space.set_page_limit(limit.soft_count());
+                }
+            }
+            Resource::Handles => process.handles.set_limit(limit.soft_count()),
+            // checked when a child is created, or when the core dump is written
+            Resource::Children | Resource::CoreSize => {}
+        }
+        Ok(())
+    })
+}
+
+/// Starts a kernel thread running `function`. The thread exits when the function returns.
+pub fn spawn_kernel_thread(function: fn()) -> Result<NonNull<Thread>, ProcessError> {
+    let context = ExceptionFrame::new_kernel(
+        thread::kernel_thread_start as extern "C" fn(u64) -> ! as usize as u64,
+        function as usize as u64,
+    );
+    let thread = Thread::new(None, None, context, 0)?;
+    without_interrupts(|| {
+        let thread = PROCESSES.lock().threads.insert(thread)?;
+        unsafe { scheduler::make_runnable(thread) };
+        Ok(thread)
+    })
+}
+
+/// Starts a thread in the process of the current thread that runs `entry` with
+/// `argument` in `x0` and `thread_pointer` in `tpidr_el0`. Returns its tid.
+pub fn create_thread(
+    entry: u64,
+    argument: u64,
+    thread_pointer: u64,
+) -> Result<usize, ProcessError> {
+    let process = current_process().ok_or(ProcessError::NotAProcess)?;
+
+    without_interrupts(|| {
+        let mut table = PROCESSES.lock();
+        let process_ref = unsafe { &mut *process.as_ptr() };
+        let slot = (0..MAX_THREADS_PER_PROCESS)
+            .find(|slot| process_ref.used_stack_slots & (1 << slot) == 0)
+            .ok_or(ProcessError::TooManyThreads)?;
+        let stack_top = user_stack_top(slot);
+        let address_space = process_ref
+            .address_space
+            .as_mut()
+            .ok_or(ProcessError::NotAProcess)?;
+        address_space
+            .map_zeroed(
+                stack_top - USER_STACK_SIZE,
+                USER_STACK_SIZE,
+                stack_flags(process_ref.image.is_stack_executable),
+            )
+            .map_err(|_| ProcessError::OutOfMemory)?;
+
+        let mut context = ExceptionFrame::new_user(entry, stack_top as u64);
+        context.set_register(0, argument);
+        let thread = Thread::new(Some(process), Some(slot), context, thread_pointer)
+            .and_then(|thread| table.threads.insert(thread));
+        let thread = match thread {
+            Ok(thread) => thread,
+            Err(error) => {
+                address_space.unmap(stack_top - USER_STACK_SIZE, USER_STACK_SIZE);
+                return Err(error);
+            }
+        };
+
+        process_ref.used_stack_slots |= 1 << slot;
+        process_ref.live_threads += 1;
+        unsafe { scheduler::make_runnable(thread) };
+        Ok(unsafe { thread.as_ref() }.tid())
+    })
+}
+
+/// Replaces the program of the current process with `image` run with `arguments`,
+/// ending all other threads of the process. The registers of the current thread
+/// are reset in `frame`, which must be its saved user state. On failure the
+/// current program keeps running.
+pub fn exec_current(
+    image: &[u8],
+    arguments: &Arguments,
+    frame: &mut ExceptionFrame,
+) -> Result<(), ProcessError> {
+    let thread = scheduler::current_thread().ok_or(ProcessError::NotAProcess)?;
+    let process = unsafe { thread.as_ref() }
+        .process()
+        .ok_or(ProcessError::NotAProcess)?;
+    let page_limit = without_interrupts(|| {
+        let _table = PROCESSES.lock();
+        let limit = unsafe { process.as_ref() }
+            .limits
+            .get(Resource::ResidentPages);
+        limit.soft_count()
+    });
+    let (address_space, loaded, initial_thread) = load_image(image, arguments, page_limit)?;
+    // taken once the other threads are gone, and otherwise freed after the lock is dropped
+    let mut program = Some((address_space, SymbolTableCopy::new(image)));
+
+    without_interrupts(|| {
+        let mut table = PROCESSES.lock();
+        kill_other_threads(&mut table, process, thread);
+        // another thread ended the process or made its own exec first
+        if unsafe { scheduler::thread_state(thread) } == ThreadState::Zombie {
+            return Err(ProcessError::Killed);
+        }
+        let process = unsafe { &mut *process.as_ptr() };
+        // the threads still on other cores are released once those cores switch away
+        if process.live_threads > 1 {
+            process.exec_waiters.block_current();
+            return Err(ProcessError::WouldBlock);
+        }
+
+        let (address_space, symbols) = program.take().unwrap();
+        unsafe { address_space.activate() };
+        process.address_space = Some(address_space);
+        process.used_stack_slots = 1;
+        process.image = loaded;
+        process.symbols = symbols;
+        process.signals.reset_handlers();
+        let thread = unsafe { &mut *thread.as_ptr() };
+        thread.user_stack_slot = Some(0);
+        unsafe { thread.set_thread_pointer(initial_thread.thread_pointer) };
+        *frame = ExceptionFrame::new_user(loaded.entry, initial_thread.stack_pointer);
+        Ok(())
+    })
+}
+
+/// Ends the current thread with `value`, which can be collected with `join`.
+/// The process ends with status 0 if this is its last thread.
+/// Returns false if there is no current thread.
+pub fn exit_current_thread(value: u64) -> bool {
+    let thread = match scheduler::current_thread() {
+        Some(thread) => thread,
+        None => return false,
+    };
+    without_interrupts(|| {
+        let _table = PROCESSES.lock();
+        unsafe { (*thread.as_ptr()).exit_value = Some(value) };
+        // the thread is released once the core has switched away from it
+        scheduler::exit_current()
+    })
+}
+
+/// Ends the current process with `status`, including all of its threads.
+/// The process is cleaned up once none of its threads are on a core anymore.
+/// Kernel threads only end themselves. Returns false if there is no current thread.
+pub fn exit_current(status: i32) -> bool {
+    let thread = match scheduler::current_thread() {
+        Some(thread) => thread,
+        None => return false,
+    };
+    let process = match unsafe { thread.as_ref() }.process() {
+        Some(process) => process,
+        None => return exit_current_thread(status as u64),
+    };
+
+    without_interrupts(|| {
+        let mut table = PROCESSES.lock();
+        unsafe { (*process.as_ptr()).exit_status.get_or_insert(status) };
+        kill_other_threads(&mut table, process, thread);
+        unsafe { (*thread.as_ptr()).exit_value = Some(status as u64) };
+        scheduler::exit_current()
+    })
+}
+
+/// Collects the exit status of a child that has exited, or of the child `pid` if it is not 0.
+/// If no matching child has exited yet, the current thread is blocked until one does.
+pub fn wait_current(pid: usize) -> Result<ExitedChild, WaitError> {
+    let thread = scheduler::current_thread().ok_or(WaitError::NoChildren)?;
+    let caller_pid = current_pid();
+
+    without_interrupts(|| {
+        let mut table = PROCESSES.lock();
+        let mut children = table
+            .children(caller_pid)
+            .filter(|child| pid == 0 || unsafe { child.as_ref() }.pid == pid)
+            .peekable();
+        if children.peek().is_none() {
+            return Err(WaitError::NoChildren);
+        }
+        let zombie = children.find(|child| unsafe { child.as_ref() }.is_zombie);
+        drop(children);
+
+        match zombie {
+            Some(child) => {
+                let child_ref = unsafe { child.as_ref() };
+                let pid = child_ref.pid;
+                if let Some(caller) = unsafe { thread.as_ref() }.process() {
+                    let children_cpu_time = unsafe { &caller.as_ref().children_cpu_time };
+                    children_cpu_time.add(&child_ref.cpu_time);
+                    children_cpu_time.add(&child_ref.children_cpu_time);
+                }
+                let status = unsafe { table.reap(child) };
+                Ok(ExitedChild { pid, status })
+            }
+            None => {
+                if let Some(caller) = unsafe { thread.as_ref() }.process() {
+                    unsafe { caller.as_ref() }.child_waiters.block_current();
+                }
+                Err(WaitError::WouldBlock)
+            }
+        }
+    })
+}
+
+/// Collects the value the thread `tid` of the current process exited with, and frees it.
+/// If it has not exited yet, the current thread is blocked until it does.
+pub fn join(tid: usize) -> Result<u64, JoinError> {
+    let caller = scheduler::current_thread().ok_or(JoinError::NoSuchThread)?;
+
+    without_interrupts(|| {
+        let mut table = PROCESSES.lock();
+        let thread = table.threads.get(tid).ok_or(JoinError::NoSuchThread)?;
+        let thread_ref = unsafe { &mut *thread.as_ptr() };
+        if thread_ref.process() != unsafe { caller.as_ref() }.process() {
+            return Err(JoinError::NoSuchThread);
+        }
+        if thread == caller
+            || thread_ref.is_detached
+            || matches!(thread_ref.joiner, Some(joiner) if joiner != caller)
+        {
+            return Err(JoinError::NotJoinable);
+        }
+
+        if thread_ref.is_released {
+            let value = thread_ref.exit_value.unwrap();
+            unsafe { table.threads.remove(thread) };
+            return Ok(value);
+        }
+        thread_ref.joiner = Some(caller);
+        thread_ref.exit_waiters.block_current();
+        Err(JoinError::WouldBlock)
+    })
+}
+
+/// Releases `thread`, which exited on the calling core that has now switched away from it.
+/// Called once a core has switched away from a thread for good.
+///
+/// # Safety
+/// `thread` may already have been freed, but must have been a valid thread.
+pub unsafe fn release_exited_thread(thread: NonNull<Thread>) {
+    let mut table = PROCESSES.lock();
+    // a thread killed while off the core may have been released and freed already,
+    // and its page may even belong to a new thread
+    if table.threads.contains(thread)
+        && !thread.as_ref().is_released
+        && scheduler::thread_state(thread) == ThreadState::Zombie
+        && !scheduler::is_on_core(thread)
+    {
+        table.release_thread(thread);
+    }
+}
+
+/// Makes every thread of `process` except `caller` a zombie, and releases
+/// the ones that are not on a core right away.
+fn kill_other_threads(
+    table: &mut ProcessTable,
+    process: NonNull<Process>,
+    caller: NonNull<Thread>,
+) {
+    for index in 0..thread::MAX_NUM_THREADS {
+        let thread = match table.threads.get(index) {
+            Some(thread)
+                if thread != caller && unsafe { thread.as_ref() }.process() == Some(process) =>
+            {
+                thread
+            }
+            _ => continue,
+        };
+        unsafe {
+            if thread.as_ref().is_released {
+                continue;
+            }
+            if !scheduler::kill(thread) {
+                table.release_thread(thread);
+            }
+        }
+    }
+}
+
+/// The registers the first thread of a program starts with, besides the entry point.
+struct InitialThread {
+    stack_pointer: u64,
+    thread_pointer: u64,
+}
+
+/// Hands the dump of an ended process to the core dump thread.
+fn queue_core_dump(core_dump: CoreDump) {
+    let mut core_dumps = CORE_DUMPS.lock();
+    match core_dumps.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(core_dump);
+            QUEUED_CORE_DUMPS.release();
+        }
+        None => crate::println!(
+            "[WARN]: too many core dumps queued, dropping the dump of process {}",
+            core_dump.status.pid
+        ),
+    }
+}
+
+/// Writes the queued core dumps to the serial port one at a time, and frees
+/// their address spaces. Runs on its own thread, since sending a dump takes
+/// seconds and no lock of the kernel is held meanwhile.
+fn write_core_dumps() {
+    loop {
+        QUEUED_CORE_DUMPS.acquire();
+        let core_dump = without_interrupts(|| {
+            let mut core_dumps = CORE_DUMPS.lock();
+            core_dumps.iter_mut().find_map(Option::take).unwrap()
+        });
+        if let Some(address_space) = &core_dump.address_space {
+            let size = coredump::core_size(address_space);
+            serial::with_serial(|write| {
+                coredump::write_core(
+                    address_space,
+                    &core_dump.image,
+                    &core_dump.frame,
+                    &core_dump.status,
+                    write,
+                )
+            });
+            crate::println!(
+                "[INFO]: core dump of process {} ({} bytes) sent over the serial port",
+                core_dump.status.pid,
+                size
+            );
+        }
+    }
+}
+
+fn stack_flags(is_executable: bool) -> PageFlags {
+    PageFlags {
+        is_writable: true,
+        is_executable,
+    }
+}
+
+/// The top of the user stack in `slot`. The stacks grow down from the end of user space.
+fn user_stack_top(slot: usize) -> usize {
+    USER_SPACE_END - slot * USER_STACK_STRIDE
+}
+
+/// Creates an address space with `image` and the stack of the first thread mapped,
+/// and returns it with where the image was loaded and how the first thread starts.
+/// The stack starts with `arguments`, below the thread local storage.
+/// No more than `page_limit` pages can be mapped into the address space.
+fn load_image(
+    image: &[u8],
+    arguments: &Arguments,
+    page_limit: usize,
+) -> Result<(AddressSpace, LoadedImage, InitialThread), ProcessError> {
+    let mut address_space = AddressSpace::new().ok_or(ProcessError::OutOfMemory)?;
+    address_space.set_page_limit(page_limit);
+    // the rest of user space is for device memory and the stacks
+    let region = USER_SPACE_START..DEVICE_MAPPING_START;
+    let loaded = elf::load_elf(image, &mut address_space, region)?;
+    let stack_top = user_stack_top(0);
+    address_space
+        .map_zeroed(
+            stack_top - USER_STACK_SIZE,
+            USER_STACK_SIZE,
+            stack_flags(loaded.is_stack_executable),
+        )
+        .map_err(|_| ProcessError::OutOfMemory)?;
+    let mut initial_thread = match &loaded.tls {
+        Some(tls) => set_up_tls(&address_space, tls, stack_top)?,
+        None => InitialThread {
+            stack_pointer: stack_top as u64,
+            thread_pointer: 0,
+        },
+    };
+    let stack_pointer = arguments::set_up_stack(
+        &address_space,
+        initial_thread.stack_pointer as usize,
+        arguments,
+        &loaded,
+    )
+    .map_err(ElfLoadError::Map)?;
+    initial_thread.stack_pointer = stack_pointer as u64;
+    Ok((address_space, loaded, initial_thread))
+}
+
+/// Puts the thread local storage of the first thread at the top of its stack, in
+/// variant 1 of the AArch64 TLS ABI: the thread pointer points to a control block,
+/// which is followed by the TLS block at the alignment of the template.
+fn set_up_tls(
+    address_space: &AddressSpace,
+    tls: &TlsTemplate,
+    stack_top: usize,
+) -> Result<InitialThread, ProcessError> {
+    let alignment = tls.alignment.max(TLS_CONTROL_BLOCK_SIZE);
+    // the control block is no larger than the alignment, which is a power of 2
+    let block_offset = alignment;
+    let thread_pointer = (stack_top - block_offset - tls.memory_size) / alignment * alignment;
+
+    // the stack is zeroed, so only the initialized part of the template is copied
+    let mut buffer = [0; 256];
+    for offset in (0..tls.file_size).step_by(buffer.len()) {
+        let len = (tls.file_size - offset).min(buffer.len());
+        let chunk = &mut buffer[..len];
+        address_space
+            .read(tls.address + offset, chunk)
+            .and_then(|_| address_space.write(thread_pointer + block_offset + offset, chunk))
+            .map_err(|error| ProcessError::InvalidImage(ElfLoadError::Map(error)))?;
     }
+    Ok(InitialThread {
+        stack_pointer: thread_pointer as u64,
+        thread_pointer: thread_pointer as u64,
+    })
 }
 
 // contains 1024 = 2^10 bottom level trees
@@ -0,0 +1,77 @@
+// BCM2835 hardware random number generator. It is not in the peripherals manual,
+// so this follows the linux driver, drivers/char/hw_random/bcm2835-rng.c
+
+use spin::mutex::spin::SpinMutex;
+
+use crate::cpu::without_interrupts;
+use crate::timer;
+
+const RNG_BASE_ADDR: usize = 0x3F10_4000;
+
+const CONTROL_PTR: *mut u32 = RNG_BASE_ADDR as _;
+const STATUS_PTR: *mut u32 = (RNG_BASE_ADDR + 0x04) as _;
+const DATA_PTR: *const u32 = (RNG_BASE_ADDR + 0x08) as _;
+const INTERRUPT_MASK_PTR: *mut u32 = (RNG_BASE_ADDR + 0x10) as _;
+
+const ENABLE_BIT: u32 = 1;
+const INTERRUPT_OFF_BIT: u32 = 1;
+// the first numbers generated are not very random, so this many are thrown away
+const WARMUP_COUNT: u32 = 0x40000;
+// the number of words ready to be read is in the top byte of the status register
+const AVAILABLE_SHIFT: u32 = 24;
+// how many times to check for a word before falling back to the counter
+const MAX_POLLS: usize = 100_000;
+
+/// Mixed into every number, so that numbers made while the generator
+/// is not ready still differ from each other.
+static STATE: SpinMutex<u64> = SpinMutex::new(0);
+
+/// Starts the generator. Numbers are only really random once it has warmed up.
+pub fn init() {
+    unsafe {
+        STATUS_PTR.write_volatile(WARMUP_COUNT);
+        INTERRUPT_MASK_PTR.write_volatile(INTERRUPT_MASK_PTR.read_volatile() | INTERRUPT_OFF_BIT);
+        CONTROL_PTR.write_volatile(CONTROL_PTR.read_volatile() | ENABLE_BIT);
+    }
+    *STATE.lock() = timer::counter_value();
+}
+
+/// Returns a random number. If the generator has nothing to give, the number
+/// is made from the counter of the generic timer, which is not secure.
+pub fn random_u64() -> u64 {
+    without_interrupts(|| {
+        let mut state = STATE.lock();
+        let value = match (read_word(), read_word()) {
+            (Some(high), Some(low)) => (high as u64) << 32 | low as u64,
+            _ => timer::counter_value(),
+        };
+        *state = mix(*state ^ value);
+        *state
+    })
+}
+
+/// Fills `buffer` with random bytes, like `random_u64`.
+pub fn fill(buffer: &mut [u8]) {
+    for chunk in buffer.chunks_mut(8) {
+        let bytes = random_u64().to_le_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
+
+fn read_word() -> Option<u32> {
+    for _ in 0..MAX_POLLS {
+        if unsafe { STATUS_PTR.read_volatile() } >> AVAILABLE_SHIFT != 0 {
+            return Some(unsafe { DATA_PTR.read_volatile() });
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+/// The finalizer of splitmix64, which spreads every bit of `value` over the result.
+fn mix(value: u64) -> u64 {
+    let mut value = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
@@ -0,0 +1,214 @@
+//! Limits on the resources of processes, and accounting of what they use.
+//!
+//! Limits work like rlimits: each resource has a soft limit that is enforced,
+//! and a hard limit that the soft limit can be raised up to. Children start
+//! with the limits of their parent.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::handle::MAX_HANDLES;
+use crate::signal::Signal;
+use crate::timer;
+
+pub const NUM_RESOURCES: usize = 5;
+/// Stands for no limit.
+pub const UNLIMITED: u64 = u64::MAX;
+// dumps go out over the serial port at about 11 KiB/s, so this takes about 20 seconds
+const DEFAULT_CORE_SIZE: u64 = 256 * 1024;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Resource {
+    /// CPU time in seconds. Going over the soft limit sends `SIGXCPU`,
+    /// and going over the hard limit sends `SIGKILL`.
+    CpuTime = 0,
+    /// Pages of memory mapped into the address space of the process.
+    ResidentPages = 1,
+    /// Handles, which can only be at indices below the limit.
+    Handles = 2,
+    /// Children that have not been collected with `wait`.
+    Children = 3,
+    /// Bytes of the core dump written when the process crashes.
+    /// Dumps that would be larger are not written.
+    CoreSize = 4,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct Limit {
+    pub soft: u64,
+    pub hard: u64,
+}
+
+#[derive(Debug)]
+pub enum LimitError {
+    /// The caller is a kernel thread or the kernel itself rather than a process.
+    NotAProcess,
+    /// The soft limit is above the hard limit.
+    InvalidLimit,
+    /// Raising a hard limit needs the `SET_LIMITS` permission.
+    NotPermitted,
+}
+
+/// The limits of a process, kept behind the lock of the process table.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    limits: [Limit; NUM_RESOURCES],
+}
+
+/// The CPU time used by a process, in ticks of the system counter. It is charged
+/// from the exception paths of every core, so it is updated without locks.
+pub struct CpuTime {
+    user: AtomicU64,
+    system: AtomicU64,
+    // the limits of the process in ticks, so that they can be checked without locks
+    soft_limit: AtomicU64,
+    hard_limit: AtomicU64,
+}
+
+/// What a process or its collected children have used, as returned by `getrusage`.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct Usage {
+    pub user_nanoseconds: u64,
+    pub system_nanoseconds: u64,
+    pub resident_pages: u64,
+    pub handles: u64,
+    pub children: u64,
+}
+
+impl Resource {
+    pub fn from_number(number: u64) -> Option<Resource> {
+        use Resource::*;
+        let resource = match number {
+            0 => CpuTime,
+            1 => ResidentPages,
+            2 => Handles,
+            3 => Children,
+            4 => CoreSize,
+            _ => return None,
+        };
+        Some(resource)
+    }
+}
+
+impl Limit {
+    pub const UNLIMITED: Limit = Limit {
+        soft: UNLIMITED,
+        hard: UNLIMITED,
+    };
+
+    /// The soft limit as a count, where `UNLIMITED` is as many as there can be.
+    pub fn soft_count(self) -> usize {
+        usize::try_from(self.soft).unwrap_or(usize::MAX)
+    }
+}
+
+impl Limits {
+    /// The limits of the processes started by the kernel. The number of handles
+    /// is limited by the size of the handle table, and the size of core dumps has
+    /// a soft limit that processes can raise.
+    pub fn new() -> Limits {
+        let mut limits = [Limit::UNLIMITED; NUM_RESOURCES];
+        limits[Resource::Handles as usize] = Limit {
+            soft: MAX_HANDLES as u64,
+            hard: MAX_HANDLES as u64,
+        };
+        limits[Resource::CoreSize as usize].soft = DEFAULT_CORE_SIZE;
+        Limits { limits }
+    }
+
+    pub fn get(&self, resource: Resource) -> Limit {
+        self.limits[resource as usize]
+    }
+
+    /// Changes the limit of `resource`. The hard limit can only be raised
+    /// if `may_raise` is set, and the limit of handles is capped by the size
+    /// of the handle table.
+    pub fn set(
+        &mut self,
+        resource: Resource,
+        mut limit: Limit,
+        may_raise: bool,
+    ) -> Result<(), LimitError> {
+        if limit.soft > limit.hard {
+            return Err(LimitError::InvalidLimit);
+        }
+        if limit.hard > self.get(resource).hard && !may_raise {
+            return Err(LimitError::NotPermitted);
+        }
+        if resource == Resource::Handles {
+            limit.soft = limit.soft.min(MAX_HANDLES as u64);
+            limit.hard = limit.hard.min(MAX_HANDLES as u64);
+        }
+        self.limits[resource as usize] = limit;
+        Ok(())
+    }
+}
+
+impl CpuTime {
+    pub const fn new() -> CpuTime {
+        CpuTime {
+            user: AtomicU64::new(0),
+            system: AtomicU64::new(0),
+            soft_limit: AtomicU64::new(UNLIMITED),
+            hard_limit: AtomicU64::new(UNLIMITED),
+        }
+    }
+
+    /// Adds `ticks` of user or system time, and returns the signal to send
+    /// to the process if that took it over one of its limits.
+    pub fn charge(&self, ticks: u64, is_user: bool) -> Option<Signal> {
+        let counter = if is_user { &self.user } else { &self.system };
+        counter.fetch_add(ticks, Ordering::Relaxed);
+        let after = self.total_ticks();
+        let before = after.saturating_sub(ticks);
+        let is_crossed = |limit: &AtomicU64| {
+            let limit = limit.load(Ordering::Relaxed);
+            before < limit && after >= limit
+        };
+        if is_crossed(&self.hard_limit) {
+            Some(Signal::SIGKILL)
+        } else if is_crossed(&self.soft_limit) {
+            Some(Signal::SIGXCPU)
+        } else {
+            None
+        }
+    }
+
+    /// Sets the limits in seconds that `charge` checks.
+    pub fn set_limit(&self, limit: Limit) {
+        let to_ticks = |seconds| match seconds {
+            UNLIMITED => UNLIMITED,
+            seconds => timer::duration_to_ticks(Duration::from_secs(seconds)),
+        };
+        self.soft_limit
+            .store(to_ticks(limit.soft), Ordering::Relaxed);
+        self.hard_limit
+            .store(to_ticks(limit.hard), Ordering::Relaxed);
+    }
+
+    /// Adds the time of `other`, without checking the limits.
+    pub fn add(&self, other: &CpuTime) {
+        self.user.fetch_add(other.user_ticks(), Ordering::Relaxed);
+        self.system
+            .fetch_add(other.system_ticks(), Ordering::Relaxed);
+    }
+
+    pub fn user_ticks(&self) -> u64 {
+        self.user.load(Ordering::Relaxed)
+    }
+
+    pub fn system_ticks(&self) -> u64 {
+        self.system.load(Ordering::Relaxed)
+    }
+
+    fn total_ticks(&self) -> u64 {
+        self.user_ticks().saturating_add(self.system_ticks())
+    }
+}
+
+/// Converts a number of ticks of the system counter to nanoseconds for `Usage`.
+pub fn ticks_to_nanoseconds(ticks: u64) -> u64 {
+    u64::try_from(timer::ticks_to_duration(ticks).as_nanos()).unwrap_or(u64::MAX)
+}
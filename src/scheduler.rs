@@ -0,0 +1,760 @@
+use core::arch::asm;
+use core::cmp::Reverse;
+use core::ptr::NonNull;
+use core::time::Duration;
+
+use spin::mutex::spin::SpinMutex;
+
+use crate::cpu::{core_id, without_interrupts, CoreMask, NUM_CORES};
+use crate::exceptions::syscalls::SYSCALL_YIELD;
+use crate::exceptions::ExceptionFrame;
+use crate::interrupts::{self, Ipi, Irq, IPI_IRQ};
+use crate::memory::addressspace::activate_kernel_address_space;
+use crate::thread::{Thread, ThreadState, MAX_NUM_THREADS};
+use crate::timer::{self, Instant};
+
+/// The number of timer ticks a thread runs before it is preempted.
+const TIME_SLICE_TICKS: u32 = 5;
+
+pub const MIN_REAL_TIME_PRIORITY: u8 = 1;
+pub const MAX_REAL_TIME_PRIORITY: u8 = 99;
+pub const MIN_NICE: i8 = -20;
+pub const MAX_NICE: i8 = 19;
+
+// the share of the core each nice value gets relative to the others,
+// each step is about 10% of the core when competing with one other thread
+const NICE_0_WEIGHT: u64 = 1024;
+const NICE_WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+const IDLE_CORE: CoreScheduler = CoreScheduler {
+    run_queue: RunQueue::new(),
+    current: None,
+    previous: None,
+    idle_context: core::ptr::null_mut(),
+    min_virtual_runtime: 0,
+    needs_reschedule: false,
+    is_started: false,
+};
+
+static SCHEDULER: SpinMutex<Scheduler> = SpinMutex::new(Scheduler {
+    cores: [IDLE_CORE; NUM_CORES],
+    sleeping: [None; MAX_NUM_THREADS],
+    next_sequence: 1,
+});
+
+/// How the scheduler picks a thread to run.
+///
+/// Every real-time thread runs before any `Normal` thread,
+/// and higher real-time priorities run before lower ones.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SchedulingPolicy {
+    /// Runs until it blocks, yields or a higher priority becomes ready.
+    Fifo { priority: u8 },
+    /// Like `Fifo`, but takes turns in time slices with equal priorities.
+    RoundRobin { priority: u8 },
+    /// Shares the cores fairly with the other `Normal` threads,
+    /// weighted by the nice value. Lower nice values get more time.
+    Normal { nice: i8 },
+}
+
+impl SchedulingPolicy {
+    pub fn is_valid(self) -> bool {
+        use SchedulingPolicy::*;
+        match self {
+            Fifo { priority } | RoundRobin { priority } => {
+                (MIN_REAL_TIME_PRIORITY..=MAX_REAL_TIME_PRIORITY).contains(&priority)
+            }
+            Normal { nice } => (MIN_NICE..=MAX_NICE).contains(&nice),
+        }
+    }
+
+    /// The real-time priority, or 0 for `Normal`.
+    fn real_time_priority(self) -> u8 {
+        use SchedulingPolicy::*;
+        match self {
+            Fifo { priority } | RoundRobin { priority } => priority,
+            Normal { .. } => 0,
+        }
+    }
+
+    /// Returns true if a thread with this policy is favoured over one with `other`.
+    /// Changing to such a policy needs the `SET_PRIORITY` permission.
+    pub fn is_higher_than(self, other: SchedulingPolicy) -> bool {
+        use SchedulingPolicy::*;
+        match (self, other) {
+            (Normal { nice }, Normal { nice: other_nice }) => nice < other_nice,
+            _ => self.real_time_priority() > other.real_time_priority(),
+        }
+    }
+}
+
+/// The state of a thread that is owned by the scheduler.
+pub struct SchedulingState {
+    // the frame the thread continues from when it is switched to
+    context: *mut ExceptionFrame,
+    state: ThreadState,
+    affinity: CoreMask,
+    last_core: usize,
+    // true from being picked by a core until that core has left the stack of the thread
+    is_on_core: bool,
+    policy: SchedulingPolicy,
+    // lent by higher priority threads waiting for a lock this one holds
+    inherited_priority: u8,
+    // the position among threads of the same priority, lower runs first
+    sequence: u64,
+    // the weighted time used by a `Normal` thread, the lowest runs first
+    virtual_runtime: u64,
+    has_yielded: bool,
+    time_slice: u32,
+    wake_time: Option<Instant>,
+}
+
+impl SchedulingState {
+    /// Creates the state of a blocked thread that starts by restoring `context`.
+    pub fn new(context: *mut ExceptionFrame) -> SchedulingState {
+        SchedulingState {
+            context,
+            state: ThreadState::Blocked,
+            affinity: CoreMask::ALL,
+            last_core: 0,
+            is_on_core: false,
+            policy: SchedulingPolicy::Normal { nice: 0 },
+            inherited_priority: 0,
+            sequence: 0,
+            virtual_runtime: 0,
+            has_yielded: false,
+            time_slice: 0,
+            wake_time: None,
+        }
+    }
+
+    /// The real-time priority the thread runs with, 0 if it runs as `Normal`.
+    fn effective_priority(&self) -> u8 {
+        self.policy
+            .real_time_priority()
+            .max(self.inherited_priority)
+    }
+
+    /// The thread with the lowest key runs first.
+    fn key(&self) -> (Reverse<u8>, u64, u64) {
+        let priority = self.effective_priority();
+        // the virtual runtime only orders threads without a real-time priority
+        let virtual_runtime = if priority == 0 {
+            self.virtual_runtime
+        } else {
+            0
+        };
+        (Reverse(priority), virtual_runtime, self.sequence)
+    }
+}
+
+struct Scheduler {
+    cores: [CoreScheduler; NUM_CORES],
+    // threads waiting for their wake time
+    sleeping: [Option<NonNull<Thread>>; MAX_NUM_THREADS],
+    next_sequence: u64,
+}
+
+unsafe impl Send for Scheduler {}
+
+struct CoreScheduler {
+    run_queue: RunQueue,
+    // `None` when the core is idle
+    current: Option<NonNull<Thread>>,
+    // the thread that was switched away from, until the core has left its stack
+    previous: Option<NonNull<Thread>>,
+    // the frame of the idle loop, saved when switching away from it
+    idle_context: *mut ExceptionFrame,
+    // threads joining the queue start here, so that they can't
+    // take over the core by having been away for a long time
+    min_virtual_runtime: u64,
+    needs_reschedule: bool,
+    is_started: bool,
+}
+
+impl CoreScheduler {
+    fn load(&self) -> usize {
+        self.run_queue.len + self.current.is_some() as usize
+    }
+}
+
+/// The runnable threads of a core, in no particular order.
+struct RunQueue {
+    threads: [Option<NonNull<Thread>>; MAX_NUM_THREADS],
+    len: usize,
+}
+
+impl RunQueue {
+    const fn new() -> RunQueue {
+        RunQueue {
+            threads: [None; MAX_NUM_THREADS],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, thread: NonNull<Thread>) {
+        assert!(self.len < MAX_NUM_THREADS, "run queue is full");
+        self.threads[self.len] = Some(thread);
+        self.len += 1;
+    }
+
+    /// Removes the thread with the lowest key among those for which `f` returns true.
+    fn take_best(&mut self, f: impl Fn(NonNull<Thread>) -> bool) -> Option<NonNull<Thread>> {
+        let index = self.threads[..self.len]
+            .iter()
+            .enumerate()
+            .filter(|(_, thread)| f(thread.unwrap()))
+            .min_by_key(|(_, thread)| unsafe { state_of(thread.unwrap()) }.key())
+            .map(|(index, _)| index)?;
+
+        self.len -= 1;
+        let thread = self.threads[index].take();
+        self.threads.swap(index, self.len);
+        thread
+    }
+}
+
+/// # Safety
+/// The scheduler lock must be held, which gives exclusive access to the scheduling state.
+unsafe fn state_of<'a>(thread: NonNull<Thread>) -> &'a mut SchedulingState {
+    &mut (*thread.as_ptr()).scheduling
+}
+
+impl Scheduler {
+    fn next_sequence(&mut self) -> u64 {
+        self.next_sequence += 1;
+        self.next_sequence
+    }
+
+    /// Makes `core` run the scheduler as soon as possible.
+    fn reschedule_core(&mut self, core: usize) {
+        if core == core_id() {
+            self.cores[core].needs_reschedule = true;
+        } else if self.cores[core].is_started {
+            interrupts::send_ipi(Ipi::Reschedule, core);
+        }
+    }
+
+    /// Puts a ready thread into the run queue of the least loaded core it may run on,
+    /// preferring the core it last ran on. The core is interrupted if it is idle
+    /// or running a lower priority.
+    fn enqueue(&mut self, thread: NonNull<Thread>) {
+        let state = unsafe { state_of(thread) };
+        let target = (0..NUM_CORES)
+            .filter(|&core| state.affinity.contains(core) && self.cores[core].is_started)
+            .min_by_key(|&core| (self.cores[core].load(), core != state.last_core))
+            // before any of the allowed cores have started, wait on the first of them
+            .or_else(|| (0..NUM_CORES).find(|&core| state.affinity.contains(core)))
+            .unwrap();
+
+        state.virtual_runtime = state
+            .virtual_runtime
+            .max(self.cores[target].min_virtual_runtime);
+        self.cores[target].run_queue.push(thread);
+
+        let should_preempt = match self.cores[target].current {
+            Some(current) => {
+                state.effective_priority() > unsafe { state_of(current) }.effective_priority()
+            }
+            None => true,
+        };
+        if should_preempt {
+            self.reschedule_core(target);
+        }
+    }
+
+    /// Moves a blocked thread to a run queue. A thread that is still on a core
+    /// is only marked as ready, and queued once the core switches away from it.
+    fn make_ready(&mut self, thread: NonNull<Thread>) {
+        let state = unsafe { state_of(thread) };
+        if state.state != ThreadState::Blocked {
+            return;
+        }
+        state.state = ThreadState::Ready;
+        state.sequence = self.next_sequence();
+        self.remove_sleeper(thread);
+        if !state.is_on_core {
+            self.enqueue(thread);
+        }
+    }
+
+    fn remove_sleeper(&mut self, thread: NonNull<Thread>) {
+        if unsafe { state_of(thread) }.wake_time.take().is_some() {
+            if let Some(sleeper) = self.sleeping.iter_mut().find(|s| **s == Some(thread)) {
+                *sleeper = None;
+            }
+        }
+    }
+
+    fn wake_sleepers(&mut self, now: Instant) {
+        for index in 0..MAX_NUM_THREADS {
+            if let Some(thread) = self.sleeping[index] {
+                let state = unsafe { state_of(thread) };
+                if matches!(state.wake_time, Some(wake_time) if wake_time <= now) {
+                    self.make_ready(thread);
+                }
+            }
+        }
+    }
+
+    fn earliest_wake_time(&self) -> Option<Instant> {
+        self.sleeping
+            .iter()
+            .flatten()
+            .filter_map(|&thread| unsafe { state_of(thread) }.wake_time)
+            .min()
+    }
+
+    /// Takes the best thread for `core` from its own run queue,
+    /// or steals one from another core if the queue is empty.
+    fn pick_next(&mut self, core: usize) -> Option<NonNull<Thread>> {
+        let allowed = |thread| unsafe { state_of(thread) }.affinity.contains(core);
+
+        // threads whose affinity changed while they were queued are moved elsewhere
+        while let Some(thread) = self.cores[core].run_queue.take_best(|p| !allowed(p)) {
+            self.enqueue(thread);
+        }
+        if let Some(thread) = self.cores[core].run_queue.take_best(allowed) {
+            return Some(thread);
+        }
+
+        // the busiest core is the most likely to have a thread left waiting
+        let mut victims = [0; NUM_CORES];
+        for (index, victim) in victims.iter_mut().enumerate() {
+            *victim = index;
+        }
+        victims.sort_unstable_by_key(|&victim| Reverse(self.cores[victim].run_queue.len));
+        victims
+            .iter()
+            .filter(|&&victim| victim != core)
+            .find_map(|&victim| self.cores[victim].run_queue.take_best(allowed))
+    }
+
+    /// Takes `thread` out of the run queue it is in, if any.
+    fn dequeue(&mut self, thread: NonNull<Thread>) {
+        for core_scheduler in &mut self.cores {
+            if core_scheduler
+                .run_queue
+                .take_best(|queued| queued == thread)
+                .is_some()
+            {
+                return;
+            }
+        }
+    }
+
+    /// Interrupts the core running `thread` if it should
+    /// no longer run there after a change to its state.
+    fn reschedule_thread(&mut self, thread: NonNull<Thread>) {
+        if let ThreadState::Running { core } = unsafe { state_of(thread) }.state {
+            self.reschedule_core(core);
+        }
+    }
+}
+
+/// Makes the calling core start scheduling threads, and runs the idle loop
+/// whenever there is nothing to run.
+///
+/// # Safety
+/// Must be called once on each core after the timer is initialized.
+pub unsafe fn run() -> ! {
+    let core = core_id();
+    // every core shares the same handlers
+    if core == 0 {
+        timer::set_timer_event_handler(handle_timer_event);
+        interrupts::register_irq(IPI_IRQ, handle_ipi).unwrap();
+    }
+    interrupts::enable_irq(IPI_IRQ, core).unwrap();
+    without_interrupts(|| SCHEDULER.lock().cores[core].is_started = true);
+    asm!("msr daifclr, #0b0011");
+
+    loop {
+        asm!("wfi");
+    }
+}
+
+/// Makes a new or blocked thread runnable.
+///
+/// # Safety
+/// `thread` must stay valid until it is removed from the scheduler.
+pub unsafe fn make_runnable(thread: NonNull<Thread>) {
+    without_interrupts(|| SCHEDULER.lock().make_ready(thread));
+}
+
+/// Returns the thread running on the calling core.
+pub fn current_thread() -> Option<NonNull<Thread>> {
+    without_interrupts(|| SCHEDULER.lock().cores[core_id()].current)
+}
+
+/// Returns what `thread` is currently doing.
+///
+/// # Safety
+/// `thread` must be a valid thread.
+pub unsafe fn thread_state(thread: NonNull<Thread>) -> ThreadState {
+    without_interrupts(|| {
+        let _scheduler = SCHEDULER.lock();
+        state_of(thread).state
+    })
+}
+
+/// Returns true while a core still runs on the stack of `thread`,
+/// even if it is no longer the current thread of that core.
+///
+/// # Safety
+/// `thread` must be a valid thread.
+pub unsafe fn is_on_core(thread: NonNull<Thread>) -> bool {
+    without_interrupts(|| {
+        let _scheduler = SCHEDULER.lock();
+        state_of(thread).is_on_core
+    })
+}
+
+/// Restricts `thread` to the cores in `affinity`. If it is running
+/// on a core that is no longer allowed, it is moved to another core.
+///
+/// # Safety
+/// `thread` must be a valid thread.
+pub unsafe fn set_affinity(thread: NonNull<Thread>, affinity: CoreMask) -> Result<(), ()> {
+    if affinity.is_empty() {
+        return Err(());
+    }
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let state = state_of(thread);
+        state.affinity = affinity;
+        if let ThreadState::Running { core } = state.state {
+            if !affinity.contains(core) {
+                scheduler.reschedule_core(core);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// # Safety
+/// `thread` must be a valid thread.
+pub unsafe fn scheduling_policy(thread: NonNull<Thread>) -> SchedulingPolicy {
+    without_interrupts(|| {
+        let _scheduler = SCHEDULER.lock();
+        state_of(thread).policy
+    })
+}
+
+/// Changes how `thread` is scheduled. The caller is responsible for permission checks.
+///
+/// # Safety
+/// `thread` must be a valid thread.
+pub unsafe fn set_scheduling_policy(thread: NonNull<Thread>, policy: SchedulingPolicy) {
+    assert!(policy.is_valid());
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        state_of(thread).policy = policy;
+        // a lowered priority may have to give way to a queued thread
+        scheduler.reschedule_thread(thread);
+    });
+}
+
+/// The real-time priority `thread` runs with, including inherited priority.
+/// This is 0 if it runs as a `Normal` thread.
+///
+/// # Safety
+/// `thread` must be a valid thread.
+pub unsafe fn effective_priority(thread: NonNull<Thread>) -> u8 {
+    without_interrupts(|| {
+        let _scheduler = SCHEDULER.lock();
+        state_of(thread).effective_priority()
+    })
+}
+
+/// Sets the priority lent to `thread` to what `update` returns for the current one.
+/// Threads waiting for a lock lend their priority to its owner, so that lower
+/// priorities can't keep the owner from releasing the lock. `update` runs with
+/// the scheduler locked, so updates of the same thread don't overlap.
+///
+/// # Safety
+/// `thread` must be a valid thread.
+pub unsafe fn update_inherited_priority(thread: NonNull<Thread>, update: impl FnOnce(u8) -> u8) {
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let state = state_of(thread);
+        let priority = update(state.inherited_priority);
+        if state.inherited_priority != priority {
+            state.inherited_priority = priority;
+            scheduler.reschedule_thread(thread);
+        }
+    });
+}
+
+/// # Safety
+/// `thread` must be a valid thread.
+pub unsafe fn affinity(thread: NonNull<Thread>) -> CoreMask {
+    without_interrupts(|| {
+        let _scheduler = SCHEDULER.lock();
+        state_of(thread).affinity
+    })
+}
+
+/// Makes the current thread give way to the other ready threads,
+/// even ones of the same priority.
+pub fn yield_current() {
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let core = core_id();
+        if let Some(thread) = scheduler.cores[core].current {
+            let sequence = scheduler.next_sequence();
+            let state = unsafe { state_of(thread) };
+            state.sequence = sequence;
+            state.has_yielded = true;
+        }
+        scheduler.cores[core].needs_reschedule = true;
+    });
+}
+
+/// Blocks the current thread until `wake_time` and requests a reschedule.
+/// Returns false if there is no current thread to block.
+pub fn sleep_current_until(wake_time: Instant) -> bool {
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let core = core_id();
+        let thread = match scheduler.cores[core].current {
+            Some(thread) => thread,
+            None => return false,
+        };
+
+        let state = unsafe { state_of(thread) };
+        state.state = ThreadState::Blocked;
+        state.wake_time = Some(wake_time);
+
+        let index = scheduler
+            .sleeping
+            .iter()
+            .position(|sleeper| sleeper.is_none())
+            .expect("too many sleeping threads");
+        scheduler.sleeping[index] = Some(thread);
+        scheduler.cores[core].needs_reschedule = true;
+
+        if let Some(earliest) = scheduler.earliest_wake_time() {
+            timer::set_deadline(earliest);
+        }
+        true
+    })
+}
+
+/// Blocks the current thread until it is made runnable again and requests a reschedule.
+/// Returns false if there is no current thread to block.
+pub fn block_current() -> bool {
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let core = core_id();
+        match scheduler.cores[core].current {
+            Some(thread) => {
+                unsafe { state_of(thread) }.state = ThreadState::Blocked;
+                scheduler.cores[core].needs_reschedule = true;
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Marks the current thread as a zombie, so that the core switches away
+/// from it for good when returning from the current exception.
+/// Returns false if there is no current thread.
+pub fn exit_current() -> bool {
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let core = core_id();
+        match scheduler.cores[core].current {
+            Some(thread) => {
+                unsafe { state_of(thread) }.state = ThreadState::Zombie;
+                scheduler.cores[core].needs_reschedule = true;
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Makes `thread` a zombie wherever it is, so that it never runs again.
+/// Returns true if it is still on a core, which then switches away from it.
+///
+/// # Safety
+/// `thread` must be a valid thread.
+pub unsafe fn kill(thread: NonNull<Thread>) -> bool {
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let state = state_of(thread);
+        match state.state {
+            ThreadState::Ready => scheduler.dequeue(thread),
+            ThreadState::Blocked => scheduler.remove_sleeper(thread),
+            ThreadState::Running { core } => scheduler.reschedule_core(core),
+            ThreadState::Zombie => {}
+        }
+        state.state = ThreadState::Zombie;
+        state.is_on_core
+    })
+}
+
+/// Gives the rest of the time slice of the current thread to another thread.
+/// This must not be called while handling an exception, use `yield_current` instead.
+pub fn yield_now() {
+    // the switch happens when returning from the supervisor call
+    unsafe { asm!("svc #0", in("x8") SYSCALL_YIELD, lateout("x0") _) };
+}
+
+/// Blocks the current thread for at least `duration`.
+/// This must not be called while handling an exception.
+pub fn sleep(duration: Duration) {
+    let wake_time = Instant::now() + duration;
+    if sleep_current_until(wake_time) {
+        yield_now();
+    } else {
+        // the idle loop can't block
+        while Instant::now() < wake_time {
+            unsafe { asm!("wfi") };
+        }
+    }
+}
+
+fn handle_timer_event(_frame: &mut ExceptionFrame) {
+    let mut scheduler = SCHEDULER.lock();
+    let core = core_id();
+
+    scheduler.wake_sleepers(Instant::now());
+    if let Some(earliest) = scheduler.earliest_wake_time() {
+        timer::set_deadline(earliest);
+    }
+
+    if let Some(thread) = scheduler.cores[core].current {
+        let state = unsafe { state_of(thread) };
+        if let SchedulingPolicy::Normal { nice } = state.policy {
+            let weight = NICE_WEIGHTS[(nice - MIN_NICE) as usize];
+            state.virtual_runtime += NICE_0_WEIGHT * NICE_0_WEIGHT / weight;
+        }
+
+        // fifo threads only stop when they have to
+        if matches!(state.policy, SchedulingPolicy::Fifo { .. }) {
+            return;
+        }
+        state.time_slice = state.time_slice.saturating_sub(1);
+        if state.time_slice == 0 {
+            // go behind the others of the same priority
+            state.sequence = scheduler.next_sequence();
+            scheduler.cores[core].needs_reschedule = true;
+        }
+    }
+}
+
+fn handle_ipi(_irq: Irq, _frame: &mut ExceptionFrame) {
+    let ipis = interrupts::take_pending_ipis();
+    if ipis & (1 << Ipi::Reschedule as u32) != 0 {
+        SCHEDULER.lock().cores[core_id()].needs_reschedule = true;
+    }
+}
+
+/// Called when returning from every exception with the frame that is about
+/// to be restored. Returns the frame that should be restored instead, which
+/// belongs to another thread if the core switched threads.
+pub fn schedule(frame: &mut ExceptionFrame) -> *mut ExceptionFrame {
+    let mut scheduler = SCHEDULER.lock();
+    let core = core_id();
+    let core_scheduler = &mut scheduler.cores[core];
+    if !core_scheduler.is_started || !core_scheduler.needs_reschedule {
+        return frame;
+    }
+    core_scheduler.needs_reschedule = false;
+
+    let current = core_scheduler.current;
+    match current {
+        Some(thread) => {
+            let state = unsafe { state_of(thread) };
+            state.context = frame;
+            if state.state == (ThreadState::Running { core }) {
+                state.state = ThreadState::Ready;
+            }
+        }
+        None => core_scheduler.idle_context = frame,
+    }
+
+    let next = scheduler.pick_next(core);
+
+    if let Some(thread) = current {
+        let state = unsafe { state_of(thread) };
+        let should_continue = state.state == ThreadState::Ready
+            && state.affinity.contains(core)
+            && next.is_none_or(|next| {
+                !state.has_yielded && state.key() < unsafe { state_of(next) }.key()
+            });
+        state.has_yielded = false;
+
+        if should_continue {
+            if let Some(next) = next {
+                scheduler.cores[core].run_queue.push(next);
+            }
+            state.state = ThreadState::Running { core };
+            if state.time_slice == 0 {
+                state.time_slice = TIME_SLICE_TICKS;
+            }
+            return frame;
+        }
+    }
+
+    // the current thread is queued again by `finish_switch`
+    scheduler.cores[core].previous = current;
+    if let Some(thread) = current {
+        unsafe { (*thread.as_ptr()).save_user_state() };
+    }
+
+    match next {
+        Some(next) => {
+            if current.is_none() {
+                timer::resume_tick(timer::DEFAULT_TICK_RATE);
+            }
+            unsafe { next.as_ref().restore_user_state() };
+            let core_scheduler = &mut scheduler.cores[core];
+            core_scheduler.current = Some(next);
+            let state = unsafe { state_of(next) };
+            core_scheduler.min_virtual_runtime = core_scheduler
+                .min_virtual_runtime
+                .max(state.virtual_runtime);
+            state.state = ThreadState::Running { core };
+            state.last_core = core;
+            state.is_on_core = true;
+            state.time_slice = TIME_SLICE_TICKS;
+            state.context
+        }
+        None => {
+            // nothing to run, so stop ticking until a sleeper has to wake up
+            scheduler.cores[core].current = None;
+            // the address space of the previous thread may be freed while idle
+            activate_kernel_address_space();
+            timer::stop_tick();
+            if let Some(earliest) = scheduler.earliest_wake_time() {
+                timer::set_deadline(earliest);
+            }
+            scheduler.cores[core].idle_context
+        }
+    }
+}
+
+/// Called after the core has switched to the frame returned by `schedule`.
+/// Only now can the previous thread run on another core,
+/// since this core was using its stack until the switch.
+/// Returns the previous thread if it exited, since it can now be freed.
+pub fn finish_switch() -> Option<NonNull<Thread>> {
+    let mut scheduler = SCHEDULER.lock();
+    let core = core_id();
+    let thread = scheduler.cores[core].previous.take()?;
+    let state = unsafe { state_of(thread) };
+    state.is_on_core = false;
+    match state.state {
+        ThreadState::Ready => scheduler.enqueue(thread),
+        ThreadState::Zombie => return Some(thread),
+        _ => {}
+    }
+    None
+}
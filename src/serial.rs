@@ -0,0 +1,102 @@
+// The PL011 UART of the BCM2837, on GPIO pins 14 and 15. Real boards connect it to
+// the bluetooth chip unless `dtoverlay=disable-bt` is in config.txt, and qemu
+// connects it to its first serial port.
+//
+// The console is drawn on the screen, so the serial port is only used for
+// output that is meant for another computer, like core dumps.
+
+use core::time::Duration;
+
+use crate::cpu::without_interrupts;
+use crate::scheduler;
+use crate::sync::Mutex;
+
+const GPIO_BASE_ADDR: usize = 0x3F20_0000;
+const UART_BASE_ADDR: usize = 0x3F20_1000;
+
+const GPIO_FUNCTION_SELECT_1_PTR: *mut u32 = (GPIO_BASE_ADDR + 0x04) as _;
+const GPIO_PULL_PTR: *mut u32 = (GPIO_BASE_ADDR + 0x94) as _;
+const GPIO_PULL_CLOCK_0_PTR: *mut u32 = (GPIO_BASE_ADDR + 0x98) as _;
+
+const DATA_PTR: *mut u32 = UART_BASE_ADDR as _;
+const FLAGS_PTR: *const u32 = (UART_BASE_ADDR + 0x18) as _;
+const INTEGER_BAUD_RATE_PTR: *mut u32 = (UART_BASE_ADDR + 0x24) as _;
+const FRACTIONAL_BAUD_RATE_PTR: *mut u32 = (UART_BASE_ADDR + 0x28) as _;
+const LINE_CONTROL_PTR: *mut u32 = (UART_BASE_ADDR + 0x2c) as _;
+const CONTROL_PTR: *mut u32 = (UART_BASE_ADDR + 0x30) as _;
+const INTERRUPT_CLEAR_PTR: *mut u32 = (UART_BASE_ADDR + 0x44) as _;
+
+const TX_PIN: u32 = 14;
+const RX_PIN: u32 = 15;
+const ALTERNATE_FUNCTION_0: u32 = 0b100;
+// how long the pull up/down control signals have to be held, in cycles
+const PULL_SETUP_CYCLES: usize = 150;
+
+const TRANSMIT_FULL_BIT: u32 = 1 << 5;
+const EIGHT_BITS: u32 = 0b11 << 5;
+const FIFO_ENABLE_BIT: u32 = 1 << 4;
+const UART_ENABLE_BIT: u32 = 1 << 0;
+const TRANSMIT_ENABLE_BIT: u32 = 1 << 8;
+const RECEIVE_ENABLE_BIT: u32 = 1 << 9;
+
+// 115200 baud from the 48 MHz uart clock the firmware sets up:
+// 48_000_000 / (16 * 115200) = 26.0416..., and 0.0416... * 64 rounds to 3
+const INTEGER_BAUD_RATE: u32 = 26;
+const FRACTIONAL_BAUD_RATE: u32 = 3;
+
+// the time it takes to send the 16 bytes of the transmit fifo, rounded down
+const TRANSMIT_WAIT: Duration = Duration::from_millis(1);
+
+// held for the whole of each write, so that writes from different threads don't mix
+static SERIAL: Mutex<()> = Mutex::new(());
+
+/// Connects the UART to its pins and sets it up for 115200 baud, 8 bits, no parity.
+pub fn init() {
+    without_interrupts(|| {
+        let _serial = SERIAL.lock();
+        unsafe {
+            CONTROL_PTR.write_volatile(0);
+
+            let mut functions = GPIO_FUNCTION_SELECT_1_PTR.read_volatile();
+            for pin in [TX_PIN, RX_PIN] {
+                let shift = (pin % 10) * 3;
+                functions = functions & !(0b111 << shift) | ALTERNATE_FUNCTION_0 << shift;
+            }
+            GPIO_FUNCTION_SELECT_1_PTR.write_volatile(functions);
+
+            // no pull up or down
+            GPIO_PULL_PTR.write_volatile(0);
+            wait_cycles(PULL_SETUP_CYCLES);
+            GPIO_PULL_CLOCK_0_PTR.write_volatile(1 << TX_PIN | 1 << RX_PIN);
+            wait_cycles(PULL_SETUP_CYCLES);
+            GPIO_PULL_CLOCK_0_PTR.write_volatile(0);
+
+            INTERRUPT_CLEAR_PTR.write_volatile(0x7ff);
+            INTEGER_BAUD_RATE_PTR.write_volatile(INTEGER_BAUD_RATE);
+            FRACTIONAL_BAUD_RATE_PTR.write_volatile(FRACTIONAL_BAUD_RATE);
+            LINE_CONTROL_PTR.write_volatile(EIGHT_BITS | FIFO_ENABLE_BIT);
+            CONTROL_PTR.write_volatile(UART_ENABLE_BIT | TRANSMIT_ENABLE_BIT | RECEIVE_ENABLE_BIT);
+        }
+    });
+}
+
+/// Calls `f` with a function that sends bytes, and keeps other threads from
+/// sending anything until `f` returns. Sending is slow, so this is meant for
+/// kernel threads, which sleep while the transmit fifo is full.
+pub fn with_serial<T>(f: impl FnOnce(&mut dyn FnMut(&[u8])) -> T) -> T {
+    let _serial = SERIAL.lock();
+    f(&mut |bytes| {
+        for &byte in bytes {
+            while unsafe { FLAGS_PTR.read_volatile() } & TRANSMIT_FULL_BIT != 0 {
+                scheduler::sleep(TRANSMIT_WAIT);
+            }
+            unsafe { DATA_PTR.write_volatile(byte as u32) };
+        }
+    })
+}
+
+fn wait_cycles(count: usize) {
+    for _ in 0..count {
+        unsafe { core::arch::asm!("nop") };
+    }
+}
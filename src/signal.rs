@@ -0,0 +1,474 @@
+//! POSIX-like signals, the asynchronous notifications of processes.
+//!
+//! A signal sent to a process stays pending until a thread of the process
+//! returns to user mode with the signal unblocked. The thread then either runs
+//! the handler of the signal or takes its default action. A handler runs on
+//! the stack of the thread, below a `SignalFrame` that holds the interrupted
+//! state, and returns to a restorer that makes the `sigreturn` syscall.
+//!
+//! Faults of user code raise their signal right away in the faulting thread.
+
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::elf::LoadedImage;
+use crate::exceptions::syscalls;
+use crate::exceptions::ExceptionFrame;
+use crate::futex;
+use crate::process;
+use crate::scheduler;
+use crate::sync::WaitQueue;
+use crate::thread::ThreadState;
+
+/// Signal numbers go up to this, exclusive.
+pub const NUM_SIGNALS: usize = 32;
+
+// the stack pointer must stay 16 byte aligned at all times
+const STACK_ALIGN: usize = 16;
+// the link register, where the handler returns to
+const LINK_REGISTER: usize = 30;
+
+/// The signals, numbered like in Linux.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum Signal {
+    SIGHUP = 1,
+    SIGINT = 2,
+    SIGQUIT = 3,
+    SIGILL = 4,
+    SIGTRAP = 5,
+    SIGABRT = 6,
+    SIGBUS = 7,
+    SIGFPE = 8,
+    SIGKILL = 9,
+    SIGUSR1 = 10,
+    SIGSEGV = 11,
+    SIGUSR2 = 12,
+    SIGPIPE = 13,
+    SIGALRM = 14,
+    SIGTERM = 15,
+    SIGCHLD = 17,
+    SIGXCPU = 24,
+}
+
+/// What happens to a process that gets a signal it has no handler for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DefaultAction {
+    Terminate,
+    /// Terminate, and dump the state of the faulting thread.
+    CoreDump,
+    Ignore,
+}
+
+/// A set of signals, with bit `n` standing for signal number `n`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SignalSet(u32);
+
+/// How a process handles a signal.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SignalAction {
+    Default,
+    Ignore,
+    /// Runs the function at `address` with the signal number in `x0`, blocking
+    /// the signal and `mask` until the function returns to `restorer`.
+    Handler {
+        address: u64,
+        restorer: u64,
+        mask: SignalSet,
+    },
+}
+
+#[derive(Debug)]
+pub enum SignalError {
+    /// `SIGKILL` can't be caught or ignored.
+    NotCatchable,
+}
+
+/// The signals of a process, kept behind the lock of the process table.
+pub struct SignalState {
+    // read without the lock to skip delivery quickly when nothing is pending
+    pending: AtomicU32,
+    blocked: SignalSet,
+    actions: [SignalAction; NUM_SIGNALS],
+}
+
+/// A signal taken from a process to be handled by the current thread.
+#[derive(Clone, Copy, Debug)]
+pub struct Delivery {
+    signal: Signal,
+    // `Ignore` is never delivered
+    action: SignalAction,
+    // the mask to restore when the handler returns
+    blocked: SignalSet,
+}
+
+/// The state of the thread before running a handler, saved on its user stack.
+#[repr(C)]
+struct SignalFrame {
+    context: ExceptionFrame,
+    blocked: u64,
+    signal: u64,
+}
+
+const _: () = assert!(size_of::<SignalFrame>().is_multiple_of(STACK_ALIGN));
+
+impl Signal {
+    pub fn from_number(number: u64) -> Option<Signal> {
+        use Signal::*;
+        let signal = match number {
+            1 => SIGHUP,
+            2 => SIGINT,
+            3 => SIGQUIT,
+            4 => SIGILL,
+            5 => SIGTRAP,
+            6 => SIGABRT,
+            7 => SIGBUS,
+            8 => SIGFPE,
+            9 => SIGKILL,
+            10 => SIGUSR1,
+            11 => SIGSEGV,
+            12 => SIGUSR2,
+            13 => SIGPIPE,
+            14 => SIGALRM,
+            15 => SIGTERM,
+            17 => SIGCHLD,
+            24 => SIGXCPU,
+            _ => return None,
+        };
+        Some(signal)
+    }
+
+    pub fn number(self) -> u32 {
+        self as u32
+    }
+
+    pub fn default_action(self) -> DefaultAction {
+        use Signal::*;
+        match self {
+            SIGQUIT | SIGILL | SIGTRAP | SIGABRT | SIGBUS | SIGFPE | SIGSEGV | SIGXCPU => {
+                DefaultAction::CoreDump
+            }
+            SIGCHLD => DefaultAction::Ignore,
+            _ => DefaultAction::Terminate,
+        }
+    }
+
+    /// Returns false for the signals that always take their default action.
+    pub fn is_catchable(self) -> bool {
+        self != Signal::SIGKILL
+    }
+}
+
+impl SignalSet {
+    pub const EMPTY: SignalSet = SignalSet(0);
+
+    /// The set of the signal numbers in `bits`. Bits that are not signals are dropped.
+    pub fn from_bits(bits: u64) -> SignalSet {
+        let mut set = SignalSet::EMPTY;
+        for number in 0..NUM_SIGNALS as u64 {
+            if bits & (1 << number) != 0 {
+                if let Some(signal) = Signal::from_number(number) {
+                    set.insert(signal);
+                }
+            }
+        }
+        set
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0 as u64
+    }
+
+    pub fn contains(self, signal: Signal) -> bool {
+        self.0 & (1 << signal.number()) != 0
+    }
+
+    pub fn insert(&mut self, signal: Signal) {
+        self.0 |= 1 << signal.number();
+    }
+
+    pub fn union(self, other: SignalSet) -> SignalSet {
+        SignalSet(self.0 | other.0)
+    }
+
+    pub fn difference(self, other: SignalSet) -> SignalSet {
+        SignalSet(self.0 & !other.0)
+    }
+
+    /// The signal with the lowest number in the set.
+    fn first(self) -> Option<Signal> {
+        (0..NUM_SIGNALS as u64)
+            .filter(|number| self.0 & (1 << number) != 0)
+            .find_map(Signal::from_number)
+    }
+
+    /// The set without the signals that can't be blocked.
+    fn blockable(self) -> SignalSet {
+        let mut unblockable = SignalSet::EMPTY;
+        unblockable.insert(Signal::SIGKILL);
+        self.difference(unblockable)
+    }
+}
+
+impl SignalState {
+    pub const fn new() -> SignalState {
+        SignalState {
+            pending: AtomicU32::new(0),
+            blocked: SignalSet::EMPTY,
+            actions: [SignalAction::Default; NUM_SIGNALS],
+        }
+    }
+
+    pub fn has_pending(&self) -> bool {
+        self.pending.load(Ordering::Relaxed) != 0
+    }
+
+    /// Makes `signal` pending unless the process ignores it. Returns true if
+    /// it can be delivered right away, so that blocked threads should be woken.
+    pub fn send(&mut self, signal: Signal) -> bool {
+        if self.is_ignored(signal) {
+            return false;
+        }
+        self.pending
+            .fetch_or(1 << signal.number(), Ordering::Relaxed);
+        !self.blocked.contains(signal)
+    }
+
+    /// Takes the lowest pending signal that is not blocked. If it has a handler,
+    /// the signals the handler blocks stay blocked until it returns.
+    pub fn take_pending(&mut self) -> Option<Delivery> {
+        loop {
+            let signal = self.pending().difference(self.blocked).first()?;
+            self.pending
+                .fetch_and(!(1 << signal.number()), Ordering::Relaxed);
+            // the action may have changed to ignoring the signal after it was sent
+            if !self.is_ignored(signal) {
+                return Some(self.start_delivery(signal, self.actions[signal as usize]));
+            }
+        }
+    }
+
+    /// Takes `signal` raised by a fault of the current thread. The default action
+    /// is taken if the signal is blocked or ignored, since the faulting instruction
+    /// would only fault again.
+    pub fn take_fault(&mut self, signal: Signal) -> Delivery {
+        let action = match self.actions[signal as usize] {
+            SignalAction::Handler { .. } if !self.blocked.contains(signal) => {
+                self.actions[signal as usize]
+            }
+            _ => SignalAction::Default,
+        };
+        self.start_delivery(signal, action)
+    }
+
+    /// Sets the action of `signal` and returns the previous one.
+    pub fn set_action(
+        &mut self,
+        signal: Signal,
+        action: SignalAction,
+    ) -> Result<SignalAction, SignalError> {
+        if !signal.is_catchable() {
+            return Err(SignalError::NotCatchable);
+        }
+        let previous = core::mem::replace(&mut self.actions[signal as usize], action);
+        // pending signals that are now ignored are dropped
+        if self.is_ignored(signal) {
+            self.pending
+                .fetch_and(!(1 << signal.number()), Ordering::Relaxed);
+        }
+        Ok(previous)
+    }
+
+    pub fn blocked(&self) -> SignalSet {
+        self.blocked
+    }
+
+    /// Sets the blocked signals, except the ones that can't be blocked,
+    /// and returns the previous ones.
+    pub fn set_blocked(&mut self, blocked: SignalSet) -> SignalSet {
+        core::mem::replace(&mut self.blocked, blocked.blockable())
+    }
+
+    /// Resets the handlers for a new program, which doesn't have them.
+    /// Ignored signals stay ignored.
+    pub fn reset_handlers(&mut self) {
+        for action in &mut self.actions {
+            if let SignalAction::Handler { .. } = action {
+                *action = SignalAction::Default;
+            }
+        }
+    }
+
+    fn pending(&self) -> SignalSet {
+        SignalSet(self.pending.load(Ordering::Relaxed))
+    }
+
+    fn is_ignored(&self, signal: Signal) -> bool {
+        match self.actions[signal as usize] {
+            SignalAction::Ignore => true,
+            SignalAction::Default => signal.default_action() == DefaultAction::Ignore,
+            SignalAction::Handler { .. } => false,
+        }
+    }
+
+    fn start_delivery(&mut self, signal: Signal, action: SignalAction) -> Delivery {
+        let blocked = self.blocked;
+        if let SignalAction::Handler { mask, .. } = action {
+            let mut handler_blocked = self.blocked.union(mask);
+            handler_blocked.insert(signal);
+            self.blocked = handler_blocked.blockable();
+        }
+        Delivery {
+            signal,
+            action,
+            blocked,
+        }
+    }
+}
+
+/// Delivers the pending signals of the current process to the current thread,
+/// whose saved user state is `frame`. Called before returning to user mode.
+pub fn deliver_pending(frame: &mut ExceptionFrame) {
+    if !process::has_pending_signals() {
+        return;
+    }
+    let delivery = match process::with_current_signals(|signals| signals.take_pending()) {
+        Some(Some(delivery)) => delivery,
+        _ => return,
+    };
+    deliver(frame, delivery);
+}
+
+/// Delivers `signal` raised by the instruction at the program counter of `frame`,
+/// the saved user state of the current thread.
+pub fn raise_fault(frame: &mut ExceptionFrame, signal: Signal) {
+    match process::with_current_signals(|signals| signals.take_fault(signal)) {
+        Some(delivery) => deliver(frame, delivery),
+        // faults of user code always have a process to blame
+        None => unreachable!(),
+    }
+}
+
+/// Restores the state saved by the delivery of the signal whose handler
+/// has just returned. The frame is read from the user stack of `frame`.
+/// A process with a corrupted frame is killed.
+pub fn sigreturn(frame: &mut ExceptionFrame) {
+    let mut signal_frame = SignalFrame {
+        context: ExceptionFrame::new_user(0, 0),
+        blocked: 0,
+        signal: 0,
+    };
+    let bytes = unsafe {
+        core::slice::from_raw_parts_mut(
+            &mut signal_frame as *mut SignalFrame as *mut u8,
+            size_of::<SignalFrame>(),
+        )
+    };
+    if !process::copy_from_current(frame.user_stack_pointer() as usize, bytes) {
+        take_default_action(frame, Signal::SIGSEGV);
+        return;
+    }
+
+    process::with_current_signals(|signals| {
+        signals.set_blocked(SignalSet::from_bits(signal_frame.blocked))
+    });
+    *frame = signal_frame.context;
+    // the saved state is writable by user code, so it must not return to the kernel
+    frame.force_user_mode();
+}
+
+fn deliver(frame: &mut ExceptionFrame, delivery: Delivery) {
+    let thread = scheduler::current_thread().unwrap();
+    // a thread killed by another one never returns to user mode
+    if unsafe { scheduler::thread_state(thread) } == ThreadState::Zombie {
+        return;
+    }
+    let (address, restorer) = match delivery.action {
+        SignalAction::Handler {
+            address, restorer, ..
+        } => (address, restorer),
+        _ => return take_default_action(frame, delivery.signal),
+    };
+
+    // a thread blocked in a syscall runs the handler first,
+    // and the syscall is made again once the handler returns
+    if unsafe { futex::interrupt(thread) } {
+        // the futex wait already succeeded, so it must not be made again
+        frame.skip_instruction();
+        frame.set_register(0, 0);
+    }
+    unsafe {
+        syscalls::interrupt_sleep(thread, frame);
+        WaitQueue::cancel(thread);
+        scheduler::make_runnable(thread);
+    }
+
+    let signal_frame = SignalFrame {
+        context: frame.clone(),
+        blocked: delivery.blocked.bits(),
+        signal: delivery.signal.number() as u64,
+    };
+    let stack_pointer = (frame.user_stack_pointer() as usize)
+        .checked_sub(size_of::<SignalFrame>())
+        .map(|address| address / STACK_ALIGN * STACK_ALIGN);
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            &signal_frame as *const SignalFrame as *const u8,
+            size_of::<SignalFrame>(),
+        )
+    };
+    let stack_pointer = match stack_pointer {
+        Some(address) if process::copy_to_current(address, bytes) => address,
+        // the handler can't run without a stack
+        _ => return take_default_action(frame, Signal::SIGSEGV),
+    };
+
+    frame.set_register(0, delivery.signal.number() as u64);
+    frame.set_register(LINK_REGISTER, restorer);
+    frame.set_user_stack_pointer(stack_pointer as u64);
+    frame.set_program_counter(address);
+}
+
+/// Ends the current process because of `signal`. The exit status is
+/// 128 plus the signal number, like in shells.
+fn take_default_action(frame: &ExceptionFrame, signal: Signal) {
+    let pid = process::current_pid();
+    match signal.default_action() {
+        DefaultAction::CoreDump => {
+            crate::println!("[INFO]: process {} killed by {:?}, registers:", pid, signal);
+            if let Some(image) = process::current_image() {
+                report_image(frame, &image);
+            }
+            crate::println!("{:?}", frame);
+            if let Some(size) = process::request_core_dump(frame, signal) {
+                crate::println!(
+                    "[INFO]: core dump of {} bytes will be sent over the serial port",
+                    size
+                );
+            }
+        }
+        _ => crate::println!("[INFO]: process {} killed by {:?}", pid, signal),
+    }
+    process::exit_current(128 + signal.number() as i32);
+}
+
+/// Prints where the crash is in the program, by its symbols if the kernel kept them.
+/// Symbols have link addresses, and position independent programs were moved by `base`.
+fn report_image(frame: &ExceptionFrame, image: &LoadedImage) {
+    if let Some(build_id) = image.build_id {
+        crate::println!("[INFO]: build id {}", build_id);
+    }
+    let address = frame.program_counter().wrapping_sub(image.base as u64);
+    if image.base != 0 {
+        crate::println!(
+            "[INFO]: loaded at base {:#x}, pc {:#x} at its link address",
+            image.base,
+            address
+        );
+    }
+    process::with_current_symbols(|symbols| match symbols.symbol_at(address) {
+        Some((symbol, offset)) => crate::println!("[INFO]: pc at {}+{:#x}", symbol.name, offset),
+        None => crate::println!("[INFO]: pc not in any symbol"),
+    });
+}
@@ -0,0 +1,63 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use super::{deadline_after, MutexGuard, TimedOut, WaitQueue};
+use crate::timer::Instant;
+
+/// Blocks threads until another thread notifies them of a change
+/// to the state protected by a `Mutex`.
+pub struct Condvar {
+    // counts notifications, so that one between unlocking the mutex
+    // and blocking is not missed
+    sequence: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Condvar {
+        Condvar {
+            sequence: AtomicU64::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlocks the mutex of `guard` and blocks until notified, then locks it again.
+    /// The thread may also wake without a notification, so the caller must check
+    /// its condition again.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_until(guard, None).0
+    }
+
+    /// Like `wait`, but also stops waiting once `timeout` has passed.
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> (MutexGuard<'a, T>, Result<(), TimedOut>) {
+        self.wait_until(guard, deadline_after(timeout))
+    }
+
+    pub fn notify_one(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        self.waiters.wake_all();
+    }
+
+    fn wait_until<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        deadline: Option<Instant>,
+    ) -> (MutexGuard<'a, T>, Result<(), TimedOut>) {
+        let mutex = guard.mutex();
+        let sequence = self.sequence.load(Ordering::Acquire);
+        drop(guard);
+        let result = self.waiters.wait_if(deadline, || {
+            self.sequence.load(Ordering::Acquire) == sequence
+        });
+        (mutex.lock(), result)
+    }
+}
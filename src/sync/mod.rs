@@ -0,0 +1,37 @@
+//! Locks that block the current thread in the scheduler instead of spinning.
+//!
+//! These must only be used by threads outside of exception handlers, since
+//! blocking switches away from the thread with a supervisor call. Code that
+//! runs without a thread, like the boot code and the idle loop, spins instead.
+
+// the kernel has no users of these yet
+#[allow(dead_code)]
+mod condvar;
+mod mutex;
+#[allow(dead_code)]
+mod rwlock;
+mod semaphore;
+mod waitqueue;
+
+#[allow(unused_imports)]
+pub use condvar::Condvar;
+pub(crate) use mutex::HeldMutex;
+pub use mutex::{Mutex, MutexGuard};
+#[allow(unused_imports)]
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::Semaphore;
+pub use waitqueue::WaitQueue;
+
+use core::time::Duration;
+
+use crate::timer::Instant;
+
+/// Returned when a wait with a timeout ends before being woken.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TimedOut;
+
+/// The time a wait of `timeout` from now ends. A timeout too large
+/// to be represented never ends.
+fn deadline_after(timeout: Duration) -> Option<Instant> {
+    Instant::now().checked_add(timeout)
+}
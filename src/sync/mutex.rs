@@ -0,0 +1,181 @@
+use core::cell::{Cell, UnsafeCell};
+use core::ops::{Deref, DerefMut};
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, Ordering};
+use core::time::Duration;
+
+use super::{deadline_after, TimedOut, WaitQueue};
+use crate::scheduler;
+use crate::thread::Thread;
+use crate::timer::Instant;
+
+/// A lock that blocks the threads waiting for it.
+///
+/// A thread waiting for the lock lends its priority to the owner,
+/// so that threads of lower priority can't keep the owner from running.
+pub struct Mutex<T> {
+    is_locked: AtomicBool,
+    // null if the lock is free or held by code without a thread
+    owner: AtomicPtr<Thread>,
+    waiters: WaitQueue,
+    held: HeldMutex,
+    value: UnsafeCell<T>,
+}
+
+/// The part of a mutex that is linked into the list of mutexes its owner holds,
+/// so that the owner keeps the priorities lent through the others when it
+/// unlocks one of them.
+pub(crate) struct HeldMutex {
+    // the highest priority lent to the owner by the waiters of the mutex
+    lent_priority: AtomicU8,
+    // the mutex the owner locked before this one, only changed by the owner
+    next: Cell<Option<NonNull<HeldMutex>>>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Mutex<T> {
+        Mutex {
+            is_locked: AtomicBool::new(false),
+            owner: AtomicPtr::new(null_mut()),
+            waiters: WaitQueue::new(),
+            held: HeldMutex {
+                lent_priority: AtomicU8::new(0),
+                next: Cell::new(None),
+            },
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.lock_until(None).unwrap()
+    }
+
+    #[allow(dead_code)]
+    pub fn lock_timeout(&self, timeout: Duration) -> Result<MutexGuard<'_, T>, TimedOut> {
+        self.lock_until(deadline_after(timeout))
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.is_locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        let owner = scheduler::current_thread();
+        if let Some(owner) = owner {
+            let owner = unsafe { &mut *owner.as_ptr() };
+            self.held.lent_priority.store(0, Ordering::Relaxed);
+            self.held.next.set(owner.held_mutexes);
+            owner.held_mutexes = Some(NonNull::from(&self.held));
+        }
+        self.owner
+            .store(owner.map_or(null_mut(), NonNull::as_ptr), Ordering::Relaxed);
+        Some(MutexGuard { mutex: self })
+    }
+
+    fn lock_until(&self, deadline: Option<Instant>) -> Result<MutexGuard<'_, T>, TimedOut> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return Ok(guard);
+            }
+            self.waiters.wait_if(deadline, || {
+                let is_locked = self.is_locked.load(Ordering::Relaxed);
+                if is_locked {
+                    self.lend_priority();
+                }
+                is_locked
+            })?;
+        }
+    }
+
+    /// Raises the priority of the owner to that of the current thread.
+    fn lend_priority(&self) {
+        let (owner, current) = match (
+            NonNull::new(self.owner.load(Ordering::Relaxed)),
+            scheduler::current_thread(),
+        ) {
+            (Some(owner), Some(current)) => (owner, current),
+            _ => return,
+        };
+        unsafe {
+            let priority = scheduler::effective_priority(current);
+            // recorded before lending, so that an unlock of another mutex that
+            // recomputes the lent priority can't miss it
+            self.held
+                .lent_priority
+                .fetch_max(priority, Ordering::Relaxed);
+            scheduler::update_inherited_priority(owner, |lent| lent.max(priority));
+        }
+    }
+
+    fn unlock(&self) {
+        // waiters stop lending once the owner is cleared
+        let owner = self.owner.swap(null_mut(), Ordering::Relaxed);
+        if let Some(owner) = NonNull::new(owner) {
+            unsafe {
+                unlink_held(&mut *owner.as_ptr(), &self.held);
+                // the priorities lent through the other mutexes are still needed
+                scheduler::update_inherited_priority(owner, |_| held_priority(owner.as_ref()));
+            }
+        }
+        self.is_locked.store(false, Ordering::Release);
+        self.waiters.wake_one();
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+impl<'a, T> MutexGuard<'a, T> {
+    /// The mutex the guard locks, for relocking it after waiting on a `Condvar`.
+    pub(super) fn mutex(&self) -> &'a Mutex<T> {
+        self.mutex
+    }
+}
+
+/// Removes `held` from the mutexes `owner` holds.
+fn unlink_held(owner: &mut Thread, held: &HeldMutex) {
+    let held = NonNull::from(held);
+    let mut link = &mut owner.held_mutexes;
+    while let Some(current) = *link {
+        if current == held {
+            *link = unsafe { current.as_ref() }.next.get();
+            return;
+        }
+        link = unsafe { &mut *current.as_ref().next.as_ptr() };
+    }
+}
+
+/// The highest priority lent to `owner` through the mutexes it holds.
+fn held_priority(owner: &Thread) -> u8 {
+    let mut priority = 0;
+    let mut current = owner.held_mutexes;
+    while let Some(held) = current {
+        let held = unsafe { held.as_ref() };
+        priority = priority.max(held.lent_priority.load(Ordering::Relaxed));
+        current = held.next.get();
+    }
+    priority
+}
@@ -0,0 +1,130 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+
+use super::{deadline_after, TimedOut, WaitQueue};
+use crate::timer::Instant;
+
+// the state is the number of readers, or this if a writer holds the lock
+const WRITER: usize = usize::MAX;
+
+/// A lock that blocks threads, and lets any number of readers or one writer hold it.
+/// Readers can keep writers waiting for as long as they overlap.
+pub struct RwLock<T> {
+    state: AtomicUsize,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> RwLock<T> {
+        RwLock {
+            state: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.read_until(None).unwrap()
+    }
+
+    pub fn read_timeout(&self, timeout: Duration) -> Result<RwLockReadGuard<'_, T>, TimedOut> {
+        self.read_until(deadline_after(timeout))
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.state
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |readers| {
+                // one less than `WRITER` would overflow into it
+                (readers < WRITER - 1).then(|| readers + 1)
+            })
+            .ok()?;
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.write_until(None).unwrap()
+    }
+
+    pub fn write_timeout(&self, timeout: Duration) -> Result<RwLockWriteGuard<'_, T>, TimedOut> {
+        self.write_until(deadline_after(timeout))
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    fn read_until(&self, deadline: Option<Instant>) -> Result<RwLockReadGuard<'_, T>, TimedOut> {
+        loop {
+            if let Some(guard) = self.try_read() {
+                return Ok(guard);
+            }
+            self.waiters
+                .wait_if(deadline, || self.state.load(Ordering::Relaxed) == WRITER)?;
+        }
+    }
+
+    fn write_until(&self, deadline: Option<Instant>) -> Result<RwLockWriteGuard<'_, T>, TimedOut> {
+        loop {
+            if let Some(guard) = self.try_write() {
+                return Ok(guard);
+            }
+            self.waiters
+                .wait_if(deadline, || self.state.load(Ordering::Relaxed) != 0)?;
+        }
+    }
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        // only writers wait for the last reader
+        if self.lock.state.fetch_sub(1, Ordering::Release) == 1 {
+            self.lock.waiters.wake_all();
+        }
+    }
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        self.lock.waiters.wake_all();
+    }
+}
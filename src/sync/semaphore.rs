@@ -0,0 +1,53 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+
+use super::{deadline_after, TimedOut, WaitQueue};
+use crate::timer::Instant;
+
+/// A count of available resources that blocks threads while it is zero.
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Semaphore {
+        Semaphore {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Takes one resource, blocking until one is available.
+    pub fn acquire(&self) {
+        self.acquire_until(None).unwrap();
+    }
+
+    #[allow(dead_code)]
+    pub fn acquire_timeout(&self, timeout: Duration) -> Result<(), TimedOut> {
+        self.acquire_until(deadline_after(timeout))
+    }
+
+    /// Takes one resource if one is available.
+    pub fn try_acquire(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Gives back one resource and wakes a thread waiting for it.
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    fn acquire_until(&self, deadline: Option<Instant>) -> Result<(), TimedOut> {
+        while !self.try_acquire() {
+            self.waiters
+                .wait_if(deadline, || self.count.load(Ordering::Relaxed) == 0)?;
+        }
+        Ok(())
+    }
+}
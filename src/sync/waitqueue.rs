@@ -0,0 +1,212 @@
+use core::ptr::NonNull;
+use core::time::Duration;
+
+use spin::mutex::spin::SpinMutex;
+
+use super::{deadline_after, TimedOut};
+use crate::cpu::without_interrupts;
+use crate::scheduler;
+use crate::thread::Thread;
+use crate::timer::Instant;
+
+/// Threads blocked until another thread or an interrupt handler wakes them.
+///
+/// The threads are linked through the threads themselves, since
+/// a thread can only be blocked in one queue at a time.
+///
+/// Kernel threads block in place with `wait_if`, while threads in a syscall are
+/// blocked with `block_current` and make the syscall again once they run.
+pub struct WaitQueue {
+    waiters: SpinMutex<Waiters>,
+}
+
+struct Waiters {
+    first: Option<NonNull<Thread>>,
+    last: Option<NonNull<Thread>>,
+}
+
+unsafe impl Send for Waiters {}
+
+impl Waiters {
+    fn push(&mut self, thread: NonNull<Thread>, queue: &WaitQueue) {
+        let thread_ref = unsafe { &mut *thread.as_ptr() };
+        thread_ref.next_waiter = None;
+        thread_ref.wait_queue = Some(NonNull::from(queue));
+        match self.last {
+            Some(last) => unsafe { (*last.as_ptr()).next_waiter = Some(thread) },
+            None => self.first = Some(thread),
+        }
+        self.last = Some(thread);
+    }
+
+    /// Unlinks `thread`, and returns false if it was not in the queue.
+    fn remove(&mut self, thread: NonNull<Thread>) -> bool {
+        if unsafe { thread.as_ref() }.wait_queue.is_none() {
+            return false;
+        }
+        let mut previous: Option<NonNull<Thread>> = None;
+        let mut current = self.first;
+        while let Some(waiter) = current {
+            let next = unsafe { waiter.as_ref() }.next_waiter;
+            if waiter == thread {
+                match previous {
+                    Some(previous) => unsafe { (*previous.as_ptr()).next_waiter = next },
+                    None => self.first = next,
+                }
+                if self.last == Some(thread) {
+                    self.last = previous;
+                }
+                let thread_ref = unsafe { &mut *thread.as_ptr() };
+                thread_ref.next_waiter = None;
+                thread_ref.wait_queue = None;
+                return true;
+            }
+            previous = current;
+            current = next;
+        }
+        false
+    }
+
+    /// Unlinks the waiter that runs first, so that high priorities don't wait behind low ones.
+    fn pop_best(&mut self) -> Option<NonNull<Thread>> {
+        let mut best = self.first?;
+        let mut current = unsafe { best.as_ref() }.next_waiter;
+        while let Some(waiter) = current {
+            if unsafe {
+                scheduler::effective_priority(waiter) > scheduler::effective_priority(best)
+            } {
+                best = waiter;
+            }
+            current = unsafe { waiter.as_ref() }.next_waiter;
+        }
+        self.remove(best);
+        Some(best)
+    }
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: SpinMutex::new(Waiters {
+                first: None,
+                last: None,
+            }),
+        }
+    }
+
+    /// Blocks the current thread until it is woken.
+    #[allow(dead_code)]
+    pub fn wait(&self) {
+        let _ = self.wait_if(None, || true);
+    }
+
+    /// Blocks the current thread until it is woken or `timeout` has passed.
+    #[allow(dead_code)]
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<(), TimedOut> {
+        self.wait_if(deadline_after(timeout), || true)
+    }
+
+    /// Blocks the current thread until it is woken or `deadline` has passed, but
+    /// only if `condition` returns true. The condition is checked while wakers are
+    /// locked out, so a wakeup that makes it false can't be missed in between.
+    ///
+    /// Waiters may also return without being woken, so the caller must check
+    /// again whatever it is waiting for.
+    pub fn wait_if(
+        &self,
+        deadline: Option<Instant>,
+        condition: impl FnOnce() -> bool,
+    ) -> Result<(), TimedOut> {
+        if matches!(deadline, Some(deadline) if deadline <= Instant::now()) {
+            return Err(TimedOut);
+        }
+        let thread = match scheduler::current_thread() {
+            Some(thread) => thread,
+            None => {
+                // there is nothing to block, so let the caller spin
+                core::hint::spin_loop();
+                return Ok(());
+            }
+        };
+
+        let is_blocked = without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            if !condition() {
+                return false;
+            }
+            waiters.push(thread, self);
+            match deadline {
+                Some(deadline) => scheduler::sleep_current_until(deadline),
+                None => scheduler::block_current(),
+            }
+        });
+        if !is_blocked {
+            return Ok(());
+        }
+        scheduler::yield_now();
+
+        // a thread that is still queued was woken by the deadline
+        let is_timed_out = without_interrupts(|| self.waiters.lock().remove(thread));
+        if is_timed_out {
+            Err(TimedOut)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Blocks the current thread in its syscall until it is woken. The syscall is
+    /// made again when the thread runs, so the caller holds the lock that wakers
+    /// take while it checks what it waits for, and checks again once it runs.
+    /// Returns false if there is no current thread to block.
+    pub fn block_current(&self) -> bool {
+        let thread = match scheduler::current_thread() {
+            Some(thread) => thread,
+            None => return false,
+        };
+        // a wait left over from before the syscall was made again is replaced
+        unsafe { WaitQueue::cancel(thread) };
+        without_interrupts(|| {
+            self.waiters.lock().push(thread, self);
+            scheduler::block_current()
+        })
+    }
+
+    /// Unlinks `thread` from the queue it is blocked in, if any, for a thread
+    /// that stops waiting without being woken, like one that exits.
+    ///
+    /// # Safety
+    /// `thread` must be a valid thread, and the queue it is in must still exist.
+    pub unsafe fn cancel(thread: NonNull<Thread>) {
+        without_interrupts(|| {
+            // a waker may unlink the thread first, and then the queue no longer has it
+            if let Some(queue) = thread.as_ref().wait_queue {
+                queue.as_ref().waiters.lock().remove(thread);
+            }
+        });
+    }
+
+    /// Wakes the waiter with the highest priority. Returns false if there were no waiters.
+    pub fn wake_one(&self) -> bool {
+        without_interrupts(|| {
+            let waiter = self.waiters.lock().pop_best();
+            if let Some(waiter) = waiter {
+                unsafe { scheduler::make_runnable(waiter) };
+            }
+            waiter.is_some()
+        })
+    }
+
+    /// Wakes every waiter and returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            let mut count = 0;
+            while let Some(waiter) = waiters.first {
+                waiters.remove(waiter);
+                unsafe { scheduler::make_runnable(waiter) };
+                count += 1;
+            }
+            count
+        })
+    }
+}
@@ -0,0 +1,312 @@
+use core::arch::asm;
+use core::mem::size_of;
+use core::ptr::{addr_of_mut, NonNull};
+
+use spin::mutex::spin::SpinMutex;
+
+use crate::cpu::without_interrupts;
+use crate::exceptions::ExceptionFrame;
+use crate::futex::FutexWait;
+use crate::memory::addressspace::activate_kernel_address_space;
+use crate::memory::pageallocator::{PAGE_ALLOCATOR, PAGE_SIZE};
+use crate::process::{self, Process, ProcessError};
+use crate::scheduler::{self, SchedulingState};
+use crate::sync::{HeldMutex, Semaphore, WaitQueue};
+use crate::timer::Instant;
+
+pub const MAX_NUM_THREADS: usize = 512;
+const KERNEL_STACK_SIZE: usize = 4 * PAGE_SIZE;
+const MAX_QUEUED_WORK: usize = 64;
+
+const _: () = assert!(size_of::<Thread>() <= PAGE_SIZE);
+
+static WORK_QUEUE: SpinMutex<WorkQueue> = SpinMutex::new(WorkQueue {
+    work: [None; MAX_QUEUED_WORK],
+    len: 0,
+    worker: None,
+});
+// counts the work in the queue, so that the worker sleeps while there is none
+static QUEUED_WORK: Semaphore = Semaphore::new(0);
+
+/// What a thread is doing, as tracked by the scheduler.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ThreadState {
+    Running {
+        core: usize,
+    },
+    /// Waiting in a run queue.
+    Ready,
+    /// Waiting for something else than a core, for example a wake time.
+    Blocked,
+    /// Exited, but not yet cleaned up.
+    Zombie,
+}
+
+/// A flow of execution that is scheduled on its own. User threads share
+/// the address space of their process, while kernel threads have no process
+/// and only run kernel code.
+#[repr(C, align(4096))]
+pub struct Thread {
+    tid: usize,
+    // `None` for kernel threads
+    process: Option<NonNull<Process>>,
+    kernel_stack: NonNull<KernelStack>,
+    // which of the stacks of the process the thread uses, `None` for kernel threads
+    pub(crate) user_stack_slot: Option<usize>,
+    // `tpidr_el0`, saved while the thread is switched away from
+    thread_pointer: u64,
+    pub(crate) exit_value: Option<u64>,
+    // true once the thread has exited and no core is on its stack anymore
+    pub(crate) is_released: bool,
+    // detached threads are freed when released instead of waiting for `join`
+    pub(crate) is_detached: bool,
+    // the thread blocked in `exit_waiters` by `join` until this one is released
+    pub(crate) joiner: Option<NonNull<Thread>>,
+    pub(crate) exit_waiters: WaitQueue,
+    // the `WaitQueue` the thread is blocked in, and the next thread in it
+    pub(crate) wait_queue: Option<NonNull<WaitQueue>>,
+    pub(crate) next_waiter: Option<NonNull<Thread>>,
+    // the `Mutex`es the thread holds, the last one locked first
+    pub(crate) held_mutexes: Option<NonNull<HeldMutex>>,
+    pub(crate) futex_wait: Option<FutexWait>,
+    // the wake time of the `sleep` syscall, kept while the syscall is restarted
+    pub(crate) sleep_wake_time: Option<Instant>,
+    // the counter value up to which the cpu time of the thread has been charged
+    pub(crate) cpu_time_mark: u64,
+    pub scheduling: SchedulingState,
+}
+
+/// The stack used by the kernel while handling exceptions of a thread.
+///
+/// While a user thread is running in user mode, `sp_el1` points to the top
+/// of this stack. Exceptions taken from el0 push their frame right below
+/// that, so the user state is always saved into `saved_register_state`.
+/// Kernel threads run on this stack, starting from the same frame.
+#[repr(C, align(4096))]
+struct KernelStack {
+    stack: [u8; KERNEL_STACK_SIZE - size_of::<ExceptionFrame>()],
+    saved_register_state: ExceptionFrame,
+}
+
+/// Every thread by tid. Tid 0 is never used.
+pub(crate) struct ThreadTable {
+    threads: [Option<NonNull<Thread>>; MAX_NUM_THREADS],
+    // where the search for a free tid starts, so that tids are not reused right away
+    next_tid: usize,
+}
+
+/// Functions run by the worker thread, in the order they were queued.
+struct WorkQueue {
+    work: [Option<fn()>; MAX_QUEUED_WORK],
+    len: usize,
+    worker: Option<NonNull<Thread>>,
+}
+
+unsafe impl Send for ThreadTable {}
+unsafe impl Send for WorkQueue {}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        unsafe {
+            PAGE_ALLOCATOR
+                .lock()
+                .free_pages(self.kernel_stack.cast(), KERNEL_STACK_SIZE / PAGE_SIZE);
+        }
+    }
+}
+
+impl Thread {
+    /// Creates a blocked thread that starts by restoring `context`.
+    pub(crate) fn new(
+        process: Option<NonNull<Process>>,
+        user_stack_slot: Option<usize>,
+        context: ExceptionFrame,
+        thread_pointer: u64,
+    ) -> Result<Thread, ProcessError> {
+        let kernel_stack = PAGE_ALLOCATOR
+            .lock()
+            .alloc_page(KERNEL_STACK_SIZE / PAGE_SIZE)
+            .ok_or(ProcessError::OutOfMemory)?
+            .cast::<KernelStack>();
+        let frame = unsafe { addr_of_mut!((*kernel_stack.as_ptr()).saved_register_state) };
+        unsafe { frame.write(context) };
+
+        Ok(Thread {
+            tid: 0,
+            process,
+            kernel_stack,
+            user_stack_slot,
+            thread_pointer,
+            exit_value: None,
+            is_released: false,
+            is_detached: process.is_none(),
+            joiner: None,
+            exit_waiters: WaitQueue::new(),
+            wait_queue: None,
+            next_waiter: None,
+            held_mutexes: None,
+            futex_wait: None,
+            sleep_wake_time: None,
+            cpu_time_mark: 0,
+            scheduling: SchedulingState::new(frame),
+        })
+    }
+
+    pub fn tid(&self) -> usize {
+        self.tid
+    }
+
+    /// The process the thread belongs to, or `None` for kernel threads.
+    pub fn process(&self) -> Option<NonNull<Process>> {
+        self.process
+    }
+
+    /// Saves the user state that is not part of the exception frame
+    /// when the calling core switches away from the thread.
+    ///
+    /// # Safety
+    /// Must only be called by the scheduler on the core that ran the thread.
+    pub(crate) unsafe fn save_user_state(&mut self) {
+        asm!("mrs {}, tpidr_el0", out(reg) self.thread_pointer);
+    }
+
+    /// Changes `tpidr_el0` of the thread.
+    ///
+    /// # Safety
+    /// The thread must be running on the calling core.
+    pub(crate) unsafe fn set_thread_pointer(&mut self, thread_pointer: u64) {
+        self.thread_pointer = thread_pointer;
+        asm!("msr tpidr_el0, {}", in(reg) thread_pointer);
+    }
+
+    /// Makes the calling core use the address space and thread pointer of the thread.
+    ///
+    /// # Safety
+    /// Must only be called by the scheduler when switching to the thread.
+    pub(crate) unsafe fn restore_user_state(&self) {
+        match self.process {
+            Some(process) => process.as_ref().activate_address_space(),
+            None => activate_kernel_address_space(),
+        }
+        asm!("msr tpidr_el0, {}", in(reg) self.thread_pointer);
+    }
+}
+
+impl ThreadTable {
+    pub(crate) const fn new() -> ThreadTable {
+        ThreadTable {
+            threads: [None; MAX_NUM_THREADS],
+            next_tid: 1,
+        }
+    }
+
+    /// Moves `thread` to its own page and gives it a tid.
+    pub(crate) fn insert(&mut self, mut thread: Thread) -> Result<NonNull<Thread>, ProcessError> {
+        let tid = (self.next_tid..MAX_NUM_THREADS)
+            .chain(1..self.next_tid)
+            .find(|&tid| self.threads[tid].is_none())
+            .ok_or(ProcessError::TooManyThreads)?;
+        let page = PAGE_ALLOCATOR
+            .lock()
+            .alloc_page(1)
+            .ok_or(ProcessError::OutOfMemory)?
+            .cast::<Thread>();
+
+        self.next_tid = if tid + 1 < MAX_NUM_THREADS {
+            tid + 1
+        } else {
+            1
+        };
+        thread.tid = tid;
+        unsafe { page.as_ptr().write(thread) };
+        self.threads[tid] = Some(page);
+        Ok(page)
+    }
+
+    pub(crate) fn get(&self, tid: usize) -> Option<NonNull<Thread>> {
+        self.threads.get(tid).copied().flatten()
+    }
+
+    pub(crate) fn contains(&self, thread: NonNull<Thread>) -> bool {
+        self.threads.contains(&Some(thread))
+    }
+
+    /// The threads of `process`, in tid order.
+    pub(crate) fn of_process(
+        &self,
+        process: NonNull<Process>,
+    ) -> impl Iterator<Item = NonNull<Thread>> + '_ {
+        self.threads
+            .iter()
+            .flatten()
+            .copied()
+            .filter(move |thread| unsafe { thread.as_ref() }.process == Some(process))
+    }
+
+    /// Removes an exited thread from the table and frees it.
+    ///
+    /// # Safety
+    /// `thread` must be a zombie in the table.
+    pub(crate) unsafe fn remove(&mut self, thread: NonNull<Thread>) {
+        // the core it exited on may not have switched away from its stack yet
+        while scheduler::is_on_core(thread) {
+            core::hint::spin_loop();
+        }
+        self.threads[thread.as_ref().tid] = None;
+        thread.as_ptr().drop_in_place();
+        PAGE_ALLOCATOR.lock().free_page(thread.cast());
+    }
+}
+
+/// Starts the worker thread that runs the work queued with `queue_work`.
+pub fn start_worker() -> Result<(), ProcessError> {
+    let worker = process::spawn_kernel_thread(run_worker)?;
+    without_interrupts(|| WORK_QUEUE.lock().worker = Some(worker));
+    Ok(())
+}
+
+/// Makes the worker thread run `work`, so that slow work can be done outside
+/// of exception handlers. Returns false if the queue is full or the worker
+/// has not been started.
+pub fn queue_work(work: fn()) -> bool {
+    let is_queued = without_interrupts(|| {
+        let mut queue = WORK_QUEUE.lock();
+        if queue.worker.is_none() || queue.len == MAX_QUEUED_WORK {
+            return false;
+        }
+        let len = queue.len;
+        queue.work[len] = Some(work);
+        queue.len += 1;
+        true
+    });
+    if is_queued {
+        QUEUED_WORK.release();
+    }
+    is_queued
+}
+
+fn run_worker() {
+    loop {
+        QUEUED_WORK.acquire();
+        let work = without_interrupts(|| {
+            let mut queue = WORK_QUEUE.lock();
+            let len = queue.len;
+            let work = queue.work[0].take().unwrap();
+            queue.work[..len].rotate_left(1);
+            queue.len -= 1;
+            work
+        });
+        work();
+    }
+}
+
+/// The first code a kernel thread runs, with the function to run in `x0`.
+pub(crate) extern "C" fn kernel_thread_start(function: u64) -> ! {
+    let function: fn() = unsafe { core::mem::transmute(function as usize) };
+    function();
+    process::exit_current_thread(0);
+    // the switch happens when returning from the supervisor call
+    loop {
+        scheduler::yield_now();
+    }
+}
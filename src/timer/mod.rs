@@ -0,0 +1,412 @@
+use core::arch::asm;
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use spin::mutex::spin::SpinMutex;
+
+use crate::cpu::{core_id, without_interrupts, NUM_CORES};
+use crate::exceptions::ExceptionFrame;
+use crate::interrupts::{self, Irq, LocalIrq};
+
+mod systemtimer;
+
+pub const DEFAULT_TICK_RATE: u32 = 100;
+
+// the system timer is compared against the generic timer this often
+const DRIFT_CHECK_INTERVAL_MICROSECONDS: u32 = 10_000_000;
+const DRIFT_CHECK_CHANNEL: usize = systemtimer::FREE_CHANNELS[0];
+const MAX_DRIFT_PPM: i64 = 500;
+
+const NANOSECONDS_PER_SECOND: u128 = 1_000_000_000;
+
+// CNTP_CTL_EL0 and CNTV_CTL_EL0 bits
+const TIMER_ENABLE_BIT: u64 = 1 << 0;
+const TIMER_INTERRUPT_MASK_BIT: u64 = 1 << 1;
+
+/// Which of the el1 generic timers is used to generate the interrupts.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GenericTimer {
+    Physical,
+    Virtual,
+}
+
+const TIMER: GenericTimer = GenericTimer::Physical;
+
+/// A function that is called on every tick and every expired deadline.
+pub type TimerEventHandler = fn(&mut ExceptionFrame);
+
+static TIMER_EVENT_HANDLER: SpinMutex<Option<TimerEventHandler>> = SpinMutex::new(None);
+
+#[derive(Clone, Copy)]
+struct CoreTimerState {
+    // the period of the tick in counter ticks, or `None` if the tick is stopped
+    tick_period: Option<u64>,
+    next_tick: u64,
+    deadline: Option<u64>,
+}
+
+static CORE_TIMER_STATES: SpinMutex<[CoreTimerState; NUM_CORES]> = SpinMutex::new(
+    [CoreTimerState {
+        tick_period: None,
+        next_tick: 0,
+        deadline: None,
+    }; NUM_CORES],
+);
+
+/// A monotonic free-running counter that time can be measured with.
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+    /// The frequency of the counter in hertz.
+    fn frequency(&self) -> u64;
+    fn counter(&self) -> u64;
+}
+
+/// The system counter of the generic timer as a clock source.
+pub struct GenericCounter;
+
+impl ClockSource for GenericCounter {
+    fn name(&self) -> &'static str {
+        "arm generic timer"
+    }
+
+    fn frequency(&self) -> u64 {
+        counter_frequency()
+    }
+
+    fn counter(&self) -> u64 {
+        counter_value()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ClockSourceKind {
+    GenericCounter = 0,
+    SystemTimer = 1,
+}
+
+static CLOCK_SOURCES: [&dyn ClockSource; 2] = [&GenericCounter, &systemtimer::SystemTimer];
+static SELECTED_CLOCK_SOURCE: AtomicUsize =
+    AtomicUsize::new(ClockSourceKind::GenericCounter as usize);
+// added to the time of each clock source, so that time doesn't jump when switching between them
+static CLOCK_SOURCE_OFFSETS: [AtomicI64; 2] = [AtomicI64::new(0), AtomicI64::new(0)];
+
+/// Returns the clock source that `Instant` is measured with.
+pub fn clock_source() -> &'static dyn ClockSource {
+    CLOCK_SOURCES[SELECTED_CLOCK_SOURCE.load(Ordering::Acquire)]
+}
+
+/// Makes `Instant` use another clock source from now on.
+pub fn select_clock_source(kind: ClockSourceKind) {
+    without_interrupts(|| {
+        // the generic timer can't be used to tell the time if its frequency is not set
+        let now = if clock_source().frequency() != 0 {
+            Instant::now().nanoseconds as i64
+        } else {
+            0
+        };
+        let new_source = CLOCK_SOURCES[kind as usize];
+        let new_source_time = ticks_to_nanoseconds(new_source.counter(), new_source.frequency());
+        // the offset must be visible before the source is selected
+        CLOCK_SOURCE_OFFSETS[kind as usize].store(now - new_source_time as i64, Ordering::Release);
+        SELECTED_CLOCK_SOURCE.store(kind as usize, Ordering::Release);
+    });
+    crate::println!(
+        "[INFO]: using {} as the clock source",
+        clock_source().name()
+    );
+}
+
+/// A point in time measured by the selected clock source.
+/// It starts from zero at boot and is the same for every core.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Instant {
+    nanoseconds: u64,
+}
+
+impl Instant {
+    pub fn now() -> Instant {
+        let selected = SELECTED_CLOCK_SOURCE.load(Ordering::Acquire);
+        let offset = CLOCK_SOURCE_OFFSETS[selected].load(Ordering::Acquire);
+        let source = CLOCK_SOURCES[selected];
+        let nanoseconds = ticks_to_nanoseconds(source.counter(), source.frequency());
+        Instant {
+            nanoseconds: (nanoseconds as i64 + offset) as u64,
+        }
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanoseconds.saturating_sub(earlier.nanoseconds))
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanoseconds = u64::try_from(duration.as_nanos()).ok()?;
+        Some(Instant {
+            nanoseconds: self.nanoseconds.checked_add(nanoseconds)?,
+        })
+    }
+
+    /// The time since boot.
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.nanoseconds)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// The frequency of the system counter in hertz.
+pub fn counter_frequency() -> u64 {
+    let frequency: u64;
+    unsafe { asm!("mrs {}, cntfrq_el0", out(reg) frequency) };
+    frequency
+}
+
+/// The value of the system counter of the generic timer, which counts at `counter_frequency`.
+pub fn counter_value() -> u64 {
+    let value: u64;
+    unsafe { asm!("isb", "mrs {}, cntpct_el0", out(reg) value) };
+    value
+}
+
+fn ticks_to_nanoseconds(ticks: u64, frequency: u64) -> u64 {
+    (ticks as u128 * NANOSECONDS_PER_SECOND / frequency as u128) as u64
+}
+
+/// Converts a number of ticks of the system counter to a duration.
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_nanos(ticks_to_nanoseconds(ticks, counter_frequency()))
+}
+
+/// Converts a duration to ticks of the system counter, saturating on overflow.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks = duration.as_nanos() * counter_frequency() as u128 / NANOSECONDS_PER_SECOND;
+    u64::try_from(ticks).unwrap_or(u64::MAX)
+}
+
+/// Registers the timer interrupt for the calling core
+/// and starts ticking `tick_rate` times per second.
+///
+/// # Safety
+/// Must be called once on each core after the interrupt controllers are initialized.
+pub unsafe fn init(tick_rate: u32) {
+    // the frequency is set by the firmware, and the generic timer is useless without it
+    if counter_frequency() == 0 {
+        if core_id() == 0 {
+            crate::println!("[WARN]: the generic timer frequency is not set");
+            select_clock_source(ClockSourceKind::SystemTimer);
+        }
+        return;
+    }
+
+    let irq = Irq::Local(match TIMER {
+        GenericTimer::Physical => LocalIrq::NonSecurePhysicalTimer,
+        GenericTimer::Virtual => LocalIrq::VirtualTimer,
+    });
+
+    // every core shares the same handler
+    if core_id() == 0 {
+        interrupts::register_irq(irq, handle_timer_irq).unwrap();
+    }
+    interrupts::enable_irq(irq, core_id()).unwrap();
+
+    set_tick_rate(tick_rate);
+}
+
+/// Sets the function that is called on every timer event.
+pub fn set_timer_event_handler(handler: TimerEventHandler) {
+    without_interrupts(|| *TIMER_EVENT_HANDLER.lock() = Some(handler));
+}
+
+/// Changes the tick rate of the calling core. The next tick happens one new period from now.
+pub fn set_tick_rate(tick_rate: u32) {
+    assert!(tick_rate > 0);
+    let period = counter_frequency() / tick_rate as u64;
+    // a period of zero would fire the tick forever
+    if period == 0 {
+        return;
+    }
+    update_core_state(|state, now| {
+        state.tick_period = Some(period);
+        state.next_tick = now + period;
+    });
+}
+
+/// Stops the periodic tick of the calling core, for example when it is idle.
+/// Deadlines still fire.
+pub fn stop_tick() {
+    update_core_state(|state, _| state.tick_period = None);
+}
+
+/// Restarts a tick stopped by `stop_tick` with the given rate.
+pub fn resume_tick(tick_rate: u32) {
+    set_tick_rate(tick_rate);
+}
+
+/// Requests a one-shot timer event on the calling core at `deadline`.
+/// An earlier deadline of the core is replaced.
+pub fn set_deadline(deadline: Instant) {
+    // the comparator counts in ticks of the generic timer
+    // regardless of the clock source that `Instant` uses
+    let remaining_ticks = duration_to_ticks(deadline.duration_since(Instant::now()));
+    update_core_state(|state, now| state.deadline = Some(now.saturating_add(remaining_ticks)));
+}
+
+fn update_core_state(f: impl FnOnce(&mut CoreTimerState, u64)) {
+    without_interrupts(|| {
+        let mut states = CORE_TIMER_STATES.lock();
+        let state = &mut states[core_id()];
+        f(state, counter_value());
+        program_timer(state);
+    })
+}
+
+/// Programs the comparator for the next event of `state`,
+/// or masks the timer interrupt if there is none.
+fn program_timer(state: &CoreTimerState) {
+    let next_event = match (state.tick_period, state.deadline) {
+        (Some(_), Some(deadline)) => Some(state.next_tick.min(deadline)),
+        (Some(_), None) => Some(state.next_tick),
+        (None, deadline) => deadline,
+    };
+
+    unsafe {
+        match next_event {
+            Some(compare_value) => {
+                write_compare_value(compare_value);
+                write_control(TIMER_ENABLE_BIT);
+            }
+            None => write_control(TIMER_ENABLE_BIT | TIMER_INTERRUPT_MASK_BIT),
+        }
+    }
+}
+
+fn handle_timer_irq(_irq: Irq, frame: &mut ExceptionFrame) {
+    {
+        let mut states = CORE_TIMER_STATES.lock();
+        let state = &mut states[core_id()];
+        let now = counter_value();
+
+        if let Some(period) = state.tick_period {
+            if now >= state.next_tick {
+                // skip the ticks that were missed instead of firing them all at once
+                let missed_ticks = (now - state.next_tick) / period;
+                state.next_tick += (missed_ticks + 1) * period;
+            }
+        }
+
+        if matches!(state.deadline, Some(deadline) if now >= deadline) {
+            state.deadline = None;
+        }
+
+        // this also clears the interrupt
+        program_timer(state);
+    }
+
+    let handler = *TIMER_EVENT_HANDLER.lock();
+    if let Some(handler) = handler {
+        handler(frame);
+    }
+}
+
+unsafe fn write_compare_value(value: u64) {
+    match TIMER {
+        GenericTimer::Physical => asm!("msr cntp_cval_el0, {}", in(reg) value),
+        GenericTimer::Virtual => asm!("msr cntv_cval_el0, {}", in(reg) value),
+    }
+}
+
+unsafe fn write_control(value: u64) {
+    match TIMER {
+        GenericTimer::Physical => asm!("msr cntp_ctl_el0, {}", in(reg) value),
+        GenericTimer::Virtual => asm!("msr cntv_ctl_el0, {}", in(reg) value),
+    }
+    asm!("isb");
+}
+
+#[derive(Clone, Copy)]
+struct DriftReference {
+    generic_counter: u64,
+    system_timer: u64,
+}
+
+static DRIFT_REFERENCE: SpinMutex<Option<DriftReference>> = SpinMutex::new(None);
+
+/// Starts periodically comparing the generic timer with the system timer.
+/// If the generic timer stops, the system timer is used as the clock source instead.
+pub fn start_drift_check() {
+    without_interrupts(|| {
+        *DRIFT_REFERENCE.lock() = Some(DriftReference {
+            generic_counter: counter_value(),
+            system_timer: systemtimer::counter(),
+        })
+    });
+    schedule_drift_check();
+    systemtimer::register_compare_handler(DRIFT_CHECK_CHANNEL, handle_drift_check).unwrap();
+}
+
+fn schedule_drift_check() {
+    let next_check =
+        (systemtimer::counter() as u32).wrapping_add(DRIFT_CHECK_INTERVAL_MICROSECONDS);
+    systemtimer::set_compare(DRIFT_CHECK_CHANNEL, next_check);
+}
+
+/// Returns how much faster the generic timer runs than the system timer
+/// in parts per million since `start_drift_check`.
+pub fn measure_drift() -> Option<i64> {
+    let reference = without_interrupts(|| *DRIFT_REFERENCE.lock())?;
+    if counter_frequency() == 0 {
+        return None;
+    }
+    let generic_elapsed = ticks_to_nanoseconds(
+        counter_value() - reference.generic_counter,
+        counter_frequency(),
+    ) as i64;
+    let system_elapsed = ticks_to_nanoseconds(
+        systemtimer::counter() - reference.system_timer,
+        systemtimer::FREQUENCY,
+    ) as i64;
+
+    if system_elapsed == 0 {
+        return None;
+    }
+    Some((generic_elapsed - system_elapsed) * 1_000_000 / system_elapsed)
+}
+
+fn handle_drift_check(_channel: usize, _frame: &mut ExceptionFrame) {
+    schedule_drift_check();
+
+    let drift = match measure_drift() {
+        Some(drift) => drift,
+        None => return,
+    };
+    if drift.abs() <= MAX_DRIFT_PPM {
+        return;
+    }
+
+    crate::println!(
+        "[WARN]: the generic timer drifts {} ppm from the system timer",
+        drift
+    );
+    // a drift of -100% means that the generic counter has stopped
+    if drift <= -1_000_000 + MAX_DRIFT_PPM
+        && SELECTED_CLOCK_SOURCE.load(Ordering::Acquire) == ClockSourceKind::GenericCounter as usize
+    {
+        select_clock_source(ClockSourceKind::SystemTimer);
+    }
+}
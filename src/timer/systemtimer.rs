@@ -0,0 +1,95 @@
+// BCM2835 system timer, see chapter 12 of
+// https://www.raspberrypi.org/app/uploads/2012/02/BCM2835-ARM-Peripherals.pdf
+
+use spin::mutex::spin::SpinMutex;
+
+use super::ClockSource;
+use crate::cpu::{core_id, without_interrupts};
+use crate::exceptions::ExceptionFrame;
+use crate::interrupts::{self, Irq, IrqError};
+
+const SYSTEM_TIMER_BASE_ADDR: usize = 0x3F00_3000;
+
+const CONTROL_STATUS_PTR: *mut u32 = SYSTEM_TIMER_BASE_ADDR as _;
+const COUNTER_LOW_PTR: *const u32 = (SYSTEM_TIMER_BASE_ADDR + 0x04) as _;
+const COUNTER_HIGH_PTR: *const u32 = (SYSTEM_TIMER_BASE_ADDR + 0x08) as _;
+// an array with one compare register for each channel
+const COMPARE_PTR: *mut u32 = (SYSTEM_TIMER_BASE_ADDR + 0x0c) as _;
+
+/// The system timer counts microseconds.
+pub const FREQUENCY: u64 = 1_000_000;
+pub const NUM_CHANNELS: usize = 4;
+/// Channels 0 and 2 are used by the GPU, so only these can be used.
+pub const FREE_CHANNELS: [usize; 2] = [1, 3];
+
+/// A function called when the counter reaches the compare value of a channel.
+/// The match has already been cleared when it is called.
+pub type CompareHandler = fn(usize, &mut ExceptionFrame);
+
+static COMPARE_HANDLERS: SpinMutex<[Option<CompareHandler>; NUM_CHANNELS]> =
+    SpinMutex::new([None; NUM_CHANNELS]);
+
+/// The free-running 64-bit counter of the system timer as a clock source.
+pub struct SystemTimer;
+
+impl ClockSource for SystemTimer {
+    fn name(&self) -> &'static str {
+        "bcm2835 system timer"
+    }
+
+    fn frequency(&self) -> u64 {
+        FREQUENCY
+    }
+
+    fn counter(&self) -> u64 {
+        counter()
+    }
+}
+
+pub fn counter() -> u64 {
+    // the halves can't be read atomically, so retry if the high half changed
+    loop {
+        unsafe {
+            let high = COUNTER_HIGH_PTR.read_volatile();
+            let low = COUNTER_LOW_PTR.read_volatile();
+            if COUNTER_HIGH_PTR.read_volatile() == high {
+                return (high as u64) << 32 | low as u64;
+            }
+        }
+    }
+}
+
+/// Makes `channel` match when the low 32 bits of the counter equal `value`.
+pub fn set_compare(channel: usize, value: u32) {
+    assert!(FREE_CHANNELS.contains(&channel));
+    unsafe { COMPARE_PTR.add(channel).write_volatile(value) }
+}
+
+pub fn clear_match(channel: usize) {
+    assert!(channel < NUM_CHANNELS);
+    // the match bits are cleared by writing a one to them
+    unsafe { CONTROL_STATUS_PTR.write_volatile(1 << channel) }
+}
+
+/// Calls `handler` on the calling core whenever `channel` matches.
+/// The system timer channels are GPU interrupts 0-3.
+pub fn register_compare_handler(channel: usize, handler: CompareHandler) -> Result<(), IrqError> {
+    assert!(FREE_CHANNELS.contains(&channel));
+    let irq = Irq::Gpu(channel as u8);
+
+    without_interrupts(|| COMPARE_HANDLERS.lock()[channel] = Some(handler));
+    clear_match(channel);
+    interrupts::register_irq(irq, handle_compare_irq)?;
+    interrupts::enable_irq(irq, core_id())
+}
+
+fn handle_compare_irq(irq: Irq, frame: &mut ExceptionFrame) {
+    if let Irq::Gpu(channel) = irq {
+        let channel = channel as usize;
+        clear_match(channel);
+        let handler = COMPARE_HANDLERS.lock()[channel];
+        if let Some(handler) = handler {
+            handler(channel, frame);
+        }
+    }
+}